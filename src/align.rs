@@ -0,0 +1,119 @@
+use std::io;
+
+use crate::device::{self, Device, Image};
+use crate::disk;
+use crate::exfat;
+use crate::fat32::spec::BootSec;
+use crate::gpt;
+
+const MIB: u64 = 1 << 20;
+const KIB4: u64 = 4096;
+
+// the filesystem found at a partition's start, in the terms the checks need
+struct Volume {
+    typ: &'static str,
+    tot_sec: u64,
+    bps: u64,
+    clus_sz: u64,
+    data_start: u64, // sectors from the partition start
+}
+
+// check where the partitions and the data regions of their filesystems land
+// against 1 MiB, 4 KiB and `erase_blk` boundaries, and whether the volumes
+// fill their partitions. everything is reported, nothing is changed
+pub fn check(file: &Image, erase_blk: u64) -> io::Result<()> {
+    let disk_secs = device::size(file)? / gpt::SEC_SZ;
+    let mut issues = 0;
+    for part in disk::partitions(&file, disk_secs)? {
+        let start = part.first * gpt::SEC_SZ;
+        println!(
+            "{}: start {} ({} bytes), {} sectors",
+            part.name, part.first, start, part.nsecs
+        );
+        if !start.is_multiple_of(MIB) {
+            issues += 1;
+            let to = start.next_multiple_of(MIB) / gpt::SEC_SZ;
+            if start.is_multiple_of(KIB4) {
+                println!("  start is 4 KiB but not 1 MiB aligned");
+            } else {
+                println!("  start isn't even 4 KiB aligned, writes straddle flash pages");
+            }
+            println!("  recommend: move the partition to sector {}", to);
+        }
+
+        let Some(vol) = volume(file, start)? else {
+            println!("  no FAT32 or exFAT volume");
+            continue;
+        };
+        let data = start + vol.data_start * vol.bps;
+        println!(
+            "  {} volume, data region at byte {}, {} byte clusters",
+            vol.typ, data, vol.clus_sz
+        );
+        if !data.is_multiple_of(erase_blk) {
+            issues += 1;
+            let shift = (data.next_multiple_of(erase_blk) - data) / vol.bps;
+            println!(
+                "  data region is off the {} byte erase block by {} bytes",
+                erase_blk,
+                data % erase_blk
+            );
+            match vol.typ {
+                "FAT32" => println!("  recommend: reformat with {} more reserved sectors", shift),
+                _ => println!(
+                    "  recommend: reformat with the cluster heap {} sectors later",
+                    shift
+                ),
+            }
+        } else if !data.is_multiple_of(vol.clus_sz) {
+            issues += 1;
+            println!("  clusters straddle {} byte boundaries", vol.clus_sz);
+        }
+
+        if vol.tot_sec * vol.bps > part.nsecs * gpt::SEC_SZ {
+            issues += 1;
+            println!(
+                "  volume of {} sectors runs past the partition's end",
+                vol.tot_sec
+            );
+            println!("  recommend: grow the partition or shrink the volume before writing to it");
+        } else if vol.tot_sec * vol.bps < part.nsecs * gpt::SEC_SZ {
+            let spare = part.nsecs - vol.tot_sec * vol.bps / gpt::SEC_SZ;
+            println!("  {} sectors of the partition are left unused", spare);
+            if vol.typ == "FAT32" {
+                println!("  recommend: resize the volume to take them in");
+            }
+        }
+    }
+    say!("[align] {} issues found", issues);
+    Ok(())
+}
+
+fn volume(file: &Image, off: u64) -> io::Result<Option<Volume>> {
+    let mut buf = [0u8; 512];
+    if file.read_exact_at(&mut buf, off).is_err() {
+        return Ok(None);
+    }
+    if let Ok(b) = exfat::spec::BootSec::new(&buf) {
+        if b.is_valid() {
+            return Ok(Some(Volume {
+                typ: "exFAT",
+                tot_sec: b.volumn_length,
+                bps: 1 << b.bytes_per_sector_shift,
+                clus_sz: 1 << (b.bytes_per_sector_shift + b.sectors_per_cluster_shift),
+                data_start: b.cluster_heap_offset as u64,
+            }));
+        }
+    }
+    let b = BootSec::new(&mut buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    if b.bs_fil_sys_type != *b"FAT32   " || b.bs_boot_sign != 0xAA55 || b.bpb_byts_per_sec == 0 {
+        return Ok(None);
+    }
+    Ok(Some(Volume {
+        typ: "FAT32",
+        tot_sec: b.bpb_tot_sec_32 as u64,
+        bps: b.bpb_byts_per_sec as u64,
+        clus_sz: b.cluster_size() as u64,
+        data_start: b.data_start_sector() as u64,
+    }))
+}
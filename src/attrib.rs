@@ -0,0 +1,91 @@
+use std::io;
+
+use crate::device::{Device, Image};
+use crate::exfat;
+use crate::fat32;
+use crate::fio::{self, FsType};
+
+// the DOS attribute bits, laid out the same in FAT32 and exFAT entries
+const READ_ONLY: u8 = 0x01;
+const HIDDEN: u8 = 0x02;
+const SYSTEM: u8 = 0x04;
+const ARCHIVE: u8 = 0x20;
+
+#[derive(Debug, Clone)]
+pub struct Flag {
+    bit: u8,
+    on: bool,
+}
+
+// `+h` sets the hidden bit, `-r` clears read-only and so on
+pub fn parse_flag(s: &str) -> Result<Flag, String> {
+    let mut chars = s.chars();
+    let on = match chars.next() {
+        Some('+') => true,
+        Some('-') => false,
+        _ => return Err(format!("{} should start with + or -", s)),
+    };
+    let bit = match chars.as_str().to_ascii_lowercase().as_str() {
+        "r" => READ_ONLY,
+        "h" => HIDDEN,
+        "s" => SYSTEM,
+        "a" => ARCHIVE,
+        _ => return Err(format!("{} isn't one of r, h, s or a", s)),
+    };
+    Ok(Flag { bit, on })
+}
+
+pub fn read_only(typ: &FsType) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("{:?} volumes are read-only", typ),
+    )
+}
+
+// apply `flags` to the entry at `path`, then print its attributes
+pub fn attrib(file: &Image, typ: &FsType, path: &str, flags: &[Flag]) -> io::Result<()> {
+    let set = flags.iter().filter(|f| f.on).fold(0, |acc, f| acc | f.bit);
+    let clear = flags.iter().filter(|f| !f.on).fold(0, |acc, f| acc | f.bit);
+    let not_found = || io::Error::new(io::ErrorKind::NotFound, format!("{} not found", path));
+
+    let attrs = match typ {
+        FsType::Fat32 => {
+            let mut fio = fat32::fio::Fio::new(file)?;
+            if !flags.is_empty() {
+                fio.writable()?;
+            }
+            let fi = fio::lookup(&mut fio, path)?.ok_or_else(not_found)?;
+            let off = fio.dirent_offset(fi.id) + 11;
+            let mut attr = [0u8];
+            file.read_exact_at(&mut attr, off)?;
+            let old = attr[0];
+            attr[0] = (old & !clear) | set;
+            if attr[0] != old {
+                file.write_all_at(&attr, off)?;
+            }
+            attr[0]
+        }
+        FsType::Exfat => {
+            let mut fio = exfat::Fio::new(file)?;
+            let fi = fio::lookup(&mut fio, path)?.ok_or_else(not_found)?;
+            fio.set_attributes(fi.id, set as u16, clear as u16)? as u8
+        }
+        FsType::Squashfs | FsType::Udf | FsType::Ext2 => return Err(read_only(typ)),
+    };
+    if !flags.is_empty() {
+        file.sync_all()?;
+    }
+
+    let bits = [
+        (ARCHIVE, 'A'),
+        (SYSTEM, 'S'),
+        (HIDDEN, 'H'),
+        (READ_ONLY, 'R'),
+    ];
+    let shown: String = bits
+        .iter()
+        .map(|&(bit, c)| if attrs & bit != 0 { c } else { '-' })
+        .collect();
+    println!("{}  {}", shown, path);
+    Ok(())
+}
@@ -0,0 +1,160 @@
+// one memory budget for every cache there is, the daemon's shared blocks
+// and the mounts' dir listings. each cache says what it holds and evicts
+// while over; when the budget runs out, the caches holding more than their
+// weighted share of it give way first
+
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+
+use crate::table;
+
+// the budget when --cache-mem isn't given
+const BUDGET: u64 = 256 << 20;
+
+static LIMIT: AtomicU64 = AtomicU64::new(BUDGET);
+static CACHES: Mutex<Vec<Arc<Counts>>> = Mutex::new(vec![]);
+
+pub fn set_budget(bytes: u64) {
+    LIMIT.store(bytes, Ordering::Relaxed);
+}
+
+// only mounts have caches, there are none without the fuse feature
+#[allow(dead_code)]
+struct Counts {
+    kind: &'static str,
+    weight: u64,
+    bytes: AtomicU64,
+    evictions: AtomicU64,
+    evicted_bytes: AtomicU64,
+}
+
+impl Counts {
+    fn new(kind: &'static str, weight: u64) -> Arc<Self> {
+        Arc::new(Counts {
+            kind,
+            weight,
+            bytes: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+            evicted_bytes: AtomicU64::new(0),
+        })
+    }
+}
+
+// a cache's share of the budget. what it holds is given back when dropped,
+// its evictions are added to those of the caches of its kind dropped before
+#[allow(dead_code)]
+pub struct Cache(Arc<Counts>);
+
+#[allow(dead_code)]
+impl Cache {
+    // a cache of `kind` as the stats name it, getting `weight` shares of
+    // the budget
+    pub fn new(kind: &'static str, weight: u64) -> Self {
+        let counts = Counts::new(kind, weight);
+        CACHES.lock().unwrap().push(counts.clone());
+        Cache(counts)
+    }
+
+    pub fn charge(&self, bytes: u64) {
+        self.0.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn release(&self, bytes: u64) {
+        self.0.bytes.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    pub fn evicted(&self, bytes: u64) {
+        self.release(bytes);
+        self.0.evictions.fetch_add(1, Ordering::Relaxed);
+        self.0.evicted_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    // whether this cache is to evict: the budget is used up and it holds
+    // more than its share, or no cache does and it's the one growing
+    pub fn over(&self) -> bool {
+        let caches = CACHES.lock().unwrap();
+        let held = |c: &Counts| c.bytes.load(Ordering::Relaxed);
+        let limit = LIMIT.load(Ordering::Relaxed);
+        if caches.iter().map(|c| held(c)).sum::<u64>() <= limit {
+            return false;
+        }
+        let weights: u64 = caches
+            .iter()
+            .filter(|c| held(c) > 0)
+            .map(|c| c.weight)
+            .sum();
+        let share = |c: &Counts| limit * c.weight / weights.max(1);
+        held(&self.0) > share(&self.0) || caches.iter().all(|c| held(c) <= share(c))
+    }
+}
+
+impl Drop for Cache {
+    // the dropped ones of a kind are kept as one with no weight and
+    // nothing held, so the list only grows by kind
+    fn drop(&mut self) {
+        let mut caches = CACHES.lock().unwrap();
+        caches.retain(|c| !Arc::ptr_eq(c, &self.0));
+        let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let retired = match caches
+            .iter()
+            .find(|c| c.kind == self.0.kind && c.weight == 0)
+        {
+            Some(retired) => retired.clone(),
+            None => {
+                let retired = Counts::new(self.0.kind, 0);
+                caches.push(retired.clone());
+                retired
+            }
+        };
+        retired
+            .evictions
+            .fetch_add(get(&self.0.evictions), Ordering::Relaxed);
+        retired
+            .evicted_bytes
+            .fetch_add(get(&self.0.evicted_bytes), Ordering::Relaxed);
+    }
+}
+
+// what each kind of cache holds and has evicted, for stats::lines
+pub fn lines() -> Vec<String> {
+    let caches = CACHES.lock().unwrap();
+    if caches.is_empty() {
+        return vec![];
+    }
+    let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+    // (bytes, caches, evictions, evicted bytes) by kind
+    let mut kinds: Vec<(&str, [u64; 4])> = vec![];
+    for c in caches.iter() {
+        let at = match kinds.iter().position(|(kind, _)| *kind == c.kind) {
+            Some(at) => at,
+            None => {
+                kinds.push((c.kind, [0; 4]));
+                kinds.len() - 1
+            }
+        };
+        let n = &mut kinds[at].1;
+        n[0] += get(&c.bytes);
+        n[1] += (get(&c.bytes) > 0) as u64;
+        n[2] += get(&c.evictions);
+        n[3] += get(&c.evicted_bytes);
+    }
+    let total: u64 = kinds.iter().map(|(_, n)| n[0]).sum();
+    let mut lines = vec![format!(
+        "[stats] caches: {} of {} held",
+        table::size(total, false),
+        table::size(LIMIT.load(Ordering::Relaxed), false)
+    )];
+    for (kind, n) in kinds {
+        lines.push(format!(
+            "[stats] {} cache: {} held, {} in use, {} evictions freeing {}",
+            kind,
+            table::size(n[0], false),
+            n[1],
+            n[2],
+            table::size(n[3], false)
+        ));
+    }
+    lines
+}
@@ -0,0 +1,243 @@
+// find files by their signatures at the starts of a volume's free clusters
+// and copy them out. each is taken to lie contiguous on the device from the
+// cluster it starts in, the way deleted files mostly do
+
+use std::{fs, io, path::Path};
+
+use crate::device::Device;
+use crate::fio::{self, FsType};
+use crate::space;
+
+const CHUNK_SZ: usize = 1 << 20;
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Jpeg,
+    Png,
+    Mp4,
+    Zip,
+}
+
+fn be16(b: &[u8]) -> usize {
+    u16::from_be_bytes([b[0], b[1]]) as usize
+}
+
+fn be32(b: &[u8]) -> usize {
+    u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as usize
+}
+
+impl Kind {
+    // the kind of file starting with `head`, 8 bytes or more
+    fn of(head: &[u8]) -> Option<Kind> {
+        if head.starts_with(&[0xFF, 0xD8, 0xFF]) {
+            Some(Kind::Jpeg)
+        } else if head.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some(Kind::Png)
+        } else if head.starts_with(b"PK\x03\x04") {
+            Some(Kind::Zip)
+        } else if &head[4..8] == b"ftyp" {
+            Some(Kind::Mp4)
+        } else {
+            None
+        }
+    }
+
+    fn ext(self) -> &'static str {
+        match self {
+            Kind::Jpeg => "jpg",
+            Kind::Png => "png",
+            Kind::Mp4 => "mp4",
+            Kind::Zip => "zip",
+        }
+    }
+
+    // where the file read from its start into `data` ends, none when that
+    // isn't in `data` yet. `p` is where the last look got to, `data` has
+    // only grown since
+    fn end(self, data: &[u8], p: &mut Parse) -> Option<usize> {
+        match self {
+            Kind::Jpeg => jpeg_end(data, p),
+            Kind::Png => png_end(data, p),
+            Kind::Mp4 => mp4_end(data, p),
+            Kind::Zip => zip_end(data, p),
+        }
+    }
+}
+
+// how far the end was looked for in what's been read, so the next chunk
+// is gone on from there rather than from the start
+#[derive(Default)]
+struct Parse {
+    at: usize,
+    coded: bool, // in the coded data of a JPEG scan
+}
+
+// segment by segment up to EOI, so that the EOI of an embedded thumbnail
+// isn't taken for the end. after SOS, the coded data runs up to the next
+// marker other than a stuffed 0xFF or a restart
+fn jpeg_end(data: &[u8], p: &mut Parse) -> Option<usize> {
+    let mut i = p.at.max(2);
+    loop {
+        if p.coded {
+            while i + 1 < data.len()
+                && !(data[i] == 0xFF && data[i + 1] != 0 && !(0xD0..=0xD7).contains(&data[i + 1]))
+            {
+                i += 1;
+            }
+            if i + 1 >= data.len() {
+                break;
+            }
+            p.coded = false;
+        }
+        if i + 4 > data.len() {
+            break;
+        }
+        if data[i] != 0xFF {
+            return Some(i);
+        }
+        match data[i + 1] {
+            0xD9 => return Some(i + 2),
+            0xFF => i += 1,
+            0x01 | 0xD0..=0xD7 => i += 2,
+            0xDA => {
+                i += 2 + be16(&data[i + 2..]);
+                p.coded = true;
+            }
+            _ => i += 2 + be16(&data[i + 2..]),
+        }
+    }
+    p.at = i;
+    None
+}
+
+// chunk by chunk up to IEND
+fn png_end(data: &[u8], p: &mut Parse) -> Option<usize> {
+    let mut i = p.at.max(8);
+    while i + 8 <= data.len() {
+        let typ = &data[i + 4..i + 8];
+        if !typ.iter().all(u8::is_ascii_alphabetic) {
+            return Some(i);
+        }
+        let next = i + 12 + be32(&data[i..]);
+        if typ == b"IEND" {
+            if next <= data.len() {
+                return Some(next);
+            }
+            break;
+        }
+        i = next;
+    }
+    p.at = i;
+    None
+}
+
+// top level box by box, the file ends where what follows isn't a box
+fn mp4_end(data: &[u8], p: &mut Parse) -> Option<usize> {
+    let mut i = p.at;
+    while i + 16 <= data.len() {
+        let typ = &data[i + 4..i + 8];
+        if !typ
+            .iter()
+            .all(|&b| b.is_ascii_alphanumeric() || b == b' ' || b == 0xA9)
+        {
+            return Some(i);
+        }
+        let size = match be32(&data[i..]) {
+            // runs to the end of the file, which can't be told
+            0 => break,
+            1 => u64::from_be_bytes(data[i + 8..i + 16].try_into().unwrap()) as usize,
+            size => size,
+        };
+        if size < 8 {
+            return Some(i);
+        }
+        i = i.saturating_add(size);
+    }
+    p.at = i;
+    None
+}
+
+// up to the end of central directory record and its comment
+fn zip_end(data: &[u8], p: &mut Parse) -> Option<usize> {
+    let Some(at) = data[p.at..].windows(4).position(|w| w == b"PK\x05\x06") else {
+        // the signature may straddle what's read and what's next
+        p.at = data.len().saturating_sub(3);
+        return None;
+    };
+    let at = p.at + at;
+    p.at = at;
+    if at + 22 > data.len() {
+        return None;
+    }
+    let end = at + 22 + u16::from_le_bytes([data[at + 20], data[at + 21]]) as usize;
+    (end <= data.len()).then_some(end)
+}
+
+// the file of `kind` at `off`, read on until its end shows up or `max`
+// bytes or the end of the volume are reached. whether the end was found
+fn carve_one(
+    device: &dyn Device,
+    off: u64,
+    volume_len: u64,
+    kind: Kind,
+    max: u64,
+) -> io::Result<(Vec<u8>, bool)> {
+    let max = max.min(volume_len - off) as usize;
+    let mut data = vec![];
+    let mut parse = Parse::default();
+    loop {
+        if let Some(end) = kind.end(&data, &mut parse) {
+            data.truncate(end);
+            return Ok((data, true));
+        }
+        if data.len() >= max {
+            data.truncate(max);
+            return Ok((data, false));
+        }
+        let from = data.len();
+        data.resize(from + CHUNK_SZ.min(max - from), 0);
+        device.read_exact_at(&mut data[from..], off + from as u64)?;
+    }
+}
+
+pub struct CarveOpts {
+    pub min_size: u64,
+    pub max_size: u64,
+}
+
+// JPEG, PNG, MP4 and ZIP files starting in free clusters, written to `dest`
+// named after their device offsets. files without their end found within
+// max_size are cut there, those under min_size are left out
+pub fn carve(device: &dyn Device, typ: &FsType, dest: &Path, opts: &CarveOpts) -> io::Result<()> {
+    let clus_sz = fio::open(device, typ)?.volume()?.clus_sz as u64;
+    let space = space::scan(device, typ)?;
+    fs::create_dir_all(dest)?;
+
+    let (mut found, mut total) = (0, 0);
+    let mut head = [0u8; 16];
+    for &(run, len) in space.free.iter() {
+        let mut off = run;
+        while off < run + len {
+            device.read_exact_at(&mut head, off)?;
+            let Some(kind) = Kind::of(&head) else {
+                off += clus_sz;
+                continue;
+            };
+            let (data, whole) = carve_one(device, off, space.volume_len, kind, opts.max_size)?;
+            if (data.len() as u64) < opts.min_size {
+                off += clus_sz;
+                continue;
+            }
+            let name = format!("{}.{}", off, kind.ext());
+            fs::write(dest.join(&name), &data)?;
+            let cut = if whole { "" } else { ", no end found, cut" };
+            println!("{}: {} bytes{}", name, data.len(), cut);
+            found += 1;
+            total += data.len() as u64;
+            // the clusters it takes up hold no other files
+            off += (data.len() as u64).div_ceil(clus_sz).max(1) * clus_sz;
+        }
+    }
+    say!("[carve] {} files, {} bytes written", found, total);
+    Ok(())
+}
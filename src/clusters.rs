@@ -0,0 +1,537 @@
+// where a file's clusters lie on the device, to get at its data with dd
+// when the filesystem can't be trusted to
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::device::{Device, Image};
+use crate::disk;
+use crate::exfat;
+use crate::extract::hex;
+use crate::fat32;
+use crate::fatdump::{self, Ent};
+use crate::fio::{self, Finfo, FsType};
+use crate::{fsck, fsck_exfat};
+
+// what copy_clusters reads at a time
+const CHUNK_SZ: usize = 1 << 20;
+
+fn find(fio: &mut dyn fio::Fio, path: &str) -> io::Result<Finfo> {
+    fio::lookup(fio, path)?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{}: not found", path)))
+}
+
+// the file or dir at `path` as (offset, length) byte runs of its clusters,
+// in file order. a file's last run ends at its size, not with the slack
+// past it, a dir's clusters are whole. offsets count from the start of
+// what was opened, the partition table of a whole disk included
+pub fn clusters_of(device: &Image, path: &str) -> io::Result<Vec<(u64, u64)>> {
+    let (runs, fi): (Vec<(u64, u64)>, Finfo) = match disk::detect(device, 0) {
+        Some("FAT32") => {
+            let mut fio = fat32::fio::Fio::new(device)?;
+            let fi = find(&mut fio, path)?;
+            if fi.fst_clus == 0 {
+                return Ok(vec![]);
+            }
+            let fat = fio.read_fat_copy(0)?;
+            let chain =
+                fat32::fio::chain_in(&fat, fio.clus_cnt() + 1, fi.fst_clus).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "the cluster chain is broken")
+                })?;
+            let clus_sz = fio.clus_sz() as u64;
+            let runs = fio::clus_runs(&chain)
+                .into_iter()
+                .map(|(first, cnt)| (fio.clus_offset(first), cnt as u64 * clus_sz))
+                .collect();
+            (runs, fi)
+        }
+        Some("exFAT") => {
+            let mut fio = exfat::Fio::new(device)?;
+            let fi = find(&mut fio, path)?;
+            let clus_sz = fio.clus_sz() as u64;
+            let runs = fio::clus_runs(&fio.clusters_of(&fi)?)
+                .into_iter()
+                .map(|(first, cnt)| (fio.clus_offset(first), cnt as u64 * clus_sz))
+                .collect();
+            (runs, fi)
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "not a FAT32 or exFAT volume",
+            ))
+        }
+    };
+    let base = device.base();
+    let mut left = if fi.is_dir { u64::MAX } else { fi.size };
+    Ok(runs
+        .into_iter()
+        .map_while(|(off, len)| {
+            let len = len.min(left);
+            left -= len;
+            (len > 0).then_some((base + off, len))
+        })
+        .collect())
+}
+
+// what following chains by hand takes: every FAT entry decoded (free by
+// the bitmap on exFAT), the cluster size and where cluster 2 starts
+pub struct Heap {
+    pub ents: Vec<Ent>,
+    pub clus_sz: u64,
+    pub base: u64,
+    typ: FsType,
+    digest: String, // of the FAT, and the bitmap on exFAT
+}
+
+impl Heap {
+    pub fn new(device: &Image) -> io::Result<Heap> {
+        match disk::detect(device, 0) {
+            Some("FAT32") => {
+                let fio = fat32::fio::Fio::new(device)?;
+                let last_clus = fio.clus_cnt() + 1;
+                let fat = fio.read_fat_copy(0)?;
+                let mut hasher = Sha256::new();
+                fat.iter().for_each(|raw| hasher.update(raw.to_le_bytes()));
+                let ents = fat
+                    .into_iter()
+                    .take(last_clus as usize + 1)
+                    .map(|raw| fatdump::fat32_ent(raw, last_clus))
+                    .collect();
+                Ok(Heap {
+                    ents,
+                    clus_sz: fio.clus_sz() as u64,
+                    base: fio.clus_offset(2),
+                    typ: FsType::Fat32,
+                    digest: hex(&hasher.finalize()),
+                })
+            }
+            Some("exFAT") => {
+                let mut fio = exfat::Fio::new(device)?;
+                let last_clus = fio.clus_cnt() + 1;
+                let bitmap = fio.read_bitmap()?;
+                let fat = fio.read_fat_table()?;
+                let mut hasher = Sha256::new();
+                fat.iter().for_each(|raw| hasher.update(raw.to_le_bytes()));
+                hasher.update(&bitmap);
+                let allocated =
+                    |no: usize| no >= 2 && bitmap[(no - 2) / 8] & (1 << ((no - 2) % 8)) != 0;
+                let ents = fat
+                    .into_iter()
+                    .enumerate()
+                    .map(|(no, raw)| fatdump::exfat_ent(raw, last_clus, allocated(no)))
+                    .collect();
+                Ok(Heap {
+                    ents,
+                    clus_sz: fio.clus_sz() as u64,
+                    base: fio.clus_offset(2),
+                    typ: FsType::Exfat,
+                    digest: hex(&hasher.finalize()),
+                })
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "not a FAT32 or exFAT volume",
+            )),
+        }
+    }
+
+    // the cluster holding byte `off` of the volume, none outside the heap
+    fn clus_at(&self, off: u64) -> Option<u32> {
+        let clus = off.checked_sub(self.base)? / self.clus_sz + 2;
+        (clus < self.ents.len() as u64).then_some(clus as u32)
+    }
+
+    // the chain from `first` up to its end, or up to `want` clusters
+    fn chain(&self, first: u32, want: usize) -> (Vec<u32>, String) {
+        let mut chain = vec![first];
+        let mut seen = BTreeSet::from([first]);
+        while chain.len() < want {
+            let last = *chain.last().unwrap();
+            match self.ents[last as usize] {
+                Ent::Next(next) if seen.insert(next) => chain.push(next),
+                Ent::Next(next) => return (chain, format!("loops back to {}", next)),
+                Ent::Eoc => return (chain, String::from("ends")),
+                ref ent => return (chain, format!("runs into a {} entry at {}", ent, last)),
+            }
+        }
+        (chain, String::from("is cut at the length"))
+    }
+
+    // which file or dir each cluster belongs to, what fsck's walk of the
+    // tree finds
+    pub fn owners(&self, device: &Image) -> io::Result<Owners> {
+        match self.typ {
+            FsType::Fat32 => {
+                let mut fio = fat32::fio::Fio::new(device)?;
+                let mut fsck = fsck::Fsck::new(&mut fio)?;
+                fsck.walk()?;
+                Ok(fsck.owners)
+            }
+            _ => {
+                let mut fio = exfat::Fio::new(device)?;
+                let mut fsck = fsck_exfat::Fsck::new(&mut fio)?;
+                fsck.walk()?;
+                Ok(fsck.owners)
+            }
+        }
+    }
+
+    // the owners, read from `index` when it was saved there for the same
+    // FAT, else walked for and saved there. changes that leave the FAT alone,
+    // such as renames, aren't noticed
+    pub fn owners_indexed(&self, device: &Image, index: Option<&Path>) -> io::Result<Owners> {
+        let Some(index) = index else {
+            return self.owners(device);
+        };
+        if let Some(owners) = Owners::load(index, &self.digest, self.ents.len())? {
+            return Ok(owners);
+        }
+        let owners = self.owners(device)?;
+        owners.save(index, &self.digest)?;
+        Ok(owners)
+    }
+
+    // what cluster `clus` holds, the owners are only asked when it's in use
+    fn describe(
+        &self,
+        clus: u32,
+        owners: impl FnOnce() -> io::Result<Owners>,
+    ) -> io::Result<String> {
+        Ok(match self.ents[clus as usize] {
+            Ent::Free => String::from("free"),
+            _ => owners()?
+                .of(clus)
+                .unwrap_or_else(|| String::from("allocated, in no file")),
+        })
+    }
+}
+
+const NO_OWNER: u32 = u32::MAX;
+
+// a path the way it's told, the exFAT bitmap and up-case table are claimed
+// under names no file can have, see fsck_exfat::Fsck::walk
+fn shown(path: &str) -> &str {
+    match path {
+        "/<bitmap>" => "metadata, the allocation bitmap",
+        "/<up-case table>" => "metadata, the up-case table",
+        _ => path,
+    }
+}
+
+// which file or dir each cluster of a volume belongs to, what fsck follows
+// chains into. the first to claim a cluster keeps it, the others that claim
+// it too are kept as cross-linked with it
+pub struct Owners {
+    paths: Vec<String>,
+    owner: Vec<u32>,                 // index into paths by cluster, NO_OWNER for none
+    shared: BTreeMap<u32, Vec<u32>>, // cluster -> the later claims, cross-links
+}
+
+// what claiming a cluster found
+pub enum Claim<'a> {
+    Claimed,
+    // the claimer had it already, its chain loops
+    Looped,
+    // another had it first, the path of that one
+    CrossLinked(&'a str),
+}
+
+impl Owners {
+    // for clusters 0 to `len` - 1
+    pub fn new(len: usize) -> Owners {
+        Owners {
+            paths: vec![],
+            owner: vec![NO_OWNER; len],
+            shared: BTreeMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.owner.len()
+    }
+
+    // a file or dir to claim clusters for
+    pub fn add(&mut self, path: &str) -> u32 {
+        self.paths.push(path.to_string());
+        self.paths.len() as u32 - 1
+    }
+
+    pub fn claim(&mut self, clus: u32, who: u32) -> Claim<'_> {
+        match self.owner[clus as usize] {
+            NO_OWNER => {
+                self.owner[clus as usize] = who;
+                Claim::Claimed
+            }
+            owner if owner == who => Claim::Looped,
+            owner => {
+                self.shared.entry(clus).or_default().push(who);
+                Claim::CrossLinked(&self.paths[owner as usize])
+            }
+        }
+    }
+
+    pub fn is_claimed(&self, clus: u32) -> bool {
+        self.owner
+            .get(clus as usize)
+            .is_some_and(|&o| o != NO_OWNER)
+    }
+
+    // the path holding `clus`, and the others cross-linked with it
+    pub fn of(&self, clus: u32) -> Option<String> {
+        let owner = *self.owner.get(clus as usize)?;
+        if owner == NO_OWNER {
+            return None;
+        }
+        let mut ret = shown(&self.paths[owner as usize]).to_string();
+        if let Some(others) = self.shared.get(&clus) {
+            let others: Vec<&str> = others
+                .iter()
+                .map(|&o| shown(&self.paths[o as usize]))
+                .collect();
+            ret += &format!(", cross-linked with {}", others.join(", "));
+        }
+        Some(ret)
+    }
+
+    // a header line with the digest of the FAT, then a line for each run of
+    // clusters with one owner: first cluster, count and path, and one for
+    // each cross-linked claim after them. a path has its backslashes and
+    // line breaks escaped
+    fn save(&self, path: &Path, digest: &str) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        writeln!(out, "{} {} {}", INDEX_MAGIC, digest, self.owner.len())?;
+        let mut clus = 0;
+        while clus < self.owner.len() {
+            let idx = self.owner[clus];
+            let cnt = self.owner[clus..].iter().take_while(|&&o| o == idx).count();
+            if idx != NO_OWNER {
+                writeln!(
+                    out,
+                    "{} {} {}",
+                    clus,
+                    cnt,
+                    escape(&self.paths[idx as usize])
+                )?;
+            }
+            clus += cnt;
+        }
+        for (clus, others) in self.shared.iter() {
+            for &idx in others {
+                writeln!(out, "{} 1 {}", clus, escape(&self.paths[idx as usize]))?;
+            }
+        }
+        out.flush()
+    }
+
+    // none when there's no index at `path` yet, or it's for another FAT
+    fn load(path: &Path, digest: &str, len: usize) -> io::Result<Option<Owners>> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let mut lines = text.lines();
+        if lines.next() != Some(&format!("{} {} {}", INDEX_MAGIC, digest, len)) {
+            return Ok(None);
+        }
+        let bad = |no: usize| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: bad line {}", path.display(), no + 2),
+            )
+        };
+        let mut owners = Owners::new(len);
+        let mut idx_of: BTreeMap<String, u32> = BTreeMap::new();
+        for (no, line) in lines.enumerate() {
+            let mut parts = line.splitn(3, ' ');
+            let (Some(first), Some(cnt), Some(fpath)) = (parts.next(), parts.next(), parts.next())
+            else {
+                return Err(bad(no));
+            };
+            let (Ok(first), Ok(cnt)) = (first.parse::<usize>(), cnt.parse::<usize>()) else {
+                return Err(bad(no));
+            };
+            if first + cnt > len {
+                return Err(bad(no));
+            }
+            let fpath = unescape(fpath).ok_or_else(|| bad(no))?;
+            let idx = match idx_of.get(&fpath) {
+                Some(&idx) => idx,
+                None => {
+                    let idx = owners.add(&fpath);
+                    idx_of.insert(fpath, idx);
+                    idx
+                }
+            };
+            for clus in first..first + cnt {
+                owners.claim(clus as u32, idx);
+            }
+        }
+        Ok(Some(owners))
+    }
+}
+
+// a path on one line of the index
+fn escape(path: &str) -> String {
+    path.replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+fn unescape(line: &str) -> Option<String> {
+    let mut ret = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next()? {
+                '\\' => ret.push('\\'),
+                'n' => ret.push('\n'),
+                'r' => ret.push('\r'),
+                _ => return None,
+            },
+            c => ret.push(c),
+        }
+    }
+    Some(ret)
+}
+
+// 2 since paths are escaped
+const INDEX_MAGIC: &str = "fat32x-owners-2";
+
+// the clusters from `first` on, enough for `length` bytes or all the chain
+// holds, and how the chain went. the FAT chain is followed while there is
+// one, a file left without one (deleted, or exFAT's NoFatChain) is taken to
+// be contiguous, which only a length says how far
+fn clusters_from(heap: &Heap, first: u32, length: Option<u64>) -> io::Result<(Vec<u32>, String)> {
+    if !(2..heap.ents.len() as u32).contains(&first) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("cluster {} isn't in the volume", first),
+        ));
+    }
+    let want = match length {
+        Some(len) => len.div_ceil(heap.clus_sz) as usize,
+        None => usize::MAX,
+    };
+    match heap.ents[first as usize] {
+        Ent::Next(_) | Ent::Eoc => {
+            let (chain, how) = heap.chain(first, want);
+            Ok((chain, format!("the chain {}", how)))
+        }
+        _ if length.is_none() => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "cluster {} has no chain, carve-chain with a length reads it as contiguous",
+                first
+            ),
+        )),
+        _ => {
+            let end = (first as usize + want).min(heap.ents.len());
+            Ok((
+                (first..end as u32).collect(),
+                String::from("taken as contiguous"),
+            ))
+        }
+    }
+}
+
+// `clusters` in order to `out`, cut at `length` bytes. how many bytes
+fn copy_clusters(
+    device: &Image,
+    heap: &Heap,
+    clusters: &[u32],
+    length: Option<u64>,
+    out: &mut dyn Write,
+) -> io::Result<u64> {
+    let mut left = length.unwrap_or(u64::MAX);
+    let mut buf = vec![0u8; CHUNK_SZ];
+    for (run, cnt) in fio::clus_runs(clusters) {
+        let start = heap.base + (run - 2) as u64 * heap.clus_sz;
+        let len = (cnt as u64 * heap.clus_sz).min(left);
+        // a run may be most of the volume, it's read a chunk at a time
+        let mut done = 0;
+        while done < len {
+            let chunk = &mut buf[..(len - done).min(CHUNK_SZ as u64) as usize];
+            device.read_exact_at(chunk, start + done)?;
+            out.write_all(chunk)?;
+            done += chunk.len() as u64;
+        }
+        left -= len;
+    }
+    out.flush()?;
+    Ok(length.unwrap_or(u64::MAX) - left)
+}
+
+// write out the data from cluster `first` on, `length` bytes or all the
+// chain holds, see clusters_from
+pub fn carve_chain(device: &Image, first: u32, length: Option<u64>, dest: &Path) -> io::Result<()> {
+    let heap = Heap::new(device)?;
+    let (clusters, how) = clusters_from(&heap, first, length)?;
+    let bytes = copy_clusters(device, &heap, &clusters, length, &mut File::create(dest)?)?;
+    say!(
+        "[carve-chain] {} bytes from {} clusters, {}",
+        bytes,
+        clusters.len(),
+        how
+    );
+    Ok(())
+}
+
+// every cluster of the FAT chain from `first` on streamed to `out`, what
+// was read said on stderr as the data may be on stdout
+pub fn read_chain(device: &Image, first: u32, out: &mut dyn Write) -> io::Result<()> {
+    let heap = Heap::new(device)?;
+    let (clusters, how) = clusters_from(&heap, first, None)?;
+    let bytes = copy_clusters(device, &heap, &clusters, None, out)?;
+    eprintln!(
+        "[read-chain] {} bytes from {} clusters, {}",
+        bytes,
+        clusters.len(),
+        how
+    );
+    Ok(())
+}
+
+// what byte `off` of the device is part of: a file or dir, free space or the
+// filesystem's own structures. the device is what was opened, a whole disk's
+// partition table counts
+pub fn whose(device: &Image, off: u64, index: Option<&Path>) -> io::Result<String> {
+    let heap = Heap::new(device)?;
+    let Some(off) = off.checked_sub(device.base()) else {
+        return Ok(String::from("before the volume"));
+    };
+    let Some(clus) = heap.clus_at(off) else {
+        let what = if off < heap.base {
+            "metadata, before the cluster heap"
+        } else {
+            "past the cluster heap"
+        };
+        return Ok(what.to_string());
+    };
+    let who = heap.describe(clus, || heap.owners_indexed(device, index))?;
+    Ok(format!(
+        "cluster {} +{}: {}",
+        clus,
+        (off - heap.base) % heap.clus_sz,
+        who
+    ))
+}
+
+// what cluster `clus` of the volume holds
+pub fn owner(device: &Image, clus: u32, index: Option<&Path>) -> io::Result<String> {
+    let heap = Heap::new(device)?;
+    if !(2..heap.ents.len() as u32).contains(&clus) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("cluster {} isn't in the volume", clus),
+        ));
+    }
+    heap.describe(clus, || heap.owners_indexed(device, index))
+}
@@ -0,0 +1,149 @@
+// References:
+// [1] https://download.microsoft.com/download/1/6/1/161ba512-40e2-4cc9-843a-923143f3456c/fatgen103.doc
+
+use std::{fs::File, io, path::Path};
+
+use crate::fat32::fio::{chain_in, Fio};
+use crate::fat32::spec::ClusNo;
+use crate::fio::{clus_runs, Finfo};
+use crate::fsck::{set_ent, sync_fats, ENT_MASK, EOC};
+use crate::journal::Writes;
+
+struct Placed {
+    path: String,
+    fi: Finfo,
+    chain: Vec<ClusNo>,
+}
+
+// move every fragmented file into the first free run long enough to hold it.
+// data goes to free clusters first, then the FATs and the dir entry are
+// switched over in one journaled commit per file, so an interrupted run leaves
+// a consistent volume and `journal::rollback` can undo all of it.
+// dirs are left where they are, moving one means rewriting its children's `..`
+pub fn defrag(fio: &mut Fio, dry_run: bool, journal_path: &Path) -> io::Result<()> {
+    if !dry_run {
+        fio.writable()?;
+    }
+    let mut fat = fio.read_fat_copy(0)?;
+    let max_clus = (fio.clus_cnt() + 1).min(fat.len() as u32 - 1);
+
+    let mut files = vec![];
+    let root = fio.root_clusno;
+    walk(fio, &fat, max_clus, root, "", &mut files)?;
+    let fragmented: Vec<Placed> = files
+        .into_iter()
+        .filter(|f| clus_runs(&f.chain).len() > 1)
+        .collect();
+    say!("[defrag] {} fragmented files", fragmented.len());
+
+    let mut journal = if dry_run {
+        None
+    } else {
+        Some(File::create(journal_path)?)
+    };
+    let (mut moved, mut sectors) = (0, 0);
+    for f in fragmented {
+        let n = f.chain.len() as u32;
+        let start = match find_run(&fat, max_clus, n) {
+            Some(start) => start,
+            None => {
+                eprintln!("[defrag] {}: no free run of {} clusters", f.path, n);
+                continue;
+            }
+        };
+        say!(
+            "[defrag] {}: {} clusters in {} fragments -> {}..={}",
+            f.path,
+            n,
+            clus_runs(&f.chain).len(),
+            start,
+            start + n - 1
+        );
+
+        let old = fat.clone();
+        for clus in start..start + n - 1 {
+            set_ent(&mut fat, clus, clus + 1);
+        }
+        set_ent(&mut fat, start + n - 1, EOC);
+        for &clus in f.chain.iter() {
+            set_ent(&mut fat, clus, 0);
+        }
+        moved += 1;
+        let journal = match journal.as_mut() {
+            Some(journal) => journal,
+            None => continue,
+        };
+
+        for (i, &clus) in f.chain.iter().enumerate() {
+            let data = fio.read_clus(clus)?;
+            fio.device()
+                .write_all_at(&data, fio.clus_offset(start + i as u32))?;
+        }
+        let mut writes = Writes::new(fio.device(), fio.bootsec.bpb_byts_per_sec as u64);
+        sync_fats(&mut writes, fio, &old, &fat)?;
+        let off = fio.dirent_offset(f.fi.id);
+        writes.patch(off + 20, &((start >> 16) as u16).to_le_bytes())?;
+        writes.patch(off + 26, &(start as u16).to_le_bytes())?;
+        sectors += writes.commit(journal)?;
+    }
+
+    if dry_run {
+        say!("[defrag] dry run, {} files would be moved", moved);
+    } else {
+        say!(
+            "[defrag] {} files moved, {} sectors journaled to {}",
+            moved,
+            sectors,
+            journal_path.display()
+        );
+    }
+    Ok(())
+}
+
+fn walk(
+    fio: &mut Fio,
+    fat: &[u32],
+    max_clus: ClusNo,
+    dir: ClusNo,
+    path: &str,
+    files: &mut Vec<Placed>,
+) -> io::Result<()> {
+    for fi in fio.read_dirents(dir)? {
+        if fi.name == "." || fi.name == ".." || fi.fst_clus == 0 {
+            continue;
+        }
+        let fpath = format!("{}/{}", path, fi.name);
+        if fi.is_dir {
+            walk(fio, fat, max_clus, fi.fst_clus, &fpath, files)?;
+        } else if let Some(chain) = chain_in(fat, max_clus, fi.fst_clus) {
+            files.push(Placed {
+                path: fpath,
+                fi,
+                chain,
+            });
+        } else {
+            eprintln!("[defrag] {}: broken chain, run fsck first", fpath);
+        }
+    }
+    Ok(())
+}
+
+// first fit search for `n` consecutive free clusters
+fn find_run(fat: &[u32], max_clus: ClusNo, n: u32) -> Option<ClusNo> {
+    let mut start = 2;
+    let mut len = 0;
+    for clus in 2..=max_clus {
+        if fat[clus as usize] & ENT_MASK == 0 {
+            if len == 0 {
+                start = clus;
+            }
+            len += 1;
+            if len == n {
+                return Some(start);
+            }
+        } else {
+            len = 0;
+        }
+    }
+    None
+}
@@ -1,5 +1,749 @@
-use std::io::{Read, Seek};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Seek, SeekFrom};
+#[cfg(unix)]
+use std::os::unix::fs::FileExt;
+#[cfg(windows)]
+use std::os::windows::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
-pub(crate) trait Device: Seek + Read {}
+use crate::stats::{self, STATS};
+use crate::throttle;
+use crate::trace;
 
-impl Device for std::fs::File {}
+// raw disks on Windows and macOS, and anything opened with O_DIRECT, only
+// take whole blocks at block aligned offsets. 512 bytes is what card readers
+// and USB sticks report, a device reporting larger blocks raises it when
+// opened
+static BLK_SZ: AtomicU64 = AtomicU64::new(512);
+// devices bypass the page cache, see set_odirect
+static ODIRECT: AtomicBool = AtomicBool::new(false);
+// several image files given to open are striped in units this big rather
+// than laid end to end, see set_stripe
+static STRIPE: AtomicU64 = AtomicU64::new(0);
+// a file named like `image.001` is opened with the pieces numbered after
+// it, see set_split
+static SPLIT: AtomicBool = AtomicBool::new(false);
+
+// BLKSSZGET, _IO(0x12, 104) from linux/fs.h
+#[cfg(target_os = "linux")]
+const BLKSSZGET: libc::c_ulong = 0x1268;
+// FILE_FLAG_NO_BUFFERING from winbase.h
+#[cfg(windows)]
+const FILE_FLAG_NO_BUFFERING: u32 = 0x20000000;
+// DKIOCGETBLOCKSIZE and DKIOCGETBLOCKCOUNT, _IOR('d', 24, uint32_t) and
+// _IOR('d', 25, uint64_t) from sys/disk.h
+#[cfg(target_os = "macos")]
+const DKIOCGETBLOCKSIZE: libc::c_ulong = 0x40046418;
+#[cfg(target_os = "macos")]
+const DKIOCGETBLOCKCOUNT: libc::c_ulong = 0x40086419;
+
+// positional access to the underlying volume, so reads never depend on (or
+// disturb) a shared cursor
+pub(crate) trait Device {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+
+    fn read_exact_at(&self, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.read_at(buf, offset) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => {
+                    buf = &mut buf[n..];
+                    offset += n as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        let _ = (buf, offset);
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "device is read-only",
+        ))
+    }
+
+    fn write_all_at(&self, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.write_at(buf, offset) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    buf = &buf[n..];
+                    offset += n as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    // fill `buf` from several device extents (offset, length) laid end to end,
+    // issuing a single request per extent
+    fn read_extents(&self, extents: &[(u64, usize)], buf: &mut [u8]) -> io::Result<()> {
+        let mut filled = 0;
+        for &(offset, len) in extents {
+            self.read_exact_at(&mut buf[filled..filled + len], offset)?;
+            filled += len;
+        }
+        Ok(())
+    }
+
+    // how much of `extents`, laid end to end, holds data that was actually
+    // read, up to the first unreadable byte. all of it unless the device is
+    // set to skip unreadable data, see retry::OnError
+    fn usable_len(&self, extents: &[(u64, usize)]) -> usize {
+        extents.iter().map(|&(_, len)| len).sum()
+    }
+
+    // the known unreadable parts of `extents`, laid end to end, as (start,
+    // len) within them. none unless the device tracks them, see retry::Retry
+    fn bad_spans(&self, _extents: &[(u64, usize)]) -> Vec<(usize, usize)> {
+        vec![]
+    }
+
+    // how many bytes the device holds, none when it can't tell
+    fn len(&self) -> Option<u64> {
+        None
+    }
+}
+
+impl<D: Device + ?Sized> Device for &D {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        (**self).read_at(buf, offset)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        (**self).write_at(buf, offset)
+    }
+
+    fn usable_len(&self, extents: &[(u64, usize)]) -> usize {
+        (**self).usable_len(extents)
+    }
+
+    fn bad_spans(&self, extents: &[(u64, usize)]) -> Vec<(usize, usize)> {
+        (**self).bad_spans(extents)
+    }
+
+    fn len(&self) -> Option<u64> {
+        (**self).len()
+    }
+}
+
+// the `len` bytes of a device starting at `start`, a partition seen as a
+// device of its own
+pub struct Slice<D> {
+    dev: D,
+    start: u64,
+    len: u64,
+}
+
+impl<D: Device> Slice<D> {
+    pub fn new(dev: D, start: u64, len: u64) -> Self {
+        Slice { dev, start, len }
+    }
+
+    fn shift(&self, extents: &[(u64, usize)]) -> Vec<(u64, usize)> {
+        extents
+            .iter()
+            .map(|&(off, len)| (self.start + off, len))
+            .collect()
+    }
+}
+
+impl<D: Device> Device for Slice<D> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let n = self.len.saturating_sub(offset).min(buf.len() as u64) as usize;
+        if n == 0 {
+            return Ok(0);
+        }
+        self.dev.read_at(&mut buf[..n], self.start + offset)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        let n = self.len.saturating_sub(offset).min(buf.len() as u64) as usize;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "write past the end of the partition",
+            ));
+        }
+        self.dev.write_at(&buf[..n], self.start + offset)
+    }
+
+    fn usable_len(&self, extents: &[(u64, usize)]) -> usize {
+        self.dev.usable_len(&self.shift(extents))
+    }
+
+    fn bad_spans(&self, extents: &[(u64, usize)]) -> Vec<(usize, usize)> {
+        self.dev.bad_spans(&self.shift(extents))
+    }
+
+    fn len(&self) -> Option<u64> {
+        Some(self.len)
+    }
+}
+
+// what open hands out: a device or image file, or several image files seen
+// as one
+pub enum Image {
+    File(File),
+    Concat(Concat),
+    Stripe(Stripe),
+    Part(Box<Slice<Image>>), // a partition of a whole disk, see disk::open_volume
+}
+
+impl Image {
+    // the one file behind the image, for what only works on a single device
+    // or image file
+    pub fn file(&self) -> io::Result<&File> {
+        match self {
+            Image::File(file) => Ok(file),
+            _ => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "this only works on a single device or image file",
+            )),
+        }
+    }
+
+    fn files(&self) -> Vec<&File> {
+        match self {
+            Image::File(file) => vec![file],
+            Image::Concat(c) => c.parts.iter().map(|(file, _)| file).collect(),
+            Image::Stripe(s) => s.parts.iter().collect(),
+            Image::Part(part) => part.dev.files(),
+        }
+    }
+
+    pub fn sync_all(&self) -> io::Result<()> {
+        self.files().into_iter().try_for_each(File::sync_all)
+    }
+
+    // where the volume starts in what was opened, past the partition table
+    // of a whole disk
+    pub fn base(&self) -> u64 {
+        match self {
+            Image::Part(part) => part.start,
+            _ => 0,
+        }
+    }
+}
+
+impl Device for Image {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        match self {
+            Image::File(file) => Device::read_at(file, buf, offset),
+            Image::Concat(c) => c.read_at(buf, offset),
+            Image::Stripe(s) => s.read_at(buf, offset),
+            Image::Part(part) => part.read_at(buf, offset),
+        }
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        match self {
+            Image::File(file) => Device::write_at(file, buf, offset),
+            Image::Concat(c) => c.write_at(buf, offset),
+            Image::Stripe(s) => s.write_at(buf, offset),
+            Image::Part(part) => part.write_at(buf, offset),
+        }
+    }
+
+    fn len(&self) -> Option<u64> {
+        size(self).ok()
+    }
+}
+
+// image files laid end to end, like the pieces image.001, image.002, ... a
+// raw acquisition is often split in
+pub struct Concat {
+    parts: Vec<(File, u64)>, // each with the offset it starts at
+    len: u64,
+}
+
+impl Concat {
+    pub fn new(files: Vec<File>) -> io::Result<Self> {
+        let mut parts = vec![];
+        let mut len = 0;
+        for file in files {
+            let sz = file_size(&file)?;
+            parts.push((file, len));
+            len += sz;
+        }
+        Ok(Concat { parts, len })
+    }
+
+    // the part holding byte `offset` and how much of it is left from there
+    fn locate(&self, offset: u64) -> Option<(&File, u64, u64)> {
+        if offset >= self.len {
+            return None;
+        }
+        let i = self.parts.partition_point(|&(_, start)| start <= offset) - 1;
+        let (file, start) = &self.parts[i];
+        let end = self.parts.get(i + 1).map_or(self.len, |&(_, start)| start);
+        Some((file, offset - start, end - offset))
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let Some((file, at, left)) = self.locate(offset) else {
+            return Ok(0);
+        };
+        let n = left.min(buf.len() as u64) as usize;
+        Device::read_at(file, &mut buf[..n], at)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        let Some((file, at, left)) = self.locate(offset) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "write past the end of the last image file",
+            ));
+        };
+        let n = left.min(buf.len() as u64) as usize;
+        Device::write_at(file, &buf[..n], at)
+    }
+}
+
+// image files striped in `unit` byte pieces taken from each in turn, the
+// members of a RAID 0 set
+pub struct Stripe {
+    parts: Vec<File>,
+    unit: u64,
+    len: u64,
+}
+
+impl Stripe {
+    pub fn new(files: Vec<File>, unit: u64) -> io::Result<Self> {
+        let mut shortest = u64::MAX;
+        for file in files.iter() {
+            shortest = shortest.min(file_size(file)?);
+        }
+        // a unit some members don't have in full is left out
+        let len = shortest / unit * unit * files.len() as u64;
+        Ok(Stripe {
+            parts: files,
+            unit,
+            len,
+        })
+    }
+
+    // the member holding byte `offset`, where in it and how much of the
+    // unit is left from there
+    fn locate(&self, offset: u64) -> Option<(&File, u64, u64)> {
+        if offset >= self.len {
+            return None;
+        }
+        let (no, within) = (offset / self.unit, offset % self.unit);
+        let n = self.parts.len() as u64;
+        let file = &self.parts[(no % n) as usize];
+        Some((file, no / n * self.unit + within, self.unit - within))
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let Some((file, at, left)) = self.locate(offset) else {
+            return Ok(0);
+        };
+        let n = left.min(buf.len() as u64) as usize;
+        Device::read_at(file, &mut buf[..n], at)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        let Some((file, at, left)) = self.locate(offset) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "write past the end of the striped set",
+            ));
+        };
+        let n = left.min(buf.len() as u64) as usize;
+        Device::write_at(file, &buf[..n], at)
+    }
+}
+
+// unaligned requests go through a bounce buffer covering the blocks they
+// touch, writes become read-modify-write of those blocks. with O_DIRECT
+// the memory has to be block aligned as well
+impl Device for File {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        stats::add(&STATS.dev_reads, 1);
+        stats::add(&STATS.dev_read_bytes, buf.len() as u64);
+        trace::record("read", offset, buf.len());
+        throttle::take(buf.len());
+        let (start, end) = blk_span(offset, buf.len());
+        if !must_align() || is_aligned(buf.as_ptr(), start, end, offset, buf.len()) {
+            return raw_read(self, buf, offset);
+        }
+        let (mut bounce, at) = aligned_buf((end - start) as usize);
+        let tmp = &mut bounce[at..at + (end - start) as usize];
+        let skip = (offset - start) as usize;
+        let n = raw_read(self, tmp, start)?
+            .saturating_sub(skip)
+            .min(buf.len());
+        buf[..n].copy_from_slice(&tmp[skip..skip + n]);
+        Ok(n)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        stats::add(&STATS.dev_writes, 1);
+        stats::add(&STATS.dev_write_bytes, buf.len() as u64);
+        trace::record("write", offset, buf.len());
+        let (start, end) = blk_span(offset, buf.len());
+        if !must_align() || is_aligned(buf.as_ptr(), start, end, offset, buf.len()) {
+            return raw_write(self, buf, offset);
+        }
+        // past the end of an image file the blocks read as zeros
+        let (mut bounce, at) = aligned_buf((end - start) as usize);
+        let tmp = &mut bounce[at..at + (end - start) as usize];
+        let mut got = 0;
+        while got < tmp.len() {
+            match raw_read(self, &mut tmp[got..], start + got as u64)? {
+                0 => break,
+                n => got += n,
+            }
+        }
+        let skip = (offset - start) as usize;
+        tmp[skip..skip + buf.len()].copy_from_slice(buf);
+        let mut put = 0;
+        while put < tmp.len() {
+            match raw_write(self, &tmp[put..], start + put as u64)? {
+                0 => return Err(io::ErrorKind::WriteZero.into()),
+                n => put += n,
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn len(&self) -> Option<u64> {
+        file_size(self).ok()
+    }
+}
+
+#[cfg(unix)]
+fn raw_read(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    FileExt::read_at(file, buf, offset)
+}
+
+#[cfg(unix)]
+fn raw_write(file: &File, buf: &[u8], offset: u64) -> io::Result<usize> {
+    FileExt::write_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn raw_read(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    file.seek_read(buf, offset)
+}
+
+#[cfg(windows)]
+fn raw_write(file: &File, buf: &[u8], offset: u64) -> io::Result<usize> {
+    file.seek_write(buf, offset)
+}
+
+fn must_align() -> bool {
+    cfg!(any(windows, target_os = "macos")) || ODIRECT.load(Ordering::Relaxed)
+}
+
+// whether a request can go to the device as is
+fn is_aligned(ptr: *const u8, start: u64, end: u64, offset: u64, len: usize) -> bool {
+    let mem_ok = !ODIRECT.load(Ordering::Relaxed)
+        || ptr.align_offset(BLK_SZ.load(Ordering::Relaxed) as usize) == 0;
+    start == offset && end == offset + len as u64 && mem_ok
+}
+
+// the block aligned byte range [start, end) covering `len` bytes at `offset`
+fn blk_span(offset: u64, len: usize) -> (u64, u64) {
+    let blk_sz = BLK_SZ.load(Ordering::Relaxed);
+    let start = offset / blk_sz * blk_sz;
+    (start, (offset + len as u64).next_multiple_of(blk_sz))
+}
+
+// a zeroed buffer holding `len` bytes at a block boundary in memory, and
+// where in it they start
+fn aligned_buf(len: usize) -> (Vec<u8>, usize) {
+    let blk_sz = BLK_SZ.load(Ordering::Relaxed) as usize;
+    let buf = vec![0u8; len + blk_sz];
+    let at = buf.as_ptr().align_offset(blk_sz);
+    (buf, at)
+}
+
+// open every device from here on bypassing the page cache: O_DIRECT on
+// Linux, F_NOCACHE on macOS and unbuffered handles on Windows. meant for
+// benchmarking, and for when a cache of our own makes the kernel's redundant
+pub fn set_odirect() {
+    ODIRECT.store(true, Ordering::Relaxed);
+}
+
+// stripe the files of every multi-file image opened from here on in `unit`
+// byte pieces, instead of laying them end to end
+pub fn set_stripe(unit: u64) {
+    STRIPE.store(unit, Ordering::Relaxed);
+}
+
+// find the other pieces of a split image from the first, `image.001`, by
+// name. without it only a `+` list opens several files
+pub fn set_split() {
+    SPLIT.store(true, Ordering::Relaxed);
+}
+
+// open a device or an image file, or several image files as one. those are
+// given as `a.img+b.img+...`, or with set_split found by name from the first
+// of a split image `image.001`. they're laid end to end unless set_stripe
+// was called
+pub fn open(path: impl AsRef<Path>, write: bool) -> io::Result<Image> {
+    let path = path.as_ref();
+    let members = match path.to_str() {
+        Some(list) if list.contains('+') && !path.exists() => {
+            list.split('+').map(PathBuf::from).collect()
+        }
+        _ if SPLIT.load(Ordering::Relaxed) => split_parts(path),
+        _ => {
+            if let Some(next) = split_parts(path).get(1) {
+                eprintln!(
+                    "[device] {} is there too, --split opens the pieces as one",
+                    next.display()
+                );
+            }
+            vec![path.to_path_buf()]
+        }
+    };
+    let stripe = STRIPE.load(Ordering::Relaxed);
+    if members.len() == 1 && stripe == 0 {
+        return Ok(Image::File(open_file(path, write)?));
+    }
+    let files = members
+        .iter()
+        .map(|member| open_file(member, write))
+        .collect::<io::Result<Vec<_>>>()?;
+    Ok(match stripe {
+        0 => Image::Concat(Concat::new(files)?),
+        unit => Image::Stripe(Stripe::new(files, unit)?),
+    })
+}
+
+// `path` and, when its extension is a number, the files numbered after it
+fn split_parts(path: &Path) -> Vec<PathBuf> {
+    let mut parts = vec![path.to_path_buf()];
+    let Some(ext) = path.extension().and_then(|ext| ext.to_str()) else {
+        return parts;
+    };
+    let Ok(mut no) = ext.parse::<u64>() else {
+        return parts;
+    };
+    if !ext.bytes().all(|b| b.is_ascii_digit()) {
+        return parts;
+    }
+    loop {
+        no += 1;
+        let next = path.with_extension(format!("{:0width$}", no, width = ext.len()));
+        if !next.exists() {
+            return parts;
+        }
+        parts.push(next);
+    }
+}
+
+// open a single device or image file.
+// on Windows `\\.\PhysicalDriveN` and `\\.\X:` work as well, a volume opened
+// for writing is locked and dismounted first so the system lets writes
+// through to it.
+// on macOS `/dev/diskN` is swapped for its raw node `/dev/rdiskN`, the
+// buffered node is slow and rejects reads off block boundaries. a disk
+// with mounted volumes only opens for reading, writes need it unmounted
+// with `diskutil unmountDisk` first
+fn open_file(path: impl AsRef<Path>, write: bool) -> io::Result<File> {
+    #[cfg(target_os = "macos")]
+    let (given, path) = (path.as_ref(), raw_node(path.as_ref()));
+    let odirect = ODIRECT.load(Ordering::Relaxed);
+    let mut opts = OpenOptions::new();
+    opts.read(true).write(write);
+    #[cfg(target_os = "linux")]
+    if odirect {
+        use std::os::unix::fs::OpenOptionsExt;
+        opts.custom_flags(libc::O_DIRECT);
+    }
+    #[cfg(windows)]
+    if odirect {
+        use std::os::windows::fs::OpenOptionsExt;
+        opts.custom_flags(FILE_FLAG_NO_BUFFERING);
+    }
+    let file = opts.open(&path);
+    #[cfg(target_os = "macos")]
+    let file = file.map_err(|e| match e.raw_os_error() {
+        Some(libc::EBUSY) => io::Error::new(
+            e.kind(),
+            format!(
+                "{} is mounted, run `diskutil unmountDisk {}` first",
+                given.display(),
+                given.display()
+            ),
+        ),
+        _ => e,
+    });
+    let file = file?;
+    #[cfg(windows)]
+    if write && path.as_ref().to_str().is_some_and(is_volume_path) {
+        lock_volume(&file)?;
+    }
+    #[cfg(target_os = "macos")]
+    if let Some(blk_sz) = dk_ioctl(&file, DKIOCGETBLOCKSIZE).filter(|&sz| sz.is_power_of_two()) {
+        BLK_SZ.fetch_max(blk_sz, Ordering::Relaxed);
+    }
+    #[cfg(target_os = "macos")]
+    if odirect {
+        use std::os::fd::AsRawFd;
+        if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_NOCACHE, 1) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    #[cfg(target_os = "linux")]
+    if odirect {
+        use std::os::fd::AsRawFd;
+        let mut blk_sz: libc::c_int = 0;
+        // fails on image files, their filesystem takes 512 byte alignment
+        if unsafe { libc::ioctl(file.as_raw_fd(), BLKSSZGET, &mut blk_sz) } == 0 && blk_sz > 0 {
+            BLK_SZ.fetch_max(blk_sz as u64, Ordering::Relaxed);
+        }
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+    if odirect {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "--odirect isn't supported on this platform",
+        ));
+    }
+    Ok(file)
+}
+
+// the length of a device or image in bytes
+pub fn size(image: &Image) -> io::Result<u64> {
+    match image {
+        Image::File(file) => file_size(file),
+        Image::Concat(c) => Ok(c.len),
+        Image::Stripe(s) => Ok(s.len),
+        Image::Part(part) => Ok(part.len),
+    }
+}
+
+// block devices report no length in their metadata, raw disks on macOS and
+// Windows not even to a seek
+fn file_size(file: &File) -> io::Result<u64> {
+    #[cfg(target_os = "macos")]
+    if let (Some(cnt), Some(sz)) = (
+        dk_ioctl(file, DKIOCGETBLOCKCOUNT),
+        dk_ioctl(file, DKIOCGETBLOCKSIZE),
+    ) {
+        return Ok(cnt * sz);
+    }
+    #[cfg(windows)]
+    {
+        use windows_sys::Win32::System::Ioctl::IOCTL_DISK_GET_LENGTH_INFO;
+        let mut len = [0u8; 8];
+        if win_ioctl(file, IOCTL_DISK_GET_LENGTH_INFO, &mut len).is_ok() {
+            return Ok(u64::from_le_bytes(len));
+        }
+    }
+    let mut file = file;
+    file.seek(SeekFrom::End(0))
+}
+
+// `/dev/rdiskN` for `/dev/diskN` and its partitions, anything else as is
+#[cfg(target_os = "macos")]
+fn raw_node(path: &Path) -> std::path::PathBuf {
+    match path.to_str().and_then(|p| p.strip_prefix("/dev/disk")) {
+        Some(rest) => format!("/dev/rdisk{}", rest).into(),
+        None => path.to_path_buf(),
+    }
+}
+
+// a disk ioctl answering with one number, None for image files
+#[cfg(target_os = "macos")]
+fn dk_ioctl(file: &File, req: libc::c_ulong) -> Option<u64> {
+    use std::os::fd::AsRawFd;
+
+    let mut val = [0u8; 8];
+    let ret = unsafe { libc::ioctl(file.as_raw_fd(), req, val.as_mut_ptr()) };
+    if ret < 0 {
+        return None;
+    }
+    // the size is a uint32_t, the count a uint64_t
+    Some(match req {
+        DKIOCGETBLOCKSIZE => u32::from_ne_bytes(val[..4].try_into().unwrap()) as u64,
+        _ => u64::from_ne_bytes(val),
+    })
+}
+
+// `\\.\X:`, a drive letter rather than a whole disk
+#[cfg(windows)]
+fn is_volume_path(path: &str) -> bool {
+    let path = path.trim_end_matches('\\');
+    path.len() == 6 && path.starts_with(r"\\.\") && path.ends_with(':')
+}
+
+// the lock lasts until the handle is closed, the volume mounts again on the
+// next access after that
+#[cfg(windows)]
+fn lock_volume(file: &File) -> io::Result<()> {
+    use windows_sys::Win32::System::Ioctl::{FSCTL_DISMOUNT_VOLUME, FSCTL_LOCK_VOLUME};
+
+    win_ioctl(file, FSCTL_LOCK_VOLUME, &mut [])?;
+    win_ioctl(file, FSCTL_DISMOUNT_VOLUME, &mut [])
+}
+
+#[cfg(windows)]
+fn win_ioctl(file: &File, code: u32, out: &mut [u8]) -> io::Result<()> {
+    use std::os::windows::io::AsRawHandle;
+    use windows_sys::Win32::System::IO::DeviceIoControl;
+
+    let mut returned = 0u32;
+    let ok = unsafe {
+        DeviceIoControl(
+            file.as_raw_handle() as _,
+            code,
+            std::ptr::null(),
+            0,
+            out.as_mut_ptr() as _,
+            out.len() as u32,
+            &mut returned,
+            std::ptr::null_mut(),
+        )
+    };
+    if ok == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// a small free list of byte buffers, letting the hot read paths reuse
+// allocations instead of creating a fresh Vec for every sector or cluster
+#[derive(Default)]
+pub(crate) struct BufPool {
+    free: Vec<Vec<u8>>,
+}
+
+impl BufPool {
+    const MAX_FREE: usize = 8;
+
+    // take a zero-filled buffer of exactly `len` bytes
+    pub fn take(&mut self, len: usize) -> Vec<u8> {
+        let mut buf = match self.free.iter().position(|buf| buf.capacity() >= len) {
+            Some(idx) => self.free.swap_remove(idx),
+            None => self.free.pop().unwrap_or_default(),
+        };
+        buf.clear();
+        buf.resize(len, 0);
+        buf
+    }
+
+    // hand a buffer back for later reuse
+    pub fn give(&mut self, buf: Vec<u8>) {
+        if self.free.len() < Self::MAX_FREE {
+            self.free.push(buf);
+        }
+    }
+
+    // the bytes of the buffers kept for reuse
+    #[allow(dead_code)]
+    pub fn held(&self) -> u64 {
+        self.free.iter().map(|buf| buf.capacity() as u64).sum()
+    }
+}
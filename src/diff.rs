@@ -0,0 +1,100 @@
+use std::{cmp::min, collections::BTreeMap, io, path::Path};
+
+use sha2::{Digest, Sha256};
+
+use crate::disk;
+use crate::extract::hex;
+use crate::fio::{self, Finfo, Fio, FsType};
+
+const CHUNK_SZ: u32 = 1 << 20;
+
+// compare the trees of two volumes path by path. files present on both sides
+// are compared by size and modification time, and with `hash` by content too
+pub fn diff(a: &Path, b: &Path, typ: &FsType, hash: bool) -> io::Result<()> {
+    let (file_a, file_b) = (disk::open_volume(a, false)?, disk::open_volume(b, false)?);
+    let mut fio_a = fio::open(&file_a, typ)?;
+    let mut fio_b = fio::open(&file_b, typ)?;
+    let tree_a = tree(fio_a.as_mut())?;
+    let tree_b = tree(fio_b.as_mut())?;
+
+    let (mut added, mut removed, mut changed) = (0, 0, 0);
+    for (path, fa) in tree_a.iter() {
+        let fb = match tree_b.get(path) {
+            Some(fb) => fb,
+            None => {
+                println!("- {}", path);
+                removed += 1;
+                continue;
+            }
+        };
+        if fa.is_dir != fb.is_dir {
+            println!("M {} (file <-> dir)", path);
+            changed += 1;
+            continue;
+        }
+        if fa.is_dir {
+            continue;
+        }
+
+        let mut why = vec![];
+        if fa.size != fb.size {
+            why.push(format!("size {} -> {}", fa.size, fb.size));
+        }
+        if fa.wrt_time != fb.wrt_time {
+            why.push(String::from("mtime"));
+        }
+        if hash && fa.size == fb.size && digest(fio_a.as_mut(), fa)? != digest(fio_b.as_mut(), fb)?
+        {
+            why.push(String::from("content"));
+        }
+        if !why.is_empty() {
+            println!("M {} ({})", path, why.join(", "));
+            changed += 1;
+        }
+    }
+    for path in tree_b.keys().filter(|path| !tree_a.contains_key(*path)) {
+        println!("+ {}", path);
+        added += 1;
+    }
+    say!(
+        "[diff] {} added, {} removed, {} changed",
+        added,
+        removed,
+        changed
+    );
+    Ok(())
+}
+
+// every entry below the root, keyed by its full path
+fn tree(fio: &mut dyn Fio) -> io::Result<BTreeMap<String, Finfo>> {
+    let mut tree = BTreeMap::new();
+    let mut dirs = vec![(String::new(), fio.list_root()?)];
+    while let Some((path, ents)) = dirs.pop() {
+        for fi in ents {
+            if fi.name == "." || fi.name == ".." {
+                continue;
+            }
+            let fpath = format!("{}/{}", path, fi.name);
+            if fi.is_dir && fi.fst_clus != 0 {
+                dirs.push((fpath.clone(), fio.list_dir(fi.fst_clus)?));
+            }
+            tree.insert(fpath, fi);
+        }
+    }
+    Ok(tree)
+}
+
+fn digest(fio: &mut dyn Fio, fi: &Finfo) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut offset: u64 = 0;
+    while offset < fi.size {
+        let size = min(CHUNK_SZ as u64, fi.size - offset) as u32;
+        let bytes = fio.read_file(fi, offset, size)?;
+        if bytes.is_empty() {
+            break;
+        }
+        hasher.update(&bytes);
+        offset += bytes.len() as u64;
+    }
+    Ok(hex(&hasher.finalize()))
+}
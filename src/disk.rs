@@ -0,0 +1,152 @@
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::device::{self, Device, Image, Slice};
+use crate::exfat;
+use crate::ext2;
+use crate::fat32::spec::BootSec;
+use crate::gpt::{self, Gpt};
+use crate::mbr::{self, Mbr};
+use crate::squashfs;
+use crate::udf;
+
+// the partition open_volume picks on whole disks, 0 for none
+static PARTITION: AtomicUsize = AtomicUsize::new(0);
+
+// a span of the disk, the whole disk for a superfloppy
+#[allow(dead_code)]
+pub struct Part {
+    pub no: usize, // the slot in the partition table, 1 for the whole disk
+    pub name: String,
+    pub first: u64,
+    pub nsecs: u64,
+}
+
+// the kind of partition table `parts` came from, "GPT" or "MBR", none when
+// partitions found no table and made the whole disk one
+pub fn table(dev: &dyn Device, disk_secs: u64, parts: &[Part]) -> Option<&'static str> {
+    if Gpt::read(&dev, disk_secs).is_ok() {
+        Some("GPT")
+    } else if parts.iter().any(|p| p.first != 0) {
+        Some("MBR")
+    } else {
+        None
+    }
+}
+
+// the GPT partitions, else the MBR ones, else the whole disk
+pub fn partitions(dev: &dyn Device, disk_secs: u64) -> io::Result<Vec<Part>> {
+    if let Ok(gpt) = Gpt::read(&dev, disk_secs) {
+        return Ok(gpt
+            .entries
+            .iter()
+            .enumerate()
+            .filter(|(_, e)| !e.is_empty())
+            .map(|(i, e)| Part {
+                no: i + 1,
+                name: format!("GPT partition {}", i + 1),
+                first: e.first_lba,
+                nsecs: e.last_lba - e.first_lba + 1,
+            })
+            .collect());
+    }
+    let mut buf = [0u8; Mbr::SZ];
+    dev.read_exact_at(&mut buf, 0)?;
+    let parts: Vec<Part> = match Mbr::new(&buf) {
+        // a FAT boot sector carries the same signature, that's a superfloppy
+        Ok(mbr) if mbr.is_valid() && detect(dev, 0).is_none() => mbr
+            .partitions()
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| !p.is_empty() && p.typ() != mbr::TYPE_PROTECTIVE)
+            .map(|(i, p)| Part {
+                no: i + 1,
+                name: format!("MBR partition {}", i + 1),
+                first: p.lba() as u64,
+                nsecs: p.nsecs() as u64,
+            })
+            .collect(),
+        _ => vec![],
+    };
+    if !parts.is_empty() {
+        return Ok(parts);
+    }
+    Ok(vec![Part {
+        no: 1,
+        name: "whole disk".to_string(),
+        first: 0,
+        nsecs: disk_secs,
+    }])
+}
+
+// take the volumes in partition `no` of the whole disks given from here on
+pub fn set_partition(no: usize) {
+    PARTITION.store(no, Ordering::Relaxed);
+}
+
+// open the volume at `path`. a whole disk, with a partition table where the
+// volume would start, needs a partition picked with set_partition, else the
+// error lists the ones there are
+pub fn open_volume(path: impl AsRef<Path>, write: bool) -> io::Result<Image> {
+    let image = device::open(path, write)?;
+    let no = PARTITION.load(Ordering::Relaxed);
+    if no == 0 && detect(&image, 0).is_some() {
+        return Ok(image);
+    }
+    let disk_secs = device::size(&image)? / gpt::SEC_SZ;
+    let parts = partitions(&image, disk_secs)?;
+    let is_disk = parts.iter().any(|p| p.first != 0);
+    if no == 0 && !is_disk {
+        return Ok(image);
+    }
+    let Some(part) = parts.iter().find(|p| p.no == no) else {
+        let list: Vec<String> = parts
+            .iter()
+            .map(|p| {
+                let fs = detect(&image, p.first * gpt::SEC_SZ).unwrap_or("unknown");
+                format!(
+                    "  {}: {}, {}, {} sectors at {}",
+                    p.no, p.name, fs, p.nsecs, p.first
+                )
+            })
+            .collect();
+        let what = match no {
+            0 => "a whole disk, pick a partition with --partition".to_string(),
+            no => format!("no partition {}", no),
+        };
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{}, there are:\n{}", what, list.join("\n")),
+        ));
+    };
+    let (start, len) = (part.first * gpt::SEC_SZ, part.nsecs * gpt::SEC_SZ);
+    Ok(Image::Part(Box::new(Slice::new(image, start, len))))
+}
+
+// the filesystem starting at byte `off`: "FAT32", "exFAT", "SquashFS", "ext2"
+// or "UDF"
+pub fn detect(dev: &dyn Device, off: u64) -> Option<&'static str> {
+    let mut buf = [0u8; 512];
+    dev.read_exact_at(&mut buf, off).ok()?;
+    if squashfs::spec::SuperBlock::new(&buf).is_ok_and(|sb| sb.is_valid()) {
+        return Some("SquashFS");
+    }
+    if exfat::spec::BootSec::new(&buf).is_ok_and(|b| b.is_valid()) {
+        return Some("exFAT");
+    }
+    if BootSec::new(&mut buf).is_ok_and(|b| {
+        b.bs_fil_sys_type == *b"FAT32   " && b.bs_boot_sign == 0xAA55 && b.bpb_byts_per_sec != 0
+    }) {
+        return Some("FAT32");
+    }
+    let mut sblk = [0u8; 1024];
+    dev.read_exact_at(&mut sblk, off + 1024).ok()?;
+    if ext2::spec::Sblk::new(&sblk).is_ok_and(|s| s.is_valid()) {
+        return Some("ext2");
+    }
+    if udf::probe(dev, off).is_some() {
+        return Some("UDF");
+    }
+    None
+}
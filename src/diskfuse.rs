@@ -0,0 +1,336 @@
+use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
+    sync::{atomic::AtomicBool, Arc},
+};
+
+use fuser::{FileAttr, FileType, Filesystem, ReplyAttr, ReplyOpen, ReplyXattr, Request};
+use libc::ENOENT;
+
+use crate::device::{self, Slice};
+use crate::disk;
+use crate::fat32fuse::{self, TTL};
+use crate::fio::{self, FsType};
+use crate::fs;
+use crate::gone::{self, Watch};
+use crate::gpt;
+
+// the inode of a partition's root, in the inode space of its Fs
+const PART_ROOT: u64 = 1;
+
+// every FAT32, exFAT, SquashFS, UDF and ext2 partition of a disk under one mount, as
+// /p1, /p2, ... named after their slots. each has an Fs of its own, the
+// inodes the kernel sees are handed out here and map to (partition, Fs inode)
+pub struct DiskFuse {
+    parts: Vec<(String, fs::Fs)>,
+    inos: BTreeMap<(usize, u64), u64>,
+    back: Vec<(usize, u64)>, // (partition, Fs inode) of inode 2 + i
+    // the dir each dir inode was found in, what its ".." lists
+    parents: BTreeMap<u64, u64>,
+    gone: Arc<AtomicBool>, // the disk's, for every partition
+}
+
+impl DiskFuse {
+    pub fn new(devname: &str) -> std::io::Result<Self> {
+        let file = device::open(devname, false)?;
+        let disk_secs = device::size(&file)? / gpt::SEC_SZ;
+        let gone = Arc::new(AtomicBool::new(false));
+        let mut parts = vec![];
+        for part in disk::partitions(&file, disk_secs)? {
+            let name = format!("p{}", part.no);
+            let (start, len) = (part.first * gpt::SEC_SZ, part.nsecs * gpt::SEC_SZ);
+            let typ = match disk::detect(&file, start) {
+                Some("FAT32") => FsType::Fat32,
+                Some("exFAT") => FsType::Exfat,
+                Some("SquashFS") => FsType::Squashfs,
+                Some("UDF") => FsType::Udf,
+                Some("ext2") => FsType::Ext2,
+                Some(other) => {
+                    eprintln!("[mount-disk] {}: {} isn't supported, left out", name, other);
+                    continue;
+                }
+                None => {
+                    eprintln!("[mount-disk] {}: no filesystem found, left out", name);
+                    continue;
+                }
+            };
+            say!("[mount-disk] {}: {}, {:?}", name, part.name, typ);
+            let dev = Slice::new(device::open(devname, false)?, start, len);
+            fat32fuse::warn_volume_flags(&dev, &typ, &name);
+            let path = devname.to_string();
+            let dev = Watch::new(dev, devname, gone.clone())
+                .reopen_with(move || Ok(Slice::new(device::open(&path, false)?, start, len)));
+            parts.push((name, fs::Fs::new(fio::open(dev, &typ)?, false)?));
+        }
+        Ok(DiskFuse {
+            parts,
+            inos: BTreeMap::new(),
+            back: vec![],
+            parents: BTreeMap::new(),
+            gone,
+        })
+    }
+
+    // see gone::guard
+    fn guard<T>(
+        &mut self,
+        op: impl FnOnce(&mut Self) -> Result<T, fs::Error>,
+    ) -> Result<T, fs::Error> {
+        let gone = self.gone.clone();
+        gone::guard(&gone, || op(self))
+    }
+
+    fn ino_of(&mut self, part: usize, id: u64) -> u64 {
+        if let Some(&ino) = self.inos.get(&(part, id)) {
+            return ino;
+        }
+        self.back.push((part, id));
+        let ino = self.back.len() as u64 + 1;
+        self.inos.insert((part, id), ino);
+        ino
+    }
+
+    // the (partition, Fs inode) behind a kernel inode, none for the root
+    fn split(&self, ino: u64) -> Option<(usize, u64)> {
+        self.back.get((ino as usize).checked_sub(2)?).copied()
+    }
+
+    fn attr(&mut self, part: usize, id: u64) -> Result<FileAttr, fs::Error> {
+        let ino = self.ino_of(part, id);
+        if id == PART_ROOT {
+            return Ok(FileAttr {
+                ino,
+                ..fat32fuse::root_dir_attr()
+            });
+        }
+        let fi = self.parts[part].1.getinfo(id)?;
+        Ok(FileAttr {
+            ino,
+            ..FileAttr::from(fi.as_ref())
+        })
+    }
+}
+
+impl Filesystem for DiskFuse {
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: fuser::ReplyEntry) {
+        let name = name.to_string_lossy();
+        let attr = self.guard(|this| {
+            let (part, id) = match this.split(parent) {
+                None if parent == 1 => this
+                    .parts
+                    .iter()
+                    .position(|(n, _)| *n == name)
+                    .map(|part| (part, PART_ROOT))
+                    .ok_or(fs::Error::NotFound),
+                None => Err(fs::Error::NotFound),
+                Some((part, id)) => this.parts[part].1.lookup(id, &name).map(|fi| (part, fi.id)),
+            }?;
+            this.attr(part, id)
+        });
+        match attr {
+            Ok(attr) => {
+                if attr.kind == FileType::Directory {
+                    self.parents.insert(attr.ino, parent);
+                }
+                reply.entry(&TTL, &attr, 0)
+            }
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+        let attr = self.guard(|this| match this.split(ino) {
+            None if ino == 1 => Ok(fat32fuse::root_dir_attr()),
+            None => Err(fs::Error::NotFound),
+            Some((part, id)) => this.attr(part, id),
+        });
+        match attr {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &fuser::Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: fuser::ReplyDirectory,
+    ) {
+        // (inode, cookie, kind, name). "." and ".." take cookies 1 and 2,
+        // the entries come after them
+        let parent = self.parents.get(&ino).copied().unwrap_or(1);
+        let dots = [
+            (ino, 1, FileType::Directory, String::from(".")),
+            (parent, 2, FileType::Directory, String::from("..")),
+        ];
+        let from = (offset as u64).saturating_sub(2);
+        let ents: Vec<(u64, i64, FileType, String)> = match self.split(ino) {
+            None if ino == 1 => (0..self.parts.len())
+                .skip(from as usize)
+                .map(|part| {
+                    let ino = self.ino_of(part, PART_ROOT);
+                    let name = self.parts[part].0.clone();
+                    (ino, part as i64 + 3, FileType::Directory, name)
+                })
+                .collect(),
+            None => return reply.error(ENOENT),
+            Some((part, id)) => {
+                let files =
+                    self.guard(|this| Ok(this.parts[part].1.readdir_from(id, from)?.to_vec()));
+                let files = match files {
+                    Ok(files) => files,
+                    Err(e) => return reply.error(e.errno()),
+                };
+                files
+                    .iter()
+                    .map(|f| {
+                        let child = self.ino_of(part, f.id);
+                        if f.is_dir {
+                            self.parents.insert(child, ino);
+                        }
+                        (child, f.pos as i64 + 3, f.as_ref().into(), f.name.clone())
+                    })
+                    .collect()
+            }
+        };
+        let dots = dots
+            .into_iter()
+            .filter(|&(_, cookie, _, _)| cookie > offset);
+        for (ino, cookie, kind, name) in dots.chain(ents) {
+            if reply.add(ino, cookie, kind, name) {
+                break;
+            }
+        }
+        reply.ok()
+    }
+
+    // read-only like the single volume mount, see fat32fuse::reply_access
+    fn access(&mut self, _req: &Request<'_>, ino: u64, mask: i32, reply: fuser::ReplyEmpty) {
+        let rdonly = match self.split(ino) {
+            None if ino == 1 => false,
+            None => return reply.error(ENOENT),
+            Some((_, PART_ROOT)) => false,
+            Some((part, id)) => match self.guard(|this| this.parts[part].1.getinfo(id)) {
+                Ok(fi) => fi.is_rdonly,
+                Err(e) => return reply.error(e.errno()),
+            },
+        };
+        fat32fuse::reply_access(rdonly, mask, reply);
+    }
+
+    // each partition's root carries the xattrs of its volume
+    fn getxattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+        reply: ReplyXattr,
+    ) {
+        let value = match self.split(ino) {
+            Some((part, PART_ROOT)) => fat32fuse::volume_xattr(&self.parts[part].1.volume, name),
+            _ => None,
+        };
+        match value {
+            Some(value) => fat32fuse::reply_xattr(&value, size, reply),
+            None => reply.error(fat32fuse::ENOATTR),
+        }
+    }
+
+    fn listxattr(&mut self, _req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        let names = match self.split(ino) {
+            Some((_, PART_ROOT)) => fat32fuse::volume_xattr_names(),
+            _ => vec![],
+        };
+        fat32fuse::reply_xattr(&names, size, reply);
+    }
+
+    fn open(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
+        // handles come from the partition's Fs, they only need to be told
+        // apart within it
+        let opened = match self.split(ino) {
+            Some((part, id)) => self.guard(|this| this.parts[part].1.open(id)),
+            None => Err(fs::Error::NotFound),
+        };
+        match opened {
+            Ok(fh) => reply.opened(fh, 0),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    // the disk's own root lists partitions and needs no handle
+    fn opendir(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
+        let opened = match self.split(ino) {
+            None if ino == 1 => Ok(0),
+            Some((part, id)) => self.guard(|this| this.parts[part].1.opendir(id)),
+            None => Err(fs::Error::NotFound),
+        };
+        match opened {
+            Ok(fh) => reply.opened(fh, 0),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn releasedir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        _flags: i32,
+        reply: fuser::ReplyEmpty,
+    ) {
+        if let Some((part, _)) = self.split(ino) {
+            self.parts[part].1.closedir(fh);
+        }
+        reply.ok();
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: fuser::ReplyEmpty,
+    ) {
+        if let Some((part, id)) = self.split(ino) {
+            self.parts[part].1.close(id, fh);
+        }
+        reply.ok();
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: fuser::ReplyData,
+    ) {
+        let bytes = match self.split(ino) {
+            Some((part, id)) => {
+                let pid = _req.pid();
+                self.guard(|this| {
+                    this.parts[part]
+                        .1
+                        .read(id, fh, offset as u64, size, &|| fat32fuse::interrupted(pid))
+                })
+            }
+            None => Err(fs::Error::NotFound),
+        };
+        match bytes {
+            Ok(bytes) => reply.data(&bytes),
+            Err(e) => {
+                eprintln!("[mount-disk] read: {}", e);
+                reply.error(e.errno());
+            }
+        }
+    }
+}
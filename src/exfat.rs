@@ -14,8 +14,9 @@ pub enum Error {
 }
 
 pub mod spec {
-    use scroll::{self, Pread, LE};
+    use scroll::{self, Pread, Pwrite, LE};
 
+    #[allow(dead_code)]
     #[derive(Debug)]
     pub struct BootSec {
         pub jmp_boot: [u8; 3],     // `unused`
@@ -28,16 +29,16 @@ pub mod spec {
         pub cluster_heap_offset: u32,
         pub cluster_count: u32, // max: 0xFFFFFFF5
         pub first_cluster_of_root_dir: u32,
-        pub volumn_serial_number: u32, // `unused`
+        pub volumn_serial_number: u32,
         pub file_system_revision: [u8; 2], /* check only,
-                                       upper byte for major and lower byte for minor */
-        pub volumn_flags: u16,          // `unused`
+                                           upper byte for major and lower byte for minor */
+        pub volumn_flags: u16,
         pub bytes_per_sector_shift: u8, // check, 9..=12 (512 to 4096 bytes)
         pub sectors_per_cluster_shift: u8, /* check, 0..=(25 - bytes_per_sector_shift)
                                         (1 sector to 32MB) */
         pub number_of_fats: u8,
         pub drive_select: u8,     // `unused`
-        pub percent_in_use: u8,   // `unused`
+        pub percent_in_use: u8,   // 0xFF when not kept
         pub reserved: [u8; 7],    // `unused`
         pub boot_code: [u8; 390], // `unused`
         boot_signature: u16,      // check only, "0xAA55"
@@ -45,7 +46,11 @@ pub mod spec {
 
     #[allow(dead_code)]
     impl BootSec {
-        pub fn new(buf: &[u8; 512]) -> Result<Self, scroll::Error> {
+        // the fields fill the first 512 bytes of the main boot sector, what
+        // follows up to its end is excess space when sectors are larger
+        pub const SZ: usize = 512;
+
+        pub fn new(buf: &[u8; Self::SZ]) -> Result<Self, scroll::Error> {
             Ok(BootSec {
                 jmp_boot: buf.pread_with(0, LE)?,
                 file_system_name: buf.pread_with(3, LE)?,
@@ -71,17 +76,57 @@ pub mod spec {
             })
         }
 
+        // the fields as they go on disk, what new() reads back
+        pub fn dump(&self) -> [u8; Self::SZ] {
+            let mut buf = [0u8; Self::SZ];
+            buf.pwrite_with(&self.jmp_boot[..], 0, ()).unwrap();
+            buf.pwrite_with(&self.file_system_name[..], 3, ()).unwrap();
+            buf.pwrite_with(&self.must_be_zero[..], 11, ()).unwrap();
+            buf.pwrite_with(self.partition_offset, 64, LE).unwrap();
+            buf.pwrite_with(self.volumn_length, 72, LE).unwrap();
+            buf.pwrite_with(self.fat_offset, 80, LE).unwrap();
+            buf.pwrite_with(self.fat_length, 84, LE).unwrap();
+            buf.pwrite_with(self.cluster_heap_offset, 88, LE).unwrap();
+            buf.pwrite_with(self.cluster_count, 92, LE).unwrap();
+            buf.pwrite_with(self.first_cluster_of_root_dir, 96, LE)
+                .unwrap();
+            buf.pwrite_with(self.volumn_serial_number, 100, LE).unwrap();
+            buf.pwrite_with(&self.file_system_revision[..], 104, ())
+                .unwrap();
+            buf.pwrite_with(self.volumn_flags, 106, LE).unwrap();
+            buf.pwrite_with(self.bytes_per_sector_shift, 108, LE)
+                .unwrap();
+            buf.pwrite_with(self.sectors_per_cluster_shift, 109, LE)
+                .unwrap();
+            buf.pwrite_with(self.number_of_fats, 110, LE).unwrap();
+            buf.pwrite_with(self.drive_select, 111, LE).unwrap();
+            buf.pwrite_with(self.percent_in_use, 112, LE).unwrap();
+            buf.pwrite_with(&self.reserved[..], 113, ()).unwrap();
+            buf.pwrite_with(&self.boot_code[..], 120, ()).unwrap();
+            buf.pwrite_with(self.boot_signature, 510, LE).unwrap();
+            buf
+        }
+
         pub fn is_valid(&self) -> bool {
             self.file_system_name == "EXFAT   ".as_bytes()
                 && self.must_be_zero.iter().all(|&b| b == 0)
                 && self.boot_signature == 0xAA55
-                && self.file_system_revision[1] == 1 // The revision number of this spec is 1.0
+                // the major version, 1.xx all share the layout of 1.00
+                && self.file_system_revision[1] == 1
                 && self.number_of_fats == 1 // only support
                 && (9..=12).contains(&self.bytes_per_sector_shift)
                 && (0..=(25 - self.bytes_per_sector_shift))
                     .contains(&self.sectors_per_cluster_shift)
         }
 
+        // FileSystemRevision as major.minor, 1.00 for the spec's own
+        pub fn revision(&self) -> String {
+            format!(
+                "{}.{:02}",
+                self.file_system_revision[1], self.file_system_revision[0]
+            )
+        }
+
         pub fn bytes_per_sec(&self) -> u32 {
             1 << self.bytes_per_sector_shift
         }
@@ -93,6 +138,37 @@ pub mod spec {
         pub fn bytes_per_clus(&self) -> u32 {
             self.secs_per_clus() * self.bytes_per_sec()
         }
+
+        // VolumeFlags bit 0, which FAT is in use when there are two
+        pub fn active_fat(&self) -> u8 {
+            (self.volumn_flags & 0x1) as u8
+        }
+
+        // VolumeFlags bit 1, set while the volume may be inconsistent
+        pub fn is_dirty(&self) -> bool {
+            self.volumn_flags & 0x2 != 0
+        }
+
+        // VolumeFlags bit 2, set once a media failure has been seen
+        pub fn media_failure(&self) -> bool {
+            self.volumn_flags & 0x4 != 0
+        }
+
+        pub fn percent_in_use(&self) -> Option<u8> {
+            (self.percent_in_use <= 100).then_some(self.percent_in_use)
+        }
+
+        // what the volume flags say is wrong with the volume
+        pub fn flag_warnings(&self) -> Vec<&'static str> {
+            let mut warnings = vec![];
+            if self.is_dirty() {
+                warnings.push("the volume is marked dirty, it wasn't unmounted cleanly");
+            }
+            if self.media_failure() {
+                warnings.push("the volume is marked as having had media failures");
+            }
+            warnings
+        }
     }
 
     #[allow(dead_code)]
@@ -125,6 +201,14 @@ pub mod spec {
             }
         })
     }
+    #[allow(dead_code)]
+    pub fn table_checksum(bytes: &[u8]) -> u32 {
+        bytes.iter().fold(0, |sum: u32, &b| {
+            (sum >> 1)
+                .wrapping_add(b as u32)
+                .wrapping_add(if sum & 1 != 0 { 0x80000000 } else { 0 })
+        })
+    }
 
     #[derive(Debug)]
     pub struct DateTime {
@@ -161,7 +245,7 @@ pub mod spec {
         use std::time::{Duration, SystemTime};
 
         use chrono::{FixedOffset, TimeZone};
-        use scroll::{Pread, LE};
+        use scroll::{Pread, Pwrite, LE};
 
         enum Type {
             AllocBitmap,
@@ -221,7 +305,7 @@ pub mod spec {
             pub volumn_label: [u16; 11],
             pub reserved: [u8; 8], // `unused`
         }
-        #[derive(Debug)]
+        #[derive(Debug, Clone)]
         pub struct FileOrDir {
             pub secondary_cnt: u8,
             pub set_checksum: u16,
@@ -241,7 +325,7 @@ pub mod spec {
             pub ent_clusno: u32,
             pub ent_off: u32,
         }
-        #[derive(Debug)]
+        #[derive(Debug, Clone)]
         pub struct StreamExt {
             pub gen_secondary_flags: u8,
             pub reserved_1: [u8; 1], // `unused`
@@ -253,12 +337,37 @@ pub mod spec {
             pub first_cluster: u32,
             pub data_length: u64,
         }
-        #[derive(Debug)]
+        #[derive(Debug, Clone)]
         pub struct FileName {
             pub gen_secondary_flags: u8, // `unused`, zero
             pub filename: [u16; 15],
         }
 
+        impl StreamExt {
+            // the clusters are contiguous and the FAT holds no chain for them
+            pub fn no_fat_chain(&self) -> bool {
+                self.gen_secondary_flags & 0x02 != 0
+            }
+            // AllocationPossible, without it FirstCluster and DataLength
+            // mean nothing
+            pub fn allocation_possible(&self) -> bool {
+                self.gen_secondary_flags & 0x01 != 0
+            }
+            // the stream has clusters, empty files and some directories don't
+            pub fn is_allocated(&self) -> bool {
+                self.allocation_possible() && self.first_cluster != 0
+            }
+            // (first cluster, data length, valid data length), all zero for
+            // an unallocated stream
+            pub fn extent(&self) -> (u32, u64, u64) {
+                if self.is_allocated() {
+                    (self.first_cluster, self.data_length, self.valid_data_length)
+                } else {
+                    (0, 0, 0)
+                }
+            }
+        }
+
         impl FileOrDir {
             pub fn is_rdonly(&self) -> bool {
                 self.file_attributes & 0x01u16 != 0
@@ -297,7 +406,7 @@ pub mod spec {
                         dt.day.into(),
                         dt.hour.into(),
                         dt.minute.into(),
-                        dt.second.into(),
+                        (dt.second * 2).into(),
                     )
                     .single()?
                     .into(),
@@ -434,6 +543,93 @@ pub mod spec {
                     Type::FinalUnused => Ok(DirEnt::FinalUnused),
                 }
             }
+
+            // parse an entry whose InUse bit is cleared as if it were still in
+            // use, None for in-use entries and the end of dir marker
+            pub fn new_unused(buf: &[u8], clusno: u32, offset: u32) -> Result<Option<Self>, Error> {
+                match buf.first() {
+                    Some(&typ) if (0x01..=0x7F).contains(&typ) => {
+                        let mut buf = buf.to_vec();
+                        buf[0] |= 0x80;
+                        match Self::new(&buf, clusno, offset) {
+                            Ok(ent) => Ok(Some(ent)),
+                            Err(Error::UndefinedDirEntry(_)) => Ok(None),
+                            Err(err) => Err(err),
+                        }
+                    }
+                    _ => Ok(None),
+                }
+            }
+
+            // the entry as it goes on disk, what new() reads back. none for
+            // an unused entry, which doesn't keep what it held
+            pub fn dump(&self) -> Option<[u8; Self::SZ]> {
+                let mut buf = [0u8; Self::SZ];
+                let units = |buf: &mut [u8], at: usize, units: &[u16]| {
+                    for (i, &c) in units.iter().enumerate() {
+                        buf.pwrite_with(c, at + i * 2, LE).unwrap();
+                    }
+                };
+                match self {
+                    DirEnt::AllocBitmap(ent) => {
+                        buf[0] = 0x81;
+                        buf.pwrite_with(ent.bitmap_flags, 1, LE).unwrap();
+                        buf.pwrite_with(&ent.reserved[..], 2, ()).unwrap();
+                        buf.pwrite_with(ent.first_cluster, 20, LE).unwrap();
+                        buf.pwrite_with(ent.data_length, 24, LE).unwrap();
+                    }
+                    DirEnt::UpcaseTable(ent) => {
+                        buf[0] = 0x82;
+                        buf.pwrite_with(&ent.reserved_1[..], 1, ()).unwrap();
+                        buf.pwrite_with(ent.table_checksum, 4, LE).unwrap();
+                        buf.pwrite_with(&ent.reserved_2[..], 8, ()).unwrap();
+                        buf.pwrite_with(ent.first_cluster, 20, LE).unwrap();
+                        buf.pwrite_with(ent.data_length, 24, LE).unwrap();
+                    }
+                    DirEnt::VolumnLabel(ent) => {
+                        buf[0] = 0x83;
+                        buf.pwrite_with(ent.chars_cnt, 1, LE).unwrap();
+                        units(&mut buf, 2, &ent.volumn_label);
+                        buf.pwrite_with(&ent.reserved[..], 24, ()).unwrap();
+                    }
+                    DirEnt::FileOrDir(ent) => {
+                        buf[0] = 0x85;
+                        buf.pwrite_with(ent.secondary_cnt, 1, LE).unwrap();
+                        buf.pwrite_with(ent.set_checksum, 2, LE).unwrap();
+                        buf.pwrite_with(ent.file_attributes, 4, LE).unwrap();
+                        buf.pwrite_with(&ent.reserved_1[..], 6, ()).unwrap();
+                        buf.pwrite_with(ent.create_dt, 8, LE).unwrap();
+                        buf.pwrite_with(ent.last_mod_dt, 12, LE).unwrap();
+                        buf.pwrite_with(ent.last_acc_dt, 16, LE).unwrap();
+                        buf.pwrite_with(ent.create_10ms_incr, 20, LE).unwrap();
+                        buf.pwrite_with(ent.last_mod_10ms_incr, 21, LE).unwrap();
+                        buf.pwrite_with(ent.create_tz_off, 22, LE).unwrap();
+                        buf.pwrite_with(ent.last_mod_tz_off, 23, LE).unwrap();
+                        buf.pwrite_with(ent.last_acc_tz_off, 24, LE).unwrap();
+                        buf.pwrite_with(&ent.reserved_2[..], 25, ()).unwrap();
+                    }
+                    DirEnt::StreamExt(ent) => {
+                        buf[0] = 0xC0;
+                        buf.pwrite_with(ent.gen_secondary_flags, 1, LE).unwrap();
+                        buf.pwrite_with(&ent.reserved_1[..], 2, ()).unwrap();
+                        buf.pwrite_with(ent.name_length, 3, LE).unwrap();
+                        buf.pwrite_with(ent.name_hash, 4, LE).unwrap();
+                        buf.pwrite_with(&ent.reserved_2[..], 6, ()).unwrap();
+                        buf.pwrite_with(ent.valid_data_length, 8, LE).unwrap();
+                        buf.pwrite_with(&ent.reserved_3[..], 16, ()).unwrap();
+                        buf.pwrite_with(ent.first_cluster, 20, LE).unwrap();
+                        buf.pwrite_with(ent.data_length, 24, LE).unwrap();
+                    }
+                    DirEnt::FileName(ent) => {
+                        buf[0] = 0xC1;
+                        buf.pwrite_with(ent.gen_secondary_flags, 1, LE).unwrap();
+                        units(&mut buf, 2, &ent.filename);
+                    }
+                    DirEnt::Unused => return None,
+                    DirEnt::FinalUnused => (),
+                }
+                Some(buf)
+            }
         }
 
         // TODO
@@ -457,23 +653,113 @@ pub mod spec {
     }
 }
 
-use std::io::{Read, Seek, SeekFrom};
+use std::{
+    cmp::min,
+    collections::{BTreeMap, BTreeSet},
+    io,
+    sync::atomic::{AtomicBool, Ordering},
+    time::SystemTime,
+};
 
 use scroll::{Pread, LE};
 
+use crate::device::{BufPool, Device};
 use crate::fio::{self, Finfo};
+use crate::stats::{self, STATS};
+use crate::trace;
 use spec::{
-    dirent::{DirEnt, EntrySet},
+    dirent::{self, DirEnt, EntrySet},
     BootSec, FatEnt,
 };
 
-const SEC_SZ: usize = 512;
-type Sec = [u8; SEC_SZ];
+// whether a volume failing its up-case table checksum is refused, see set_strict
+static STRICT: AtomicBool = AtomicBool::new(false);
+
+// refuse volumes whose up-case table is corrupt instead of falling back to
+// the mandatory table
+pub fn set_strict() {
+    STRICT.store(true, Ordering::Relaxed);
+}
+
+fn corrupt(what: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what)
+}
+
+fn not_exfat(what: String) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("not an exFAT volume: {}", what),
+    )
+}
+
+// the up-case table fully expanded, indexed by UTF-16 code unit
+pub struct UpcaseTable(Box<[u16; 0x10000]>);
+
+#[allow(dead_code)]
+impl UpcaseTable {
+    // refer to [1], the mandatory first 128 up-case table entries
+    pub fn mandatory() -> Self {
+        let mut table = Self::identity();
+        for c in b'a'..=b'z' {
+            table.0[c as usize] = c.to_ascii_uppercase() as u16;
+        }
+        table
+    }
+
+    fn identity() -> Self {
+        let table: Vec<u16> = (0..=0xFFFF).collect();
+        UpcaseTable(table.into_boxed_slice().try_into().unwrap())
+    }
+
+    // decompress the on-disk table, where `0xFFFF, n` stands for n identity mappings
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let mut table = Self::identity();
+        let mut units = bytes
+            .chunks_exact(2)
+            .map(|b| u16::from_le_bytes([b[0], b[1]]));
+        let mut idx: usize = 0;
+        while let Some(unit) = units.next() {
+            if idx >= 0x10000 {
+                return None;
+            }
+            if unit == 0xFFFF {
+                idx += units.next()? as usize;
+            } else {
+                table.0[idx] = unit;
+                idx += 1;
+            }
+        }
+        Some(table)
+    }
+
+    #[inline]
+    pub fn upcase(&self, c: u16) -> u16 {
+        self.0[c as usize]
+    }
+
+    // refer to [1] 7.6.4, the NameHash of the stream extension entry, over
+    // the up-cased name a byte at a time
+    pub fn name_hash(&self, units: &[u16]) -> u16 {
+        let mut hash: u16 = 0;
+        for &c in units {
+            let c = self.upcase(c);
+            for b in [c as u8, (c >> 8) as u8] {
+                hash = hash.rotate_right(1).wrapping_add(b as u16);
+            }
+        }
+        hash
+    }
+
+    // how many code units up-case to something other than themselves
+    pub fn mapped(&self) -> usize {
+        (0..=0xFFFF).filter(|&c| self.0[c] != c as u16).count()
+    }
+}
 
 #[allow(dead_code)]
-pub struct Fio<D: Seek + Read> {
+pub struct Fio<D: Device> {
     device: D,
-    root_clusno: u32,
+    pub root_clusno: u32,
     bitmap_clusno: u32,
     sec_sz: u32,
     secs_per_clus: u32,
@@ -483,18 +769,69 @@ pub struct Fio<D: Seek + Read> {
     clus_cnt: u32,
     fat_offset: u32, // in sectors
     dirents_per_sec: u32,
+    upcase_ent: Option<dirent::UpcaseTable>,
+    upcase: UpcaseTable,
+    pool: BufPool,
+    // first cluster -> clusters of the dirs listed so far that have no FAT
+    // chain, what their own listing goes by
+    runs: BTreeMap<u32, u32>,
     pub bootsec: BootSec,
 }
 
 #[allow(dead_code)]
-impl<D: Seek + Read> Fio<D> {
-    pub fn new(mut device: D) -> Self {
-        let mut buf: Sec = [0u8; SEC_SZ];
-        device.seek(SeekFrom::Start(0)).unwrap();
-        device.read_exact(&mut buf).unwrap();
-
-        let bootsec = BootSec::new(&buf).unwrap();
-        assert!(bootsec.is_valid());
+impl<D: Device> Fio<D> {
+    // Unsupported when the boot sector isn't one fat32x can read,
+    // InvalidData when the root dir is broken, or with --strict when the
+    // up-case table is
+    pub fn new(device: D) -> io::Result<Self> {
+        Self::open(device, STRICT.load(Ordering::Relaxed))
+    }
+
+    // opened whatever the up-case table is like, for what checks it
+    pub fn lenient(device: D) -> io::Result<Self> {
+        Self::open(device, false)
+    }
+
+    // the clusters of the heap that the volume, the FAT and the device, no
+    // further than `reach` bytes, can all hold
+    fn count_clusters(bootsec: &BootSec, reach: u64) -> u32 {
+        let shift = bootsec.sectors_per_cluster_shift;
+        let bps = bootsec.bytes_per_sec() as u64;
+        let heap = bootsec.cluster_heap_offset as u64;
+        let in_volume = bootsec.volumn_length.saturating_sub(heap) >> shift;
+        let on_device = (reach / bps).saturating_sub(heap) >> shift;
+        let in_fat = (bootsec.fat_length as u64 * bps / FatEnt::SZ as u64).saturating_sub(2);
+        fio::fit_count(
+            "exfat",
+            "cluster count",
+            bootsec.cluster_count as u64,
+            &[
+                (in_volume, "the volume length"),
+                (in_fat, "the FAT"),
+                (0xFFFFFFF5, "the highest cluster number"),
+                (on_device, "the device"),
+            ],
+        ) as u32
+    }
+
+    fn open(device: D, strict: bool) -> io::Result<Self> {
+        let _p = trace::purpose("boot");
+        let mut buf = [0u8; BootSec::SZ];
+        device.read_exact_at(&mut buf, 0)?;
+
+        // every other sector sized buffer takes its size from the boot
+        // sector, 512 to 4096 bytes
+        let bootsec = BootSec::new(&buf).map_err(|e| not_exfat(e.to_string()))?;
+        if !bootsec.is_valid() {
+            return Err(not_exfat(String::from("the boot sector isn't valid")));
+        }
+        if bootsec.file_system_revision[0] != 0 {
+            eprintln!(
+                "[exfat] revision {}, read the way 1.00 is",
+                bootsec.revision()
+            );
+        }
+        let clus_cnt = Self::count_clusters(&bootsec, fio::reach(&device));
         let mut fio = Fio {
             device,
             root_clusno: bootsec.first_cluster_of_root_dir,
@@ -504,65 +841,193 @@ impl<D: Seek + Read> Fio<D> {
             clus_heap_offset: bootsec.cluster_heap_offset,
             clus_heap_base: bootsec.cluster_heap_offset as u64 * bootsec.bytes_per_sec() as u64,
             clus_sz: bootsec.bytes_per_clus(),
-            clus_cnt: bootsec.cluster_count,
+            clus_cnt,
             fat_offset: bootsec.fat_offset,
             dirents_per_sec: bootsec.bytes_per_sec() / 32,
+            upcase_ent: None,
+            upcase: UpcaseTable::mandatory(),
+            pool: BufPool::default(),
+            runs: BTreeMap::new(),
             bootsec,
         };
 
-        let root_ents = fio.read_dirents(fio.root_clusno);
-        if let Some(DirEnt::AllocBitmap(allocmap)) = root_ents
-            .into_iter()
-            .find(|ent| matches!(ent, DirEnt::AllocBitmap(_)))
-        {
-            fio.bitmap_clusno = allocmap.first_cluster;
-        } else {
-            panic!("[fio] init: allocation map not found in root dir");
+        for ent in fio.read_dirents(fio.root_clusno)? {
+            match ent {
+                DirEnt::AllocBitmap(allocmap) if fio.bitmap_clusno == 0 => {
+                    fio.bitmap_clusno = allocmap.first_cluster;
+                }
+                DirEnt::UpcaseTable(upcase) if fio.upcase_ent.is_none() => {
+                    fio.upcase_ent = Some(upcase);
+                }
+                _ => (),
+            }
+        }
+        if fio.bitmap_clusno == 0 {
+            return Err(corrupt(String::from(
+                "allocation map not found in root dir",
+            )));
+        }
+        match fio.check_upcase() {
+            Ok(table) => fio.upcase = table,
+            Err(e) if strict => return Err(corrupt(format!("up-case table: {}", e))),
+            Err(e) => eprintln!(
+                "[fio] init: up-case table: {}, falling back to the mandatory table",
+                e
+            ),
+        }
+        Ok(fio)
+    }
+
+    pub fn volume_label(&mut self) -> io::Result<String> {
+        for ent in self.read_dirents(self.root_clusno)? {
+            if let DirEnt::VolumnLabel(label) = ent {
+                let n = min(label.chars_cnt as usize, label.volumn_label.len());
+                return Ok(String::from_utf16_lossy(&label.volumn_label[..n]));
+            }
+        }
+        Ok(String::new())
+    }
+
+    // checked when the volume is opened, the mandatory table when the
+    // on-disk one is missing or fails its checksum
+    pub fn upcase_table(&self) -> &UpcaseTable {
+        &self.upcase
+    }
+
+    // read the up-case table its root dir entry points to and check it
+    // against the entry's checksum
+    pub fn check_upcase(&mut self) -> Result<UpcaseTable, String> {
+        let (first_cluster, data_length, checksum) = match &self.upcase_ent {
+            Some(ent) => (ent.first_cluster, ent.data_length, ent.table_checksum),
+            None => return Err("no entry in the root dir".to_string()),
+        };
+        if first_cluster < 2 || data_length == 0 || data_length > 0x20000 {
+            return Err(format!(
+                "bad entry, cluster {}, {} bytes",
+                first_cluster, data_length
+            ));
+        }
+        let mut bytes = vec![];
+        for clusno in self.walk_fats(first_cluster).map_err(|e| e.to_string())? {
+            bytes.extend(self.read_clus(clusno).map_err(|e| e.to_string())?);
+            if bytes.len() as u64 >= data_length {
+                break;
+            }
+        }
+        if (bytes.len() as u64) < data_length {
+            return Err(format!(
+                "chain ends after {} of {} bytes",
+                bytes.len(),
+                data_length
+            ));
+        }
+        bytes.truncate(data_length as usize);
+        let sum = spec::table_checksum(&bytes);
+        if sum != checksum {
+            return Err(format!(
+                "checksum mismatch, recorded {:#010x}, computed {:#010x}",
+                checksum, sum
+            ));
         }
-        fio
+        UpcaseTable::from_bytes(&bytes).ok_or_else(|| "runs past 0x10000 code units".to_string())
     }
 
-    pub fn read_clus(&mut self, clusno: u32) -> Vec<u8> {
+    pub fn read_clus(&mut self, clusno: u32) -> io::Result<Vec<u8>> {
         if clusno < 2 || clusno > self.clus_cnt + 1 {
-            println!("[fio] read_clus: cluster over reading");
-            return vec![];
+            eprintln!("[fio] read_clus: cluster over reading");
+            return Ok(vec![]);
         }
         let mut buf = vec![0u8; self.clus_sz as usize];
+        self.device.read_exact_at(
+            &mut buf,
+            self.clus_heap_base + (clusno - 2) as u64 * self.clus_sz as u64,
+        )?;
+        Ok(buf)
+    }
+
+    pub fn clus_sz(&self) -> u32 {
+        self.clus_sz
+    }
+
+    pub fn clus_cnt(&self) -> u32 {
+        self.clus_cnt
+    }
+
+    pub fn clus_offset(&self, clusno: u32) -> u64 {
+        self.clus_heap_base + (clusno - 2) as u64 * self.clus_sz as u64
+    }
+
+    // the percentage of clusters allocated in the bitmap, the way
+    // PercentInUse is figured: rounded down
+    pub fn percent_allocated(&mut self) -> io::Result<u8> {
+        let used = self.allocated()? as u64;
+        Ok((used * 100 / self.clus_cnt.max(1) as u64) as u8)
+    }
+
+    // the clusters the bitmap has allocated
+    pub fn allocated(&mut self) -> io::Result<u32> {
+        Ok(self.read_bitmap()?.iter().map(|b| b.count_ones()).sum())
+    }
+
+    // the raw (undecoded) entries of the FAT, index n for cluster n
+    pub fn read_fat_table(&mut self) -> io::Result<Vec<u32>> {
+        let _p = trace::purpose("fat");
+        let len = (self.clus_cnt as usize + 2) * FatEnt::SZ;
+        let mut buf = vec![0u8; len.next_multiple_of(self.sec_sz as usize)];
         self.device
-            .seek(SeekFrom::Start(
-                self.clus_heap_base + (clusno - 2) as u64 * self.clus_sz as u64,
-            ))
-            .unwrap();
-        self.device.read_exact(&mut buf).unwrap();
-        buf
+            .read_exact_at(&mut buf, self.fat_offset as u64 * self.sec_sz as u64)?;
+        Ok(buf[..len]
+            .chunks_exact(FatEnt::SZ)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect())
     }
 
-    pub fn read_sec(&mut self, secno: u64) -> Vec<u8> {
+    // the allocation bitmap, bit n of the table stands for cluster n + 2
+    pub fn read_bitmap(&mut self) -> io::Result<Vec<u8>> {
+        let _p = trace::purpose("bitmap");
+        let len = (self.clus_cnt as usize).div_ceil(8);
+        let mut bytes = vec![];
+        for clusno in self.walk_fats(self.bitmap_clusno)? {
+            bytes.extend(self.read_clus(clusno)?);
+            if bytes.len() >= len {
+                break;
+            }
+        }
+        bytes.resize(len, 0);
+        Ok(bytes)
+    }
+
+    pub fn read_sec(&mut self, secno: u64) -> io::Result<Vec<u8>> {
         let mut buf = vec![0u8; self.sec_sz as usize];
-        self.device
-            .seek(SeekFrom::Start(secno * self.sec_sz as u64))
-            .unwrap();
-        self.device.read_exact(&mut buf).unwrap();
-        buf
+        self.read_sec_into(secno, &mut buf)?;
+        Ok(buf)
     }
 
-    fn read_fat(&mut self, clusno: u32) -> FatEnt {
+    fn read_sec_into(&mut self, secno: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.device.read_exact_at(buf, secno * self.sec_sz as u64)
+    }
+
+    fn read_fat(&mut self, clusno: u32) -> io::Result<FatEnt> {
+        stats::add(&STATS.fat_reads, 1);
+        let _p = trace::purpose("fat");
         if clusno < 2 || clusno > self.clus_cnt + 1 {
-            println!("[fio] read_fat: FAT over reading");
-            return FatEnt::Reserved;
+            eprintln!("[fio] read_fat: FAT over reading");
+            return Ok(FatEnt::Reserved);
         }
         // TODO: check out the bitmap first
         // if !self.read_allocbit(clusno) {
         //     return FatEnt::Free;
         // }
-        let sec_no = clusno / self.dirents_per_sec;
-        let ent_off = (clusno % self.dirents_per_sec) as usize;
-        let sec = self.read_sec((self.fat_offset + sec_no).into());
+        let ents_per_sec = self.sec_sz / FatEnt::SZ as u32;
+        let sec_no = clusno / ents_per_sec;
+        let ent_off = (clusno % ents_per_sec) as usize;
+        let mut sec = self.pool.take(self.sec_sz as usize);
+        self.read_sec_into((self.fat_offset + sec_no).into(), &mut sec)?;
         let off = FatEnt::SZ * ent_off;
         let ent: u32 = sec.pread_with(off, LE).unwrap();
+        self.pool.give(sec);
 
-        println!("{}", ent);
-        if ent <= self.clus_cnt + 1 {
+        Ok(if ent <= self.clus_cnt + 1 {
             if ent >= 2 {
                 FatEnt::Chain(ent)
             } else {
@@ -574,21 +1039,44 @@ impl<D: Seek + Read> Fio<D> {
             FatEnt::BadCluster
         } else {
             FatEnt::Reserved
-        }
+        })
     }
 
     // TODO
     // fn read_allocbit(&mut self, clusno: u32) -> bool {}
 
     // walking the fat chain, return cluster numbers including the first one
-    fn walk_fats(&mut self, mut clusno: u32) -> Vec<u32> {
+    // the clusters of the dir starting at `clusno`, contiguous when its
+    // entry said so
+    fn dir_clusters(&mut self, clusno: u32) -> io::Result<Vec<u32>> {
+        match self.runs.get(&clusno) {
+            Some(&cnt) => Ok((clusno..clusno + cnt).collect()),
+            None => self.walk_fats(clusno),
+        }
+    }
+
+    fn walk_fats(&mut self, mut clusno: u32) -> io::Result<Vec<u32>> {
+        // cluster 0 is how an unallocated stream says it has none
+        if clusno < 2 {
+            return Ok(vec![]);
+        }
+        stats::add(&STATS.fat_walks, 1);
         let mut ret = vec![];
         loop {
             ret.push(clusno);
-            match self.read_fat(clusno) {
+            match self.read_fat(clusno)? {
+                // a chain can't hold more clusters than the volume, past that
+                // it loops
+                _ if fio::sanitizing() && ret.len() > self.clus_cnt as usize => break,
                 // FatEnt::Free => panic!("[fio] walk_fats: unexpected fat entry"),
                 FatEnt::Chain(next) => clusno = next,
-                FatEnt::BadCluster => panic!("[fio] walk_fats: read a bad clustor"),
+                FatEnt::BadCluster if fio::sanitizing() => break,
+                FatEnt::BadCluster => {
+                    return Err(corrupt(format!(
+                        "the chain goes on from cluster {} to a bad one",
+                        clusno
+                    )))
+                }
                 FatEnt::EndOfChain => break,
                 FatEnt::Reserved => {
                     // TODO: after complete read_allocbit
@@ -597,7 +1085,166 @@ impl<D: Seek + Read> Fio<D> {
                 }
             }
         }
-        ret
+        Ok(ret)
+    }
+
+    // rewrite the attributes in the primary entry of the set `id` points at
+    // and fix up the set checksum, return the new attributes. nothing is
+    // written when they don't change
+    pub fn set_attributes(&mut self, id: u64, set: u16, clear: u16) -> io::Result<u16> {
+        let primary = self.update_primary(id, |ent| {
+            let attrs = (u16::from_le_bytes([ent[4], ent[5]]) & !clear) | set;
+            ent[4..6].copy_from_slice(&attrs.to_le_bytes());
+        })?;
+        Ok(u16::from_le_bytes([primary[4], primary[5]]))
+    }
+
+    // apply `edit` to the primary entry of the set identified by `id` and
+    // rewrite it with the set checksum recomputed, nothing is written when
+    // `edit` changes nothing. return the primary entry as it ends up
+    pub fn update_primary(
+        &mut self,
+        id: u64,
+        edit: impl FnOnce(&mut [u8; DirEnt::SZ]),
+    ) -> io::Result<[u8; DirEnt::SZ]> {
+        let mut bytes = self.entset_bytes(id)?;
+        let mut primary: [u8; DirEnt::SZ] = bytes[..DirEnt::SZ].try_into().unwrap();
+        let old = primary;
+        edit(&mut primary);
+        if primary == old {
+            return Ok(primary);
+        }
+
+        bytes[..DirEnt::SZ].copy_from_slice(&primary);
+        let checksum = spec::entset_checksum(&bytes, primary[1]);
+        primary[2..4].copy_from_slice(&checksum.to_le_bytes());
+        let (clusno, idx) = (id as u32, (id >> 32) as u32);
+        let at = self.clus_offset(clusno) + idx as u64 * DirEnt::SZ as u64;
+        self.device.write_all_at(&primary, at)?;
+        Ok(primary)
+    }
+
+    // the raw entries of the set `id` points at, the primary and its
+    // secondaries
+    pub fn entset_bytes(&mut self, id: u64) -> io::Result<Vec<u8>> {
+        let per_clus = self.clus_sz / DirEnt::SZ as u32;
+        let (mut clusno, mut idx) = (id as u32, (id >> 32) as u32);
+        let mut ent = [0u8; DirEnt::SZ];
+        let at = self.clus_offset(clusno) + idx as u64 * DirEnt::SZ as u64;
+        self.device.read_exact_at(&mut ent, at)?;
+        let secondary_cnt = ent[1];
+
+        // the secondaries may run into the next cluster of the directory
+        let mut bytes = ent.to_vec();
+        for _ in 0..secondary_cnt {
+            idx += 1;
+            if idx == per_clus {
+                let in_run = self
+                    .runs
+                    .range(..=clusno)
+                    .next_back()
+                    .is_some_and(|(&first, &cnt)| clusno + 1 < first + cnt);
+                clusno = match self.read_fat(clusno)? {
+                    _ if in_run => clusno + 1,
+                    FatEnt::Chain(next) => next,
+                    _ => return Err(io::Error::other("entry set runs off its directory")),
+                };
+                idx = 0;
+            }
+            let off = self.clus_offset(clusno) + idx as u64 * DirEnt::SZ as u64;
+            self.device.read_exact_at(&mut ent, off)?;
+            bytes.extend(ent);
+        }
+        Ok(bytes)
+    }
+
+    // see fio::clamp, the size of a file is held to its chain, or to the end
+    // of the volume when it's contiguous
+    fn clamp_entry(&mut self, fi: &mut fio::Finfo) -> io::Result<()> {
+        let last_clus = self.clus_cnt + 1;
+        let clusters = if !(2..=last_clus).contains(&fi.fst_clus) {
+            0
+        } else if fi.no_fat_chain {
+            let left = (last_clus - fi.fst_clus + 1) as u64;
+            fi.size.div_ceil(self.clus_sz as u64).min(left)
+        } else {
+            self.walk_fats(fi.fst_clus)?.len() as u64
+        };
+        fio::clamp(fi, self.clus_sz, last_clus, clusters);
+        Ok(())
+    }
+
+    // every cluster allocated to a file, in order
+    pub fn clusters_of(&mut self, fi: &fio::Finfo) -> io::Result<Vec<u32>> {
+        if fi.fst_clus == 0 {
+            Ok(vec![])
+        } else if fi.no_fat_chain {
+            let cnt = fi.size.div_ceil(self.clus_sz as u64) as u32;
+            Ok((fi.fst_clus..fi.fst_clus + cnt).collect())
+        } else {
+            self.walk_fats(fi.fst_clus)
+        }
+    }
+
+    // the device extents holding the file bytes [offset, offset + size).
+    // they stop at ValidDataLength, what's past it was never written
+    fn file_extents(
+        &mut self,
+        fi: &fio::Finfo,
+        offset: u64,
+        size: u32,
+    ) -> io::Result<Vec<(u64, usize)>> {
+        let valid = min(fi.valid_size, fi.size);
+        if fi.fst_clus == 0 || offset >= valid || size == 0 {
+            return Ok(vec![]);
+        }
+        let sz = min(size as u64, valid - offset) as u32;
+        let clus_sz = self.clus_sz as u64;
+        let start_clus = offset / clus_sz;
+        let end_clus = (offset + sz as u64 - 1) / clus_sz;
+        let cnt = end_clus - start_clus + 1;
+
+        let clusnos: Vec<u32> = if fi.no_fat_chain {
+            let first = fi.fst_clus as u64 + start_clus;
+            (first..first + cnt)
+                .map_while(|clusno| u32::try_from(clusno).ok())
+                .collect()
+        } else {
+            self.walk_fats(fi.fst_clus)?
+                .into_iter()
+                .skip(start_clus as usize)
+                .take(cnt as usize)
+                .collect()
+        };
+
+        let mut extents = vec![];
+        let (mut skip, mut left) = (offset % clus_sz, sz as u64);
+        for (first, cnt) in fio::clus_runs(&clusnos) {
+            let run_len = min(cnt as u64 * self.clus_sz as u64 - skip, left);
+            extents.push((
+                self.clus_heap_base + (first - 2) as u64 * self.clus_sz as u64 + skip,
+                run_len as usize,
+            ));
+            left -= run_len;
+            skip = 0;
+        }
+        Ok(extents)
+    }
+
+    pub fn readfile(&mut self, fi: &fio::Finfo, offset: u64, size: u32) -> io::Result<Vec<u8>> {
+        let _p = trace::purpose("data");
+        let extents = self.file_extents(fi, offset, size)?;
+        let len = extents.iter().map(|&(_, len)| len).sum();
+        let mut bytes = vec![0u8; len];
+        self.device.read_extents(&extents, &mut bytes)?;
+        bytes.truncate(self.device.usable_len(&extents));
+        // between ValidDataLength and DataLength the clusters hold whatever
+        // was there before, it reads as zeros
+        if bytes.len() == len {
+            let want = min(size as u64, fi.size.saturating_sub(offset));
+            bytes.resize(want as usize, 0);
+        }
+        Ok(bytes)
     }
 
     // given a cluster number, return the absolute sector numbers this cluster holds
@@ -607,28 +1254,329 @@ impl<D: Seek + Read> Fio<D> {
         off..off + self.secs_per_clus as u64
     }
 
-    pub fn read_dirents(&mut self, clusno: u32) -> Vec<DirEnt> {
+    // the entry sets of the page of read_dirents_page, those with a stream
+    // extension `keep` turns down are left out before their names are decoded
+    fn list_sets(
+        &mut self,
+        clusno: u32,
+        from: u64,
+        max: usize,
+        keep: impl Fn(&dirent::StreamExt) -> bool,
+    ) -> io::Result<(Vec<fio::Finfo>, Option<u64>)> {
         let mut ret = vec![];
+        let (ents, next) = self.read_dirents_page(clusno, from, max)?;
+        let mut pending_list = vec![];
+        let mut pending_cnt = 0;
+        // where each cluster is in the chain, for the entries' positions
+        let per_clus = (self.clus_sz as usize / DirEnt::SZ) as u64;
+        let chain: BTreeMap<u32, u64> = self
+            .dir_clusters(clusno)?
+            .into_iter()
+            .enumerate()
+            .map(|(i, clusno)| (clusno, i as u64))
+            .collect();
 
-        let clusno_list = self.walk_fats(clusno);
-        'reading: for clusno in clusno_list.into_iter() {
+        let mut flush = |pending_list: Vec<EntrySet>| {
+            if let Some(EntrySet::StreamExt(ent)) = pending_list.get(1) {
+                if !keep(ent) {
+                    return Ok(());
+                }
+            }
+            if let Ok(mut fi) = fio::Finfo::try_from(pending_list) {
+                fi.pos += chain[&(fi.id as u32)] * per_clus;
+                if fio::sanitizing() {
+                    self.clamp_entry(&mut fi)?;
+                }
+                ret.push(fi);
+            } else {
+                eprintln!("[fio] list_dir: dirents reduction failed");
+            };
+            io::Result::Ok(())
+        };
+        for ent in ents.into_iter() {
+            if let Some(set_ent) = Option::<EntrySet>::from(ent) {
+                if let EntrySet::FileOrDir(primary) = &set_ent {
+                    if !pending_list.is_empty() {
+                        flush(std::mem::take(&mut pending_list))?;
+                    }
+                    pending_cnt = primary.secondary_cnt as usize + 1;
+                    if fio::sanitizing() {
+                        // a stream extension and the names of up to 255
+                        // characters, what's past them isn't part of the set
+                        pending_cnt = pending_cnt.clamp(3, 2 + 255usize.div_ceil(15));
+                    }
+                } else if pending_list.is_empty() {
+                    // a secondary entry left behind by a deleted set
+                    continue;
+                }
+                pending_list.push(set_ent);
+                if pending_list.len() == pending_cnt {
+                    flush(std::mem::take(&mut pending_list))?;
+                }
+            }
+        }
+        if !pending_list.is_empty() {
+            flush(pending_list)?;
+        }
+
+        let last_clus = self.clus_cnt + 1;
+        for fi in ret.iter().filter(|fi| fi.is_dir && fi.no_fat_chain) {
+            if (2..=last_clus).contains(&fi.fst_clus) {
+                let left = last_clus - fi.fst_clus + 1;
+                let cnt = fi.size.div_ceil(self.clus_sz as u64).min(left as u64);
+                self.runs.insert(fi.fst_clus, cnt as u32);
+            }
+        }
+        Ok((ret, next))
+    }
+
+    pub fn read_dirents(&mut self, clusno: u32) -> io::Result<Vec<DirEnt>> {
+        Ok(self.read_dirents_page(clusno, 0, usize::MAX)?.0)
+    }
+
+    // the in-use entries from slot `from` on, up to the file entry after
+    // `max` of them. the slot of that one comes along when there's more
+    pub fn read_dirents_page(
+        &mut self,
+        clusno: u32,
+        from: u64,
+        max: usize,
+    ) -> io::Result<(Vec<DirEnt>, Option<u64>)> {
+        let _p = trace::purpose("dirent");
+        let mut ret = vec![];
+        let mut files = 0;
+        let mut next = None;
+
+        let per_clus = (self.clus_sz as usize / DirEnt::SZ) as u64;
+        let clusno_list = self.dir_clusters(clusno)?;
+        let mut sec = self.pool.take(self.sec_sz as usize);
+        'reading: for (i, clusno) in clusno_list
+            .into_iter()
+            .enumerate()
+            .skip((from / per_clus) as usize)
+        {
             let mut off = 0;
             for secno in self.secnos_of_clusno(clusno) {
-                let sec = self.read_sec(secno);
+                let slot = i as u64 * per_clus + off as u64;
+                let per_sec = (sec.len() / DirEnt::SZ) as u32;
+                if slot + (per_sec as u64) <= from {
+                    off += per_sec;
+                    continue;
+                }
+                self.read_sec_into(secno, &mut sec)?;
+                let at = secno * self.sec_sz as u64;
+                if self.device.usable_len(&[(at, sec.len())]) < sec.len() {
+                    // an unreadable sector, the next one may still list entries
+                    off += per_sec;
+                    continue;
+                }
                 for buf in sec.chunks(DirEnt::SZ) {
-                    match DirEnt::new(buf, clusno, off) {
+                    let slot = i as u64 * per_clus + off as u64;
+                    off += 1;
+                    if slot < from {
+                        continue;
+                    }
+                    match DirEnt::new(buf, clusno, off - 1) {
                         Ok(dirent) => match dirent {
                             DirEnt::Unused => (),
                             DirEnt::FinalUnused => break 'reading,
+                            DirEnt::FileOrDir(_) if files == max => {
+                                next = Some(slot);
+                                break 'reading;
+                            }
+                            DirEnt::FileOrDir(_) => {
+                                files += 1;
+                                ret.push(dirent);
+                            }
                             _ => ret.push(dirent),
                         },
-                        Err(err) => panic!("[fio] read_dirents: {}", err),
+                        Err(err) => return Err(corrupt(format!("cluster {}: {}", clusno, err))),
+                    }
+                }
+            }
+        }
+        self.pool.give(sec);
+        Ok((ret, next))
+    }
+}
+
+// an entry as found on disk, including the ones marked unused
+struct RawEnt {
+    in_use: bool,
+    ent: DirEnt,
+    clusno: u32,
+    off: u32,
+}
+
+#[allow(dead_code)]
+impl<D: Device> Fio<D> {
+    // like read_dirents, but keeps the entries whose InUse bit is cleared
+    fn read_raw_dirents(&mut self, clusno: u32) -> io::Result<Vec<RawEnt>> {
+        let mut ret = vec![];
+        let clusno_list = self.dir_clusters(clusno)?;
+        let mut sec = self.pool.take(self.sec_sz as usize);
+        'reading: for clusno in clusno_list.into_iter() {
+            let mut off = 0;
+            for secno in self.secnos_of_clusno(clusno) {
+                self.read_sec_into(secno, &mut sec)?;
+                for buf in sec.chunks(DirEnt::SZ) {
+                    if buf[0] == 0 {
+                        break 'reading;
+                    }
+                    let parsed = if buf[0] & 0x80 != 0 {
+                        DirEnt::new(buf, clusno, off).ok().map(|ent| (true, ent))
+                    } else {
+                        DirEnt::new_unused(buf, clusno, off)
+                            .ok()
+                            .flatten()
+                            .map(|ent| (false, ent))
+                    };
+                    if let Some((in_use, ent)) = parsed {
+                        ret.push(RawEnt {
+                            in_use,
+                            ent,
+                            clusno,
+                            off,
+                        });
                     }
                     off += 1;
                 }
             }
         }
-        ret
+        self.pool.give(sec);
+        Ok(ret)
+    }
+
+    // entry sets whose primary entry was marked unused (or overwritten) while
+    // the secondaries survived, reassembled from every directory reachable from
+    // the root. the results are returned as (parent dir path, Finfo)
+    pub fn recover_entsets(&mut self) -> io::Result<Vec<(String, fio::Finfo)>> {
+        let mut ret = vec![];
+        let mut dirs = vec![(String::from("/"), self.root_clusno)];
+        // a corrupt dir may list one of its parents as a subdir
+        let mut seen = BTreeSet::new();
+        while let Some((path, clusno)) = dirs.pop() {
+            if !seen.insert(clusno) {
+                continue;
+            }
+            let mut ents = self.list_sets(clusno, 0, usize::MAX, |_| true)?.0;
+            fio::dedup_names(&mut ents);
+            for fi in ents {
+                if fi.is_dir && fi.fst_clus != 0 {
+                    dirs.push((format!("{}{}/", path, fi.name), fi.fst_clus));
+                }
+            }
+            for mut fi in self.recover_in(clusno)? {
+                // a freed chain reads as a single cluster, assume contiguity then
+                if !fi.no_fat_chain && fi.size > self.clus_sz as u64 {
+                    let needed = fi.size.div_ceil(self.clus_sz as u64) as usize;
+                    if self.walk_fats(fi.fst_clus)?.len() < needed {
+                        fi.no_fat_chain = true;
+                    }
+                }
+                ret.push((path.clone(), fi));
+            }
+        }
+        Ok(ret)
+    }
+
+    fn recover_in(&mut self, clusno: u32) -> io::Result<Vec<fio::Finfo>> {
+        let ents = self.read_raw_dirents(clusno)?;
+        let mut ret = vec![];
+        let mut i = 0;
+        while i < ents.len() {
+            let (skip, fi) = match &ents[i].ent {
+                DirEnt::FileOrDir(primary) => {
+                    let cnt = primary.secondary_cnt as usize;
+                    if ents[i].in_use {
+                        (cnt + 1, None)
+                    } else {
+                        (cnt + 1, self.reassemble(&ents, i, cnt))
+                    }
+                }
+                DirEnt::StreamExt(_) => {
+                    let (cnt, fi) = self.reassemble_orphan_stream(&ents, i);
+                    (cnt, fi)
+                }
+                _ => (1, None),
+            };
+            ret.extend(fi);
+            i += skip.max(1);
+        }
+        Ok(ret)
+    }
+
+    // a set whose primary entry is marked unused, the secondaries still follow
+    fn reassemble(&self, ents: &[RawEnt], i: usize, cnt: usize) -> Option<fio::Finfo> {
+        if cnt < 2 || i + cnt >= ents.len() {
+            return None;
+        }
+        let mut set = vec![];
+        for raw in ents[i..=i + cnt].iter() {
+            set.push(match &raw.ent {
+                DirEnt::FileOrDir(ent) => EntrySet::FileOrDir(ent.clone()),
+                DirEnt::StreamExt(ent) => EntrySet::StreamExt(ent.clone()),
+                DirEnt::FileName(ent) => EntrySet::FileName(ent.clone()),
+                _ => return None,
+            });
+        }
+        fio::Finfo::try_from(set)
+            .ok()
+            .filter(|fi| self.plausible(fi))
+    }
+
+    // a stream extension whose primary entry is gone, only the name, size and
+    // first cluster can be recovered
+    fn reassemble_orphan_stream(&self, ents: &[RawEnt], i: usize) -> (usize, Option<fio::Finfo>) {
+        let stream = match &ents[i].ent {
+            DirEnt::StreamExt(stream) => stream,
+            _ => return (1, None),
+        };
+        let name_ents = (stream.name_length as usize).div_ceil(15);
+        let mut units = vec![];
+        for raw in ents.iter().skip(i + 1).take(name_ents) {
+            match &raw.ent {
+                DirEnt::FileName(ent) => units.extend(ent.filename),
+                _ => return (1, None),
+            }
+        }
+        units.truncate(stream.name_length as usize);
+        if let Some(end) = units.iter().position(|&c| c == 0) {
+            units.truncate(end);
+        }
+        let name = fio::name_from_utf16(&units);
+        if name.is_empty() {
+            return (1, None);
+        }
+        let (fst_clus, size, valid_size) = stream.extent();
+        let fi = fio::Finfo {
+            id: (ents[i].off as u64) << 32 | ents[i].clusno as u64,
+            pos: ents[i].off as u64,
+            name,
+            is_rdonly: true,
+            is_hidden: false,
+            is_system: false,
+            is_dir: false,
+            size,
+            valid_size,
+            fst_clus,
+            no_fat_chain: stream.no_fat_chain(),
+            crt_time: SystemTime::UNIX_EPOCH,
+            wrt_time: SystemTime::UNIX_EPOCH,
+            acc_time: SystemTime::UNIX_EPOCH,
+        };
+        let fi = Some(fi).filter(|fi| self.plausible(fi));
+        (1 + name_ents, fi)
+    }
+
+    fn plausible(&self, fi: &fio::Finfo) -> bool {
+        let max_bytes = self.clus_cnt as u64 * self.clus_sz as u64;
+        let clus_ok = if fi.fst_clus == 0 {
+            fi.size == 0
+        } else {
+            (2..=self.clus_cnt + 1).contains(&fi.fst_clus)
+        };
+        clus_ok && fi.valid_size <= fi.size && fi.size <= max_bytes
     }
 }
 
@@ -644,66 +1592,139 @@ impl TryFrom<Vec<EntrySet>> for fio::Finfo {
             _ => return Err(Self::Error::DirEntReductionFailed),
         };
 
-        let mut name = String::new();
+        let mut units = vec![];
         for ent in ents[2..].iter() {
             let ent_name = match ent {
                 EntrySet::FileName(ent_name) => ent_name,
                 _ => return Err(Self::Error::DirEntReductionFailed),
             };
-            name.push_str(&String::from(ent_name));
+            units.extend(ent_name.filename);
         }
+        if fio::sanitizing() {
+            // NameLength characters, as many as the entries present hold
+            units.truncate(ent_stream.name_length as usize);
+        } else if let Some(end) = units.iter().position(|&c| c == 0) {
+            units.truncate(end);
+        }
+        let name = fio::name_from_utf16(&units);
 
-        // TODO
-        // 'check: {}
-
+        let (fst_clus, size, valid_size) = ent_stream.extent();
         Ok(Finfo {
             id: (ent_file.ent_off as u64) << 32 | ent_file.ent_clusno as u64,
+            pos: ent_file.ent_off as u64, // within the cluster, see list_dir
             name,
             acc_time: ent_file.acc_time(),
             crt_time: ent_file.crt_time(),
             wrt_time: ent_file.mod_time(),
-            fst_clus: ent_stream.first_cluster,
+            fst_clus,
+            no_fat_chain: ent_stream.no_fat_chain(),
             is_dir: ent_file.is_dir(),
             is_hidden: ent_file.is_hidden(),
             is_rdonly: ent_file.is_rdonly(),
             is_system: ent_file.is_system(),
-            size32: 0,
-            size: ent_stream.valid_data_length,
+            size,
+            valid_size,
         })
     }
 }
 
-impl<D: Seek + Read> fio::Fio for Fio<D> {
-    fn list_dir(&mut self, clusno: u32) -> Vec<fio::Finfo> {
-        let mut ret = vec![];
-        let ents = self.read_dirents(clusno);
-        let mut pending_list = vec![];
+impl<D: Device> fio::Fio for Fio<D> {
+    fn list_dir(&mut self, clusno: u32) -> io::Result<Vec<fio::Finfo>> {
+        let mut ents = self.list_dir_page(clusno, 0, usize::MAX)?.0;
+        fio::dedup_names(&mut ents);
+        Ok(ents)
+    }
 
-        for ent in ents.into_iter() {
-            if let Some(set_ent) = Option::<EntrySet>::from(ent) {
-                if set_ent.is_primary() && !pending_list.is_empty() {
-                    if let Ok(fi) = fio::Finfo::try_from(pending_list) {
-                        ret.push(fi);
-                    } else {
-                        println!("[fio] list_dir: dirents reduction failed");
-                    };
-                    pending_list = vec![];
-                }
-                pending_list.push(set_ent);
-            }
+    fn list_dir_page(
+        &mut self,
+        clusno: u32,
+        from: u64,
+        max: usize,
+    ) -> io::Result<(Vec<fio::Finfo>, Option<u64>)> {
+        self.list_sets(clusno, from, max, |_| true)
+    }
+
+    // the sets whose stream extension has the hash of `name` are the only
+    // ones decoded. with NFC on the name may be stored in another form, a
+    // U+FFFD may stand for a unit that isn't one, neither hashes the same
+    fn find(&mut self, clusno: u32, name: &str) -> Option<io::Result<Option<fio::Finfo>>> {
+        if fio::normalizing() || name.contains('\u{FFFD}') {
+            return None;
         }
+        let units: Vec<u16> = name.encode_utf16().collect();
+        let hash = self.upcase.name_hash(&units);
+        let found = self.list_sets(clusno, 0, usize::MAX, |ent| ent.name_hash == hash);
+        Some(found.map(|(files, _)| files.into_iter().find(|fi| fi.name == name)))
+    }
 
-        ret
+    fn lost_found(&mut self) -> io::Result<Vec<fio::Finfo>> {
+        let found = self.recover_entsets()?;
+        Ok(found.into_iter().map(|(_, fi)| fi).collect())
     }
 
-    fn list_root(&mut self) -> Vec<fio::Finfo> {
+    fn list_root(&mut self) -> io::Result<Vec<fio::Finfo>> {
         self.list_dir(self.root_clusno)
     }
 
-    fn read_file(&mut self, fi: &fio::Finfo, offset: u32, size: u32) -> Vec<u8> {
-        let _ = fi;
-        let _ = offset;
-        let _ = size;
-        vec![]
+    fn read_file(&mut self, fi: &fio::Finfo, offset: u64, size: u32) -> io::Result<Vec<u8>> {
+        self.readfile(fi, offset, size)
+    }
+
+    fn unreadable(&mut self, fi: &fio::Finfo, offset: u64, size: u32) -> Vec<(u64, u64)> {
+        // a chain that can't be followed failed the read before
+        let extents = self.file_extents(fi, offset, size).unwrap_or_default();
+        fio::file_spans(offset, self.device.bad_spans(&extents))
+    }
+
+    fn volume(&mut self) -> io::Result<fio::VolumeInfo> {
+        Ok(fio::VolumeInfo {
+            serial: self.bootsec.volumn_serial_number,
+            label: self.volume_label()?,
+            clus_sz: self.clus_sz,
+            clus_cnt: self.clus_cnt,
+            free: Some(self.clus_cnt.saturating_sub(self.allocated()?)),
+        })
+    }
+
+    // the up-case table, the runs of the dirs without a FAT chain and the
+    // buffers
+    fn held(&self) -> u64 {
+        let upcase = std::mem::size_of::<[u16; 0x10000]>() as u64;
+        upcase + self.runs.len() as u64 * 32 + self.pool.held()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::spec::{dirent::DirEnt, BootSec};
+    use crate::testing::round_trips;
+
+    #[test]
+    fn bootsec_round_trip() {
+        round_trips(BootSec::SZ, |buf| {
+            let buf: &[u8; BootSec::SZ] = (&*buf).try_into().unwrap();
+            assert_eq!(BootSec::new(buf).unwrap().dump(), *buf);
+        });
+    }
+
+    #[test]
+    fn dirent_round_trip() {
+        let types = [0x81, 0x82, 0x83, 0x85, 0xC0, 0xC1];
+        let mut i = 0;
+        round_trips(DirEnt::SZ, |buf| {
+            buf[0] = types[i % types.len()];
+            i += 1;
+            let ent = DirEnt::new(buf, 2, 0).unwrap();
+            assert_eq!(ent.dump(), Some(buf.try_into().unwrap()));
+        });
+    }
+
+    #[test]
+    fn unused_dirents() {
+        let end = [0u8; DirEnt::SZ];
+        assert_eq!(DirEnt::new(&end, 2, 0).unwrap().dump(), Some(end));
+        let mut deleted = [0u8; DirEnt::SZ];
+        deleted[0] = 0x05;
+        assert_eq!(DirEnt::new(&deleted, 2, 0).unwrap().dump(), None);
     }
 }
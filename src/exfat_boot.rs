@@ -0,0 +1,262 @@
+// the main and backup boot regions of an exFAT volume, sectors 0 to 11 and
+// 12 to 23. refer to [1] 3.1
+// [1] https://learn.microsoft.com/en-us/windows/win32/fileio/exfat-specification
+
+use std::{fmt, io};
+
+use crate::device::Device;
+use crate::exfat::spec::{self, BootSec};
+use crate::extract::hex;
+use crate::gpt::Guid;
+
+// refer to [1] 3.3.2, the parameters of flash media
+const FLASH_PARAMS: Guid = Guid::from_fields(0x0A0C7E46, 0x3399, 0x4021, 0x90C8FA6D389C4BA2);
+
+// what extended boot sectors end with
+const EXT_BOOT_SIGNATURE: u32 = 0xAA550000;
+
+pub struct Region {
+    sec_sz: usize,
+    bytes: Vec<u8>,
+}
+
+impl Region {
+    pub const SECS: usize = 12;
+
+    pub fn read<D: Device>(device: &D, first_sec: u64, sec_sz: usize) -> io::Result<Self> {
+        let mut bytes = vec![0u8; Self::SECS * sec_sz];
+        device.read_exact_at(&mut bytes, first_sec * sec_sz as u64)?;
+        Ok(Region { sec_sz, bytes })
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    fn sec(&self, no: usize) -> &[u8] {
+        &self.bytes[no * self.sec_sz..(no + 1) * self.sec_sz]
+    }
+
+    pub fn boot_sec(&self) -> BootSec {
+        BootSec::new(self.bytes[..BootSec::SZ].try_into().unwrap()).unwrap()
+    }
+
+    // the checksum sector repeats one value, none when its copies disagree
+    pub fn recorded_checksum(&self) -> Option<u32> {
+        let mut sums = self
+            .sec(11)
+            .chunks_exact(4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+        let first = sums.next()?;
+        sums.all(|sum| sum == first).then_some(first)
+    }
+
+    pub fn computed_checksum(&self) -> u32 {
+        spec::boot_checksum(&self.bytes, self.sec_sz as u16)
+    }
+
+    pub fn checksum_ok(&self) -> bool {
+        self.recorded_checksum() == Some(self.computed_checksum())
+    }
+
+    // every field of the region decoded, (name, value) in on-disk order
+    pub fn fields(&self) -> Vec<(String, String)> {
+        let b = self.boot_sec();
+        let raw = &self.bytes;
+        let mut fields = vec![
+            field("JumpBoot", hex(&b.jmp_boot)),
+            field(
+                "FileSystemName",
+                format!("{:?}", String::from_utf8_lossy(&raw[3..11])),
+            ),
+            field("MustBeZero", zeros(&raw[11..64])),
+            field("PartitionOffset", b.partition_offset),
+            field("VolumeLength", b.volumn_length),
+            field("FatOffset", b.fat_offset),
+            field("FatLength", b.fat_length),
+            field("ClusterHeapOffset", b.cluster_heap_offset),
+            field("ClusterCount", b.cluster_count),
+            field("FirstClusterOfRootDirectory", b.first_cluster_of_root_dir),
+            field(
+                "VolumeSerialNumber",
+                format!("{:#010x}", b.volumn_serial_number),
+            ),
+            field("FileSystemRevision", b.revision()),
+            field("VolumeFlags", format!("{:#06x}", b.volumn_flags)),
+            field("BytesPerSectorShift", b.bytes_per_sector_shift),
+            field("SectorsPerClusterShift", b.sectors_per_cluster_shift),
+            field("NumberOfFats", b.number_of_fats),
+            field("DriveSelect", format!("{:#04x}", b.drive_select)),
+            field("PercentInUse", b.percent_in_use),
+            field("BootCode", code(&b.boot_code)),
+            field(
+                "BootSignature",
+                format!("{:#06x}", u16::from_le_bytes([raw[510], raw[511]])),
+            ),
+        ];
+        for no in 1..=8 {
+            let sec = self.sec(no);
+            let (code_part, sig) = sec.split_at(sec.len() - 4);
+            let sig = u32::from_le_bytes([sig[0], sig[1], sig[2], sig[3]]);
+            let sig = match sig {
+                EXT_BOOT_SIGNATURE => String::from("signed"),
+                sig => format!("signature {:#010x}", sig),
+            };
+            fields.push(field(
+                &format!("ExtendedBootSector{}", no),
+                format!("{}, {}", sig, code(code_part)),
+            ));
+        }
+        for (no, param) in self.sec(9).chunks_exact(48).take(10).enumerate() {
+            fields.push(field(&format!("OemParameter{}", no), oem_param(param)));
+        }
+        fields.push(field("Reserved", zeros(self.sec(10))));
+        let recorded = match self.recorded_checksum() {
+            Some(sum) => format!("{:#010x}", sum),
+            None => String::from("copies disagree"),
+        };
+        fields.push(field("BootChecksum", recorded));
+        fields.push(field(
+            "BootChecksum computed",
+            format!("{:#010x}", self.computed_checksum()),
+        ));
+        fields
+    }
+}
+
+fn field(name: &str, value: impl fmt::Display) -> (String, String) {
+    (name.to_string(), value.to_string())
+}
+
+fn zeros(bytes: &[u8]) -> String {
+    match bytes.iter().filter(|&&b| b != 0).count() {
+        0 => String::from("zeros"),
+        n => format!("{} bytes set", n),
+    }
+}
+
+// code only told apart by a checksum of it
+fn code(bytes: &[u8]) -> String {
+    if bytes.iter().all(|&b| b == 0) {
+        return String::from("no code");
+    }
+    format!("code {:#010x}", spec::table_checksum(bytes))
+}
+
+// refer to [1] 3.3.1, a GUID and 32 bytes that depend on it
+fn oem_param(param: &[u8]) -> String {
+    let guid = Guid(param[..16].try_into().unwrap());
+    if guid.is_zero() {
+        return String::from("unused");
+    }
+    if guid != FLASH_PARAMS {
+        return format!("{}, {}", guid, hex(&param[16..]));
+    }
+    let f: Vec<u32> = param[16..44]
+        .chunks_exact(4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect();
+    format!(
+        "flash, erase block {}, page {}, spare sectors {}, random access {}ns, \
+         programming {}ns, read cycle {}ns, write cycle {}ns",
+        f[0], f[1], f[2], f[3], f[4], f[5], f[6]
+    )
+}
+
+// the sector size both regions are read with: the main boot sector's, or
+// the first backup boot sector found when the main one is unreadable
+fn sec_sz<D: Device>(device: &D) -> io::Result<usize> {
+    let mut buf = [0u8; BootSec::SZ];
+    device.read_exact_at(&mut buf, 0)?;
+    let shift = buf[108];
+    if (9..=12).contains(&shift) && &buf[3..11] == b"EXFAT   " {
+        return Ok(1 << shift);
+    }
+    for shift in 9..=12u8 {
+        let sec_sz = 1usize << shift;
+        if device
+            .read_exact_at(&mut buf, (Region::SECS * sec_sz) as u64)
+            .is_ok()
+            && &buf[3..11] == b"EXFAT   "
+            && buf[108] == shift
+        {
+            return Ok(sec_sz);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "no exFAT boot sector, main or backup",
+    ))
+}
+
+pub fn read_regions<D: Device>(device: &D) -> io::Result<(Region, Region)> {
+    let sec_sz = sec_sz(device)?;
+    let main = Region::read(device, 0, sec_sz)?;
+    let backup = Region::read(device, Region::SECS as u64, sec_sz)?;
+    Ok((main, backup))
+}
+
+fn verdict(region: &Region) -> &'static str {
+    if region.checksum_ok() {
+        "checksum ok"
+    } else {
+        "checksum bad"
+    }
+}
+
+// both regions side by side, fields that differ marked with `*`
+pub fn dump<D: Device>(device: &D) -> io::Result<()> {
+    let (main, backup) = read_regions(device)?;
+    let rows: Vec<_> = main.fields().into_iter().zip(backup.fields()).collect();
+    // OEM parameters can run long, the backup's goes under those
+    let width = 24;
+    println!("  {:<28} {:<width$} backup", "", "main");
+    for ((name, a), (_, b)) in rows.iter() {
+        let mark = if a != b { '*' } else { ' ' };
+        if a.len() > width {
+            println!("{} {:<28} {}", mark, name, a);
+            println!("  {:<28} {:<width$} {}", "", "", b);
+        } else {
+            println!("{} {:<28} {:<width$} {}", mark, name, a, b);
+        }
+    }
+    println!(
+        "  {:<28} {:<width$} {}",
+        "",
+        verdict(&main),
+        verdict(&backup)
+    );
+    Ok(())
+}
+
+// the fields the boot checksum leaves out, refer to [1] 3.4. only the main
+// region's are kept up to date, so the backup's may differ on a good volume
+const UNCHECKED: [&str; 2] = ["VolumeFlags", "PercentInUse"];
+
+// only the fields that differ, and which region can be trusted
+pub fn diff<D: Device>(device: &D) -> io::Result<()> {
+    let (main, backup) = read_regions(device)?;
+    let mut differ = 0;
+    for ((name, a), (_, b)) in main.fields().into_iter().zip(backup.fields()) {
+        if a == b {
+            continue;
+        }
+        differ += 1;
+        let note = if UNCHECKED.contains(&name.as_str()) {
+            " (not checksummed)"
+        } else {
+            ""
+        };
+        println!("{}{}: main {}, backup {}", name, note, a, b);
+    }
+    let verdict = match (main.checksum_ok(), backup.checksum_ok()) {
+        (true, true) => "both regions have a valid checksum",
+        (true, false) => "only the main region has a valid checksum",
+        (false, true) => {
+            "only the backup region has a valid checksum, the main one can be rebuilt from it"
+        }
+        (false, false) => "neither region has a valid checksum",
+    };
+    say!("[boot-diff] {} fields differ, {}", differ, verdict);
+    Ok(())
+}
@@ -0,0 +1,67 @@
+// what fat32x exits with, the same for every command, so scripts can tell
+// a volume that needs fsck from a device that's gone
+
+use std::{
+    io, process,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+pub const OK: i32 = 0;
+// bad arguments, a path that isn't on the volume, a whole disk without
+// --partition
+pub const USAGE: i32 = 1;
+// not a filesystem the command works with
+pub const NOT_A_FS: i32 = 2;
+// the filesystem was read but is corrupt, fsck found problems
+pub const CORRUPT: i32 = 3;
+// the device couldn't be read or written
+pub const IO: i32 = 4;
+
+pub const HELP: &str = "\
+Exit codes:
+  0    done
+  1    usage: bad arguments, or a path that isn't on the volume
+  2    not a filesystem the command works with
+  3    the filesystem is corrupt, fsck found problems
+  4    the device couldn't be read or written
+  101  a bug, fat32x panicked";
+
+pub fn code(e: &io::Error) -> i32 {
+    match e.kind() {
+        io::ErrorKind::InvalidInput | io::ErrorKind::NotFound => USAGE,
+        io::ErrorKind::Unsupported => NOT_A_FS,
+        io::ErrorKind::InvalidData => CORRUPT,
+        _ => IO,
+    }
+}
+
+// why the command failed, on stderr so it shows through --quiet, and the
+// exit with the code for it
+pub fn fail(e: impl Into<io::Error>) -> ! {
+    let e = e.into();
+    eprintln!("{}", e);
+    process::exit(code(&e))
+}
+
+// whether what's said along the way is left out, see say!
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+// from here on only errors and what was asked for are printed, the
+// progress and summary lines of say! aren't
+pub fn set_quiet() {
+    QUIET.store(true, Ordering::Relaxed);
+}
+
+pub fn quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+// println! for what's said along the way, the "[cmd] ..." lines. left out
+// with --quiet, data a command was asked for is printed with println!
+macro_rules! say {
+    ($($arg:tt)*) => {
+        if !$crate::exit::quiet() {
+            println!($($arg)*);
+        }
+    };
+}
@@ -3,7 +3,7 @@
 
 #![allow(dead_code)]
 
-mod spec {
+pub mod spec {
     use scroll::{Pread, LE};
 
     #[derive(Debug)]
@@ -13,12 +13,12 @@ mod spec {
         r_blocks_cnt: u32,
         free_blocks_cnt: u32,
         free_inodes_cnt: u32,
-        first_data_block: u32,
+        pub first_data_block: u32,
         log2_block_size: u32, // in KBytes
         log2_frag_size: u32,  // in KBytes
-        blocks_per_group: u32,
+        pub blocks_per_group: u32,
         frags_per_group: u32,
-        inodes_per_group: u32,
+        pub inodes_per_group: u32,
         mtime: u32,           // `unused`
         wtime: u32,           // `unused`
         mnt_cnt: u16,         // `unused`
@@ -37,7 +37,7 @@ mod spec {
         first_ino: u32,
         inode_size: u16,
         block_group_nr: u16,    // `unused`
-        feature_compat: u32,    // `unused`
+        feature_compat: u32,    // check only
         feature_incompat: u32,  // check only
         feature_ro_compat: u32, // `unused`
         uuid: [u8; 16],         // `unused`
@@ -49,9 +49,9 @@ mod spec {
         realloc_dir_blocks: u8,
         // 208..=236 Journaling Support
         journal_uuid: [u8; 16], // `unused`
-        journal_inum: u32,      // `unused`
-        journal_dev: u32,       // `unused`
-        last_orphan: u32,       // `unused`
+        pub journal_inum: u32,
+        journal_dev: u32, // `unused`
+        last_orphan: u32, // `unused`
         // 236..=252 Directory Indexing Support
         hash_seed: [u32; 4],
         def_hash_version: u8,
@@ -64,6 +64,7 @@ mod spec {
         const EXT2_SUPER_MAGIC: u16 = 0xEF53;
         const EXT2_GOOD_OLD_INODE_SIZE: u16 = 128;
         const EXT2_GOOD_OLD_FIRST_INO: u32 = 11;
+        const EXT3_FEATURE_COMPAT_HAS_JOURNAL: u32 = 0x04;
         const EXT2_FEATURE_INCOMPAT_COMPRESSION: u32 = 0x01;
         const EXT2_FEATURE_INCOMPAT_FILETYPE: u32 = 0x02;
         const EXT3_FEATURE_INCOMPAT_RECOVER: u32 = 0x04;
@@ -132,11 +133,20 @@ mod spec {
             self.magic == Self::EXT2_SUPER_MAGIC
                 && self.state == Self::EXT2_VALID_FS
                 && self.feature_incompat & Self::EXT2_FEATURE_INCOMPAT_COMPRESSION == 0
-                && self.feature_incompat & Self::EXT3_FEATURE_INCOMPAT_RECOVER == 0
                 && self.feature_incompat & Self::EXT3_FEATURE_INCOMPAT_JOURNAL_DEV == 0
                 && self.feature_incompat & Self::EXT2_FEATURE_INCOMPAT_META_BG == 0
         }
 
+        // an ext3 volume that wasn't cleanly unmounted, its journal holds
+        // writes that never made it to their place
+        pub fn needs_recovery(&self) -> bool {
+            self.feature_incompat & Self::EXT3_FEATURE_INCOMPAT_RECOVER != 0
+        }
+
+        pub fn has_journal(&self) -> bool {
+            self.feature_compat & Self::EXT3_FEATURE_COMPAT_HAS_JOURNAL != 0
+        }
+
         #[inline]
         pub fn blk_sz(&self) -> u32 {
             1 << self.log2_block_size << 10
@@ -162,50 +172,721 @@ mod spec {
             }
         }
 
+        pub fn inodes_cnt(&self) -> u32 {
+            self.inodes_cnt
+        }
+
+        pub fn label(&self) -> String {
+            let end = self.volume_name.iter().position(|&b| b == 0);
+            String::from_utf8_lossy(&self.volume_name[..end.unwrap_or(16)]).into_owned()
+        }
+
+        // the UUID the way blkid shows it
+        pub fn uuid(&self) -> String {
+            let u = &self.uuid;
+            [&u[..4], &u[4..6], &u[6..8], &u[8..10], &u[10..]]
+                .iter()
+                .map(|part| {
+                    part.iter()
+                        .map(|b| format!("{:02x}", b))
+                        .collect::<String>()
+                })
+                .collect::<Vec<_>>()
+                .join("-")
+        }
+
+        // what statfs(2) reports for the volume, the reserved blocks are
+        // free but not available to unprivileged users
+        pub fn statfs(&self) -> Statfs {
+            Statfs {
+                blocks: self.blocks_cnt as u64,
+                bfree: self.free_blocks_cnt as u64,
+                bavail: self.free_blocks_cnt.saturating_sub(self.r_blocks_cnt) as u64,
+                files: self.inodes_cnt as u64,
+                ffree: self.free_inodes_cnt as u64,
+                bsize: self.blk_sz(),
+                namelen: 255,
+            }
+        }
+
         pub fn groups_cnt(&self) -> u32 {
-            if self.blocks_cnt % self.blocks_per_group != 0 {
-                self.blocks_cnt / self.blocks_per_group + 1
+            self.blocks_cnt
+                .saturating_sub(self.first_data_block)
+                .div_ceil(self.blocks_per_group)
+        }
+
+        pub fn blocks_cnt(&self) -> u32 {
+            self.blocks_cnt
+        }
+
+        // no more blocks than `fit`, what the device holds
+        pub fn fit_blocks(&mut self, fit: u64) {
+            let cnt = self.blocks_cnt as u64;
+            self.blocks_cnt =
+                crate::fio::fit_count("ext2", "block count", cnt, &[(fit, "the device")]) as u32;
+        }
+    }
+
+    // in the order of the fuser::ReplyStatfs arguments
+    #[derive(Debug)]
+    pub struct Statfs {
+        pub blocks: u64,
+        pub bfree: u64,
+        pub bavail: u64,
+        pub files: u64,
+        pub ffree: u64,
+        pub bsize: u32,
+        pub namelen: u32,
+    }
+
+    // an entry of the block group descriptor table, which starts in the
+    // block after the superblock
+    #[derive(Debug)]
+    pub struct GroupDesc {
+        pub block_bitmap: u32,
+        pub inode_bitmap: u32,
+        pub inode_table: u32,
+        pub free_blocks_count: u16,
+        pub free_inodes_count: u16,
+        pub used_dirs_count: u16,
+        pad: u16,           // `unused`
+        reserved: [u8; 12], // `unused`
+    }
+
+    impl GroupDesc {
+        pub const SZ: usize = 32;
+
+        pub fn new(buf: &[u8]) -> Result<Self, scroll::Error> {
+            Ok(GroupDesc {
+                block_bitmap: buf.pread_with(0, LE)?,
+                inode_bitmap: buf.pread_with(4, LE)?,
+                inode_table: buf.pread_with(8, LE)?,
+                free_blocks_count: buf.pread_with(12, LE)?,
+                free_inodes_count: buf.pread_with(14, LE)?,
+                used_dirs_count: buf.pread_with(16, LE)?,
+                pad: buf.pread_with(18, LE)?,
+                reserved: buf.pread_with(20, LE)?,
+            })
+        }
+    }
+
+    #[derive(Debug)]
+    pub struct Inode {
+        pub mode: u16,
+        pub uid: u16,
+        pub size: u32,
+        pub atime: u32,
+        pub ctime: u32,
+        pub mtime: u32,
+        pub dtime: u32,
+        pub gid: u16,
+        pub links_count: u16,
+        pub blocks: u32, // in 512-byte units
+        pub flags: u32,
+        osd1: u32, // `unused`
+        // 12 direct, then the single, double and triple indirect pointers,
+        // or the extent tree root with EXT4_EXTENTS_FL
+        pub block: [u32; 15],
+        pub generation: u32, // `unused`
+        pub file_acl: u32,   // `unused`
+        // i_dir_acl, rev 1 keeps the high 32 bits of a regular file's size
+        // there (i_size_high), files of 2 GiB and more need them
+        pub size_high: u32,
+        faddr: u32,        // `unused`
+        pub uid_high: u16, // Linux osd2
+        pub gid_high: u16, // Linux osd2
+    }
+
+    // an extent of a leaf node in the extent tree
+    #[derive(Debug)]
+    pub struct Extent {
+        pub logical: u32,
+        pub len: u16,
+        pub start: u64,
+    }
+
+    impl Inode {
+        pub const EXT2_ROOT_INO: u32 = 2;
+        const EXT4_EXTENTS_FL: u32 = 0x80000;
+        const EXT4_EXT_MAGIC: u16 = 0xF30A;
+
+        pub fn new(buf: &[u8]) -> Result<Self, scroll::Error> {
+            Ok(Inode {
+                mode: buf.pread_with(0, LE)?,
+                uid: buf.pread_with(2, LE)?,
+                size: buf.pread_with(4, LE)?,
+                atime: buf.pread_with(8, LE)?,
+                ctime: buf.pread_with(12, LE)?,
+                mtime: buf.pread_with(16, LE)?,
+                dtime: buf.pread_with(20, LE)?,
+                gid: buf.pread_with(24, LE)?,
+                links_count: buf.pread_with(26, LE)?,
+                blocks: buf.pread_with(28, LE)?,
+                flags: buf.pread_with(32, LE)?,
+                osd1: buf.pread_with(36, LE)?,
+                block: buf.pread_with(40, LE)?,
+                generation: buf.pread_with(100, LE)?,
+                file_acl: buf.pread_with(104, LE)?,
+                size_high: buf.pread_with(108, LE)?,
+                faddr: buf.pread_with(112, LE)?,
+                uid_high: buf.pread_with(120, LE)?,
+                gid_high: buf.pread_with(122, LE)?,
+            })
+        }
+
+        pub fn is_dir(&self) -> bool {
+            self.mode & 0xF000 == 0x4000
+        }
+
+        pub fn is_reg(&self) -> bool {
+            self.mode & 0xF000 == 0x8000
+        }
+
+        pub fn file_size(&self) -> u64 {
+            if self.is_reg() {
+                (self.size_high as u64) << 32 | self.size as u64
             } else {
-                self.blocks_cnt / self.blocks_per_group
+                self.size as u64
+            }
+        }
+
+        pub fn uid(&self) -> u32 {
+            (self.uid_high as u32) << 16 | self.uid as u32
+        }
+
+        pub fn gid(&self) -> u32 {
+            (self.gid_high as u32) << 16 | self.gid as u32
+        }
+
+        // like `ls -l`, "drwxr-xr-x"
+        pub fn mode_str(&self) -> String {
+            let kind = match self.mode & 0xF000 {
+                0x1000 => 'p',
+                0x2000 => 'c',
+                0x4000 => 'd',
+                0x6000 => 'b',
+                0x8000 => '-',
+                0xA000 => 'l',
+                0xC000 => 's',
+                _ => '?',
+            };
+            let mut s = String::from(kind);
+            for (i, c) in "rwxrwxrwx".chars().enumerate() {
+                s.push(if self.mode & (0o400 >> i) != 0 {
+                    c
+                } else {
+                    '-'
+                });
+            }
+            s
+        }
+
+        pub fn uses_extents(&self) -> bool {
+            self.flags & Self::EXT4_EXTENTS_FL != 0
+        }
+
+        // the extents in the tree root, none when it isn't a leaf or holds
+        // no tree at all. (depth, extents)
+        pub fn extents(&self) -> Option<(u16, Vec<Extent>)> {
+            let mut raw = [0u8; 60];
+            for (i, b) in self.block.iter().enumerate() {
+                raw[i * 4..i * 4 + 4].copy_from_slice(&b.to_le_bytes());
+            }
+            let magic: u16 = raw.pread_with(0, LE).ok()?;
+            if !self.uses_extents() || magic != Self::EXT4_EXT_MAGIC {
+                return None;
             }
+            let entries: u16 = raw.pread_with(2, LE).ok()?;
+            let depth: u16 = raw.pread_with(6, LE).ok()?;
+            if depth != 0 {
+                return Some((depth, vec![]));
+            }
+            let mut ret = vec![];
+            for i in 0..entries.min(4) as usize {
+                let off = 12 + i * 12;
+                let hi: u16 = raw.pread_with(off + 6, LE).ok()?;
+                let lo: u32 = raw.pread_with(off + 8, LE).ok()?;
+                ret.push(Extent {
+                    logical: raw.pread_with(off, LE).ok()?,
+                    len: raw.pread_with(off + 4, LE).ok()?,
+                    start: (hi as u64) << 32 | lo as u64,
+                });
+            }
+            Some((0, ret))
         }
     }
 }
 
-use std::io::{Read, Seek, SeekFrom};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    io::{self, Write},
+    time::{Duration, SystemTime},
+};
+
+use chrono::DateTime;
+use clap::ValueEnum;
+
+use crate::device::Device;
+use crate::fio::{self, Finfo};
+use crate::jbd2;
 
-use spec::Sblk;
+use spec::{GroupDesc, Inode, Sblk};
 
-pub struct Fio<D: Seek + Read> {
+fn fmt_time(t: u32) -> String {
+    match t {
+        0 => "-".to_string(),
+        t => DateTime::from_timestamp(t as i64, 0).map_or(t.to_string(), |t| t.to_string()),
+    }
+}
+
+impl fmt::Display for Inode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "mode: {} ({:06o})", self.mode_str(), self.mode)?;
+        writeln!(f, "uid: {}, gid: {}", self.uid(), self.gid())?;
+        writeln!(f, "size: {}", self.file_size())?;
+        writeln!(f, "links: {}", self.links_count)?;
+        writeln!(f, "blocks: {} (512-byte)", self.blocks)?;
+        writeln!(f, "flags: {:#x}", self.flags)?;
+        writeln!(f, "atime: {}", fmt_time(self.atime))?;
+        writeln!(f, "ctime: {}", fmt_time(self.ctime))?;
+        writeln!(f, "mtime: {}", fmt_time(self.mtime))?;
+        writeln!(f, "dtime: {}", fmt_time(self.dtime))?;
+        if self.uses_extents() {
+            match self.extents() {
+                Some((0, extents)) => {
+                    for e in extents {
+                        writeln!(
+                            f,
+                            "extent: logical {}, {} blocks at {}",
+                            e.logical, e.len, e.start
+                        )?;
+                    }
+                }
+                Some((depth, _)) => writeln!(f, "extent tree of depth {}", depth)?,
+                None => writeln!(f, "extents flag set, no extent header")?,
+            }
+            return Ok(());
+        }
+        writeln!(f, "direct: {:?}", &self.block[..12])?;
+        write!(
+            f,
+            "indirect: {}, double: {}, triple: {}",
+            self.block[12], self.block[13], self.block[14]
+        )
+    }
+}
+
+// what to do about a volume whose journal still needs replaying
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Journal {
+    // don't open it
+    Refuse,
+    // read the blocks as they are on the device, what the journal holds is
+    // missing and metadata may be inconsistent
+    Ignore,
+    // read the blocks as replaying the journal would leave them, in memory
+    // only
+    Replay,
+}
+
+pub struct Fio<D: Device> {
     blk_sz: u32,
     bgp_per_block: u32,
     device: D,
+    // block -> content, the journal's version of blocks, see Journal::Replay
+    overlay: BTreeMap<u64, Vec<u8>>,
+    // (inode, its blocks) of the file read last, see file_blocks
+    last_blocks: Option<(u32, Vec<u32>)>,
+    listed: fio::LastListed,
     pub sblk: Sblk,
 }
 
-impl<D: Seek + Read> Fio<D> {
-    pub fn new(mut device: D) -> Self {
+// keeps a Finfo id off the root's 1, which a corrupt dir may name
+const ID_TAG: u64 = 1 << 63;
+
+impl<D: Device> Fio<D> {
+    pub fn new(device: D, journal: Journal) -> io::Result<Self> {
         let mut buf = [0u8; 1024];
 
-        device.seek(SeekFrom::Start(1024)).unwrap();
-        device.read_exact(&mut buf).unwrap();
-        let sblk = Sblk::new(&buf).unwrap();
-        assert!(sblk.is_valid());
+        device.read_exact_at(&mut buf, 1024)?;
+        let mut sblk = Sblk::new(&buf).map_err(io::Error::other)?;
+        if !sblk.is_valid() {
+            return Err(io::Error::other(
+                "no ext2 volume, or one with unsupported features",
+            ));
+        }
+        let recover = sblk.needs_recovery();
 
-        Fio {
+        sblk.fit_blocks(fio::reach(&device) / sblk.blk_sz() as u64);
+        let mut fio = Fio {
             blk_sz: sblk.blk_sz(),
             bgp_per_block: sblk.blk_sz() / 32,
             device,
+            overlay: BTreeMap::new(),
+            last_blocks: None,
+            listed: fio::LastListed::default(),
             sblk,
+        };
+        match journal {
+            _ if !recover => (),
+            Journal::Refuse => {
+                return Err(io::Error::other(
+                    "the journal needs recovery, see --journal",
+                ))
+            }
+            Journal::Ignore => eprintln!("[ext2] the journal needs recovery, ignoring it"),
+            Journal::Replay => fio.replay_journal()?,
+        }
+        Ok(fio)
+    }
+
+    fn replay_journal(&mut self) -> io::Result<()> {
+        let ino = self.sblk.journal_inum;
+        let inode = self
+            .read_inode(ino)?
+            .filter(|_| self.sblk.has_journal())
+            .ok_or_else(|| io::Error::other("the volume needs recovery but has no journal"))?;
+        let blocks = self.file_blocks(&inode)?;
+        // a block that can't be read fails the replay, saying which it is
+        let overlay = jbd2::replay(|n| match blocks.get(n as usize) {
+            Some(&blk) if blk != 0 => self.read_block(blk).map_err(|e| {
+                io::Error::new(e.kind(), format!("journal block {} ({}): {}", n, blk, e))
+            }),
+            _ => Err(io::Error::other("journal block out of its inode")),
+        })?;
+        say!(
+            "[ext2] replayed {} blocks from the journal in memory",
+            overlay.len()
+        );
+        self.overlay = overlay;
+
+        // the journal may carry a newer superblock
+        let (blk, off) = (1024 / self.blk_sz, (1024 % self.blk_sz) as usize);
+        if let Some(buf) = self.overlay.get(&(blk as u64)) {
+            if let Ok(mut sblk) = Sblk::new(&buf[off..]) {
+                sblk.fit_blocks(fio::reach(&self.device) / self.blk_sz as u64);
+                self.sblk = sblk;
+            }
         }
+        Ok(())
     }
 
-    fn read_block(&mut self, blk_no: u32) -> Vec<u8> {
+    fn read_block(&mut self, blk_no: u32) -> io::Result<Vec<u8>> {
+        if let Some(buf) = self.overlay.get(&(blk_no as u64)) {
+            return Ok(buf.clone());
+        }
         let mut buf = vec![0u8; self.blk_sz as usize];
         self.device
-            .seek(SeekFrom::Start((blk_no * self.blk_sz) as u64))
-            .unwrap();
-        self.device.read_exact(&mut buf).unwrap();
-        buf
+            .read_exact_at(&mut buf, blk_no as u64 * self.blk_sz as u64)?;
+        Ok(buf)
+    }
+
+    // the block group descriptor table, one entry per group
+    pub fn read_groups(&mut self) -> io::Result<Vec<GroupDesc>> {
+        let cnt = self.sblk.groups_cnt();
+        let first = self.sblk.first_data_block + 1;
+        let mut ret = Vec::with_capacity(cnt as usize);
+        for blk in 0..cnt.div_ceil(self.bgp_per_block) {
+            let buf = self.read_block(first + blk)?;
+            for ent in buf.chunks(GroupDesc::SZ) {
+                if ret.len() == cnt as usize {
+                    break;
+                }
+                ret.push(GroupDesc::new(ent).map_err(io::Error::other)?);
+            }
+        }
+        Ok(ret)
+    }
+
+    fn read_group(&mut self, no: u32) -> io::Result<GroupDesc> {
+        let first = self.sblk.first_data_block + 1;
+        let buf = self.read_block(first + no / self.bgp_per_block)?;
+        let off = (no % self.bgp_per_block) as usize * GroupDesc::SZ;
+        GroupDesc::new(&buf[off..]).map_err(io::Error::other)
+    }
+
+    // inode `ino` out of the inode table of its group, inodes count from 1.
+    // none when there's no such inode
+    pub fn read_inode(&mut self, ino: u32) -> io::Result<Option<Inode>> {
+        if ino == 0 || ino > self.sblk.inodes_cnt() {
+            return Ok(None);
+        }
+        let ipg = self.sblk.inodes_per_group;
+        let group = self.read_group((ino - 1) / ipg)?;
+        let inode_sz = self.sblk.inode_sz() as u64;
+        let off = ((ino - 1) % ipg) as u64 * inode_sz;
+        let blk = group.inode_table + (off / self.blk_sz as u64) as u32;
+        let buf = self.read_block(blk)?;
+        let at = (off % self.blk_sz as u64) as usize;
+        let Ok(mut inode) = Inode::new(&buf[at..at + inode_sz as usize]) else {
+            return Ok(None);
+        };
+        // rev 0 has no large files, whatever is there isn't a size
+        if self.sblk.is_rev0() {
+            inode.size_high = 0;
+        }
+        Ok(Some(inode))
+    }
+
+    // the block numbers of the file in order, 0 for a hole
+    fn file_blocks(&mut self, inode: &Inode) -> io::Result<Vec<u32>> {
+        let mut cnt = inode.file_size().div_ceil(self.blk_sz as u64);
+        if fio::sanitizing() {
+            let blocks = self.sblk.blocks_cnt() as u64;
+            cnt = fio::fit_count("ext2", "file blocks", cnt, &[(blocks, "the volume")]);
+        }
+        let cnt = cnt as usize;
+        let mut ret = vec![];
+        if inode.uses_extents() {
+            let Some((0, extents)) = inode.extents() else {
+                return Err(io::Error::other(
+                    "extent trees deeper than the inode aren't supported",
+                ));
+            };
+            for e in extents {
+                // lengths over 32768 mark uninitialized extents, they read as zeros
+                let (len, init) = match e.len {
+                    len if len > 32768 => (len - 32768, false),
+                    len => (len, true),
+                };
+                // nothing past the end is kept, however far out it says it is
+                if e.logical as usize >= cnt {
+                    continue;
+                }
+                ret.resize(e.logical as usize, 0);
+                for i in 0..(len as u64).min((cnt - ret.len()) as u64) {
+                    ret.push(if init { (e.start + i) as u32 } else { 0 });
+                }
+            }
+        } else {
+            ret.extend(&inode.block[..12]);
+            for (level, &blk) in inode.block[12..].iter().enumerate() {
+                if ret.len() >= cnt {
+                    break;
+                }
+                self.indirect_blocks(blk, level as u32, cnt, &mut ret)?;
+            }
+        }
+        ret.resize(cnt, 0);
+        Ok(ret)
+    }
+
+    // append the blocks behind an indirect block of `level` (0 for single)
+    // until there are `cnt`
+    fn indirect_blocks(
+        &mut self,
+        blk: u32,
+        level: u32,
+        cnt: usize,
+        out: &mut Vec<u32>,
+    ) -> io::Result<()> {
+        let per_blk = (self.blk_sz / 4) as usize;
+        if blk == 0 {
+            let covers = per_blk.pow(level + 1);
+            out.resize(cnt.min(out.len() + covers), 0);
+            return Ok(());
+        }
+        let buf = self.read_block(blk)?;
+        for ptr in buf
+            .chunks(4)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+        {
+            if out.len() >= cnt {
+                break;
+            }
+            if level == 0 {
+                out.push(ptr);
+            } else {
+                self.indirect_blocks(ptr, level - 1, cnt, out)?;
+            }
+        }
+        Ok(())
+    }
+
+    // (inode, name) of every entry in the directory
+    fn read_dir(&mut self, dir: &Inode) -> io::Result<Vec<(u32, String)>> {
+        let mut ret = vec![];
+        for blk in self.file_blocks(dir)? {
+            if blk == 0 {
+                continue;
+            }
+            let buf = self.read_block(blk)?;
+            let mut off = 0;
+            while off + 8 <= buf.len() {
+                let ino = u32::from_le_bytes(buf[off..off + 4].try_into().unwrap());
+                let rec_len = u16::from_le_bytes([buf[off + 4], buf[off + 5]]) as usize;
+                let name_len = buf[off + 6] as usize;
+                if rec_len < 8 || off + 8 + name_len > buf.len() {
+                    return Err(io::Error::other("corrupt directory entry"));
+                }
+                if ino != 0 {
+                    let name = String::from_utf8_lossy(&buf[off + 8..off + 8 + name_len]);
+                    ret.push((ino, name.into_owned()));
+                }
+                off += rec_len;
+            }
+        }
+        Ok(ret)
+    }
+
+    // follow `path` down from the root directory
+    pub fn lookup_path(&mut self, path: &str) -> io::Result<(u32, Inode)> {
+        let not_found = || io::Error::new(io::ErrorKind::NotFound, format!("{}: not found", path));
+        let mut ino = Inode::EXT2_ROOT_INO;
+        let mut inode = self.read_inode(ino)?.ok_or_else(not_found)?;
+        for name in path.split('/').filter(|n| !n.is_empty()) {
+            if !inode.is_dir() {
+                return Err(not_found());
+            }
+            ino = self
+                .read_dir(&inode)?
+                .into_iter()
+                .find(|(_, n)| n == name)
+                .map(|(ino, _)| ino)
+                .ok_or_else(not_found)?;
+            inode = self.read_inode(ino)?.ok_or_else(not_found)?;
+        }
+        Ok((ino, inode))
+    }
+
+    // write the content of a regular file to `out` a block at a time
+    pub fn cat(&mut self, inode: &Inode, out: &mut impl Write) -> io::Result<()> {
+        if !inode.is_reg() {
+            return Err(io::Error::other("not a regular file"));
+        }
+        let mut left = inode.file_size();
+        for blk in self.file_blocks(inode)? {
+            let n = left.min(self.blk_sz as u64) as usize;
+            if blk == 0 {
+                out.write_all(&vec![0u8; n])?;
+            } else {
+                out.write_all(&self.read_block(blk)?[..n])?;
+            }
+            left -= n as u64;
+        }
+        Ok(())
+    }
+
+    // the entries of dir `ino` but . and .., what isn't a dir or a regular
+    // file is left out
+    fn list(&mut self, ino: u32) -> io::Result<Vec<Finfo>> {
+        let Some(dir) = self.read_inode(ino)?.filter(Inode::is_dir) else {
+            return Ok(vec![]);
+        };
+        let time = |t: u32| SystemTime::UNIX_EPOCH + Duration::from_secs(t as u64);
+        let mut ret = vec![];
+        for (ino, name) in self.read_dir(&dir)? {
+            if name == "." || name == ".." {
+                continue;
+            }
+            let Some(inode) = self.read_inode(ino)? else {
+                continue;
+            };
+            let (is_dir, size, fst_clus) = match () {
+                _ if inode.is_dir() => (true, 0, ino),
+                _ if inode.is_reg() => (false, inode.file_size(), 0),
+                _ => continue,
+            };
+            ret.push(Finfo {
+                id: ID_TAG | ino as u64,
+                pos: ret.len() as u64,
+                name: fio::shown_name(name),
+                is_rdonly: inode.mode & 0o222 == 0,
+                is_hidden: false,
+                is_system: false,
+                is_dir,
+                size,
+                valid_size: size,
+                fst_clus,
+                no_fat_chain: false,
+                crt_time: time(inode.ctime),
+                wrt_time: time(inode.mtime),
+                acc_time: time(inode.atime),
+            });
+        }
+        Ok(ret)
+    }
+
+    fn read(&mut self, fi: &Finfo, offset: u64, size: u32) -> io::Result<Vec<u8>> {
+        let ino = (fi.id & !ID_TAG) as u32;
+        // reads go on where the last left off, the block list is kept
+        let blocks = match self.last_blocks.take() {
+            Some((i, blocks)) if i == ino => blocks,
+            _ => {
+                let inode = self.read_inode(ino)?.ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::NotFound, format!("no inode {}", ino))
+                })?;
+                self.file_blocks(&inode)?
+            }
+        };
+        let bs = self.blk_sz as u64;
+        let end = (offset + size as u64).min(fi.size);
+        let mut ret = vec![];
+        let mut at = offset;
+        while at < end {
+            let from = (at % bs) as usize;
+            let n = (bs - from as u64).min(end - at) as usize;
+            match blocks.get((at / bs) as usize) {
+                Some(&blk) if blk != 0 => ret.extend(&self.read_block(blk)?[from..from + n]),
+                _ => ret.resize(ret.len() + n, 0),
+            }
+            at += n as u64;
+        }
+        self.last_blocks = Some((ino, blocks));
+        Ok(ret)
+    }
+}
+
+// dirs are numbered by their inode
+impl<D: Device> fio::Fio for Fio<D> {
+    fn list_dir(&mut self, no: u32) -> io::Result<Vec<Finfo>> {
+        self.list(no)
+    }
+
+    fn list_root(&mut self) -> io::Result<Vec<Finfo>> {
+        self.list(Inode::EXT2_ROOT_INO)
+    }
+
+    fn list_dir_page(
+        &mut self,
+        no: u32,
+        from: u64,
+        max: usize,
+    ) -> io::Result<(Vec<Finfo>, Option<u64>)> {
+        let mut listed = std::mem::take(&mut self.listed);
+        let page = listed.page(no, from, max, |no| self.list(no));
+        self.listed = listed;
+        page
+    }
+
+    fn read_file(&mut self, fi: &Finfo, offset: u64, size: u32) -> io::Result<Vec<u8>> {
+        self.read(fi, offset, size)
+    }
+
+    // a block that can't be read fails the read, none is zero-filled
+    fn unreadable(&mut self, _fi: &Finfo, _offset: u64, _size: u32) -> Vec<(u64, u64)> {
+        vec![]
+    }
+
+    fn volume(&mut self) -> io::Result<fio::VolumeInfo> {
+        Ok(fio::VolumeInfo {
+            serial: 0,
+            label: self.sblk.label(),
+            clus_sz: self.blk_sz,
+            clus_cnt: self.sblk.blocks_cnt(),
+            free: Some(self.sblk.statfs().bfree as u32),
+        })
+    }
+
+    // the blocks replayed from the journal, the block list of the file read
+    // last and the dir listed last
+    fn held(&self) -> u64 {
+        let overlay = self.overlay.len() as u64 * (self.blk_sz as u64 + 48);
+        let blocks = self
+            .last_blocks
+            .as_ref()
+            .map_or(0, |(_, b)| b.len() as u64 * 4);
+        overlay + blocks + self.listed.held()
     }
 }
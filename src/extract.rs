@@ -0,0 +1,501 @@
+use std::{
+    borrow::Cow,
+    cmp::min,
+    collections::BTreeSet,
+    fs::{self, File},
+    io::{self, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+};
+
+use clap::ValueEnum;
+use sha2::{Digest, Sha256};
+
+use crate::device::Device;
+use crate::fio::{self, Finfo, Fio, FsType};
+
+const CHUNK_SZ: u32 = 1 << 20;
+// granularity at which all-zero data is skipped, leaving holes in the host file
+const HOLE_SZ: usize = 4096;
+
+// what becomes of a name the host can't have: a `/` or NUL, one of
+// <>:"\|?* or a reserved name like CON on Windows
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Names {
+    // the characters become `_`, a reserved name gets a `_` in front
+    Replace,
+    // the characters become %XX of their UTF-8 bytes, and `%` too
+    Percent,
+    // nothing is extracted
+    Fail,
+}
+
+#[cfg(windows)]
+const RESERVED: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+fn bad_char(c: char) -> bool {
+    if cfg!(windows) {
+        c < ' ' || "<>:\"/\\|?*".contains(c)
+    } else {
+        c == '/' || c == '\0'
+    }
+}
+
+// reserved whatever the extension, CON.txt as much as CON
+#[cfg(windows)]
+fn reserved(name: &str) -> bool {
+    let stem = name.split('.').next().unwrap().trim_end();
+    RESERVED.iter().any(|r| r.eq_ignore_ascii_case(stem))
+}
+
+#[cfg(not(windows))]
+fn reserved(_name: &str) -> bool {
+    false
+}
+
+// `name` the way it can be created on the host, an error when `names`
+// says to fail
+fn host_name(name: &str, names: Names) -> Result<Cow<'_, str>, String> {
+    // Windows drops trailing dots and spaces
+    let trailing =
+        |i: usize, c: char| cfg!(windows) && (c == '.' || c == ' ') && i == name.len() - 1;
+    let fits = !name.is_empty()
+        && name != "."
+        && name != ".."
+        && !reserved(name)
+        && !name
+            .char_indices()
+            .any(|(i, c)| bad_char(c) || trailing(i, c));
+    if fits {
+        return Ok(Cow::Borrowed(name));
+    }
+    let mut out = String::new();
+    match names {
+        Names::Fail => return Err(format!("{:?} can't be a file name here", name)),
+        Names::Replace => {
+            if reserved(name) || name.is_empty() || name == "." || name == ".." {
+                out.push('_');
+            }
+            for (i, c) in name.char_indices() {
+                out.push(if bad_char(c) || trailing(i, c) {
+                    '_'
+                } else {
+                    c
+                });
+            }
+        }
+        Names::Percent => {
+            for (i, c) in name.char_indices() {
+                let first_reserved = i == 0 && reserved(name);
+                let dots = i == 0 && (name == "." || name == "..");
+                if bad_char(c) || trailing(i, c) || c == '%' || first_reserved || dots {
+                    let mut buf = [0u8; 4];
+                    for b in c.encode_utf8(&mut buf).bytes() {
+                        out.push_str(&format!("%{:02X}", b));
+                    }
+                } else {
+                    out.push(c);
+                }
+            }
+            if out.is_empty() {
+                out.push_str("%00");
+            }
+        }
+    }
+    Ok(Cow::Owned(out))
+}
+
+pub struct ExtractOpts {
+    pub jobs: usize,
+    pub names: Names,
+    // hash the data while reading it, re-hash the written files and record both here
+    pub manifest: Option<PathBuf>,
+    // rescue mode: files with unreadable parts are kept with those parts
+    // zero-filled, and the parts are listed here
+    pub map: Option<PathBuf>,
+}
+
+struct Job {
+    fi: Finfo,
+    dest: PathBuf,
+}
+
+struct Verified {
+    path: PathBuf,
+    digest: String,
+    ok: bool,
+}
+
+#[derive(Default)]
+struct Tally {
+    files: usize,
+    bytes: u64,
+    verified: Vec<Verified>,
+    unreadable: Vec<PathBuf>,
+    missing: Vec<(PathBuf, Vec<(u64, u64)>)>,
+}
+
+// a digest of what was read of a file, and whether every read came back
+// with all it asked for. one that didn't fails verification whatever the
+// copy hashes to, the copy is made up to size with zeros that weren't read
+struct Hashed {
+    hex: String,
+    whole: bool,
+}
+
+// what became of a file, a digest of its content when verifying
+enum Copied {
+    Whole(Option<Hashed>),
+    // the device skipped unreadable data in it, see retry::OnError, and
+    // the partial copy was removed
+    Unreadable,
+    // rescue mode, the file was kept but these byte ranges (start, len) of
+    // it couldn't be read and are zeros
+    Partial(Option<Hashed>, Vec<(u64, u64)>),
+}
+
+// copy the whole volume tree under `dest`, the directory walk happens up front
+// and file contents are then read and written by `jobs` workers which share
+// the positional-read device
+pub fn extract<D: Device + Sync>(
+    device: &D,
+    typ: &FsType,
+    dest: &Path,
+    opts: &ExtractOpts,
+) -> io::Result<()> {
+    let mut queue = vec![];
+    let mut renamed = vec![];
+    let mut unlisted = vec![];
+    {
+        let mut fio = fio::open(device, typ)?;
+        fs::create_dir_all(dest)?;
+        let mut walker = Walker {
+            fio: fio.as_mut(),
+            names: opts.names,
+            queue: &mut queue,
+            renamed: &mut renamed,
+            unlisted: &mut unlisted,
+        };
+        let root = walker.list(None, "/");
+        walker.walk(root, "", dest)?;
+    }
+    report_renamed(&renamed, dest);
+    report_unlisted(&unlisted);
+
+    let verify = opts.manifest.is_some();
+    let rescue = opts.map.is_some();
+    let next = AtomicUsize::new(0);
+    let mut tally = thread::scope(|s| {
+        let workers: Vec<_> = (0..opts.jobs.max(1))
+            .map(|_| {
+                s.spawn(|| -> io::Result<Tally> {
+                    let mut fio = fio::open(device, typ)?;
+                    let mut tally = Tally::default();
+                    while let Some(job) = queue.get(next.fetch_add(1, Ordering::Relaxed)) {
+                        let digest = match write_file(fio.as_mut(), job, verify, rescue)? {
+                            Copied::Whole(digest) => digest,
+                            Copied::Unreadable => {
+                                tally.unreadable.push(job.dest.clone());
+                                continue;
+                            }
+                            Copied::Partial(digest, missing) => {
+                                let path = job.dest.strip_prefix(dest).unwrap().to_owned();
+                                tally.missing.push((path, missing));
+                                digest
+                            }
+                        };
+                        if let Some(Hashed { hex, whole }) = digest {
+                            let ok = whole && hash_file(&job.dest)? == hex;
+                            tally.verified.push(Verified {
+                                path: job.dest.strip_prefix(dest).unwrap().to_owned(),
+                                digest: hex,
+                                ok,
+                            });
+                        }
+                        tally.files += 1;
+                        tally.bytes += job.fi.size;
+                    }
+                    Ok(tally)
+                })
+            })
+            .collect();
+
+        let mut tally = Tally::default();
+        for worker in workers {
+            let part = worker.join().expect("[extract] worker panicked")?;
+            tally.files += part.files;
+            tally.bytes += part.bytes;
+            tally.verified.extend(part.verified);
+            tally.unreadable.extend(part.unreadable);
+            tally.missing.extend(part.missing);
+        }
+        io::Result::Ok(tally)
+    })?;
+    say!("[extract] {} files, {} bytes", tally.files, tally.bytes);
+    if !tally.unreadable.is_empty() {
+        tally.unreadable.sort();
+        say!(
+            "[extract] {} files left out, they hold unreadable data:",
+            tally.unreadable.len()
+        );
+        for path in tally.unreadable.iter() {
+            println!("  {}", path.display());
+        }
+    }
+
+    if let Some(map) = &opts.map {
+        write_map(map, &mut tally.missing)?;
+    }
+
+    if let Some(manifest) = &opts.manifest {
+        tally.verified.sort_by(|a, b| a.path.cmp(&b.path));
+        let mut out = BufWriter::new(File::create(manifest)?);
+        for v in tally.verified.iter() {
+            let status = if v.ok { "OK" } else { "FAILED" };
+            writeln!(out, "{}  {}  {}", v.digest, status, v.path.display())?;
+        }
+        out.flush()?;
+        let failed = tally.verified.iter().filter(|v| !v.ok).count();
+        say!(
+            "[extract] verified {} files, {} failed, manifest: {}",
+            tally.verified.len(),
+            failed,
+            manifest.display()
+        );
+    }
+    Ok(())
+}
+
+// copy the given entries (and whatever is below the dirs among them) under
+// `dest` on the calling thread
+pub fn extract_entries(fio: &mut dyn Fio, ents: Vec<Finfo>, dest: &Path) -> io::Result<()> {
+    let mut queue = vec![];
+    let mut renamed = vec![];
+    let mut unlisted = vec![];
+    fs::create_dir_all(dest)?;
+    Walker {
+        fio,
+        names: Names::Replace,
+        queue: &mut queue,
+        renamed: &mut renamed,
+        unlisted: &mut unlisted,
+    }
+    .walk(ents, "", dest)?;
+    report_renamed(&renamed, dest);
+    report_unlisted(&unlisted);
+    let mut unreadable = 0;
+    for job in queue.iter() {
+        if let Copied::Unreadable = write_file(fio, job, false, false)? {
+            unreadable += 1;
+        }
+    }
+    say!("[extract] {} files", queue.len() - unreadable);
+    if unreadable > 0 {
+        say!("[extract] {} files left out as unreadable", unreadable);
+    }
+    Ok(())
+}
+
+// the dirs made and the files queued while walking the tree, with the
+// volume paths of the names the host couldn't take as they were and of
+// the dirs that couldn't be listed
+struct Walker<'a, 'f> {
+    fio: &'a mut (dyn Fio + 'f),
+    names: Names,
+    queue: &'a mut Vec<Job>,
+    renamed: &'a mut Vec<(String, PathBuf)>,
+    unlisted: &'a mut Vec<String>,
+}
+
+impl Walker<'_, '_> {
+    // the entries of dir `no`, the root for none. one that can't be listed
+    // is noted and left empty, the walk goes on without it
+    fn list(&mut self, no: Option<u32>, vol_path: &str) -> Vec<Finfo> {
+        let ents = match no {
+            Some(no) => self.fio.list_dir(no),
+            None => self.fio.list_root(),
+        };
+        ents.unwrap_or_else(|e| {
+            eprintln!("[extract] {}: {}", vol_path, e);
+            self.unlisted.push(vol_path.to_string());
+            vec![]
+        })
+    }
+
+    fn walk(&mut self, dir: Vec<Finfo>, vol_path: &str, dest: &Path) -> io::Result<()> {
+        // the names that are fine as they are come first, a renamed entry
+        // can't take one of theirs
+        let mut taken: BTreeSet<String> = dir.iter().map(|fi| fi.name.clone()).collect();
+        for fi in dir {
+            if fi.name == "." || fi.name == ".." {
+                continue;
+            }
+            let fpath = format!("{}/{}", vol_path, fi.name);
+            let name = host_name(&fi.name, self.names).map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", fpath, e))
+            })?;
+            let path = match name {
+                Cow::Borrowed(name) => dest.join(name),
+                Cow::Owned(mut name) => {
+                    if taken.contains(&name) {
+                        name = fio::unique_name(&name, |n| taken.contains(n));
+                    }
+                    taken.insert(name.clone());
+                    let path = dest.join(name);
+                    self.renamed.push((fpath.clone(), path.clone()));
+                    path
+                }
+            };
+            if fi.is_dir {
+                fs::create_dir_all(&path)?;
+                if fi.fst_clus != 0 {
+                    let ents = self.list(Some(fi.fst_clus), &fpath);
+                    self.walk(ents, &fpath, &path)?;
+                }
+            } else {
+                self.queue.push(Job { fi, dest: path });
+            }
+        }
+        Ok(())
+    }
+}
+
+fn report_unlisted(unlisted: &[String]) {
+    if unlisted.is_empty() {
+        return;
+    }
+    say!(
+        "[extract] {} dirs left out, they can't be listed:",
+        unlisted.len()
+    );
+    for path in unlisted.iter() {
+        println!("  {}", path);
+    }
+}
+
+fn report_renamed(renamed: &[(String, PathBuf)], dest: &Path) {
+    if renamed.is_empty() {
+        return;
+    }
+    say!(
+        "[extract] {} names renamed to suit the host:",
+        renamed.len()
+    );
+    for (from, to) in renamed.iter() {
+        println!("  {} -> {}", from, to.strip_prefix(dest).unwrap().display());
+    }
+}
+
+// write the file sparsely, zero blocks and anything past the valid data
+// length are seeked over rather than written. when `verify` is set, return
+// the digest of the content as it was read from the volume. when `rescue` is
+// set, note the parts the device zero-filled
+fn write_file(fio: &mut dyn Fio, job: &Job, verify: bool, rescue: bool) -> io::Result<Copied> {
+    let mut file = File::create(&job.dest)?;
+    let mut hasher = verify.then(Sha256::new);
+    let mut missing: Vec<(u64, u64)> = vec![];
+    let valid_size = min(job.fi.valid_size, job.fi.size);
+    let mut offset: u64 = 0;
+    let mut whole = true;
+    while offset < valid_size {
+        let size = min(CHUNK_SZ as u64, valid_size - offset) as u32;
+        // a file whose clusters can't be found is left out like one the
+        // device can't read
+        let bytes = match fio.read_file(&job.fi, offset, size) {
+            Ok(bytes) if !bytes.is_empty() => bytes,
+            Ok(_) => break,
+            Err(e) => {
+                eprintln!("[extract] {}: {}", job.dest.display(), e);
+                break;
+            }
+        };
+        whole &= bytes.len() == size as usize;
+        if rescue {
+            for (start, len) in fio.unreadable(&job.fi, offset, bytes.len() as u32) {
+                match missing.last_mut() {
+                    Some((s, n)) if *s + *n == start => *n += len,
+                    _ => missing.push((start, len)),
+                }
+            }
+        }
+        if let Some(hasher) = hasher.as_mut() {
+            hasher.update(&bytes);
+        }
+        for blk in bytes.chunks(HOLE_SZ) {
+            if blk.iter().all(|&b| b == 0) {
+                file.seek(SeekFrom::Current(blk.len() as i64))?;
+            } else {
+                file.write_all(blk)?;
+            }
+        }
+        offset += bytes.len() as u64;
+    }
+    if offset < valid_size {
+        drop(file);
+        fs::remove_file(&job.dest)?;
+        return Ok(Copied::Unreadable);
+    }
+    file.set_len(job.fi.size)?;
+    file.set_modified(job.fi.wrt_time)?;
+
+    let digest = hasher.map(|mut hasher| {
+        let zeros = [0u8; HOLE_SZ];
+        while offset < job.fi.size {
+            let n = min(HOLE_SZ as u64, job.fi.size - offset) as usize;
+            hasher.update(&zeros[..n]);
+            offset += n as u64;
+        }
+        Hashed {
+            hex: hex(&hasher.finalize()),
+            whole,
+        }
+    });
+    Ok(match missing.is_empty() {
+        true => Copied::Whole(digest),
+        false => Copied::Partial(digest, missing),
+    })
+}
+
+// one line per missing range, `start  len  path` with the numbers in hex
+// like a ddrescue map, the files in path order
+fn write_map(map: &Path, missing: &mut [(PathBuf, Vec<(u64, u64)>)]) -> io::Result<()> {
+    missing.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut out = BufWriter::new(File::create(map)?);
+    writeln!(out, "# start  len  file")?;
+    let mut total = 0;
+    for (path, ranges) in missing.iter() {
+        for &(start, len) in ranges {
+            writeln!(out, "{:#010x}  {:#010x}  {}", start, len, path.display())?;
+            total += len;
+        }
+    }
+    out.flush()?;
+    say!(
+        "[extract] {} files with missing data, {} bytes zero-filled, map: {}",
+        missing.len(),
+        total,
+        map.display()
+    );
+    Ok(())
+}
+
+fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; CHUNK_SZ as usize];
+    loop {
+        match file.read(&mut buf)? {
+            0 => break,
+            n => hasher.update(&buf[..n]),
+        }
+    }
+    Ok(hex(&hasher.finalize()))
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -1,8 +1,10 @@
-use std::{cmp::min, io::SeekFrom, vec};
+use std::{cmp::min, io, vec};
 
-use super::spec::{BootSec, ClusNo, DirEnt, DirEntLfn, FatEnt};
-use crate::device::Device;
+use super::spec::{BootSec, ClusNo, DirEnt, DirEntLfn, FatEnt, FsInfo};
+use crate::device::{BufPool, Device};
 use crate::fio::{self, Finfo};
+use crate::stats::{self, STATS};
+use crate::trace;
 
 #[allow(dead_code)]
 #[derive(Debug, thiserror::Error)]
@@ -17,21 +19,35 @@ const SEC_SZ: usize = 512;
 type Sec = [u8; SEC_SZ];
 type Clus = Vec<u8>;
 
+fn corrupt(what: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what)
+}
+
+// the free entries of `fat` among clusters 2..=max_clus, what statfs and
+// fsck's FSInfo check both count
+pub fn free_in(fat: &[u32], max_clus: ClusNo) -> u32 {
+    (2..=max_clus as usize)
+        .filter(|&c| fat.get(c).is_some_and(|&ent| ent & 0x0FFFFFFF == 0))
+        .count() as u32
+}
+
+fn not_fat32(what: String) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("not a FAT32 volume: {}", what),
+    )
+}
+
 struct SecIo {
     base: u64, // sec number
     skip: u64, // secs
 }
 
 impl SecIo {
-    fn read(&self, sec_no: u64, device: &mut dyn Device) -> Sec {
+    fn read(&self, sec_no: u64, device: &dyn Device) -> io::Result<Sec> {
         let mut buf: Sec = [0u8; SEC_SZ];
-        device
-            .seek(SeekFrom::Start(
-                (self.base + self.skip + sec_no) * SEC_SZ as u64,
-            ))
-            .unwrap();
-        device.read_exact(&mut buf).unwrap();
-        buf
+        device.read_exact_at(&mut buf, (self.base + self.skip + sec_no) * SEC_SZ as u64)?;
+        Ok(buf)
     }
 }
 
@@ -42,59 +58,106 @@ struct ClusIo {
 }
 
 impl ClusIo {
-    fn read(&self, clus_no: u32, device: &mut dyn Device) -> Clus {
+    fn read(&self, clus_no: u32, device: &dyn Device) -> io::Result<Clus> {
         let mut buf = vec![0u8; self.clus_sz as usize];
-        device
-            .seek(SeekFrom::Start(
-                self.start + (self.skip + clus_no - 2) as u64 * self.clus_sz as u64,
-            ))
-            .unwrap();
-        device.read_exact(&mut buf).unwrap();
-        buf
+        self.read_into(clus_no, &mut buf, device)?;
+        Ok(buf)
     }
 
-    fn read_all(&self, fats: Vec<ClusNo>, device: &mut dyn Device) -> Vec<Clus> {
-        fats.into_iter()
-            .map(|clusno| self.read(clusno, device))
-            .collect()
+    fn read_into(&self, clus_no: u32, buf: &mut [u8], device: &dyn Device) -> io::Result<()> {
+        device.read_exact_at(buf, self.offset_of(clus_no))
+    }
+
+    fn offset_of(&self, clus_no: u32) -> u64 {
+        self.start + (self.skip + clus_no - 2) as u64 * self.clus_sz as u64
+    }
+
+    // the device extents holding `len` bytes starting `skip` bytes into the
+    // first of `clusnos`, one per run of physically consecutive clusters
+    fn extents(&self, clusnos: &[ClusNo], skip: u32, len: u32) -> Vec<(u64, usize)> {
+        let mut extents = vec![];
+        let (mut skip, mut left) = (skip as u64, len as u64);
+        for (first, cnt) in fio::clus_runs(clusnos) {
+            let run_len = min(cnt as u64 * self.clus_sz as u64 - skip, left);
+            extents.push((self.offset_of(first) + skip, run_len as usize));
+            left -= run_len;
+            skip = 0;
+        }
+        extents
     }
 }
 
 struct Fat {
     sec_io: SecIo,
     entries_per_sec: u64,
+    last_clus: ClusNo,
 }
 
 impl Fat {
     const ENT_SZ: usize = 4;
-    fn read_one(&self, no: u64, device: &mut dyn Device) -> FatEnt {
+    fn read_one(&self, no: u64, device: &dyn Device) -> io::Result<FatEnt> {
+        stats::add(&STATS.fat_reads, 1);
+        let _p = trace::purpose("fat");
         let sec_no = no / self.entries_per_sec;
         let ent_offset = (no % self.entries_per_sec) as usize;
-        let sec = self.sec_io.read(sec_no, device);
-        FatEnt::new(&sec[Fat::ENT_SZ * ent_offset..Fat::ENT_SZ * (ent_offset + 1)])
+        let sec = self.sec_io.read(sec_no, device)?;
+        Ok(FatEnt::new(
+            &sec[Fat::ENT_SZ * ent_offset..Fat::ENT_SZ * (ent_offset + 1)],
+        ))
     }
 
-    fn read_all(&self, device: &mut dyn Device, first_clusno: ClusNo) -> Vec<ClusNo> {
-        self.new_iter(device, first_clusno).collect()
+    fn read_all(&self, device: &dyn Device, first_clusno: ClusNo) -> io::Result<Vec<ClusNo>> {
+        let mut iter = self.new_iter(device, first_clusno);
+        let chain = iter.by_ref().collect();
+        iter.end().map(|_| chain)
     }
 
-    fn new_iter<'a>(&'a self, device: &'a mut dyn Device, first_clusno: ClusNo) -> FatIter {
-        match self.read_one(first_clusno.into(), device) {
-            FatEnt::Eoc | FatEnt::Next(_) => (),
-            en => panic!("fs err: trying to iterate a {:#?} Fat entry", en),
-        };
-        FatIter {
+    fn new_iter<'a>(&'a self, device: &'a dyn Device, first_clusno: ClusNo) -> FatIter<'a> {
+        stats::add(&STATS.fat_walks, 1);
+        let mut iter = FatIter {
             fat: self,
             device,
             next_clusno: Some(first_clusno),
+            walked: 0,
+            err: None,
+        };
+        if fio::sanitizing() && !(2..=self.last_clus).contains(&first_clusno) {
+            iter.next_clusno = None;
+        } else {
+            match self.read_one(first_clusno.into(), device) {
+                Ok(FatEnt::Eoc | FatEnt::Next(_)) => (),
+                Ok(_) if fio::sanitizing() => iter.next_clusno = None,
+                Ok(en) => iter.fail(corrupt(format!(
+                    "cluster {} starts a chain but its FAT entry is {:?}",
+                    first_clusno, en
+                ))),
+                Err(e) => iter.fail(e),
+            };
         }
+        iter
     }
 }
 
+// the clusters of a chain in order. it stops at the first FAT entry that
+// can't be read or makes no sense, end tells whether that's what happened
 struct FatIter<'a> {
     fat: &'a Fat,
-    device: &'a mut dyn Device,
+    device: &'a dyn Device,
     next_clusno: Option<ClusNo>,
+    walked: u32,
+    err: Option<io::Error>,
+}
+
+impl FatIter<'_> {
+    fn fail(&mut self, e: io::Error) {
+        self.next_clusno = None;
+        self.err = Some(e);
+    }
+
+    // why the chain stopped short, if it did
+    fn end(&mut self) -> io::Result<()> {
+        self.err.take().map_or(Ok(()), Err)
+    }
 }
 
 impl<'a> Iterator for FatIter<'a> {
@@ -102,10 +165,26 @@ impl<'a> Iterator for FatIter<'a> {
     fn next(&mut self) -> Option<Self::Item> {
         let curr = self.next_clusno;
         if let Some(no) = curr {
+            self.walked += 1;
             self.next_clusno = match self.fat.read_one(no.into(), self.device) {
-                FatEnt::Eoc => None,
-                FatEnt::Next(no) => Some(no),
-                _ => panic!("fs: err0"),
+                Err(e) => {
+                    self.fail(e);
+                    None
+                }
+                Ok(FatEnt::Eoc) => None,
+                // a chain can't hold more clusters than the volume, past
+                // that it loops
+                _ if fio::sanitizing() && self.walked > self.fat.last_clus => None,
+                Ok(FatEnt::Next(no)) if (2..=self.fat.last_clus).contains(&no) => Some(no),
+                Ok(FatEnt::Next(no)) if !fio::sanitizing() => Some(no),
+                _ if fio::sanitizing() => None,
+                Ok(en) => {
+                    self.fail(corrupt(format!(
+                        "the chain goes on from cluster {} to a {:?} FAT entry",
+                        no, en
+                    )));
+                    None
+                }
             };
         };
         curr
@@ -114,23 +193,33 @@ impl<'a> Iterator for FatIter<'a> {
 
 #[allow(dead_code)]
 pub struct Fio<'a> {
-    device: Box<(dyn Device + 'a)>,
+    device: Box<dyn Device + 'a>,
     fat: Fat,
     clus_io: ClusIo,
     pub root_clusno: ClusNo,
     clus_sz: u32,
+    pool: BufPool,
     pub bootsec: BootSec,
+    // what check_fat32 found unusual, nothing writes to such a volume
+    pub odd: Vec<String>,
+    // (dir, n, cluster n of its chain) where list_dir_page stopped, the
+    // next page walks the chain on from there
+    resume: Option<(ClusNo, usize, ClusNo)>,
 }
 
 #[allow(dead_code)]
 impl<'a> Fio<'a> {
-    pub fn new(mut device: impl Device + 'a) -> Self {
+    // Unsupported when the boot sector isn't one fat32x can read
+    pub fn new(device: impl Device + 'a) -> io::Result<Self> {
+        let _p = trace::purpose("boot");
         let mut buf: Sec = [0u8; SEC_SZ];
-        device.seek(SeekFrom::Start(0)).unwrap();
-        device.read_exact(&mut buf).unwrap();
+        device.read_exact_at(&mut buf, 0)?;
 
-        let bootsec = BootSec::new(&mut buf).unwrap();
-        bootsec.check_fat32();
+        let bootsec = BootSec::new(&mut buf).map_err(|e| not_fat32(e.to_string()))?;
+        let odd = bootsec.check_fat32().map_err(not_fat32)?;
+        for what in odd.iter() {
+            eprintln!("[fat32] {}, the volume is only read", what);
+        }
 
         let clus_io = ClusIo {
             start: bootsec.data_start_sector() as u64 * bootsec.bpb_byts_per_sec as u64,
@@ -143,34 +232,75 @@ impl<'a> Fio<'a> {
                 skip: bootsec.bpb_fat_sz_32.into(),
             },
             entries_per_sec: bootsec.bpb_byts_per_sec as u64 / Fat::ENT_SZ as u64,
+            last_clus: Self::count_clusters(&bootsec, fio::reach(&device)) + 1,
         };
-        Fio {
+        Ok(Fio {
             device: Box::new(device),
             fat: fat_1,
             clus_io,
             root_clusno: bootsec.bpb_root_clus,
             clus_sz: bootsec.cluster_size(),
+            pool: BufPool::default(),
             bootsec,
-        }
+            odd,
+            resume: None,
+        })
     }
 
-    pub fn read_clus(&mut self, clusno: ClusNo) -> Clus {
-        self.clus_io.read(clusno, self.device.as_mut())
+    pub fn read_clus(&mut self, clusno: ClusNo) -> io::Result<Clus> {
+        self.clus_io.read(clusno, self.device.as_ref())
     }
 
-    pub fn read_dirents(&mut self, first_clusno: ClusNo) -> Vec<Finfo> {
+    pub fn read_dirents(&mut self, first_clusno: ClusNo) -> io::Result<Vec<Finfo>> {
         if first_clusno == 0 {
             // a empty dir entry has first_clusno set to 0
-            return vec![];
+            return Ok(vec![]);
+        }
+        if first_clusno == 1 {
+            return Err(corrupt(String::from("a dir starts at cluster 1")));
         }
-        assert!(first_clusno != 1);
+        let fats = self.fat.read_all(self.device.as_ref(), first_clusno)?;
+        // let mut fat_iter = self.fat.new_iter(self.device.as_ref(), first_clusno);
+        self.read_dirents_in(&fats)
+    }
+
+    // parse the dir entries held by an already resolved cluster chain
+    pub fn read_dirents_in(&mut self, fats: &[ClusNo]) -> io::Result<Vec<Finfo>> {
+        Ok(self.read_dirents_page(fats, 0, 0, usize::MAX)?.0)
+    }
+
+    // up to `max` entries of the chain from slot `from` on, and the slot
+    // after the last one when there may be more. `fats` is the chain from
+    // its cluster `base` on
+    pub fn read_dirents_page(
+        &mut self,
+        fats: &[ClusNo],
+        base: usize,
+        from: u64,
+        max: usize,
+    ) -> io::Result<(Vec<Finfo>, Option<u64>)> {
+        let _p = trace::purpose("dirent");
         let mut res: Vec<Finfo> = vec![];
-        let fats = self.fat.read_all(self.device.as_mut(), first_clusno);
-        // let mut fat_iter = self.fat.new_iter(self.device.as_mut(), first_clusno);
         let mut ents: Vec<DirEnt> = vec![];
-        for clus_no in fats.into_iter() {
-            let clus = self.clus_io.read(clus_no, self.device.as_mut());
-            for (off, buf) in clus.chunks(DirEnt::SZ as usize).enumerate() {
+        let mut clus = self.pool.take(self.clus_sz as usize);
+        let per_clus = (self.clus_sz / DirEnt::SZ) as u64;
+        let mut next = None;
+        let first = (from / per_clus) as usize;
+        'reading: for (i, &clus_no) in (base..).zip(fats).skip(first.saturating_sub(base)) {
+            self.clus_io
+                .read_into(clus_no, &mut clus, self.device.as_ref())?;
+            let at = self.clus_io.offset_of(clus_no);
+            if self.device.usable_len(&[(at, clus.len())]) < clus.len() {
+                // an unreadable cluster, the next one may still list entries
+                ents.clear();
+                continue;
+            }
+            let skip = if i == first { from % per_clus } else { 0 };
+            for (off, buf) in clus
+                .chunks(DirEnt::SZ as usize)
+                .enumerate()
+                .skip(skip as usize)
+            {
                 match DirEnt::new(buf, clus_no, off as u32) {
                     Ok(dirent @ DirEnt::Lfn(_)) => {
                         ents.push(dirent);
@@ -181,60 +311,290 @@ impl<'a> Fio<'a> {
                             break;
                         }
                         ents.push(DirEnt::Sfn(en));
-                        if let Ok(file) = Finfo::try_from(ents) {
+                        if let Ok(mut file) = Finfo::try_from(ents) {
+                            file.pos += i as u64 * per_clus;
+                            if fio::sanitizing() {
+                                self.clamp_entry(&mut file)?;
+                            }
                             res.push(file)
                         };
                         ents = vec![];
+                        if res.len() == max {
+                            next = Some(i as u64 * per_clus + off as u64 + 1);
+                            break 'reading;
+                        }
                     }
-                    Err(_) => panic!("[fio] read_dirents: failed."),
+                    Err(e) => return Err(corrupt(format!("cluster {}: {}", clus_no, e))),
                 };
             }
         }
-        res
+        self.pool.give(clus);
+        Ok((res, next))
+    }
+
+    // see fio::clamp, the size of a file is held to its chain. a dir's is
+    // zero on FAT32
+    fn clamp_entry(&self, fi: &mut Finfo) -> io::Result<()> {
+        let clusters = if fi.is_dir || fi.fst_clus == 0 {
+            0
+        } else {
+            let mut iter = self.fat.new_iter(self.device.as_ref(), fi.fst_clus);
+            let cnt = iter.by_ref().count() as u64;
+            iter.end()?;
+            cnt
+        };
+        fio::clamp(fi, self.clus_sz, self.fat.last_clus, clusters);
+        Ok(())
     }
 
-    pub fn readroot(&mut self) -> Vec<Finfo> {
+    // the label entry in the root dir, else the one in the boot sector
+    pub fn volume_label(&mut self) -> io::Result<String> {
+        let fats = self.fat.read_all(self.device.as_ref(), self.root_clusno)?;
+        for clus_no in fats {
+            let clus = self.read_clus(clus_no)?;
+            for ent in clus.chunks(DirEnt::SZ as usize) {
+                match ent[0] {
+                    0 => break,
+                    0xE5 => continue,
+                    // a volume id entry that isn't part of a long name
+                    _ if ent[11] & 0x08 != 0 && ent[11] & 0x0F != 0x0F => {
+                        return Ok(String::from_utf8_lossy(&ent[..11]).trim_end().to_string());
+                    }
+                    _ => (),
+                }
+            }
+        }
+        Ok(match &self.bootsec.bs_vol_lab {
+            b"NO NAME    " => String::new(),
+            lab => String::from_utf8_lossy(lab).trim_end().to_string(),
+        })
+    }
+
+    pub fn device(&self) -> &dyn Device {
+        self.device.as_ref()
+    }
+
+    pub fn clus_sz(&self) -> u32 {
+        self.clus_sz
+    }
+
+    // an error when the layout is too unusual to write to, see check_fat32
+    pub fn writable(&self) -> io::Result<()> {
+        match self.odd.first() {
+            None => Ok(()),
+            Some(what) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("the volume is only read, {}", what),
+            )),
+        }
+    }
+
+    // the clusters of the data region that the FAT and the device, no further
+    // than `reach` bytes, can both hold
+    fn count_clusters(bootsec: &BootSec, reach: u64) -> u32 {
+        let spc = bootsec.bpb_sec_per_clus as u64;
+        let bps = bootsec.bpb_byts_per_sec as u64;
+        let on_device = (reach / bps).saturating_sub(bootsec.data_start_sector() as u64) / spc;
+        let in_fat = (bootsec.bpb_fat_sz_32 as u64 * bps / Fat::ENT_SZ as u64).saturating_sub(2);
+        fio::fit_count(
+            "fat32",
+            "cluster count",
+            bootsec.data_sectors() as u64 / spc,
+            &[
+                (in_fat, "the FAT"),
+                (0x0FFFFFF5, "the highest cluster number"),
+                (on_device, "the device"),
+            ],
+        ) as u32
+    }
+
+    // number of clusters in the data region, valid cluster numbers are 2..=clus_cnt + 1
+    pub fn clus_cnt(&self) -> u32 {
+        self.fat.last_clus - 1
+    }
+
+    pub fn fat_copy_offset(&self, copy: u8) -> u64 {
+        (self.bootsec.fat_start_sector() as u64 + copy as u64 * self.bootsec.bpb_fat_sz_32 as u64)
+            * self.bootsec.bpb_byts_per_sec as u64
+    }
+
+    // the raw (undecoded) entries of one FAT copy
+    pub fn read_fat_copy(&self, copy: u8) -> io::Result<Vec<u32>> {
+        let len = self.bootsec.bpb_fat_sz_32 as usize * self.bootsec.bpb_byts_per_sec as usize;
+        let mut buf = vec![0u8; len];
+        self.device
+            .read_exact_at(&mut buf, self.fat_copy_offset(copy))?;
+        Ok(buf
+            .chunks_exact(Fat::ENT_SZ)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect())
+    }
+
+    // where the FSInfo sector is, none when the volume has none
+    pub fn fsinfo_offset(&self) -> Option<u64> {
+        match self.bootsec.bpb_fs_info {
+            0 | 0xFFFF => None,
+            sec => Some(sec as u64 * self.bootsec.bpb_byts_per_sec as u64),
+        }
+    }
+
+    // the FSInfo sector, none when the volume has none or it isn't valid
+    pub fn fsinfo(&self) -> Option<FsInfo> {
+        let mut buf = [0u8; FsInfo::SZ];
+        self.device
+            .read_exact_at(&mut buf, self.fsinfo_offset()?)
+            .ok()?;
+        FsInfo::new(&buf).ok().filter(|fsinfo| fsinfo.is_valid())
+    }
+
+    // the free entries of FAT 0, counted one by one
+    pub fn scan_free(&self) -> io::Result<u32> {
+        let fat = self.read_fat_copy(0)?;
+        Ok(free_in(&fat, self.clus_cnt() + 1))
+    }
+
+    // the FSInfo free count when it's known and could be right, the FAT
+    // scanned otherwise
+    pub fn free_clusters(&self) -> io::Result<u32> {
+        match self.fsinfo() {
+            Some(fsinfo) if fsinfo.free_count <= self.clus_cnt() => Ok(fsinfo.free_count),
+            _ => self.scan_free(),
+        }
+    }
+
+    pub fn clus_offset(&self, clus_no: ClusNo) -> u64 {
+        self.clus_io.offset_of(clus_no)
+    }
+
+    // byte offset on the device of the short entry identified by a Finfo id
+    pub fn dirent_offset(&self, id: u64) -> u64 {
+        self.clus_io.offset_of(id as u32) + (id >> 32) * DirEnt::SZ as u64
+    }
+
+    pub fn readroot(&mut self) -> io::Result<Vec<Finfo>> {
         self.read_dirents(self.root_clusno)
     }
 
-    pub fn readfile(&mut self, fi: &Finfo, offset: u32, size: u32) -> Vec<u8> {
-        if offset >= fi.size32 || size == 0 {
-            return vec![];
+    // the device extents holding the file bytes [offset, offset + size)
+    fn file_extents(&self, fi: &Finfo, offset: u64, size: u32) -> io::Result<Vec<(u64, usize)>> {
+        if offset >= fi.size || size == 0 {
+            return Ok(vec![]);
         }
-        let sz = min(size, fi.size32 - offset);
-        let start_clus = offset / self.clus_sz;
-        let start_off = (offset % self.clus_sz) as usize;
-        let end_clus = (offset + sz - 1) / self.clus_sz;
-
-        let fats: Vec<ClusNo> = self
-            .fat
-            .new_iter(self.device.as_mut(), fi.fst_clus)
+        let sz = min(size as u64, fi.size - offset) as u32;
+        let clus_sz = self.clus_sz as u64;
+        let start_clus = offset / clus_sz;
+        let start_off = (offset % clus_sz) as u32;
+        let end_clus = (offset + sz as u64 - 1) / clus_sz;
+
+        let mut iter = self.fat.new_iter(self.device.as_ref(), fi.fst_clus);
+        let fats: Vec<ClusNo> = iter
+            .by_ref()
             .skip(start_clus as usize)
             .take((end_clus - start_clus + 1) as usize)
             .collect();
+        iter.end()?;
+        Ok(self.clus_io.extents(&fats, start_off, sz))
+    }
 
-        let bytes: Vec<u8> = self.clus_io.read_all(fats, self.device.as_mut()).concat();
-        println!(
+    pub fn readfile(&mut self, fi: &Finfo, offset: u64, size: u32) -> io::Result<Vec<u8>> {
+        let _p = trace::purpose("data");
+        let extents = self.file_extents(fi, offset, size)?;
+        let sz: usize = extents.iter().map(|&(_, len)| len).sum();
+        if sz == 0 {
+            return Ok(vec![]);
+        }
+        let mut bytes: Vec<u8> = vec![0u8; sz];
+        self.device.read_extents(&extents, &mut bytes)?;
+        bytes.truncate(self.device.usable_len(&extents));
+        say!(
             "[fio] readfile: file({}) off({offset}) size({sz}) got({})",
             fi.name,
             bytes.len()
         );
-        bytes[start_off..(start_off + sz as usize)].to_vec()
+        Ok(bytes)
     }
 }
 
 impl<'a> fio::Fio for Fio<'a> {
-    fn list_dir(&mut self, no: u32) -> Vec<Finfo> {
-        self.read_dirents(no)
+    fn list_dir(&mut self, no: u32) -> io::Result<Vec<Finfo>> {
+        let mut ents = self.read_dirents(no)?;
+        fio::dedup_names(&mut ents);
+        Ok(ents)
     }
 
-    fn list_root(&mut self) -> Vec<Finfo> {
-        self.readroot()
+    fn list_root(&mut self) -> io::Result<Vec<Finfo>> {
+        let mut ents = self.readroot()?;
+        fio::dedup_names(&mut ents);
+        Ok(ents)
     }
 
-    fn read_file(&mut self, fi: &Finfo, offset: u32, size: u32) -> Vec<u8> {
+    fn list_dir_page(
+        &mut self,
+        no: u32,
+        from: u64,
+        max: usize,
+    ) -> io::Result<(Vec<Finfo>, Option<u64>)> {
+        if no == 0 {
+            return Ok((vec![], None));
+        }
+        let per_clus = (self.clus_sz / DirEnt::SZ) as u64;
+        let first = (from / per_clus) as usize;
+        // the chain is walked on from where the page before ended, and only
+        // as far as a page can reach: an entry takes up to 21 slots
+        let (mut at, start) = match self.resume {
+            Some((dir, i, clus)) if dir == no && i <= first => (i, clus),
+            _ => (0, no),
+        };
+        let reach = (max as u64)
+            .saturating_mul(21)
+            .saturating_add(from % per_clus)
+            .div_ceil(per_clus)
+            .saturating_add(1);
+        let mut iter = self.fat.new_iter(self.device.as_ref(), start);
+        let mut fats = vec![];
+        for clus in iter.by_ref() {
+            if at >= first {
+                fats.push(clus);
+                if fats.len() as u64 == reach {
+                    break;
+                }
+            }
+            at += 1;
+        }
+        iter.end()?;
+        let (files, next) = self.read_dirents_page(&fats, first, from, max)?;
+        self.resume = next.and_then(|next| {
+            let i = ((next / per_clus) as usize).min((first + fats.len()).checked_sub(1)?);
+            Some((no, i, *fats.get(i.checked_sub(first)?)?))
+        });
+        Ok((files, next))
+    }
+
+    fn read_file(&mut self, fi: &Finfo, offset: u64, size: u32) -> io::Result<Vec<u8>> {
         self.readfile(fi, offset, size)
     }
+
+    fn unreadable(&mut self, fi: &Finfo, offset: u64, size: u32) -> Vec<(u64, u64)> {
+        // a chain that can't be followed failed the read before
+        let extents = self.file_extents(fi, offset, size).unwrap_or_default();
+        let spans = self.device.bad_spans(&extents);
+        fio::file_spans(offset, spans)
+    }
+
+    fn volume(&mut self) -> io::Result<fio::VolumeInfo> {
+        Ok(fio::VolumeInfo {
+            serial: self.bootsec.bs_vol_id,
+            label: self.volume_label()?,
+            clus_sz: self.clus_sz,
+            clus_cnt: self.clus_cnt(),
+            free: Some(self.free_clusters()?),
+        })
+    }
+
+    // the FAT is read a sector at a time, only the buffers are kept
+    fn held(&self) -> u64 {
+        self.pool.held()
+    }
 }
 
 impl TryFrom<Vec<DirEnt>> for Finfo {
@@ -271,14 +631,15 @@ impl TryFrom<Vec<DirEnt>> for Finfo {
                 } else {
                     break 'check;
                 }
-                let mut longname = String::new();
-                // checksum and build name
-                for &en in lfns.iter() {
+                let mut units = vec![];
+                // checksum and build name, the last part comes first
+                for &en in lfns.iter().rev() {
                     if en.chksum != chksum {
                         break 'check;
                     }
-                    longname.insert_str(0, &en.name());
+                    units.extend(en.units());
                 }
+                let longname = fio::name_from_utf16(&units);
                 // check order
                 if lfns
                     .iter()
@@ -299,17 +660,37 @@ impl TryFrom<Vec<DirEnt>> for Finfo {
         }
         Ok(Finfo {
             id: (sfn.off as u64) << 32 | sfn.clus_no as u64,
+            pos: sfn.off as u64, // within the cluster, see read_dirents_in
             name,
             is_rdonly: sfn.is_rdonly(),
             is_dir: sfn.is_dir(),
             is_hidden: sfn.is_hidden(),
             is_system: sfn.is_system(),
-            size32: sfn.file_size,
             size: sfn.file_size.into(),
+            valid_size: sfn.file_size.into(),
             fst_clus: sfn.fst_clus(),
+            no_fat_chain: false,
             crt_time: sfn.crt_time(),
             wrt_time: sfn.wrt_time(),
             acc_time: sfn.last_acc_time(),
         })
     }
 }
+
+// the chain starting at `first` over raw FAT entries, None unless it is
+// properly terminated
+pub fn chain_in(fat: &[u32], max_clus: ClusNo, first: ClusNo) -> Option<Vec<ClusNo>> {
+    let mut chain = vec![];
+    let mut clus = first;
+    loop {
+        if !(2..=max_clus).contains(&clus) || chain.len() > max_clus as usize {
+            return None;
+        }
+        chain.push(clus);
+        let next = fat[clus as usize] & 0x0FFFFFFF;
+        if next >= 0x0FFFFFF8 {
+            return Some(chain);
+        }
+        clus = next;
+    }
+}
@@ -5,15 +5,23 @@
 
 use std::time::SystemTime;
 
-use chrono::{Local, TimeZone};
-use scroll::{self, Pread, LE};
+use chrono::{Datelike, Local, NaiveDateTime, TimeZone, Timelike};
+use scroll::{self, Pread, Pwrite, LE};
 
 pub type ClusNo = u32; // static
 
+// the checksum of a short name that its long entries carry, refer to [1]
+pub fn sfn_chksum(name: &[u8; 11]) -> u8 {
+    name.iter().fold(0u8, |sum, &c| {
+        c.wrapping_add(sum >> 1).wrapping_add(sum << 7)
+    })
+}
+
+#[allow(dead_code)]
 #[derive(Debug)]
 pub struct BootSec {
     // > 0-35
-    // BS_JmpBoot
+    pub bs_jmp_boot: [u8; 3], // `unused`
     pub bs_oem_name: [u8; 8], // `unused`
     pub bpb_byts_per_sec: u16,
     pub bpb_sec_per_clus: u8,
@@ -23,33 +31,36 @@ pub struct BootSec {
     pub bpb_tot_sec_16: u16,   // check only
     pub bpb_media: u8,         // `unused`
     pub bpb_fat_sz_16: u16,    // check only
-    // BPB_SecPerTrk
-    // BPB_NumHeads
-    // BPB_HiddSec
+    pub bpb_sec_per_trk: u16,  // `unused`
+    pub bpb_num_heads: u16,    // `unused`
+    pub bpb_hidd_sec: u32,     // `unused`
     pub bpb_tot_sec_32: u32,
 
     // > 36-511
     pub bpb_fat_sz_32: u32,
-    // BPB_ExtFlags
-    pub bpb_fs_ver: u16, // `unused`
+    pub bpb_ext_flags: u16, // `unused`
+    pub bpb_fs_ver: u16,    // `unused`
     pub bpb_root_clus: u32,
     pub bpb_fs_info: u16, // `unused` temporarily
     pub bpb_bk_boot_sec: u16,
-    // BPB_Reserved
-    // BS_DrvNum
-    // BS_Reserved
-    pub bs_boot_sig: u8, // `unused`
-    // BS_VolID
-    // BS_VolLab
-    pub bs_fil_sys_type: [u8; 8],   // `unused`
+    pub bpb_reserved: [u8; 12], // `unused`
+    pub bs_drv_num: u8,         // `unused`
+    pub bs_reserved_1: u8,      // `unused`
+    pub bs_boot_sig: u8,        // `unused`
+    pub bs_vol_id: u32,
+    pub bs_vol_lab: [u8; 11], // the root dir label entry takes precedence
+    pub bs_fil_sys_type: [u8; 8], // check only
     pub bs_boot_code_32: [u8; 420], // `unused`
-    pub bs_boot_sign: u16,          // check only
+    pub bs_boot_sign: u16,    // check only
 }
 
 #[allow(dead_code)]
 impl BootSec {
+    pub const SZ: usize = 512;
+
     pub fn new(buf: &mut [u8; 512]) -> Result<Self, scroll::Error> {
         Ok(BootSec {
+            bs_jmp_boot: buf.pread_with(0, LE)?,
             bs_oem_name: buf.pread_with(3, LE)?,
             bpb_byts_per_sec: buf.pread_with(11, LE)?,
             bpb_sec_per_clus: buf.pread_with(13, LE)?,
@@ -59,26 +70,71 @@ impl BootSec {
             bpb_tot_sec_16: buf.pread_with(19, LE)?,
             bpb_media: buf.pread_with(21, LE)?,
             bpb_fat_sz_16: buf.pread_with(22, LE)?,
+            bpb_sec_per_trk: buf.pread_with(24, LE)?,
+            bpb_num_heads: buf.pread_with(26, LE)?,
+            bpb_hidd_sec: buf.pread_with(28, LE)?,
             bpb_tot_sec_32: buf.pread_with(32, LE)?,
 
             bpb_fat_sz_32: buf.pread_with(36, LE)?,
+            bpb_ext_flags: buf.pread_with(40, LE)?,
             bpb_fs_ver: buf.pread_with(42, LE)?,
             bpb_root_clus: buf.pread_with(44, LE)?,
             bpb_fs_info: buf.pread_with(48, LE)?,
             bpb_bk_boot_sec: buf.pread_with(50, LE)?,
+            bpb_reserved: buf.pread_with(52, LE)?,
+            bs_drv_num: buf.pread_with(64, LE)?,
+            bs_reserved_1: buf.pread_with(65, LE)?,
             bs_boot_sig: buf.pread_with(66, LE)?,
+            bs_vol_id: buf.pread_with(67, LE)?,
+            bs_vol_lab: buf.pread_with(71, LE)?,
             bs_fil_sys_type: buf.pread_with(82, LE)?,
             bs_boot_code_32: buf.pread_with(90, LE)?,
             bs_boot_sign: buf.pread_with(510, LE)?,
         })
     }
 
+    // the sector as it goes on disk, what new() reads back
+    pub fn dump(&self) -> [u8; Self::SZ] {
+        let mut buf = [0u8; Self::SZ];
+        buf.pwrite_with(&self.bs_jmp_boot[..], 0, ()).unwrap();
+        buf.pwrite_with(&self.bs_oem_name[..], 3, ()).unwrap();
+        buf.pwrite_with(self.bpb_byts_per_sec, 11, LE).unwrap();
+        buf.pwrite_with(self.bpb_sec_per_clus, 13, LE).unwrap();
+        buf.pwrite_with(self.bpb_rsvd_sec_cnt, 14, LE).unwrap();
+        buf.pwrite_with(self.bpb_num_fats, 16, LE).unwrap();
+        buf.pwrite_with(self.bpb_root_ent_cnt, 17, LE).unwrap();
+        buf.pwrite_with(self.bpb_tot_sec_16, 19, LE).unwrap();
+        buf.pwrite_with(self.bpb_media, 21, LE).unwrap();
+        buf.pwrite_with(self.bpb_fat_sz_16, 22, LE).unwrap();
+        buf.pwrite_with(self.bpb_sec_per_trk, 24, LE).unwrap();
+        buf.pwrite_with(self.bpb_num_heads, 26, LE).unwrap();
+        buf.pwrite_with(self.bpb_hidd_sec, 28, LE).unwrap();
+        buf.pwrite_with(self.bpb_tot_sec_32, 32, LE).unwrap();
+
+        buf.pwrite_with(self.bpb_fat_sz_32, 36, LE).unwrap();
+        buf.pwrite_with(self.bpb_ext_flags, 40, LE).unwrap();
+        buf.pwrite_with(self.bpb_fs_ver, 42, LE).unwrap();
+        buf.pwrite_with(self.bpb_root_clus, 44, LE).unwrap();
+        buf.pwrite_with(self.bpb_fs_info, 48, LE).unwrap();
+        buf.pwrite_with(self.bpb_bk_boot_sec, 50, LE).unwrap();
+        buf.pwrite_with(&self.bpb_reserved[..], 52, ()).unwrap();
+        buf.pwrite_with(self.bs_drv_num, 64, LE).unwrap();
+        buf.pwrite_with(self.bs_reserved_1, 65, LE).unwrap();
+        buf.pwrite_with(self.bs_boot_sig, 66, LE).unwrap();
+        buf.pwrite_with(self.bs_vol_id, 67, LE).unwrap();
+        buf.pwrite_with(&self.bs_vol_lab[..], 71, ()).unwrap();
+        buf.pwrite_with(&self.bs_fil_sys_type[..], 82, ()).unwrap();
+        buf.pwrite_with(&self.bs_boot_code_32[..], 90, ()).unwrap();
+        buf.pwrite_with(self.bs_boot_sign, 510, LE).unwrap();
+        buf
+    }
+
     pub fn fat_start_sector(&self) -> u16 {
         self.bpb_rsvd_sec_cnt
     }
 
     pub fn fat_sectors(&self) -> u32 {
-        self.bpb_fat_sz_32 * self.bpb_num_fats as u32
+        self.bpb_fat_sz_32.saturating_mul(self.bpb_num_fats as u32)
     }
 
     // >> UNUSED
@@ -92,27 +148,133 @@ impl BootSec {
     // << UNUSED
 
     pub fn data_start_sector(&self) -> u32 {
-        self.fat_start_sector() as u32 + self.fat_sectors()
+        (self.fat_start_sector() as u32).saturating_add(self.fat_sectors())
     }
 
     pub fn data_sectors(&self) -> u32 {
-        self.bpb_tot_sec_32 - self.data_start_sector()
+        self.bpb_tot_sec_32.saturating_sub(self.data_start_sector())
     }
 
     pub fn cluster_size(&self) -> u32 {
         self.bpb_byts_per_sec as u32 * self.bpb_sec_per_clus as u32
     }
 
-    pub fn check_fat32(&self) {
-        assert!(self.bs_boot_sign == 0xAA55);
-
-        assert!(self.bpb_sec_per_clus != 0);
+    // an error on what can't be read at all, else what is unusual but still
+    // readable. fio keeps volumes with any of those from being written
+    pub fn check_fat32(&self) -> Result<Vec<String>, String> {
+        if self.bs_boot_sign != 0xAA55 {
+            return Err(format!(
+                "no boot signature, {:#06X} instead",
+                self.bs_boot_sign
+            ));
+        }
+        // temporarily only support sector size 512
+        if self.bpb_byts_per_sec != 512 {
+            return Err(format!(
+                "{}-byte sectors, only 512 are supported",
+                self.bpb_byts_per_sec
+            ));
+        }
+        if self.bpb_sec_per_clus == 0 {
+            return Err(String::from("0 sectors per cluster"));
+        }
+        // the FATs can't sit on the boot sector
+        if self.bpb_rsvd_sec_cnt == 0 {
+            return Err(String::from("no reserved sectors"));
+        }
+        if self.bpb_num_fats == 0 {
+            return Err(String::from("no FATs"));
+        }
+        if self.data_start_sector() >= self.bpb_tot_sec_32 {
+            return Err(format!(
+                "the data region starts at sector {}, past the {} of the volume",
+                self.data_start_sector(),
+                self.bpb_tot_sec_32
+            ));
+        }
         let num_clusters = self.data_sectors() / self.bpb_sec_per_clus as u32;
-        assert!(num_clusters >= 65526);
+        if num_clusters < 65526 {
+            return Err(format!("{} clusters, too few for FAT32", num_clusters));
+        }
 
-        // temporarily only support sector size 512
-        assert!(self.bpb_byts_per_sec as usize == 512);
-        assert!(self.bpb_num_fats == 2);
+        let mut odd = vec![];
+        if self.bpb_num_fats != 2 {
+            odd.push(format!("{} FATs instead of 2", self.bpb_num_fats));
+        }
+        // 32 is what formatters use, FSInfo and the backup boot sector with
+        // its FSInfo have to be inside the reserved region whatever it is
+        let rsvd = self.bpb_rsvd_sec_cnt;
+        if self.bpb_fs_info != 0 && self.bpb_fs_info != 0xFFFF && self.bpb_fs_info >= rsvd {
+            odd.push(format!(
+                "FSInfo at sector {}, past the {} reserved ones",
+                self.bpb_fs_info, rsvd
+            ));
+        }
+        if self.bpb_bk_boot_sec != 0
+            && self.bpb_bk_boot_sec != 0xFFFF
+            && self.bpb_bk_boot_sec as u32 + self.bpb_fs_info as u32 >= rsvd as u32
+        {
+            odd.push(format!(
+                "the backup boot sector at sector {} doesn't fit the {} reserved ones",
+                self.bpb_bk_boot_sec, rsvd
+            ));
+        }
+        if self.bpb_tot_sec_16 != 0 {
+            odd.push(format!(
+                "BPB_TotSec16 is {}, it's 0 on FAT32",
+                self.bpb_tot_sec_16
+            ));
+        }
+        if self.bpb_fat_sz_32 < (num_clusters + 2).div_ceil(self.bpb_byts_per_sec as u32 / 4) {
+            odd.push(String::from("the FAT is too small for the data region"));
+        }
+        Ok(odd)
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub struct FsInfo {
+    pub lead_sig: u32,         // check only, 0x41615252
+    pub reserved_1: [u8; 480], // `unused`
+    pub struc_sig: u32,        // check only, 0x61417272
+    pub free_count: u32,       // 0xFFFFFFFF when unknown
+    pub nxt_free: u32,         // 0xFFFFFFFF when unknown
+    pub reserved_2: [u8; 12],  // `unused`
+    pub trail_sig: u32,        // check only, 0xAA550000
+}
+
+#[allow(dead_code)]
+impl FsInfo {
+    pub const SZ: usize = 512;
+    pub const FREE_COUNT_OFF: usize = 488;
+
+    pub fn new(buf: &[u8]) -> Result<Self, scroll::Error> {
+        Ok(FsInfo {
+            lead_sig: buf.pread_with(0, LE)?,
+            reserved_1: buf.pread_with(4, LE)?,
+            struc_sig: buf.pread_with(484, LE)?,
+            free_count: buf.pread_with(488, LE)?,
+            nxt_free: buf.pread_with(492, LE)?,
+            reserved_2: buf.pread_with(496, LE)?,
+            trail_sig: buf.pread_with(508, LE)?,
+        })
+    }
+
+    pub fn dump(&self) -> [u8; Self::SZ] {
+        let mut buf = [0u8; Self::SZ];
+        buf.pwrite_with(self.lead_sig, 0, LE).unwrap();
+        buf.pwrite_with(&self.reserved_1[..], 4, ()).unwrap();
+        buf.pwrite_with(self.struc_sig, 484, LE).unwrap();
+        buf.pwrite_with(self.free_count, 488, LE).unwrap();
+        buf.pwrite_with(self.nxt_free, 492, LE).unwrap();
+        buf.pwrite_with(&self.reserved_2[..], 496, ()).unwrap();
+        buf.pwrite_with(self.trail_sig, 508, LE).unwrap();
+        buf
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.lead_sig == 0x41615252 && self.struc_sig == 0x61417272 && self.trail_sig == 0xAA550000
     }
 }
 
@@ -194,6 +356,21 @@ impl From<Time> for u16 {
     }
 }
 
+// the date and time fields of a wall clock time, to 2 seconds
+pub fn date_time(wall: &NaiveDateTime) -> (u16, u16) {
+    let date = Date {
+        year: (wall.year() - 1980) as u8,
+        month: wall.month() as u8,
+        day: wall.day() as u8,
+    };
+    let time = Time {
+        hour: wall.hour() as u8,
+        minute: wall.minute() as u8,
+        second: (wall.second() / 2) as u8,
+    };
+    (date.into(), time.into())
+}
+
 #[allow(dead_code)]
 #[derive(Debug)]
 pub struct DirEntSfn {
@@ -203,7 +380,7 @@ pub struct DirEntSfn {
     crt_time_tenth: u8,
     crt_time: u16,
     crt_date: u16,
-    lst_acc_date: u16, // `unused` temporarily
+    lst_acc_date: u16,
     fst_clus_hi: u16,
     wrt_time: u16,
     wrt_date: u16,
@@ -221,9 +398,30 @@ impl DirEntSfn {
     const BODY_LOW_CASE: u8 = 0x08;
 
     pub fn create_chksum(&self) -> u8 {
-        (0..11).fold(0u8, |sum, i| {
-            self.name[i].wrapping_add(sum >> 1).wrapping_add(sum << 7)
-        })
+        sfn_chksum(&self.name)
+    }
+
+    // a fresh short entry stamped with the current local time
+    pub fn encode(name: &[u8; 11], is_dir: bool, fst_clus: ClusNo, size: u32) -> [u8; 32] {
+        let (date, time) = date_time(&Local::now().naive_local());
+        let attr = if is_dir {
+            DirEnt::ATTR_DIRECTORY
+        } else {
+            DirEnt::ATTR_ARCHIVE
+        };
+
+        let mut buf = [0u8; 32];
+        buf[..11].copy_from_slice(name);
+        buf[11] = attr;
+        buf[14..16].copy_from_slice(&time.to_le_bytes());
+        buf[16..18].copy_from_slice(&date.to_le_bytes());
+        buf[18..20].copy_from_slice(&date.to_le_bytes());
+        buf[20..22].copy_from_slice(&((fst_clus >> 16) as u16).to_le_bytes());
+        buf[22..24].copy_from_slice(&time.to_le_bytes());
+        buf[24..26].copy_from_slice(&date.to_le_bytes());
+        buf[26..28].copy_from_slice(&(fst_clus as u16).to_le_bytes());
+        buf[28..32].copy_from_slice(&size.to_le_bytes());
+        buf
     }
 
     // `imprecise`
@@ -314,7 +512,7 @@ impl DirEntSfn {
                     date.day.into(),
                     time.hour.into(),
                     time.minute.into(),
-                    time.second.into(),
+                    (time.second * 2).into(),
                 )
                 .single()?
                 .into(),
@@ -331,16 +529,15 @@ impl DirEntSfn {
 
     pub fn crt_time(&self) -> SystemTime {
         if let Some(time) = Self::make_dt(&self.crt_date.into(), &self.crt_time.into()) {
-            let tenth_sec = self.crt_time_tenth / 100;
-            let tenth_milsec = self.crt_time_tenth % 100;
-            time + std::time::Duration::new(tenth_sec.into(), tenth_milsec as u32 * 1000_1000)
+            // 10ms units on top of the 2 second resolution, 0..=199
+            time + std::time::Duration::from_millis(self.crt_time_tenth as u64 * 10)
         } else {
             SystemTime::UNIX_EPOCH
         }
     }
 
     pub fn last_acc_time(&self) -> SystemTime {
-        if let Some(time) = Self::make_dt(&self.crt_date.into(), &0.into()) {
+        if let Some(time) = Self::make_dt(&self.lst_acc_date.into(), &0.into()) {
             time
         } else {
             SystemTime::UNIX_EPOCH
@@ -363,8 +560,46 @@ pub struct DirEntLfn {
 
 #[allow(dead_code)]
 impl DirEntLfn {
+    pub const MAX_NAME: usize = 255;
+    const CHARS_PER_ENT: usize = 13;
+
+    // the long entries for `name` in on-disk order, the last ordinal first.
+    // the name is null terminated and 0xFFFF padded when it doesn't fill the
+    // last entry, refer to [1]
+    pub fn encode(name: &[u16], chksum: u8) -> Vec<[u8; 32]> {
+        let cnt = name.len().div_ceil(Self::CHARS_PER_ENT);
+        let mut units = name.to_vec();
+        if units.len() < cnt * Self::CHARS_PER_ENT {
+            units.push(0x0000);
+            units.resize(cnt * Self::CHARS_PER_ENT, 0xFFFF);
+        }
+        (0..cnt)
+            .rev()
+            .map(|i| {
+                let part = &units[i * Self::CHARS_PER_ENT..(i + 1) * Self::CHARS_PER_ENT];
+                let mut buf = [0u8; 32];
+                buf[0] = (i + 1) as u8 | if i + 1 == cnt { 0x40 } else { 0 };
+                buf[11] = DirEnt::ATTR_LONG_FILE_NAME;
+                buf[13] = chksum;
+                let at = (1..11)
+                    .step_by(2)
+                    .chain((14..26).step_by(2))
+                    .chain((28..32).step_by(2));
+                for (off, c) in at.zip(part) {
+                    buf[off..off + 2].copy_from_slice(&c.to_le_bytes());
+                }
+                buf
+            })
+            .collect()
+    }
+
     // `imprecise`
     pub fn name(&self) -> String {
+        String::from_utf16_lossy(&self.units())
+    }
+
+    // the UTF-16 code units of this part of the name, up to the terminator
+    pub fn units(&self) -> Vec<u16> {
         let mut bytes: Vec<u16> = Vec::new();
         bytes.extend_from_slice(&self.name1);
         bytes.extend_from_slice(&self.name2);
@@ -376,7 +611,8 @@ impl DirEntLfn {
                 break;
             }
         }
-        String::from_utf16_lossy(&bytes[0..term_idx])
+        bytes.truncate(term_idx);
+        bytes
     }
 
     pub fn is_last(&self) -> bool {
@@ -439,4 +675,105 @@ impl DirEnt {
             }))
         }
     }
+
+    // the entry as it goes on disk, what new() reads back
+    pub fn dump(&self) -> [u8; Self::SZ as usize] {
+        let mut buf = [0u8; Self::SZ as usize];
+        match self {
+            DirEnt::Lfn(ent) => {
+                buf.pwrite_with(ent.ord, 0, LE).unwrap();
+                for (i, &c) in ent.name1.iter().enumerate() {
+                    buf.pwrite_with(c, 1 + i * 2, LE).unwrap();
+                }
+                buf.pwrite_with(ent.attr, 11, LE).unwrap();
+                buf.pwrite_with(ent.typ, 12, LE).unwrap();
+                buf.pwrite_with(ent.chksum, 13, LE).unwrap();
+                for (i, &c) in ent.name2.iter().enumerate() {
+                    buf.pwrite_with(c, 14 + i * 2, LE).unwrap();
+                }
+                buf.pwrite_with(ent.fst_clus_lo, 26, LE).unwrap();
+                for (i, &c) in ent.name3.iter().enumerate() {
+                    buf.pwrite_with(c, 28 + i * 2, LE).unwrap();
+                }
+            }
+            DirEnt::Sfn(ent) => {
+                buf.pwrite_with(&ent.name[..], 0, ()).unwrap();
+                buf.pwrite_with(ent.attr, 11, LE).unwrap();
+                buf.pwrite_with(ent.nt_res, 12, LE).unwrap();
+                buf.pwrite_with(ent.crt_time_tenth, 13, LE).unwrap();
+                buf.pwrite_with(ent.crt_time, 14, LE).unwrap();
+                buf.pwrite_with(ent.crt_date, 16, LE).unwrap();
+                buf.pwrite_with(ent.lst_acc_date, 18, LE).unwrap();
+                buf.pwrite_with(ent.fst_clus_hi, 20, LE).unwrap();
+                buf.pwrite_with(ent.wrt_time, 22, LE).unwrap();
+                buf.pwrite_with(ent.wrt_date, 24, LE).unwrap();
+                buf.pwrite_with(ent.fst_clus_lo, 26, LE).unwrap();
+                buf.pwrite_with(ent.file_size, 28, LE).unwrap();
+            }
+        }
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::round_trips;
+
+    #[test]
+    fn bootsec_round_trip() {
+        round_trips(BootSec::SZ, |buf| {
+            let buf: &mut [u8; BootSec::SZ] = buf.try_into().unwrap();
+            let dumped = BootSec::new(&mut buf.clone()).unwrap().dump();
+            assert_eq!(dumped, *buf);
+        });
+    }
+
+    #[test]
+    fn fsinfo_round_trip() {
+        round_trips(FsInfo::SZ, |buf| {
+            assert_eq!(FsInfo::new(buf).unwrap().dump(), *buf);
+        });
+    }
+
+    #[test]
+    fn dirent_round_trip() {
+        round_trips(DirEnt::SZ as usize, |buf| {
+            // every other one a long name entry
+            if buf[0] & 1 == 0 {
+                buf[11] = DirEnt::ATTR_LONG_FILE_NAME;
+            }
+            let ent = DirEnt::new(buf, 2, 0).unwrap();
+            assert_eq!(
+                matches!(ent, DirEnt::Lfn(_)),
+                buf[11] == DirEnt::ATTR_LONG_FILE_NAME
+            );
+            assert_eq!(ent.dump(), *buf);
+        });
+    }
+
+    #[test]
+    fn encoded_entries_parse() {
+        let sfn = DirEntSfn::encode(b"HELLO   TXT", false, 0x12345, 99);
+        let DirEnt::Sfn(ent) = DirEnt::new(&sfn, 2, 0).unwrap() else {
+            panic!("not a short entry");
+        };
+        assert_eq!(ent.name(), "HELLO.TXT");
+        assert_eq!(ent.fst_clus(), 0x12345);
+        assert_eq!(ent.file_size, 99);
+        assert_eq!(DirEnt::Sfn(ent).dump(), sfn);
+
+        let name: Vec<u16> = "a long file name.txt".encode_utf16().collect();
+        let lfns = DirEntLfn::encode(&name, 0x5A);
+        let mut parsed = String::new();
+        for raw in lfns.iter().rev() {
+            let DirEnt::Lfn(ent) = DirEnt::new(raw, 2, 0).unwrap() else {
+                panic!("not a long entry");
+            };
+            assert_eq!(ent.chksum, 0x5A);
+            parsed.push_str(&ent.name());
+            assert_eq!(DirEnt::Lfn(ent).dump(), *raw);
+        }
+        assert_eq!(parsed, "a long file name.txt");
+    }
 }
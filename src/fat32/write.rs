@@ -0,0 +1,394 @@
+// References:
+// [1] https://download.microsoft.com/download/1/6/1/161ba512-40e2-4cc9-843a-923143f3456c/fatgen103.doc
+
+use std::io::{self, Read};
+
+use crate::device::{Device, Image};
+use crate::fio::{self, Finfo};
+use crate::fsck::{set_ent, sync_fats, ENT_MASK, EOC};
+use crate::journal::Writes;
+use crate::trace;
+
+use super::fio::Fio;
+use super::spec::{sfn_chksum, ClusNo, DirEnt, DirEntLfn, DirEntSfn, FsInfo};
+
+const NEXT_FREE_OFF: usize = FsInfo::FREE_COUNT_OFF + 4;
+// a directory holds at most 65536 entries, refer to [1]
+const MAX_DIR_ENTS: usize = 65536;
+
+// metadata changes to a FAT32 volume. the FAT and the dir entries are staged
+// and go out together on flush, file data goes straight to the device as it
+// only lands in clusters nothing references yet
+pub struct Writer<'f> {
+    pub fio: Fio<'f>,
+    file: &'f Image,
+    writes: Writes<'f>,
+    fat: Vec<u32>,
+    old: Vec<u32>,
+}
+
+impl<'f> Writer<'f> {
+    pub fn new(file: &'f Image) -> io::Result<Self> {
+        let fio = Fio::new(file)?;
+        fio.writable()?;
+        let fat = fio.read_fat_copy(0)?;
+        let sec_sz = fio.bootsec.bpb_byts_per_sec as u64;
+        Ok(Writer {
+            fio,
+            file,
+            writes: Writes::new(file, sec_sz),
+            old: fat.clone(),
+            fat,
+        })
+    }
+
+    fn max_clus(&self) -> ClusNo {
+        (self.fio.clus_cnt() + 1).min(self.fat.len() as u32 - 1)
+    }
+
+    pub fn chain_of(&self, first: ClusNo) -> Vec<ClusNo> {
+        let mut chain = vec![];
+        let mut clus = first;
+        while (2..=self.max_clus()).contains(&clus) && chain.len() <= self.max_clus() as usize {
+            chain.push(clus);
+            clus = self.fat[clus as usize] & ENT_MASK;
+        }
+        chain
+    }
+
+    // `cnt` free clusters linked into a terminated chain, the first run long
+    // enough to hold them all when there is one, else first-fit
+    pub fn alloc(&mut self, cnt: u32) -> io::Result<Vec<ClusNo>> {
+        if cnt == 0 {
+            return Ok(vec![]);
+        }
+        let free: Vec<ClusNo> = (2..=self.max_clus())
+            .filter(|&c| self.fat[c as usize] & ENT_MASK == 0)
+            .collect();
+        if free.len() < cnt as usize {
+            return Err(io::Error::other("no free clusters left"));
+        }
+        let contiguous = free
+            .windows(cnt as usize)
+            .find(|run| run[run.len() - 1] - run[0] == cnt - 1);
+        let chain = match contiguous {
+            Some(run) => run.to_vec(),
+            None => free[..cnt as usize].to_vec(),
+        };
+        for pair in chain.windows(2) {
+            set_ent(&mut self.fat, pair[0], pair[1]);
+        }
+        set_ent(&mut self.fat, *chain.last().unwrap(), EOC);
+        Ok(chain)
+    }
+
+    // fill `chain` from `src`, return the number of bytes read
+    pub fn write_data(&mut self, chain: &[ClusNo], src: &mut dyn Read) -> io::Result<u64> {
+        let _p = trace::purpose("data");
+        let clus_sz = self.fio.clus_sz() as usize;
+        let mut buf = vec![0u8; clus_sz];
+        let mut total = 0;
+        for &clus in chain {
+            let mut n = 0;
+            while n < clus_sz {
+                match src.read(&mut buf[n..])? {
+                    0 => break,
+                    got => n += got,
+                }
+            }
+            buf[n..].fill(0);
+            self.file.write_all_at(&buf, self.fio.clus_offset(clus))?;
+            total += n as u64;
+        }
+        Ok(total)
+    }
+
+    // the entry named `name` in the dir starting at `dir`, names compare
+    // case-insensitively like FAT does
+    pub fn find(&mut self, dir: ClusNo, name: &str) -> io::Result<Option<Finfo>> {
+        let chain = self.chain_of(dir);
+        Ok(self
+            .fio
+            .read_dirents_in(&chain)?
+            .into_iter()
+            .find(|fi| fi.name.eq_ignore_ascii_case(name)))
+    }
+
+    // add an entry for `name` to the dir starting at `dir`, with long entries
+    // in front of the short one unless the name fits 8.3 as is. the dir grows
+    // by a cluster when it has no room left
+    pub fn add_entry(
+        &mut self,
+        dir: ClusNo,
+        name: &str,
+        is_dir: bool,
+        fst_clus: ClusNo,
+        size: u32,
+    ) -> io::Result<()> {
+        let ent = DirEntSfn::encode(&[b' '; 11], is_dir, fst_clus, size);
+        self.add_entry_like(dir, name, ent)
+    }
+
+    // add an entry for `name` carrying everything but the name over from the
+    // short entry `ent`, its attributes, times, cluster and size
+    pub fn add_entry_like(&mut self, dir: ClusNo, name: &str, mut ent: [u8; 32]) -> io::Result<()> {
+        let units: Vec<u16> = name.encode_utf16().collect();
+        if name.is_empty() || units.len() > DirEntLfn::MAX_NAME {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} can't be a FAT name", name),
+            ));
+        }
+        let mut chain = self.chain_of(dir);
+        let (slots, taken) = self.scan_dir(&chain)?;
+        let (sfn, exact) = short_name(name, &taken)?;
+        let mut ents = vec![];
+        if !exact {
+            ents = DirEntLfn::encode(&units, sfn_chksum(&sfn));
+        }
+        ent[..11].copy_from_slice(&sfn);
+        // the case flags belonged to the old name
+        ent[12] = 0;
+        ents.push(ent);
+
+        // the first run of free slots long enough, past the end of the dir
+        // if need be
+        let per_clus = self.fio.clus_sz() as usize / DirEnt::SZ as usize;
+        let mut run = 0;
+        let mut at = None;
+        for i in 0..MAX_DIR_ENTS {
+            if i >= slots.len() || slots[i] {
+                run += 1;
+            } else {
+                run = 0;
+            }
+            if run == ents.len() {
+                at = Some(i + 1 - run);
+                break;
+            }
+        }
+        let at = at.ok_or_else(|| io::Error::other("the directory is full"))?;
+        while chain.len() * per_clus < at + ents.len() {
+            let clus = self.alloc(1)?[0];
+            set_ent(&mut self.fat, *chain.last().unwrap(), clus);
+            self.writes.patch(
+                self.fio.clus_offset(clus),
+                &vec![0u8; self.fio.clus_sz() as usize],
+            )?;
+            chain.push(clus);
+        }
+        for (i, ent) in ents.iter().enumerate() {
+            let (clus, idx) = (chain[(at + i) / per_clus], (at + i) % per_clus);
+            let off = self.fio.clus_offset(clus) + (idx * DirEnt::SZ as usize) as u64;
+            self.writes.patch(off, ent)?;
+        }
+        Ok(())
+    }
+
+    // mark the entry `fi` in the dir starting at `dir` deleted, along with the
+    // long entries in front of it
+    pub fn remove_entry(&mut self, dir: ClusNo, fi: &Finfo) -> io::Result<()> {
+        let chain = self.chain_of(dir);
+        let per_clus = self.fio.clus_sz() as usize / DirEnt::SZ as usize;
+        let pos = chain
+            .iter()
+            .position(|&clus| clus == fi.id as u32)
+            .ok_or_else(|| io::Error::other(format!("{} isn't in its directory", fi.name)))?;
+        let mut slot = pos * per_clus + (fi.id >> 32) as usize;
+        let ent_at = |slot: usize| {
+            let (clus, idx) = (chain[slot / per_clus], slot % per_clus);
+            self.fio.clus_offset(clus) + (idx * DirEnt::SZ as usize) as u64
+        };
+
+        let mut sfn = [0u8; 11];
+        self.file.read_exact_at(&mut sfn, ent_at(slot))?;
+        let chksum = sfn_chksum(&sfn);
+        let mut offs = vec![ent_at(slot)];
+        while slot > 0 {
+            slot -= 1;
+            let off = ent_at(slot);
+            let mut ent = [0u8; DirEnt::SZ as usize];
+            self.file.read_exact_at(&mut ent, off)?;
+            if ent[11] != 0x0F || ent[0] == 0xE5 || ent[13] != chksum {
+                break;
+            }
+            offs.push(off);
+            if ent[0] & 0x40 != 0 {
+                break;
+            }
+        }
+        for off in offs {
+            self.writes.patch(off, &[0xE5])?;
+        }
+        Ok(())
+    }
+
+    // free the chain starting at `first`
+    pub fn free_chain(&mut self, first: ClusNo) {
+        for clus in self.chain_of(first) {
+            set_ent(&mut self.fat, clus, 0);
+        }
+    }
+
+    // point the `..` entry of the dir starting at `dir` at `parent`, the root
+    // being recorded as cluster 0, refer to [1]
+    pub fn set_dotdot(&mut self, dir: ClusNo, parent: ClusNo) -> io::Result<()> {
+        let parent = if parent == self.fio.root_clusno {
+            0
+        } else {
+            parent
+        };
+        let off = self.fio.clus_offset(dir) + DirEnt::SZ as u64;
+        let mut ent = [0u8; DirEnt::SZ as usize];
+        self.file.read_exact_at(&mut ent, off)?;
+        if &ent[..11] != b"..         " {
+            return Err(io::Error::other("the directory has no .. entry"));
+        }
+        self.writes
+            .patch(off + 20, &((parent >> 16) as u16).to_le_bytes())?;
+        self.writes
+            .patch(off + 26, &(parent as u16).to_le_bytes())?;
+        Ok(())
+    }
+
+    // the raw short entry identified by a Finfo id
+    pub fn read_sfn(&self, fi: &Finfo) -> io::Result<[u8; 32]> {
+        let mut ent = [0u8; DirEnt::SZ as usize];
+        self.file
+            .read_exact_at(&mut ent, self.fio.dirent_offset(fi.id))?;
+        Ok(ent)
+    }
+
+    // the first cluster of the dir at `path`, the root for an empty one
+    pub fn dir_clus(&mut self, path: &str) -> io::Result<ClusNo> {
+        if path.split('/').all(|part| part.is_empty()) {
+            return Ok(self.fio.root_clusno);
+        }
+        match fio::lookup(&mut self.fio, path)? {
+            Some(fi) if fi.is_dir => Ok(fi.fst_clus),
+            Some(_) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a directory", path),
+            )),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} not found", path),
+            )),
+        }
+    }
+
+    // which entry slots of the dir are free, and the short names in use
+    fn scan_dir(&mut self, chain: &[ClusNo]) -> io::Result<(Vec<bool>, Vec<[u8; 11]>)> {
+        let mut slots = vec![];
+        let mut taken = vec![];
+        let mut end = false;
+        for &clus in chain {
+            let data = self.fio.read_clus(clus)?;
+            for ent in data.chunks(DirEnt::SZ as usize) {
+                end |= ent[0] == 0x00;
+                slots.push(end || ent[0] == 0xE5);
+                if !end && ent[0] != 0xE5 && ent[11] != 0x0F {
+                    taken.push(ent[..11].try_into().unwrap());
+                }
+            }
+        }
+        Ok((slots, taken))
+    }
+
+    // every FAT copy and the FSInfo free count and hint, then the staged
+    // dir entries. return the number of sectors written
+    pub fn flush(mut self) -> io::Result<usize> {
+        let _p = trace::purpose("flush");
+        sync_fats(&mut self.writes, &self.fio, &self.old, &self.fat)?;
+
+        let b = &self.fio.bootsec;
+        let bps = b.bpb_byts_per_sec as u64;
+        let mut fsinfo_secs = vec![b.bpb_fs_info as u64];
+        if b.bpb_bk_boot_sec != 0 {
+            fsinfo_secs.push(b.bpb_bk_boot_sec as u64 + b.bpb_fs_info as u64);
+        }
+        let max_clus = self.max_clus();
+        let free = (2..=max_clus)
+            .filter(|&c| self.fat[c as usize] & ENT_MASK == 0)
+            .count() as u32;
+        let next = (2..=max_clus)
+            .rev()
+            .find(|&c| self.fat[c as usize] & ENT_MASK != 0)
+            .map_or(2, |c| c + 1);
+        for sec in fsinfo_secs.into_iter().filter(|_| b.bpb_fs_info != 0) {
+            let off = sec * bps;
+            if FsInfo::new(&self.writes.read_sector(off)?).is_ok_and(|fsinfo| fsinfo.is_valid()) {
+                let (free_off, next_off) = (FsInfo::FREE_COUNT_OFF as u64, NEXT_FREE_OFF as u64);
+                self.writes.patch(off + free_off, &free.to_le_bytes())?;
+                self.writes.patch(off + next_off, &next.to_le_bytes())?;
+            }
+        }
+        let n = self.writes.apply()?;
+        self.file.sync_all()?;
+        Ok(n)
+    }
+}
+
+// refuse a path going through `.` or `..`, the entries by those names are
+// links to dirs that have entries of their own elsewhere
+pub fn no_dots(path: &str) -> io::Result<()> {
+    match path.split('/').find(|&part| part == "." || part == "..") {
+        Some(part) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{}: `{}` isn't allowed in the path", path, part),
+        )),
+        None => Ok(()),
+    }
+}
+
+// the 8.3 name standing for `name` and whether it is exactly `name`, i.e.
+// needs no long entries. lossy or cut names get the lowest free `~N` tail,
+// refer to [1]
+fn short_name(name: &str, taken: &[[u8; 11]]) -> io::Result<([u8; 11], bool)> {
+    let valid = |c: char| c.is_ascii_alphanumeric() || "$%'-_@~`!(){}^#&".contains(c);
+    let trimmed = name.trim_start_matches('.').replace(' ', "");
+    let (base, ext) = match trimmed.rfind('.') {
+        Some(i) => (&trimmed[..i], &trimmed[i + 1..]),
+        None => (&trimmed[..], ""),
+    };
+    let mut lossy = trimmed.len() != name.len() || base.contains('.');
+    let mut conv = |s: &str| -> Vec<u8> {
+        s.chars()
+            .filter(|&c| c != '.')
+            .map(|c| {
+                let up = c.to_ascii_uppercase();
+                if valid(up) {
+                    up as u8
+                } else {
+                    lossy = true;
+                    b'_'
+                }
+            })
+            .collect()
+    };
+    let (base, ext) = (conv(base), conv(ext));
+    lossy |= base.len() > 8 || ext.len() > 3 || base.is_empty();
+
+    let mut sfn = [b' '; 11];
+    let base = if base.is_empty() { b"_".to_vec() } else { base };
+    sfn[..base.len().min(8)].copy_from_slice(&base[..base.len().min(8)]);
+    sfn[8..8 + ext.len().min(3)].copy_from_slice(&ext[..ext.len().min(3)]);
+    if !lossy && !taken.contains(&sfn) {
+        let shown: Vec<u8> = sfn.iter().copied().filter(|&c| c != b' ').collect();
+        let exact = name.len() == shown.len() + (!ext.is_empty()) as usize
+            && name.bytes().all(|c| !c.is_ascii_lowercase());
+        return Ok((sfn, exact));
+    }
+    for n in 1..1000000 {
+        let tail = format!("~{}", n);
+        let keep = base.len().min(8 - tail.len());
+        let mut cand = sfn;
+        cand[..8].fill(b' ');
+        cand[..keep].copy_from_slice(&base[..keep]);
+        cand[keep..keep + tail.len()].copy_from_slice(tail.as_bytes());
+        if !taken.contains(&cand) {
+            return Ok((cand, false));
+        }
+    }
+    Err(io::Error::other("no short name left"))
+}
@@ -1,23 +1,31 @@
-use std::fs::File;
-use std::time::{Duration, UNIX_EPOCH};
+use std::{
+    ffi::OsStr,
+    io,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc,
+    },
+    time::{Duration, UNIX_EPOCH},
+};
 
-use fuser::{FileAttr, FileType, Filesystem, ReplyAttr, ReplyOpen, Request};
-use libc::ENOENT;
+use fuser::{FileAttr, FileType, Filesystem, ReplyAttr, ReplyOpen, ReplyXattr, Request};
+use libc::{EACCES, ERANGE, EROFS, W_OK};
 
+use crate::device::Device;
+use crate::disk;
 use crate::exfat;
-use crate::fat32;
-use crate::fio::{self, Finfo};
+use crate::fio::{self, Finfo, FsType, VolumeInfo};
 use crate::fs;
+use crate::gone::{self, Watch};
+use crate::prefetch::Prefetched;
 
 pub struct FuseW {
     fs: fs::Fs,
-}
-
-#[allow(dead_code)]
-#[derive(Debug, Clone)]
-pub enum FsType {
-    Fat32,
-    Exfat,
+    gone: Arc<AtomicBool>,
+    // FOPEN_* flags every file open replies with
+    open_flags: u32,
+    // the uid and gid of every file, see own
+    owner: (u32, u32),
 }
 
 // impl FromStr for FsType {
@@ -35,16 +43,67 @@ pub enum FsType {
 // }
 
 impl FuseW {
-    pub fn new(devname: &str, typ: FsType) -> Self {
-        let device = File::open(devname).unwrap();
-        let fio: Box<dyn fio::Fio> = match typ {
-            FsType::Fat32 => Box::new(fat32::fio::Fio::new(device)),
-            FsType::Exfat => Box::new(exfat::Fio::new(device)),
-        };
-        FuseW {
-            fs: fs::Fs::new(fio),
+    pub fn new(
+        devname: &str,
+        typ: FsType,
+        forensic: bool,
+        open_flags: u32,
+        readahead: u32,
+    ) -> io::Result<Self> {
+        let device = disk::open_volume(devname, false)?;
+        let path = devname.to_string();
+        let device = Watch::new(device, devname, Arc::new(AtomicBool::new(false)))
+            .reopen_with(move || disk::open_volume(&path, false));
+        Self::with_device(device, devname, typ, forensic, open_flags, readahead)
+    }
+
+    // over a device opened elsewhere, named `name` in warnings
+    pub fn with_device<D: Device + 'static>(
+        device: Watch<D>,
+        name: &str,
+        typ: FsType,
+        forensic: bool,
+        open_flags: u32,
+        readahead: u32,
+    ) -> io::Result<Self> {
+        warn_volume_flags(&device, &typ, name);
+        let gone = device.flag();
+        let mut fs = fs::Fs::new(fio::open(device, &typ)?, forensic)?;
+        fs.set_readahead(readahead);
+        Ok(FuseW {
+            fs,
+            gone,
+            open_flags,
+            owner: (UID.load(Ordering::Relaxed), GID.load(Ordering::Relaxed)),
+        })
+    }
+
+    // the files of this mount belong to `uid` and `gid` instead of the
+    // ones set_owner gave, for the mounts of a daemon
+    pub fn own(&mut self, uid: Option<u32>, gid: Option<u32>) {
+        self.owner = (uid.unwrap_or(self.owner.0), gid.unwrap_or(self.owner.1));
+    }
+
+    fn attr(&self, attr: FileAttr) -> FileAttr {
+        FileAttr {
+            uid: self.owner.0,
+            gid: self.owner.1,
+            ..attr
         }
     }
+
+    // see fs::Fs::prefetch
+    pub fn prefetch(&mut self, path: &str) -> Result<Prefetched, fs::Error> {
+        self.guard(|fs| fs.prefetch(path))
+    }
+
+    // see gone::guard
+    fn guard<T>(
+        &mut self,
+        op: impl FnOnce(&mut fs::Fs) -> Result<T, fs::Error>,
+    ) -> Result<T, fs::Error> {
+        gone::guard(&self.gone, || op(&mut self.fs))
+    }
 }
 
 impl From<&Finfo> for FileType {
@@ -70,8 +129,8 @@ impl From<&Finfo> for FileAttr {
             kind: f.into(),
             perm: 0o755,
             nlink: 2,
-            uid: 501,
-            gid: 20,
+            uid: UID.load(Ordering::Relaxed),
+            gid: GID.load(Ordering::Relaxed),
             rdev: 0,
             flags: 0,
             blksize: 512,
@@ -79,24 +138,134 @@ impl From<&Finfo> for FileAttr {
     }
 }
 
-const TTL: Duration = Duration::from_secs(10);
-const ROOT_DIR_ATTR: FileAttr = FileAttr {
-    ino: 1,
-    size: 0,
-    blocks: 0,
-    atime: UNIX_EPOCH,
-    mtime: UNIX_EPOCH,
-    ctime: UNIX_EPOCH,
-    crtime: UNIX_EPOCH,
-    kind: FileType::Directory,
-    perm: 0o755,
-    nlink: 2,
-    uid: 501,
-    gid: 20,
-    rdev: 0,
-    flags: 0,
-    blksize: 512,
-};
+// who every file and dir of a mount belongs to, see set_owner
+static UID: AtomicU32 = AtomicU32::new(0);
+static GID: AtomicU32 = AtomicU32::new(0);
+
+// the user and group of the mounting process, unless given
+pub fn set_owner(uid: Option<u32>, gid: Option<u32>) {
+    let uid = uid.unwrap_or_else(|| unsafe { libc::getuid() });
+    let gid = gid.unwrap_or_else(|| unsafe { libc::getgid() });
+    UID.store(uid, Ordering::Relaxed);
+    GID.store(gid, Ordering::Relaxed);
+}
+
+pub const TTL: Duration = Duration::from_secs(10);
+pub fn root_dir_attr() -> FileAttr {
+    FileAttr {
+        ino: 1,
+        size: 0,
+        blocks: 0,
+        atime: UNIX_EPOCH,
+        mtime: UNIX_EPOCH,
+        ctime: UNIX_EPOCH,
+        crtime: UNIX_EPOCH,
+        kind: FileType::Directory,
+        perm: 0o755,
+        nlink: 2,
+        uid: UID.load(Ordering::Relaxed),
+        gid: GID.load(Ordering::Relaxed),
+        rdev: 0,
+        flags: 0,
+        blksize: 512,
+    }
+}
+
+// exFAT keeps health bits in its boot sector, point them out before mounting
+pub fn warn_volume_flags(device: &dyn Device, typ: &FsType, name: &str) {
+    let FsType::Exfat = typ else { return };
+    let mut buf = [0u8; exfat::spec::BootSec::SZ];
+    if device.read_exact_at(&mut buf, 0).is_err() {
+        return;
+    }
+    if let Ok(b) = exfat::spec::BootSec::new(&buf) {
+        for warning in b.flag_warnings() {
+            eprintln!("[fuse] {}: {}, consider running fsck", name, warning);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub const ENOATTR: i32 = libc::ENODATA;
+#[cfg(not(target_os = "linux"))]
+pub const ENOATTR: i32 = libc::ENOATTR;
+
+// the xattrs of a volume's root dir, so a mount can be told apart from
+// others without reading the boot sector
+const VOLUME_XATTRS: [&str; 2] = ["user.volume.label", "user.volume.serial"];
+
+pub fn volume_xattr(vol: &VolumeInfo, name: &OsStr) -> Option<Vec<u8>> {
+    match name.to_str()? {
+        "user.volume.label" => Some(vol.label.clone().into_bytes()),
+        // the way blkid and Windows show it
+        "user.volume.serial" => {
+            Some(format!("{:04X}-{:04X}", vol.serial >> 16, vol.serial & 0xFFFF).into_bytes())
+        }
+        _ => None,
+    }
+}
+
+// answer a getxattr or listxattr with `data`, or with its size when asked
+pub fn reply_xattr(data: &[u8], size: u32, reply: ReplyXattr) {
+    if size == 0 {
+        reply.size(data.len() as u32);
+    } else if (size as usize) < data.len() {
+        reply.error(ERANGE);
+    } else {
+        reply.data(data);
+    }
+}
+
+// the mount never writes, so asking for write access fails up front
+// instead of on the first write. files marked read-only say so first
+pub fn reply_access(rdonly: bool, mask: i32, reply: fuser::ReplyEmpty) {
+    if mask & W_OK == 0 {
+        reply.ok();
+    } else if rdonly {
+        reply.error(EACCES);
+    } else {
+        reply.error(EROFS);
+    }
+}
+
+// fuser answers the kernel's INTERRUPT requests with ENOSYS and can't read
+// them while a read is in flight anyway, so long reads look at the caller's
+// pending signals instead. only a SIGKILL or an unblocked SIGINT count,
+// the others don't end the caller
+#[cfg(target_os = "linux")]
+pub fn interrupted(pid: u32) -> bool {
+    const KILL: u64 = 1 << (libc::SIGKILL - 1);
+    const INT: u64 = 1 << (libc::SIGINT - 1);
+    // requests the kernel makes on its own carry no caller
+    if pid == 0 {
+        return false;
+    }
+    let Ok(status) = std::fs::read_to_string(format!("/proc/{}/status", pid)) else {
+        // the caller is gone
+        return true;
+    };
+    let mask = |key: &str| {
+        status
+            .lines()
+            .find_map(|l| l.strip_prefix(key))
+            .and_then(|v| u64::from_str_radix(v.trim(), 16).ok())
+            .unwrap_or(0)
+    };
+    let pending = mask("SigPnd:") | mask("ShdPnd:");
+    pending & KILL != 0 || pending & INT & !mask("SigBlk:") != 0
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn interrupted(_pid: u32) -> bool {
+    false
+}
+
+pub fn volume_xattr_names() -> Vec<u8> {
+    VOLUME_XATTRS
+        .iter()
+        .flat_map(|name| name.bytes().chain([0]))
+        .collect()
+}
 
 impl Filesystem for FuseW {
     fn lookup(
@@ -109,22 +278,24 @@ impl Filesystem for FuseW {
         let name = _name.to_string_lossy();
         // println!("lookup `{name}` from `{parent}`");
 
-        if let Some(file) = self.fs.lookup(parent, &name) {
-            reply.entry(&TTL, &FileAttr::from(file.as_ref()), 0);
-        } else {
-            reply.error(ENOENT);
+        match self.guard(|fs| fs.lookup(parent, &name)) {
+            Ok(file) => reply.entry(&TTL, &self.attr(file.as_ref().into()), 0),
+            Err(e) => reply.error(e.errno()),
         }
     }
 
     fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
         // println!("getattr ino: {ino}");
-        if ino == 1 {
-            reply.attr(&TTL, &ROOT_DIR_ATTR)
-        } else if let Some(fi) = self.fs.getinfo(ino) {
-            // println!("{:?}", fi);
-            reply.attr(&TTL, &fi.as_ref().into())
+        if gone::is_gone(&self.gone) {
+            reply.error(fs::Error::Gone.errno())
+        } else if ino == 1 {
+            reply.attr(&TTL, &self.attr(root_dir_attr()))
         } else {
-            reply.error(ENOENT);
+            match self.guard(|fs| fs.getinfo(ino)) {
+                // println!("{:?}", fi);
+                Ok(fi) => reply.attr(&TTL, &self.attr(fi.as_ref().into())),
+                Err(e) => reply.error(e.errno()),
+            }
         }
     }
 
@@ -136,27 +307,67 @@ impl Filesystem for FuseW {
         _offset: i64,
         mut reply: fuser::ReplyDirectory,
     ) {
-        println!("readdir ino: {ino}");
-        for (i, f) in self
-            .fs
-            .readdir(ino)
-            .iter()
-            .enumerate()
-            .skip(_offset as usize)
-        {
-            if reply.add(f.id, (i + 1) as i64, f.as_ref().into(), f.name.clone()) {
-                println!("readdir: break;");
+        let files = match self.guard(|fs| Ok(fs.readdir_from(ino, _offset as u64)?.to_vec())) {
+            Ok(files) => files,
+            Err(e) => return reply.error(e.errno()),
+        };
+        for f in files {
+            if reply.add(f.id, (f.pos + 1) as i64, f.as_ref().into(), f.name.clone()) {
                 break;
             }
         }
         reply.ok()
     }
 
+    // see reply_access
+    fn access(&mut self, _req: &Request<'_>, ino: u64, mask: i32, reply: fuser::ReplyEmpty) {
+        let rdonly = match self.guard(|fs| fs.getinfo(ino)) {
+            Ok(fi) => fi.is_rdonly,
+            Err(fs::Error::Gone) => return reply.error(fs::Error::Gone.errno()),
+            Err(_) if ino == 1 => false,
+            Err(e) => return reply.error(e.errno()),
+        };
+        reply_access(rdonly, mask, reply);
+    }
+
+    // the volume's size and free space in clusters, none of them available
+    // on a read-only mount
+    fn statfs(&mut self, _req: &Request<'_>, _ino: u64, reply: fuser::ReplyStatfs) {
+        if gone::is_gone(&self.gone) {
+            return reply.error(fs::Error::Gone.errno());
+        }
+        let vol = &self.fs.volume;
+        let clusters = vol.clus_cnt as u64;
+        let free = vol.free.unwrap_or(0) as u64;
+        reply.statfs(clusters, free, 0, 0, 0, vol.clus_sz, 255, vol.clus_sz);
+    }
+
+    fn getxattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+        reply: ReplyXattr,
+    ) {
+        match volume_xattr(&self.fs.volume, name).filter(|_| ino == 1) {
+            Some(value) => reply_xattr(&value, size, reply),
+            None => reply.error(ENOATTR),
+        }
+    }
+
+    fn listxattr(&mut self, _req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        let names = match ino {
+            1 => volume_xattr_names(),
+            _ => vec![],
+        };
+        reply_xattr(&names, size, reply);
+    }
+
     fn open(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
-        if self.fs.open(ino) {
-            reply.opened(0, 0);
-        } else {
-            reply.error(ENOENT);
+        match self.guard(|fs| fs.open(ino)) {
+            Ok(fh) => reply.opened(fh, self.open_flags),
+            Err(e) => reply.error(e.errno()),
         }
     }
 
@@ -164,13 +375,13 @@ impl Filesystem for FuseW {
         &mut self,
         _req: &Request<'_>,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         _flags: i32,
         _lock_owner: Option<u64>,
         _flush: bool,
         reply: fuser::ReplyEmpty,
     ) {
-        self.fs.close(ino);
+        self.fs.close(ino, fh);
         reply.ok();
     }
 
@@ -178,38 +389,45 @@ impl Filesystem for FuseW {
         &mut self,
         _req: &Request<'_>,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         size: u32,
         _flags: i32,
         _lock_owner: Option<u64>,
         reply: fuser::ReplyData,
     ) {
-        if let Some(bytes) = self.fs.read(ino, offset as u32, size) {
-            reply.data(&bytes);
-        } else {
-            reply.error(ENOENT);
+        let pid = _req.pid();
+        match self.guard(|fs| fs.read(ino, fh, offset as u64, size, &|| interrupted(pid))) {
+            Ok(bytes) => reply.data(&bytes),
+            Err(e) => {
+                eprintln!("[fuse] read: {}", e);
+                reply.error(e.errno());
+            }
         }
     }
 
     fn opendir(&mut self, _req: &Request<'_>, _ino: u64, _flags: i32, reply: ReplyOpen) {
-        if let Some(fi) = self.fs.getinfo(_ino) {
-            println!("[fuse] open dir: {}", fi.name);
+        if let Ok(fi) = self.fs.getinfo(_ino) {
+            say!("[fuse] open dir: {}", fi.name);
+        }
+        match self.guard(|fs| fs.opendir(_ino)) {
+            Ok(fh) => reply.opened(fh, 0),
+            Err(e) => reply.error(e.errno()),
         }
-        reply.opened(0, 0);
     }
 
     fn releasedir(
         &mut self,
         _req: &Request<'_>,
         _ino: u64,
-        _fh: u64,
+        fh: u64,
         _flags: i32,
         reply: fuser::ReplyEmpty,
     ) {
-        if let Some(fi) = self.fs.getinfo(_ino) {
-            println!("[fuse] close dir: {}", fi.name);
+        if let Ok(fi) = self.fs.getinfo(_ino) {
+            say!("[fuse] close dir: {}", fi.name);
         }
+        self.fs.closedir(fh);
         reply.ok();
     }
 }
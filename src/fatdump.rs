@@ -0,0 +1,132 @@
+// the FAT of a FAT32 or exFAT volume decoded entry by entry, to look at the
+// allocation state without scripting against read-clus output
+
+use std::{fmt, io, ops::Range};
+
+use crate::device::Device;
+use crate::disk;
+use crate::exfat;
+use crate::fat32;
+
+pub enum Ent {
+    Free,
+    Next(u32),
+    Eoc,
+    Bad,
+    // exFAT, no chain kept for a cluster the bitmap has allocated, the file
+    // it's in is contiguous
+    Unchained,
+    // anything else, links out of the volume included
+    Reserved(u32),
+}
+
+impl fmt::Display for Ent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Ent::Free => write!(f, "free"),
+            Ent::Next(no) => write!(f, "next {}", no),
+            Ent::Eoc => write!(f, "eoc"),
+            Ent::Bad => write!(f, "bad"),
+            Ent::Unchained => write!(f, "unchained"),
+            Ent::Reserved(raw) => write!(f, "reserved {:#010x}", raw),
+        }
+    }
+}
+
+// "2..1000" or "2..", clusters from the first up to the end, not included
+pub fn parse_range(s: &str) -> Result<Range<u32>, String> {
+    let (first, end) = s
+        .split_once("..")
+        .ok_or_else(|| String::from("expected FIRST..END"))?;
+    let first = first.parse::<u32>().map_err(|e| e.to_string())?;
+    let end = match end {
+        "" => u32::MAX,
+        end => end.parse::<u32>().map_err(|e| e.to_string())?,
+    };
+    if first >= end {
+        return Err(String::from("the range is empty"));
+    }
+    Ok(first..end)
+}
+
+// refer to the FAT32 spec, the top 4 bits aren't part of the entry
+pub fn fat32_ent(raw: u32, last_clus: u32) -> Ent {
+    match raw & 0x0FFFFFFF {
+        0 => Ent::Free,
+        0x0FFFFFF7 => Ent::Bad,
+        0x0FFFFFF8.. => Ent::Eoc,
+        no if (2..=last_clus).contains(&no) => Ent::Next(no),
+        _ => Ent::Reserved(raw),
+    }
+}
+
+pub fn exfat_ent(raw: u32, last_clus: u32, allocated: bool) -> Ent {
+    match raw {
+        0 if allocated => Ent::Unchained,
+        0 => Ent::Free,
+        0xFFFFFFF7 => Ent::Bad,
+        0xFFFFFFFF => Ent::Eoc,
+        no if (2..=last_clus).contains(&no) => Ent::Next(no),
+        _ => Ent::Reserved(raw),
+    }
+}
+
+// print the entries of the clusters in `range`, with `used` only the ones
+// that aren't free
+pub fn dump<D: Device>(device: D, range: Range<u32>, used: bool) -> io::Result<()> {
+    let ents: Vec<Ent> = match disk::detect(&device, 0) {
+        Some("FAT32") => {
+            let fio = fat32::fio::Fio::new(device)?;
+            let last_clus = fio.clus_cnt() + 1;
+            let fat = fio.read_fat_copy(0)?;
+            fat.iter()
+                .take(last_clus as usize + 1)
+                .map(|&raw| fat32_ent(raw, last_clus))
+                .collect()
+        }
+        Some("exFAT") => {
+            let mut fio = exfat::Fio::new(device)?;
+            let last_clus = fio.clus_cnt() + 1;
+            let bitmap = fio.read_bitmap()?;
+            let allocated =
+                |no: usize| no >= 2 && bitmap[(no - 2) / 8] & (1 << ((no - 2) % 8)) != 0;
+            fio.read_fat_table()?
+                .iter()
+                .enumerate()
+                .map(|(no, &raw)| exfat_ent(raw, last_clus, allocated(no)))
+                .collect()
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "not a FAT32 or exFAT volume",
+            ))
+        }
+    };
+
+    let (mut free, mut eoc, mut bad, mut shown) = (0, 0, 0, 0);
+    let first = range.start.max(2) as usize;
+    let end = (range.end as usize).min(ents.len());
+    for (no, ent) in ents.iter().enumerate().take(end).skip(first) {
+        match ent {
+            Ent::Free => free += 1,
+            Ent::Eoc => eoc += 1,
+            Ent::Bad => bad += 1,
+            _ => (),
+        }
+        if used && matches!(ent, Ent::Free) {
+            continue;
+        }
+        println!("{}: {}", no, ent);
+        shown += 1;
+    }
+    say!(
+        "[fat] {} of {} entries shown, {} free, {} eoc, {} bad",
+        shown,
+        end.saturating_sub(first),
+        free,
+        eoc,
+        bad
+    );
+    Ok(())
+}
@@ -1,24 +1,333 @@
-use std::time::SystemTime;
+use std::{
+    borrow::Cow,
+    collections::BTreeSet,
+    io,
+    sync::atomic::{AtomicBool, Ordering},
+    time::SystemTime,
+};
 
+use clap::ValueEnum;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::device::Device;
+use crate::{disk, exfat, ext2, fat32, squashfs, udf};
+
+#[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub struct Finfo {
-    pub id: u64, // a unique id consists of entry's clus_no and offset
+    pub id: u64,  // a unique id consists of entry's clus_no and offset
+    pub pos: u64, // where the entry is in its dir, ascending in listing order
     pub name: String,
     pub is_rdonly: bool, // `unused`, especially in FAT fs
     pub is_hidden: bool, // `unused`, especially in FAT fs
     pub is_system: bool, // `unused`, especially in FAT fs
     pub is_dir: bool,
-    pub size32: u32, // used in Fat32
     pub size: u64,
-    pub fst_clus: u32, // implementation specific field
+    pub valid_size: u64, // bytes holding real data, the rest reads as zeros (exFAT)
+    pub fst_clus: u32,   // implementation specific field
+    pub no_fat_chain: bool, // exFAT only, the clusters are contiguous and not in the FAT
     pub crt_time: SystemTime,
     pub wrt_time: SystemTime,
     pub acc_time: SystemTime,
     // pub ctime: SystemTime, // last change time
 }
 
+// what tells one volume from another, and its size
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub struct VolumeInfo {
+    pub serial: u32,
+    pub label: String,
+    pub clus_sz: u32,
+    pub clus_cnt: u32,
+    pub free: Option<u32>, // clusters, none when the fs doesn't keep count
+}
+
 pub trait Fio {
-    fn list_dir(&mut self, no: u32) -> Vec<Finfo>;
-    fn list_root(&mut self) -> Vec<Finfo>;
-    fn read_file(&mut self, fi: &Finfo, offset: u32, size: u32) -> Vec<u8>;
+    fn list_dir(&mut self, no: u32) -> io::Result<Vec<Finfo>>;
+    fn list_root(&mut self) -> io::Result<Vec<Finfo>>;
+    // up to `max` entries of dir `no` from pos `from` on, and the pos to go
+    // on from when there may be more. the failure is passed on, this is
+    // what mounts list dirs with
+    fn list_dir_page(
+        &mut self,
+        no: u32,
+        from: u64,
+        max: usize,
+    ) -> io::Result<(Vec<Finfo>, Option<u64>)>;
+    // InvalidData when the filesystem's structures are broken
+    fn read_file(&mut self, fi: &Finfo, offset: u64, size: u32) -> io::Result<Vec<u8>>;
+    // the byte ranges (start, len) of the file in [offset, offset + size)
+    // that the device couldn't read and handed over as zeros
+    fn unreadable(&mut self, fi: &Finfo, offset: u64, size: u32) -> Vec<(u64, u64)>;
+    #[allow(dead_code)]
+    fn volume(&mut self) -> io::Result<VolumeInfo>;
+    // deleted or damaged entries recovered from the directories, if supported
+    #[allow(dead_code)]
+    fn lost_found(&mut self) -> io::Result<Vec<Finfo>> {
+        Ok(vec![])
+    }
+    // the first entry of dir `no` with `name` on disk, found without
+    // decoding the names of the others. none when the fs can't tell that
+    // way, see find_in
+    #[allow(dead_code)]
+    fn find(&mut self, _no: u32, _name: &str) -> Option<io::Result<Option<Finfo>>> {
+        None
+    }
+    // roughly the bytes of tables and listings it keeps in memory, what a
+    // mount charges to the cache budget for it
+    #[allow(dead_code)]
+    fn held(&self) -> u64 {
+        0
+    }
+}
+
+// the last dir listed whole, for a fs that can't start a listing part way:
+// the pages of a dir are cut from it rather than listing it again for each
+#[derive(Default)]
+pub struct LastListed {
+    no: Option<u32>,
+    files: Vec<Finfo>,
+}
+
+impl LastListed {
+    // the page of dir `no` from pos `from` on, `list` lists it whole
+    pub fn page(
+        &mut self,
+        no: u32,
+        from: u64,
+        max: usize,
+        list: impl FnOnce(u32) -> io::Result<Vec<Finfo>>,
+    ) -> io::Result<(Vec<Finfo>, Option<u64>)> {
+        if self.no != Some(no) {
+            self.no = None;
+            self.files = list(no)?;
+            self.no = Some(no);
+        }
+        let at = self.files.partition_point(|f| f.pos < from);
+        let end = at.saturating_add(max).min(self.files.len());
+        let next = self.files.get(end).map(|f| f.pos);
+        Ok((self.files[at..end].to_vec(), next))
+    }
+
+    #[allow(dead_code)]
+    pub fn held(&self) -> u64 {
+        self.files.iter().map(held).sum()
+    }
+}
+
+// roughly what an entry takes up in memory
+#[allow(dead_code)]
+pub fn held(fi: &Finfo) -> u64 {
+    (std::mem::size_of::<Finfo>() + fi.name.len()) as u64
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub enum FsType {
+    Fat32,
+    Exfat,
+    Squashfs,
+    Udf,
+    Ext2,
+}
+
+// the type of the filesystem at byte `off`, of the ones there's a Fio for
+pub fn detect(dev: &dyn Device, off: u64) -> Option<FsType> {
+    match disk::detect(dev, off)? {
+        "FAT32" => Some(FsType::Fat32),
+        "exFAT" => Some(FsType::Exfat),
+        "SquashFS" => Some(FsType::Squashfs),
+        "UDF" => Some(FsType::Udf),
+        "ext2" => Some(FsType::Ext2),
+        _ => None,
+    }
+}
+
+// Unsupported when what's there can't be read as `typ` after all
+pub fn open<'a>(device: impl Device + 'a, typ: &FsType) -> io::Result<Box<dyn Fio + 'a>> {
+    Ok(match typ {
+        FsType::Fat32 => Box::new(fat32::fio::Fio::new(device)?),
+        FsType::Exfat => Box::new(exfat::Fio::new(device)?),
+        FsType::Squashfs => Box::new(squashfs::Fio::new(device)?),
+        FsType::Udf => Box::new(udf::Fio::new(device)?),
+        // what the journal holds is replayed in memory, nothing is written
+        FsType::Ext2 => Box::new(ext2::Fio::new(device, ext2::Journal::Replay)?),
+    })
+}
+
+// whether values read from the media are clamped before use, see set_sanitize
+static SANITIZE: AtomicBool = AtomicBool::new(false);
+
+// bound every size, count and cluster number read from the media by the
+// volume's geometry and the device's size, for hostile or corrupt images
+pub fn set_sanitize() {
+    SANITIZE.store(true, Ordering::Relaxed);
+}
+
+pub fn sanitizing() -> bool {
+    SANITIZE.load(Ordering::Relaxed)
+}
+
+// how many bytes of the device a volume may use: all it holds when
+// sanitizing and the device can tell, else no bound
+pub fn reach(device: &dyn Device) -> u64 {
+    match sanitizing() {
+        true => device.len().unwrap_or(u64::MAX),
+        false => u64::MAX,
+    }
+}
+
+// `cnt` cut to the least of `fits`, each what some part of `fs`'s geometry
+// holds, said when it's cut
+pub fn fit_count(fs: &str, what: &str, cnt: u64, fits: &[(u64, &str)]) -> u64 {
+    let mut ret = cnt;
+    for &(fit, by) in fits {
+        if fit < ret {
+            eprintln!("[{}] {} {} cut to {} by {}", fs, what, ret, fit, by);
+            ret = fit;
+        }
+    }
+    ret
+}
+
+// bring an entry in line with its clusters: a first cluster out of the
+// volume is dropped, the sizes can't go past `clusters` of `clus_sz`
+pub fn clamp(fi: &mut Finfo, clus_sz: u32, last_clus: u32, clusters: u64) {
+    let clusters = if (2..=last_clus).contains(&fi.fst_clus) {
+        clusters
+    } else {
+        fi.fst_clus = 0;
+        0
+    };
+    let max = clusters * clus_sz as u64;
+    if fi.size > max {
+        eprintln!(
+            "[fio] sanitize: {}: size {} clamped to {}",
+            fi.name, fi.size, max
+        );
+        fi.size = max;
+    }
+    fi.valid_size = fi.valid_size.min(fi.size);
+}
+
+// what a mount does with a name longer than the host's 255 bytes once in
+// UTF-8, long UTF-16 names can be up to 765 bytes
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum LongNames {
+    // cut short and ended with `~` and a hash of the whole name, the same
+    // every time it's listed
+    Truncate,
+    // left out of listings, looked up it's ENAMETOOLONG
+    Error,
+}
+
+// whether names are put in NFC, see set_nfc
+static NFC: AtomicBool = AtomicBool::new(false);
+
+// show names in Unicode NFC and look them up that way, so a name stored
+// decomposed is found by its composed spelling and the other way around
+pub fn set_nfc() {
+    NFC.store(true, Ordering::Relaxed);
+}
+
+pub fn normalizing() -> bool {
+    NFC.load(Ordering::Relaxed)
+}
+
+// a UTF-16 name as a whole, so that a surrogate pair split over two entries
+// stays one character. lone surrogates become U+FFFD
+pub fn name_from_utf16(units: &[u16]) -> String {
+    let name: String = char::decode_utf16(units.iter().copied())
+        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+        .collect();
+    shown_name(name)
+}
+
+// a name read from the media the way it's listed, in NFC with set_nfc
+pub fn shown_name(name: String) -> String {
+    match name_key(&name) {
+        Cow::Owned(nfc) => nfc,
+        Cow::Borrowed(_) => name,
+    }
+}
+
+// the form a name is compared in when looking it up
+pub fn name_key(name: &str) -> Cow<'_, str> {
+    if normalizing() {
+        Cow::Owned(name.nfc().collect())
+    } else {
+        Cow::Borrowed(name)
+    }
+}
+
+// group a cluster chain into runs of physically consecutive clusters,
+// as (first clusno, count)
+pub fn clus_runs(clusnos: &[u32]) -> Vec<(u32, u32)> {
+    let mut runs: Vec<(u32, u32)> = vec![];
+    for &clusno in clusnos {
+        match runs.last_mut() {
+            Some((first, cnt)) if *first + *cnt == clusno => *cnt += 1,
+            _ => runs.push((clusno, 1)),
+        }
+    }
+    runs
+}
+
+// spans within the data read at `offset` of a file, as file byte ranges
+pub fn file_spans(offset: u64, spans: Vec<(usize, usize)>) -> Vec<(u64, u64)> {
+    spans
+        .into_iter()
+        .map(|(start, len)| (offset + start as u64, len as u64))
+        .collect()
+}
+
+// the entry at a `/` separated path below the root, the root itself has none
+pub fn lookup(fio: &mut dyn Fio, path: &str) -> io::Result<Option<Finfo>> {
+    let mut found: Option<Finfo> = None;
+    for name in path.split('/').filter(|name| !name.is_empty()) {
+        let name = name_key(name);
+        let fi = match found {
+            None => fio.list_root()?.into_iter().find(|fi| fi.name == name),
+            Some(di) if di.is_dir && di.fst_clus != 0 => find_in(fio, di.fst_clus, &name)?,
+            Some(_) => None,
+        };
+        let Some(fi) = fi else {
+            return Ok(None);
+        };
+        found = Some(fi);
+    }
+    Ok(found)
+}
+
+// the entry of dir `no` named `name`, by Fio::find when it can tell. a
+// `~N` name may have been given to a repeat by list_dir, those are only
+// known from the whole listing
+pub fn find_in(fio: &mut dyn Fio, no: u32, name: &str) -> io::Result<Option<Finfo>> {
+    match fio.find(no, name) {
+        Some(Ok(Some(fi))) => Ok(Some(fi)),
+        Some(Ok(None)) if !name.contains('~') => Ok(None),
+        _ => Ok(fio.list_dir(no)?.into_iter().find(|fi| fi.name == name)),
+    }
+}
+
+// make names unique within one listing by appending `~N` to the repeats,
+// recovered entries may come from several dirs or shadow each other, and a
+// corrupt dir may list a name twice
+pub fn dedup_names(ents: &mut [Finfo]) {
+    let mut taken = BTreeSet::new();
+    for fi in ents.iter_mut() {
+        if taken.contains(&fi.name) {
+            fi.name = unique_name(&fi.name, |name| taken.contains(name));
+        }
+        taken.insert(fi.name.clone());
+    }
+}
+
+// `name` with the first `~N` suffix that isn't `taken`
+pub fn unique_name(name: &str, taken: impl Fn(&str) -> bool) -> String {
+    (1..)
+        .map(|n| format!("{}~{}", name, n))
+        .find(|name| !taken(name))
+        .unwrap()
 }
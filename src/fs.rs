@@ -1,89 +1,555 @@
-use std::{collections::BTreeMap, rc::Rc, vec};
+use std::{
+    cmp::min,
+    collections::{BTreeMap, BTreeSet},
+    io,
+    rc::Rc,
+    sync::atomic::{AtomicBool, Ordering},
+    time::SystemTime,
+    vec,
+};
 
-use crate::fio::{Finfo, Fio};
+use sha2::{Digest, Sha256};
 
-type DirMap = BTreeMap<u64, Vec<Rc<Finfo>>>;
+use crate::cache::Cache;
+use crate::extract::hex;
+use crate::fio::{self, Finfo, Fio, LongNames, VolumeInfo};
+use crate::prefetch::Prefetched;
+use crate::stats::{self, STATS};
+
+type DirMap = BTreeMap<u64, Listing>;
 type FinfoMap = BTreeMap<u64, Rc<Finfo>>;
 
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("no such file or dir")]
+    NotFound,
+    #[error("not a file")]
+    NotAFile,
+    #[error("not a dir")]
+    NotADir,
+    #[error("filesystem is corrupt: {0}")]
+    Corrupt(String),
+    #[error("device read failed: {0}")]
+    Io(io::Error),
+    #[error("interrupted")]
+    Interrupted,
+    #[error("name too long")]
+    NameTooLong,
+    #[error("the device is gone")]
+    Gone,
+}
+
+// the fio layer reports broken on-disk structures as InvalidData
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::InvalidData => Error::Corrupt(e.to_string()),
+            _ => Error::Io(e),
+        }
+    }
+}
+
+// the kinds exit::code tells apart, for what fails before serving
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Io(e) => e,
+            Error::Corrupt(_) => io::Error::new(io::ErrorKind::InvalidData, e),
+            Error::NotFound => io::Error::new(io::ErrorKind::NotFound, e),
+            e => io::Error::other(e),
+        }
+    }
+}
+
+impl Error {
+    pub fn errno(&self) -> i32 {
+        match self {
+            Error::NotFound => libc::ENOENT,
+            Error::NotAFile => libc::EISDIR,
+            Error::NotADir => libc::ENOTDIR,
+            Error::Corrupt(_) => libc::EIO,
+            Error::Io(e) => e.raw_os_error().unwrap_or(libc::EIO),
+            Error::Interrupted => libc::EINTR,
+            Error::NameTooLong => libc::ENAMETOOLONG,
+            Error::Gone => libc::ENODEV,
+        }
+    }
+}
+
+// the synthetic root dir holding recovered entries in forensic mode
+pub const LOST_FOUND_ID: u64 = u64::MAX;
+// clusters read past a read that picks up where the last one on the same
+// handle ended
+const READAHEAD: u32 = 16;
+// device reads are split in pieces this big, to give up between them
+// when the reader is interrupted
+const CHUNK: u32 = 64 * 1024;
+// dir entries read from the disk at a time
+const PAGE: usize = 1024;
+// the names of a dir kept for lookups, past that a lookup pages through it
+const NAMES_MAX: usize = 16 * PAGE;
+// the shares of the cache budget the dir listings get, see cache::Cache
+const DIRS_WEIGHT: u64 = 1;
+// and what can't be evicted, the entries known by id, the fio's tables and
+// the data read ahead. it has the others give way when it grows
+const FILES_WEIGHT: u64 = 1;
+// the longest name the host takes, in UTF-8 bytes, see fio::LongNames
+const NAME_MAX: usize = 255;
+
+// whether long names are left out, see set_long_names
+static LONG_NAMES_ERROR: AtomicBool = AtomicBool::new(false);
+
+pub fn set_long_names(policy: LongNames) {
+    LONG_NAMES_ERROR.store(policy == LongNames::Error, Ordering::Relaxed);
+}
+
+// the name of `fi` as listed and looked up, none when it's left out
+fn fit_name(mut fi: Finfo) -> Option<Finfo> {
+    if fi.name.len() <= NAME_MAX {
+        return Some(fi);
+    }
+    if LONG_NAMES_ERROR.load(Ordering::Relaxed) {
+        eprintln!(
+            "[fs] {}...: name too long, left out",
+            &fi.name[..fi.name.floor_char_boundary(32)]
+        );
+        return None;
+    }
+    let suffix = format!("~{}", &hex(&Sha256::digest(fi.name.as_bytes()))[..8]);
+    let cut = fi.name.floor_char_boundary(NAME_MAX - suffix.len());
+    fi.name.truncate(cut);
+    fi.name.push_str(&suffix);
+    Some(fi)
+}
+
+// a run of a dir's entries, from readdir cookie `from` on
+struct Page {
+    from: u64,
+    files: Vec<Rc<Finfo>>,
+    next: Option<u64>, // where the page after it starts, none at the end
+}
+
+impl Page {
+    fn covers(&self, cookie: u64) -> bool {
+        self.from <= cookie
+            && (self.next.is_none() || self.files.last().is_some_and(|f| cookie <= f.pos))
+    }
+}
+
+// what has been read of a dir. huge ones are never held whole, readdir
+// keeps the page it's on and lookup reads on until it finds the name
+struct Listing {
+    no: u32,
+    page: Page, // the last one readdir asked for
+    // the entries read so far by name, up to NAMES_MAX of them
+    names: BTreeMap<String, Rc<Finfo>>,
+    full: bool,           // whether names stopped short of some
+    indexed: Option<u64>, // where lookup reads on from, none once all is read
+    // the names given to entries repeating one read before them, kept so a
+    // page read again shows them the same way
+    renamed: BTreeMap<u64, String>,
+    bytes: u64, // what it's charged to the dirs cache with
+    used: u64,  // Fs::tick when last used, the oldest goes first
+}
+
+impl Listing {
+    fn whole(files: Vec<Rc<Finfo>>) -> Self {
+        Listing {
+            no: 0,
+            names: files.iter().map(|f| (f.name.clone(), f.clone())).collect(),
+            full: false,
+            page: Page {
+                from: 0,
+                files,
+                next: None,
+            },
+            indexed: None,
+            renamed: BTreeMap::new(),
+            bytes: 0,
+            used: 0,
+        }
+    }
+
+    // roughly what it takes up
+    fn size(&self) -> u64 {
+        let names: usize = self
+            .names
+            .keys()
+            .map(|name| name.len() + 48 + std::mem::size_of::<Finfo>())
+            .sum();
+        let files = self.page.files.len() * std::mem::size_of::<Rc<Finfo>>();
+        (names + files + self.renamed.len() * 64) as u64
+    }
+
+    // a dir read back from the disk when it's needed again. the root and
+    // lost+found are made up and aren't, a dir with entries renamed would
+    // have them renamed differently after reading part of it
+    fn evictable(&self) -> bool {
+        self.no != 0 && self.renamed.is_empty()
+    }
+}
+
+// an open file or dir handle, with the data read ahead for a file
+#[derive(Default)]
+struct Handle {
+    id: u64,
+    next: u64, // where the last read ended
+    ahead_off: u64,
+    ahead: Vec<u8>, // file bytes [ahead_off, ahead_off + len)
+}
+
 // #[allow(dead_code)]
 pub struct Fs {
     dirmap: DirMap,
     fmap: FinfoMap,
     filesopen: BTreeMap<u64, u32>,
+    handles: BTreeMap<u64, Handle>,
+    next_fh: u64,
+    readahead: u64, // in bytes
+    dirs: Cache,    // what dirmap takes up
+    files: Cache,   // what fmap, the fio and the handles' read-ahead take up
+    fio_held: u64,  // what the fio is charged with, see Fio::held
+    tick: u64,
     fio: Box<dyn Fio>,
+    pub volume: VolumeInfo,
 }
 
 // #[allow(dead_code)]
 impl Fs {
-    pub fn new(fio: Box<dyn Fio>) -> Self {
+    pub fn new(mut fio: Box<dyn Fio>, forensic: bool) -> Result<Self, Error> {
         let dirmap = DirMap::new();
         let fmap = FinfoMap::new();
+        let volume = fio.volume()?;
         let mut fs = Fs {
             dirmap,
             fmap,
             filesopen: BTreeMap::new(),
+            handles: BTreeMap::new(),
+            next_fh: 1,
+            readahead: READAHEAD as u64 * volume.clus_sz as u64,
+            dirs: Cache::new("dirs", DIRS_WEIGHT),
+            files: Cache::new("files", FILES_WEIGHT),
+            fio_held: 0,
+            tick: 0,
             fio,
+            volume,
         };
-        let rootfiles: Vec<Rc<Finfo>> = fs.fio.list_root().into_iter().map(Rc::new).collect();
+        let rootfiles: Vec<Rc<Finfo>> = fs
+            .fio
+            .list_root()?
+            .into_iter()
+            .filter_map(fit_name)
+            .map(Rc::new)
+            .collect();
+
+        rootfiles.iter().for_each(|rc_fi| fs.know(rc_fi));
 
-        rootfiles.iter().for_each(|rc_fi| {
-            fs.fmap.insert(rc_fi.id, rc_fi.clone());
+        fs.dirmap.insert(1, Listing::whole(rootfiles));
+        if forensic {
+            fs.add_lost_found()?;
+        }
+        Ok(fs)
+    }
+
+    fn add_lost_found(&mut self) -> Result<(), Error> {
+        let mut found = self.fio.lost_found()?;
+        fio::dedup_names(&mut found);
+        for (i, fi) in found.iter_mut().enumerate() {
+            fi.pos = i as u64;
+        }
+        let found: Vec<Rc<Finfo>> = found
+            .into_iter()
+            .filter_map(fit_name)
+            .map(Rc::new)
+            .collect();
+        found.iter().for_each(|rc_fi| self.know(rc_fi));
+
+        let dir = Rc::new(Finfo {
+            id: LOST_FOUND_ID,
+            // after everything in the root, as a readdir cookie too
+            pos: i64::MAX as u64 - 1,
+            name: String::from("lost+found"),
+            is_rdonly: true,
+            is_hidden: false,
+            is_system: false,
+            is_dir: true,
+            size: 0,
+            valid_size: 0,
+            fst_clus: 0,
+            no_fat_chain: false,
+            crt_time: SystemTime::UNIX_EPOCH,
+            wrt_time: SystemTime::UNIX_EPOCH,
+            acc_time: SystemTime::UNIX_EPOCH,
         });
+        self.know(&dir);
+        let root = self.dirmap.get_mut(&1).unwrap();
+        root.names.insert(dir.name.clone(), dir.clone());
+        root.page.files.push(dir);
+        self.dirmap.insert(LOST_FOUND_ID, Listing::whole(found));
+        Ok(())
+    }
 
-        fs.dirmap.insert(1, rootfiles);
-        fs
-    }
-
-    pub fn readdir(&mut self, id: u64) -> &Vec<Rc<Finfo>> {
-        if self.dirmap.get(&id).is_none() {
-            if let Some(di) = self.fmap.get(&id) {
-                let rc_files = if di.fst_clus != 0 {
-                    self.fio
-                        .list_dir(di.fst_clus)
-                        .into_iter()
-                        .map(Rc::new)
-                        .collect()
-                } else {
-                    vec![]
-                };
-                rc_files.iter().for_each(|rc_fi| {
-                    self.fmap.insert(rc_fi.id, rc_fi.clone());
-                });
-                self.dirmap.insert(id, rc_files);
+    // keep `fi` for the kernel to ask for by id
+    fn know(&mut self, fi: &Rc<Finfo>) {
+        self.files.charge(fio::held(fi));
+        if let Some(old) = self.fmap.insert(fi.id, fi.clone()) {
+            self.files.release(fio::held(&old));
+        }
+    }
+
+    // start the listing of dir `id` with its first page
+    fn open_listing(&mut self, id: u64) -> Result<(), Error> {
+        if self.dirmap.contains_key(&id) {
+            return Ok(());
+        }
+        let di = self.fmap.get(&id).ok_or(Error::NotFound)?;
+        if !di.is_dir {
+            return Err(Error::NotADir);
+        }
+        // an empty dir has no clusters to read
+        if di.fst_clus == 0 {
+            self.dirmap.insert(id, Listing::whole(vec![]));
+            return Ok(());
+        }
+        let listing = Listing {
+            no: di.fst_clus,
+            indexed: Some(0),
+            ..Listing::whole(vec![])
+        };
+        self.dirmap.insert(id, listing);
+        let page = match self.read_page(id, 0) {
+            Ok(page) => page,
+            Err(e) => {
+                // tried again from the start next time
+                self.dirmap.remove(&id);
+                return Err(e);
+            }
+        };
+        self.dirmap.get_mut(&id).unwrap().page = page;
+        self.used(id);
+        Ok(())
+    }
+
+    // charge the listing of `id` anew and mark it used, then evict the
+    // least recently used others while the dirs cache is over budget
+    fn used(&mut self, id: u64) {
+        let held = self.fio.held();
+        self.files.release(self.fio_held);
+        self.files.charge(held);
+        self.fio_held = held;
+        self.tick += 1;
+        let listing = self.dirmap.get_mut(&id).unwrap();
+        let bytes = listing.size();
+        self.dirs.release(listing.bytes);
+        self.dirs.charge(bytes);
+        listing.bytes = bytes;
+        listing.used = self.tick;
+        while self.dirs.over() {
+            let open: BTreeSet<u64> = self.handles.values().map(|h| h.id).collect();
+            let oldest = self
+                .dirmap
+                .iter()
+                .filter(|(&lid, l)| lid != id && l.evictable() && !open.contains(&lid))
+                .min_by_key(|(_, l)| l.used)
+                .map(|(&lid, _)| lid);
+            let Some(lid) = oldest else {
+                break;
+            };
+            let listing = self.dirmap.remove(&lid).unwrap();
+            self.dirs.evicted(listing.bytes);
+        }
+    }
+
+    // the page of dir `id` at `from`, with its entries indexed
+    fn read_page(&mut self, id: u64, from: u64) -> Result<Page, Error> {
+        let listing = self.dirmap.get_mut(&id).unwrap();
+        let (listed, next) = self.fio.list_dir_page(listing.no, from, PAGE)?;
+        let mut files = vec![];
+        // a page only ever holds part of the dir, a name may repeat one on
+        // another page
+        for mut fi in listed.into_iter().filter_map(fit_name) {
+            if let Some(name) = listing.renamed.get(&fi.id) {
+                fi.name = name.clone();
+            } else if listing.names.get(&fi.name).is_some_and(|f| f.id != fi.id) {
+                fi.name = fio::unique_name(&fi.name, |name| listing.names.contains_key(name));
+                listing.renamed.insert(fi.id, fi.name.clone());
+            }
+            let fi = Rc::new(fi);
+            if listing.names.len() < NAMES_MAX || listing.names.contains_key(&fi.name) {
+                listing.names.insert(fi.name.clone(), fi.clone());
             } else {
-                panic!("fs: readdir")
+                listing.full = true;
             }
+            files.push(fi);
+        }
+        // pages read in order carry the index along
+        if listing.indexed == Some(from) {
+            listing.indexed = next;
+        }
+        Ok(Page { from, files, next })
+    }
+
+    // the entries of a dir past readdir cookie `cookie`, 0 to start or an
+    // entry's pos + 1. positions come from the disk, so a cookie picks up
+    // where it left off even if the listing was loaded again in between
+    pub fn readdir_from(&mut self, id: u64, cookie: u64) -> Result<&[Rc<Finfo>], Error> {
+        self.open_listing(id)?;
+        if self.dirmap[&id].page.covers(cookie) {
+            stats::add(&STATS.dir_hits, 1);
+        } else {
+            stats::add(&STATS.dir_misses, 1);
+            let page = self.read_page(id, cookie)?;
+            self.dirmap.get_mut(&id).unwrap().page = page;
         }
-        &self.dirmap[&id]
+        self.used(id);
+        let files = &self.dirmap[&id].page.files;
+        let from = files.partition_point(|f| f.pos < cookie);
+        Ok(&files[from..])
     }
 
-    pub fn lookup(&mut self, parent: u64, name: &str) -> Option<Rc<Finfo>> {
-        for fi in self.readdir(parent) {
-            if fi.name == name {
-                return Some(fi.clone());
+    pub fn lookup(&mut self, parent: u64, name: &str) -> Result<Rc<Finfo>, Error> {
+        if name.len() > NAME_MAX {
+            return Err(Error::NameTooLong);
+        }
+        self.open_listing(parent)?;
+        let key = fio::name_key(name);
+        let listing = &self.dirmap[&parent];
+        let unread = listing.indexed.is_some() || listing.full;
+        let found = match listing.names.get(key.as_ref()) {
+            Some(fi) => Some(fi.clone()),
+            // entries on disk are found without paging up to them, a `~N`
+            // name given to a repeat only by reading the pages before it
+            None if unread => match self.fio.find(listing.no, &key) {
+                Some(Err(e)) => return Err(e.into()),
+                Some(Ok(Some(fi))) => Some(Rc::new(fi)),
+                Some(Ok(None)) if !key.contains('~') => None,
+                _ => self.read_on(parent, &key)?,
+            },
+            None => None,
+        };
+        self.used(parent);
+        let fi = found.ok_or(Error::NotFound)?;
+        // what's looked up is what the kernel asks for by id later
+        self.know(&fi);
+        Ok(fi)
+    }
+
+    // the entry named `key` in the pages of dir `id` its names don't cover:
+    // those past where they stopped, or all of them once they're full
+    fn read_on(&mut self, id: u64, key: &str) -> Result<Option<Rc<Finfo>>, Error> {
+        let listing = &self.dirmap[&id];
+        let mut from = match (listing.full, listing.indexed) {
+            (true, _) => 0,
+            (false, Some(from)) => from,
+            (false, None) => return Ok(None),
+        };
+        loop {
+            let page = self.read_page(id, from)?;
+            if let Some(fi) = page.files.iter().find(|fi| fi.name == key) {
+                return Ok(Some(fi.clone()));
+            }
+            match page.next {
+                Some(next) => from = next,
+                None => return Ok(None),
             }
         }
-        None
     }
 
-    pub fn getinfo(&mut self, id: u64) -> Option<Rc<Finfo>> {
-        self.fmap.get(&id).cloned()
+    // every dir under `path` listed into the dir caches ahead of a walk
+    // through them, as far as the cache budget holds them
+    pub fn prefetch(&mut self, path: &str) -> Result<Prefetched, Error> {
+        let mut id = 1;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            id = self.lookup(id, name)?.id;
+        }
+        if !self.is_dir(id)? {
+            return Err(Error::NotADir);
+        }
+        let mut done = Prefetched::default();
+        let mut seen = BTreeSet::from([id]);
+        let mut todo = vec![id];
+        while let Some(dir) = todo.pop() {
+            done.dirs += 1;
+            let mut cookie = 0;
+            loop {
+                let files = self.readdir_from(dir, cookie)?.to_vec();
+                let Some(last) = files.last() else {
+                    break;
+                };
+                cookie = last.pos + 1;
+                for fi in files.iter().filter(|fi| fi.name != "." && fi.name != "..") {
+                    if !fi.is_dir {
+                        done.files += 1;
+                    } else if seen.insert(fi.id) {
+                        self.know(fi);
+                        todo.push(fi.id);
+                    }
+                }
+            }
+        }
+        Ok(done)
     }
 
-    pub fn open(&mut self, id: u64) -> bool {
-        if let Some(_fi) = self.getinfo(id) {
-            println!("open: {:?}", _fi.name);
-            self.filesopen
-                .entry(id)
-                .and_modify(|cnt| *cnt += 1)
-                .or_insert(1);
-            true
-        } else {
-            false
+    pub fn getinfo(&mut self, id: u64) -> Result<Rc<Finfo>, Error> {
+        self.fmap.get(&id).cloned().ok_or(Error::NotFound)
+    }
+
+    // read `clusters` clusters ahead of sequential reads, 0 turns it off
+    pub fn set_readahead(&mut self, clusters: u32) {
+        self.readahead = clusters as u64 * self.volume.clus_sz as u64;
+    }
+
+    // the root and lost+found are only in dirmap
+    fn is_dir(&mut self, id: u64) -> Result<bool, Error> {
+        Ok(self.dirmap.contains_key(&id) || self.getinfo(id)?.is_dir)
+    }
+
+    fn new_handle(&mut self, id: u64) -> u64 {
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        self.handles.insert(
+            fh,
+            Handle {
+                id,
+                ..Default::default()
+            },
+        );
+        fh
+    }
+
+    fn drop_handle(&mut self, fh: u64) {
+        if let Some(h) = self.handles.remove(&fh) {
+            self.files.release(h.ahead.len() as u64);
         }
     }
 
-    pub fn close(&mut self, id: u64) {
+    // a new handle for the file, dirs are opened with opendir
+    pub fn open(&mut self, id: u64) -> Result<u64, Error> {
+        if self.is_dir(id)? {
+            return Err(Error::NotAFile);
+        }
+        let fi = self.getinfo(id)?;
+        say!("[fs] open: {}", fi.name);
+        self.filesopen
+            .entry(id)
+            .and_modify(|cnt| *cnt += 1)
+            .or_insert(1);
+        Ok(self.new_handle(id))
+    }
+
+    pub fn opendir(&mut self, id: u64) -> Result<u64, Error> {
+        if !self.is_dir(id)? {
+            return Err(Error::NotADir);
+        }
+        Ok(self.new_handle(id))
+    }
+
+    pub fn closedir(&mut self, fh: u64) {
+        self.drop_handle(fh);
+    }
+
+    pub fn close(&mut self, id: u64, fh: u64) {
+        self.drop_handle(fh);
         if let Some(cnt) = self.filesopen.get_mut(&id) {
             *cnt -= 1;
             if *cnt == 0 {
@@ -92,9 +558,96 @@ impl Fs {
         }
     }
 
-    pub fn read(&mut self, id: u64, offset: u32, size: u32) -> Option<Vec<u8>> {
-        self.fmap
-            .get(&id)
-            .map(|fi| self.fio.read_file(fi, offset, size))
+    // reads continuing the previous one on the handle fetch `readahead`
+    // more bytes in the same device request and the following reads are
+    // served from them. a read coming back empty inside the file means its
+    // clusters can't be found, a short one is passed on and the rest asked
+    // for again
+    // `cancel` is asked whether the caller went away, once the read takes
+    // more than one device read
+    pub fn read(
+        &mut self,
+        id: u64,
+        fh: u64,
+        offset: u64,
+        size: u32,
+        cancel: &dyn Fn() -> bool,
+    ) -> Result<Vec<u8>, Error> {
+        let fi = self.fmap.get(&id).ok_or(Error::NotFound)?;
+        if fi.is_dir {
+            return Err(Error::NotAFile);
+        }
+        let want = min(size as u64, fi.size.saturating_sub(offset));
+        let bytes = self.read_ahead(id, fh, offset, size, cancel)?;
+        if bytes.is_empty() && want > 0 {
+            return Err(Error::Corrupt(format!(
+                "no data for {} bytes at {} of file {}",
+                want, offset, id
+            )));
+        }
+        Ok(bytes)
+    }
+
+    fn read_ahead(
+        &mut self,
+        id: u64,
+        fh: u64,
+        offset: u64,
+        size: u32,
+        cancel: &dyn Fn() -> bool,
+    ) -> Result<Vec<u8>, Error> {
+        let fi = &self.fmap[&id];
+        let fio = self.fio.as_mut();
+        // a handle of another file doesn't carry this one's read-ahead
+        let Some(h) = self.handles.get_mut(&fh).filter(|h| h.id == id) else {
+            return read_chunked(fio, fi, offset, size as u64, cancel);
+        };
+        let end = offset.saturating_add(size as u64);
+        let ahead_end = h.ahead_off + h.ahead.len() as u64;
+        let bytes = if offset >= h.ahead_off && end <= ahead_end {
+            let from = (offset - h.ahead_off) as usize;
+            h.ahead[from..from + size as usize].to_vec()
+        } else if offset == h.next && self.readahead > 0 {
+            let mut bytes = read_chunked(fio, fi, offset, size as u64 + self.readahead, cancel)?;
+            h.ahead_off = offset;
+            self.files.release(h.ahead.len() as u64);
+            self.files.charge(bytes.len() as u64);
+            h.ahead = bytes.clone();
+            bytes.truncate(size as usize);
+            bytes
+        } else {
+            read_chunked(fio, fi, offset, size as u64, cancel)?
+        };
+        h.next = offset + bytes.len() as u64;
+        Ok(bytes)
+    }
+}
+
+// [offset, offset + size) of the file in CHUNK sized reads, stopping at
+// the first short one. `cancel` is asked once, after the first chunk, a
+// read that fits in one isn't worth giving up
+fn read_chunked(
+    fio: &mut dyn Fio,
+    fi: &Finfo,
+    offset: u64,
+    size: u64,
+    cancel: &dyn Fn() -> bool,
+) -> Result<Vec<u8>, Error> {
+    let mut bytes = vec![];
+    let end = offset.saturating_add(size);
+    let mut off = offset;
+    while off < end {
+        if off - offset == CHUNK as u64 && cancel() {
+            return Err(Error::Interrupted);
+        }
+        let len = min(CHUNK as u64, end - off) as u32;
+        let chunk = fio.read_file(fi, off, len)?;
+        let got = chunk.len() as u32;
+        bytes.extend(chunk);
+        if got < len {
+            break;
+        }
+        off += got as u64;
     }
+    Ok(bytes)
 }
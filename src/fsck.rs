@@ -0,0 +1,635 @@
+// References:
+// [1] https://download.microsoft.com/download/1/6/1/161ba512-40e2-4cc9-843a-923143f3456c/fatgen103.doc
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    fmt,
+    fs::File,
+    io,
+    path::PathBuf,
+};
+
+use clap::ValueEnum;
+
+use crate::clusters::{Claim, Owners};
+use crate::fat32::fio::{free_in, Fio};
+use crate::fat32::spec::{ClusNo, DirEnt, DirEntSfn, FsInfo};
+use crate::journal::Writes;
+
+const ROOT_ID: u64 = 1;
+pub const ENT_MASK: u32 = 0x0FFFFFFF; // the upper 4 bits are reserved, refer to [1]
+pub const EOC: u32 = 0x0FFFFFFF;
+const BAD: u32 = 0x0FFFFFF7;
+const PREVIEW_LEN: usize = 32;
+
+#[derive(Debug)]
+pub enum Problem {
+    // the chain holds more clusters than the file size needs
+    ChainTooLong {
+        id: u64,
+        path: String,
+        chain: Vec<ClusNo>,
+        needed: u32,
+    },
+    // the file size claims more data than the chain holds
+    ChainTooShort {
+        id: u64,
+        path: String,
+        size: u64,
+        clusters: u32,
+    },
+    // the chain runs into a free, reserved, bad or out-of-range entry
+    Unterminated {
+        path: String,
+        last: ClusNo,
+        next: u32,
+    },
+    // the chain points back into itself
+    Looped {
+        path: String,
+        last: ClusNo,
+        to: ClusNo,
+    },
+    BadFirstCluster {
+        id: u64,
+        path: String,
+        clus: ClusNo,
+    },
+    // the cluster is already part of another file's chain
+    CrossLinked {
+        path: String,
+        clus: ClusNo,
+        other: String,
+    },
+    // an allocated chain no entry leads to
+    Orphaned {
+        chain: Vec<ClusNo>,
+        bytes: u64,
+        preview: String,
+    },
+    FatMismatch {
+        copy: u8,
+        entries: usize,
+    },
+    FreeCountMismatch {
+        recorded: u32,
+        actual: u32,
+    },
+    // more than one entry of the dir goes by the name, all but the first
+    // are shown with a ~N suffix
+    DuplicateName {
+        dir: String,
+        name: String,
+    },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::ChainTooLong {
+                path,
+                chain,
+                needed,
+                ..
+            } => write!(
+                f,
+                "{path}: chain has {} clusters, the size needs {needed}",
+                chain.len()
+            ),
+            Problem::ChainTooShort {
+                path,
+                size,
+                clusters,
+                ..
+            } => write!(f, "{path}: size {size} exceeds its {clusters} clusters"),
+            Problem::Unterminated { path, last, next } => write!(
+                f,
+                "{path}: chain breaks after cluster {last} (entry 0x{next:08X})"
+            ),
+            Problem::Looped { path, last, to } => {
+                write!(f, "{path}: chain loops from cluster {last} back to {to}")
+            }
+            Problem::BadFirstCluster { path, clus, .. } => {
+                write!(f, "{path}: invalid first cluster {clus}")
+            }
+            Problem::CrossLinked { path, clus, other } => {
+                write!(f, "{path}: cluster {clus} is cross-linked with {other}")
+            }
+            Problem::Orphaned {
+                chain,
+                bytes,
+                preview,
+            } => write!(
+                f,
+                "orphaned chain at cluster {}: {} clusters, {bytes} bytes \"{preview}\"",
+                chain[0],
+                chain.len()
+            ),
+            Problem::FatMismatch { copy, entries } => {
+                write!(f, "FAT copy {copy} differs from FAT 0 in {entries} entries")
+            }
+            Problem::FreeCountMismatch { recorded, actual } => write!(
+                f,
+                "FSInfo free count is {recorded}, the FAT has {actual} free clusters"
+            ),
+            Problem::DuplicateName { dir, name } => {
+                write!(f, "{dir}: more than one entry is named {name}")
+            }
+        }
+    }
+}
+
+pub struct Fsck<'f, 'a> {
+    fio: &'f mut Fio<'a>,
+    fat: Vec<u32>, // raw entries of FAT 0
+    // the file or dir each cluster belongs to, none when unreachable
+    pub owners: Owners,
+    pub problems: Vec<Problem>,
+}
+
+impl<'f, 'a> Fsck<'f, 'a> {
+    pub fn new(fio: &'f mut Fio<'a>) -> io::Result<Self> {
+        let fat = fio.read_fat_copy(0)?;
+        let max_clus = (fio.clus_cnt() + 1).min(fat.len() as u32 - 1);
+        Ok(Fsck {
+            fio,
+            fat,
+            owners: Owners::new(max_clus as usize + 1),
+            problems: vec![],
+        })
+    }
+
+    fn max_clus(&self) -> ClusNo {
+        self.owners.len() as ClusNo - 1
+    }
+
+    pub fn check(&mut self) -> io::Result<()> {
+        self.walk()?;
+        self.check_orphans()?;
+        self.check_fat_copies()?;
+        self.check_fsinfo();
+        Ok(())
+    }
+
+    // the tree from the root, each chain followed claims its clusters
+    pub fn walk(&mut self) -> io::Result<()> {
+        let root = self.fio.root_clusno;
+        self.check_dir(ROOT_ID, "", root)
+    }
+
+    fn check_dir(&mut self, id: u64, path: &str, first: ClusNo) -> io::Result<()> {
+        let chain = match self.follow(id, path, first) {
+            Some(chain) => chain,
+            None => return Ok(()),
+        };
+        let mut names = BTreeSet::new();
+        for fi in self.fio.read_dirents_in(&chain)? {
+            if fi.name == "." || fi.name == ".." {
+                continue;
+            }
+            if !names.insert(fi.name.clone()) {
+                self.problems.push(Problem::DuplicateName {
+                    dir: if path.is_empty() { "/" } else { path }.to_owned(),
+                    name: fi.name.clone(),
+                });
+            }
+            let fpath = format!("{}/{}", path, fi.name);
+            if fi.is_dir {
+                if fi.fst_clus != 0 {
+                    self.check_dir(fi.id, &fpath, fi.fst_clus)?;
+                }
+                continue;
+            }
+
+            let clus_sz = self.fio.clus_sz() as u64;
+            let needed = fi.size.div_ceil(clus_sz) as u32;
+            let chain = if fi.fst_clus == 0 {
+                vec![]
+            } else {
+                match self.follow(fi.id, &fpath, fi.fst_clus) {
+                    Some(chain) => chain,
+                    None => continue,
+                }
+            };
+            if chain.len() as u32 > needed {
+                self.problems.push(Problem::ChainTooLong {
+                    id: fi.id,
+                    path: fpath,
+                    chain,
+                    needed,
+                });
+            } else if (chain.len() as u32) < needed {
+                self.problems.push(Problem::ChainTooShort {
+                    id: fi.id,
+                    path: fpath,
+                    size: fi.size,
+                    clusters: chain.len() as u32,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    // walk a chain over the in-memory FAT, claiming its clusters for `id`.
+    // return None when it cannot be trusted at all (bad start or cross-linked)
+    fn follow(&mut self, id: u64, path: &str, first: ClusNo) -> Option<Vec<ClusNo>> {
+        let path = if path.is_empty() { "/" } else { path };
+        if !(2..=self.max_clus()).contains(&first) {
+            self.problems.push(Problem::BadFirstCluster {
+                id,
+                path: path.to_owned(),
+                clus: first,
+            });
+            return None;
+        }
+        let who = self.owners.add(path);
+        let mut chain = vec![];
+        let mut clus = first;
+        loop {
+            match self.owners.claim(clus, who) {
+                Claim::Claimed => (),
+                Claim::Looped => {
+                    self.problems.push(Problem::Looped {
+                        path: path.to_owned(),
+                        last: *chain.last().unwrap(),
+                        to: clus,
+                    });
+                    break;
+                }
+                Claim::CrossLinked(other) => {
+                    let other = other.to_owned();
+                    self.problems.push(Problem::CrossLinked {
+                        path: path.to_owned(),
+                        clus,
+                        other,
+                    });
+                    return None;
+                }
+            }
+            chain.push(clus);
+
+            let next = self.fat[clus as usize] & ENT_MASK;
+            if next >= 0x0FFFFFF8 {
+                break;
+            } else if (2..=self.max_clus()).contains(&next) {
+                clus = next;
+            } else {
+                self.problems.push(Problem::Unterminated {
+                    path: path.to_owned(),
+                    last: clus,
+                    next,
+                });
+                break;
+            }
+        }
+        Some(chain)
+    }
+
+    // group the allocated but unreachable clusters into chains. a chain starts
+    // at a cluster no other orphan points to, what is left over are loops
+    fn check_orphans(&mut self) -> io::Result<()> {
+        let orphans: BTreeSet<ClusNo> = (2..=self.max_clus())
+            .filter(|&c| {
+                let ent = self.fat[c as usize] & ENT_MASK;
+                ent != 0 && ent != BAD && !self.owners.is_claimed(c)
+            })
+            .collect();
+        let pointed: HashSet<ClusNo> = orphans
+            .iter()
+            .map(|&c| self.fat[c as usize] & ENT_MASK)
+            .filter(|next| orphans.contains(next))
+            .collect();
+        let heads: Vec<ClusNo> = orphans
+            .iter()
+            .copied()
+            .filter(|c| !pointed.contains(c))
+            .collect();
+
+        let mut seen = HashSet::new();
+        let mut chains = vec![];
+        for head in heads.into_iter().chain(orphans.iter().copied()) {
+            let mut chain = vec![];
+            let mut clus = head;
+            while orphans.contains(&clus) && seen.insert(clus) {
+                chain.push(clus);
+                clus = self.fat[clus as usize] & ENT_MASK;
+            }
+            if !chain.is_empty() {
+                chains.push(chain);
+            }
+        }
+
+        let clus_sz = self.fio.clus_sz() as u64;
+        for chain in chains {
+            let head = self.fio.read_clus(chain[0])?;
+            let preview = head
+                .iter()
+                .take(PREVIEW_LEN)
+                .map(|&b| {
+                    if b.is_ascii_graphic() || b == b' ' {
+                        b as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            self.problems.push(Problem::Orphaned {
+                bytes: chain.len() as u64 * clus_sz,
+                chain,
+                preview,
+            });
+        }
+        Ok(())
+    }
+
+    fn check_fat_copies(&mut self) -> io::Result<()> {
+        for copy in 1..self.fio.bootsec.bpb_num_fats {
+            let other = self.fio.read_fat_copy(copy)?;
+            let entries = (0..=self.max_clus() as usize)
+                .filter(|&i| self.fat[i] & ENT_MASK != other[i] & ENT_MASK)
+                .count();
+            if entries != 0 {
+                self.problems.push(Problem::FatMismatch { copy, entries });
+            }
+        }
+        Ok(())
+    }
+
+    fn check_fsinfo(&mut self) {
+        let Some(fsinfo) = self.fio.fsinfo() else {
+            return;
+        };
+        let actual = free_in(&self.fat, self.max_clus());
+        if fsinfo.free_count != 0xFFFFFFFF && fsinfo.free_count != actual {
+            self.problems.push(Problem::FreeCountMismatch {
+                recorded: fsinfo.free_count,
+                actual,
+            });
+        }
+    }
+
+    // fix what can be fixed without guessing: chains are cut to the file sizes
+    // (or sizes shrunk to the chains), broken chains get terminated, orphans are
+    // freed (or recovered) and every FAT copy is rewritten from the repaired FAT 0
+    pub fn repair(&mut self, opts: &RepairOpts) -> io::Result<usize> {
+        self.fio.writable()?;
+        let mut fat = self.fat.clone();
+        // (first cluster, size) overrides for short entries
+        let mut dirents: BTreeMap<u64, (Option<ClusNo>, Option<u32>)> = BTreeMap::new();
+        let mut orphans = vec![];
+        let clus_sz = self.fio.clus_sz();
+
+        for problem in self.problems.iter() {
+            match problem {
+                Problem::ChainTooLong {
+                    id, chain, needed, ..
+                } => {
+                    let needed = *needed as usize;
+                    if needed == 0 {
+                        dirents.entry(*id).or_default().0 = Some(0);
+                    } else {
+                        set_ent(&mut fat, chain[needed - 1], EOC);
+                    }
+                    for &clus in chain[needed..].iter() {
+                        set_ent(&mut fat, clus, 0);
+                    }
+                }
+                Problem::ChainTooShort { id, clusters, .. } => {
+                    dirents.entry(*id).or_default().1 = Some(clusters * clus_sz);
+                }
+                Problem::Unterminated { last, .. } | Problem::Looped { last, .. } => {
+                    set_ent(&mut fat, *last, EOC);
+                }
+                Problem::BadFirstCluster { id, .. } => {
+                    dirents.insert(*id, (Some(0), Some(0)));
+                }
+                Problem::Orphaned { chain, .. } => {
+                    if opts.recover_orphans {
+                        set_ent(&mut fat, *chain.last().unwrap(), EOC);
+                        orphans.push(chain.clone());
+                    } else {
+                        for &clus in chain.iter() {
+                            set_ent(&mut fat, clus, 0);
+                        }
+                    }
+                }
+                Problem::CrossLinked { path, .. } => {
+                    say!("[fsck] {path}: cross-linked chains are left alone");
+                }
+                Problem::DuplicateName { dir, name } => {
+                    say!("[fsck] {dir}: {name}, duplicate names are left alone");
+                }
+                Problem::FatMismatch { .. } | Problem::FreeCountMismatch { .. } => (),
+            }
+        }
+
+        let patches = if orphans.is_empty() {
+            vec![]
+        } else {
+            self.recover_orphans(&mut fat, &orphans)?
+        };
+
+        let mut writes = Writes::new(self.fio.device(), self.fio.bootsec.bpb_byts_per_sec as u64);
+        for (off, bytes) in patches {
+            writes.patch(off, &bytes)?;
+        }
+
+        for (id, (fst_clus, size)) in dirents {
+            let off = self.fio.dirent_offset(id);
+            if let Some(clus) = fst_clus {
+                writes.patch(off + 20, &((clus >> 16) as u16).to_le_bytes())?;
+                writes.patch(off + 26, &(clus as u16).to_le_bytes())?;
+            }
+            if let Some(size) = size {
+                writes.patch(off + 28, &size.to_le_bytes())?;
+            }
+        }
+
+        // every FAT copy is synced to the repaired FAT 0
+        sync_fats(&mut writes, self.fio, &self.fat, &fat)?;
+
+        if let Some(fsinfo_off) = self.fio.fsinfo_offset() {
+            if FsInfo::new(&writes.read_sector(fsinfo_off)?).is_ok_and(|fsinfo| fsinfo.is_valid()) {
+                let free = free_in(&fat, self.max_clus());
+                writes.patch(
+                    fsinfo_off + FsInfo::FREE_COUNT_OFF as u64,
+                    &free.to_le_bytes(),
+                )?;
+            }
+        }
+
+        let n = writes.commit(&mut File::create(&opts.backup)?)?;
+        self.fat = fat;
+        Ok(n)
+    }
+
+    // attach every orphaned chain as FILEnnnn.CHK under a new /FOUND.nnn dir,
+    // returning the (offset, bytes) patches for the dir and its root entry
+    fn recover_orphans(
+        &mut self,
+        fat: &mut [u32],
+        orphans: &[Vec<ClusNo>],
+    ) -> io::Result<Vec<(u64, Vec<u8>)>> {
+        let clus_sz = self.fio.clus_sz() as usize;
+        let root_chain = self.chain_of(fat, self.fio.root_clusno);
+        let root = self.fio.read_dirents_in(&root_chain)?;
+        let found = (0..1000)
+            .map(|n| format!("FOUND.{:03}", n))
+            .find(|name| !root.iter().any(|fi| fi.name.eq_ignore_ascii_case(name)))
+            .ok_or_else(|| io::Error::other("no FOUND.nnn name left"))?;
+
+        let mut dir = vec![];
+        dir.extend(DirEntSfn::encode(b".          ", true, 0, 0));
+        dir.extend(DirEntSfn::encode(b"..         ", true, 0, 0));
+        for (i, chain) in orphans.iter().enumerate() {
+            let name = format!("FILE{:04}CHK", i);
+            let size = (chain.len() * clus_sz).min(u32::MAX as usize) as u32;
+            dir.extend(DirEntSfn::encode(
+                name.as_bytes().try_into().unwrap(),
+                false,
+                chain[0],
+                size,
+            ));
+        }
+        let dir_chain = self.alloc(fat, dir.len().div_ceil(clus_sz) as u32)?;
+        dir[20..22].copy_from_slice(&((dir_chain[0] >> 16) as u16).to_le_bytes());
+        dir[26..28].copy_from_slice(&(dir_chain[0] as u16).to_le_bytes());
+        dir.resize(dir_chain.len() * clus_sz, 0);
+
+        let mut patches: Vec<(u64, Vec<u8>)> = dir_chain
+            .iter()
+            .zip(dir.chunks(clus_sz))
+            .map(|(&clus, data)| (self.fio.clus_offset(clus), data.to_vec()))
+            .collect();
+
+        let mut slot = None;
+        for &clus in root_chain.iter() {
+            let data = self.fio.read_clus(clus)?;
+            slot = data
+                .chunks(DirEnt::SZ as usize)
+                .position(|ent| ent[0] == 0x00 || ent[0] == 0xE5)
+                .map(|i| self.fio.clus_offset(clus) + i as u64 * DirEnt::SZ as u64);
+            if slot.is_some() {
+                break;
+            }
+        }
+        let slot = match slot {
+            Some(slot) => slot,
+            None => {
+                // the root dir is full, grow it by one cluster
+                let clus = self.alloc(fat, 1)?[0];
+                set_ent(fat, *root_chain.last().unwrap(), clus);
+                let off = self.fio.clus_offset(clus);
+                patches.push((off, vec![0u8; clus_sz]));
+                off
+            }
+        };
+        let name = format!("FOUND   {}", &found[6..]);
+        patches.push((
+            slot,
+            DirEntSfn::encode(name.as_bytes().try_into().unwrap(), true, dir_chain[0], 0).to_vec(),
+        ));
+        say!(
+            "[fsck] {} orphaned chains recovered under /{}",
+            orphans.len(),
+            found
+        );
+        Ok(patches)
+    }
+
+    fn chain_of(&self, fat: &[u32], first: ClusNo) -> Vec<ClusNo> {
+        let mut chain = vec![];
+        let mut clus = first;
+        while (2..=self.max_clus()).contains(&clus) && chain.len() <= self.max_clus() as usize {
+            chain.push(clus);
+            clus = fat[clus as usize] & ENT_MASK;
+        }
+        chain
+    }
+
+    // first-fit allocation of `cnt` free clusters, linked into a terminated chain
+    fn alloc(&self, fat: &mut [u32], cnt: u32) -> io::Result<Vec<ClusNo>> {
+        let chain: Vec<ClusNo> = (2..=self.max_clus())
+            .filter(|&c| fat[c as usize] & ENT_MASK == 0)
+            .take(cnt as usize)
+            .collect();
+        if chain.len() < cnt as usize {
+            return Err(io::Error::other("no free clusters left"));
+        }
+        for pair in chain.windows(2) {
+            set_ent(fat, pair[0], pair[1]);
+        }
+        set_ent(fat, *chain.last().unwrap(), EOC);
+        Ok(chain)
+    }
+}
+
+pub struct RepairOpts {
+    // the original content of each sector is saved here before it is overwritten
+    pub backup: PathBuf,
+    // attach orphaned chains under /FOUND.nnn instead of freeing them
+    pub recover_orphans: bool,
+}
+
+pub fn set_ent(fat: &mut [u32], clus: ClusNo, val: u32) {
+    let ent = &mut fat[clus as usize];
+    *ent = (*ent & !ENT_MASK) | val;
+}
+
+// rewrite every FAT copy from `new` wherever a sector differs, `old` being the
+// current content of FAT 0
+pub fn sync_fats(writes: &mut Writes, fio: &Fio, old: &[u32], new: &[u32]) -> io::Result<()> {
+    let sec_sz = fio.bootsec.bpb_byts_per_sec as usize;
+    let new_bytes: Vec<u8> = new.iter().flat_map(|ent| ent.to_le_bytes()).collect();
+    for copy in 0..fio.bootsec.bpb_num_fats {
+        let base = fio.fat_copy_offset(copy);
+        let old = if copy == 0 {
+            old.to_vec()
+        } else {
+            fio.read_fat_copy(copy)?
+        };
+        let old_bytes: Vec<u8> = old.iter().flat_map(|ent| ent.to_le_bytes()).collect();
+        for (i, (new, old)) in new_bytes
+            .chunks(sec_sz)
+            .zip(old_bytes.chunks(sec_sz))
+            .enumerate()
+        {
+            if new != old {
+                writes.patch(base + (i * sec_sz) as u64, new)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// what a mount with --verify does when problems are found
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum OnBad {
+    // don't mount
+    Refuse,
+    // mount anyway, after listing them
+    Warn,
+}
+
+// the problems fsck finds on a FAT32 or exFAT volume, none for other types.
+// only mounts verify
+#[cfg(all(unix, feature = "fuse"))]
+pub fn verify(device: crate::device::Image) -> io::Result<Option<Vec<String>>> {
+    use crate::{disk, exfat, fsck_exfat};
+
+    match disk::detect(&device, 0) {
+        Some("FAT32") => {
+            let mut fio = Fio::new(device)?;
+            let mut fsck = Fsck::new(&mut fio)?;
+            fsck.check()?;
+            Ok(Some(fsck.problems.iter().map(|p| p.to_string()).collect()))
+        }
+        Some("exFAT") => {
+            let mut fio = exfat::Fio::new(device)?;
+            let mut fsck = fsck_exfat::Fsck::new(&mut fio)?;
+            fsck.check()?;
+            Ok(Some(fsck.problems.iter().map(|p| p.to_string()).collect()))
+        }
+        _ => Ok(None),
+    }
+}
@@ -0,0 +1,428 @@
+use std::{collections::BTreeSet, fmt, io};
+
+use crate::clusters::{Claim, Owners};
+use crate::device::Device;
+use crate::exfat::spec::{self, dirent::DirEnt};
+use crate::exfat::Fio;
+use crate::fio::Fio as _;
+
+const EOC: u32 = 0xFFFFFFFF;
+
+#[derive(Debug)]
+pub enum Problem {
+    // VolumeFlags says the last writer didn't finish
+    VolumeDirty,
+    // VolumeFlags says the media has failed before
+    MediaFailure,
+    PercentInUseMismatch {
+        recorded: u8,
+        actual: u8,
+    },
+    // none recorded when the copies in the checksum sector disagree
+    BootChecksumMismatch {
+        recorded: Option<u32>,
+        computed: u32,
+    },
+    Upcase(String),
+    SetChecksumMismatch {
+        path: String,
+        recorded: u16,
+        computed: u16,
+    },
+    // the NameHash of the stream extension isn't the one of the name, a
+    // lookup by the hash (as Windows does) misses the file
+    NameHashMismatch {
+        path: String,
+        recorded: u16,
+        computed: u16,
+    },
+    BadFirstCluster {
+        path: String,
+        clus: u32,
+    },
+    // the chain runs into a free, bad, reserved or out-of-range entry
+    Unterminated {
+        path: String,
+        last: u32,
+        next: u32,
+    },
+    Looped {
+        path: String,
+        last: u32,
+        to: u32,
+    },
+    CrossLinked {
+        path: String,
+        clus: u32,
+        other: String,
+    },
+    ChainTooLong {
+        path: String,
+        clusters: u32,
+        needed: u32,
+    },
+    ChainTooShort {
+        path: String,
+        size: u64,
+        clusters: u32,
+    },
+    // clusters of the file the bitmap has free
+    NotAllocated {
+        path: String,
+        clusters: u32,
+    },
+    // clusters the bitmap has allocated that no file holds
+    Leaked {
+        clusters: u32,
+    },
+    // more than one entry of the dir goes by the name, all but the first
+    // are shown with a ~N suffix
+    DuplicateName {
+        dir: String,
+        name: String,
+    },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::VolumeDirty => write!(f, "the volume is marked dirty"),
+            Problem::MediaFailure => write!(f, "the volume is marked as having had media failures"),
+            Problem::PercentInUseMismatch { recorded, actual } => write!(
+                f,
+                "PercentInUse is {recorded}%, the bitmap has {actual}% allocated"
+            ),
+            Problem::BootChecksumMismatch {
+                recorded: Some(recorded),
+                computed,
+            } => write!(
+                f,
+                "boot checksum is {recorded:#010x}, the boot region sums to {computed:#010x}"
+            ),
+            Problem::BootChecksumMismatch { recorded: None, .. } => {
+                write!(f, "the copies of the boot checksum disagree")
+            }
+            Problem::Upcase(e) => write!(f, "up-case table: {e}"),
+            Problem::SetChecksumMismatch {
+                path,
+                recorded,
+                computed,
+            } => write!(
+                f,
+                "{path}: set checksum is {recorded:#06x}, the entries sum to {computed:#06x}"
+            ),
+            Problem::NameHashMismatch {
+                path,
+                recorded,
+                computed,
+            } => write!(
+                f,
+                "{path}: name hash is {recorded:#06x}, the name hashes to {computed:#06x}"
+            ),
+            Problem::BadFirstCluster { path, clus } => {
+                write!(f, "{path}: invalid first cluster {clus}")
+            }
+            Problem::Unterminated { path, last, next } => write!(
+                f,
+                "{path}: chain breaks after cluster {last} (entry 0x{next:08X})"
+            ),
+            Problem::Looped { path, last, to } => {
+                write!(f, "{path}: chain loops from cluster {last} back to {to}")
+            }
+            Problem::CrossLinked { path, clus, other } => {
+                write!(f, "{path}: cluster {clus} is cross-linked with {other}")
+            }
+            Problem::ChainTooLong {
+                path,
+                clusters,
+                needed,
+            } => write!(
+                f,
+                "{path}: chain has {clusters} clusters, the size needs {needed}"
+            ),
+            Problem::ChainTooShort {
+                path,
+                size,
+                clusters,
+            } => write!(f, "{path}: size {size} exceeds its {clusters} clusters"),
+            Problem::NotAllocated { path, clusters } => {
+                write!(f, "{path}: {clusters} clusters are free in the bitmap")
+            }
+            Problem::Leaked { clusters } => write!(
+                f,
+                "{clusters} clusters are allocated in the bitmap but in no file"
+            ),
+            Problem::DuplicateName { dir, name } => {
+                write!(f, "{dir}: more than one entry is named {name}")
+            }
+        }
+    }
+}
+
+pub struct Fsck<'f, D: Device> {
+    fio: &'f mut Fio<D>,
+    fat: Vec<u32>,
+    bitmap: Vec<u8>,
+    // the file or dir each cluster belongs to
+    pub owners: Owners,
+    pub problems: Vec<Problem>,
+}
+
+impl<'f, D: Device> Fsck<'f, D> {
+    pub fn new(fio: &'f mut Fio<D>) -> io::Result<Self> {
+        let fat = fio.read_fat_table()?;
+        let bitmap = fio.read_bitmap()?;
+        let owners = Owners::new(fio.clus_cnt() as usize + 2);
+        Ok(Fsck {
+            fio,
+            fat,
+            bitmap,
+            owners,
+            problems: vec![],
+        })
+    }
+
+    pub fn check(&mut self) -> io::Result<()> {
+        self.check_boot_sector()?;
+        self.check_boot_checksum()?;
+        if let Err(e) = self.fio.check_upcase() {
+            self.problems.push(Problem::Upcase(e));
+        }
+        self.walk()?;
+        self.check_leaked();
+        Ok(())
+    }
+
+    fn check_boot_sector(&mut self) -> io::Result<()> {
+        let b = &self.fio.bootsec;
+        if b.is_dirty() {
+            self.problems.push(Problem::VolumeDirty);
+        }
+        if b.media_failure() {
+            self.problems.push(Problem::MediaFailure);
+        }
+        let recorded = b.percent_in_use();
+        let actual = self.fio.percent_allocated()?;
+        say!(
+            "[fsck] {}% of the clusters allocated, {} recorded",
+            actual,
+            recorded.map_or("none".to_string(), |p| format!("{}%", p))
+        );
+        if let Some(recorded) = recorded.filter(|&p| p != actual) {
+            self.problems
+                .push(Problem::PercentInUseMismatch { recorded, actual });
+        }
+        Ok(())
+    }
+
+    // the main boot region, sectors 0 to 10 summed against sector 11
+    fn check_boot_checksum(&mut self) -> io::Result<()> {
+        let sec_sz = self.fio.bootsec.bytes_per_sec();
+        let mut bytes = vec![];
+        for no in 0..12 {
+            bytes.extend(self.fio.read_sec(no)?);
+        }
+        let computed = spec::boot_checksum(&bytes, sec_sz as u16);
+        let mut sums = bytes[11 * sec_sz as usize..]
+            .chunks_exact(4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+        let first = sums.next().unwrap();
+        let recorded = sums.all(|sum| sum == first).then_some(first);
+        if recorded != Some(computed) {
+            self.problems
+                .push(Problem::BootChecksumMismatch { recorded, computed });
+        }
+        Ok(())
+    }
+
+    // the tree from the root and the bitmap and up-case table it lists,
+    // each chain followed claims its clusters
+    pub fn walk(&mut self) -> io::Result<()> {
+        let root = self.fio.bootsec.first_cluster_of_root_dir;
+        let Some(chain) = self.follow("/", root, None) else {
+            return Ok(());
+        };
+        let clus_sz = self.fio.clus_sz() as u64;
+        for ent in self.fio.read_dirents(root)? {
+            let (name, first, len) = match ent {
+                DirEnt::AllocBitmap(ent) => ("bitmap", ent.first_cluster, ent.data_length),
+                DirEnt::UpcaseTable(ent) => ("up-case table", ent.first_cluster, ent.data_length),
+                _ => continue,
+            };
+            let path = format!("/<{}>", name);
+            if let Some(chain) = self.follow(&path, first, None) {
+                self.check_len(&path, len, chain.len() as u32, clus_sz);
+            }
+        }
+        self.check_dir("", chain[0])
+    }
+
+    fn check_dir(&mut self, path: &str, first: u32) -> io::Result<()> {
+        let clus_sz = self.fio.clus_sz() as u64;
+        // the raw listing, list_dir tells repeated names apart
+        let mut names = BTreeSet::new();
+        for fi in self.fio.list_dir_page(first, 0, usize::MAX)?.0 {
+            if !names.insert(fi.name.clone()) {
+                self.problems.push(Problem::DuplicateName {
+                    dir: if path.is_empty() { "/" } else { path }.to_owned(),
+                    name: fi.name.clone(),
+                });
+            }
+            let fpath = format!("{}/{}", path, fi.name);
+            match self.fio.entset_bytes(fi.id) {
+                Ok(bytes) => {
+                    let recorded = u16::from_le_bytes([bytes[2], bytes[3]]);
+                    let computed = spec::entset_checksum(&bytes, bytes[1]);
+                    if recorded != computed {
+                        self.problems.push(Problem::SetChecksumMismatch {
+                            path: fpath.clone(),
+                            recorded,
+                            computed,
+                        });
+                    }
+                    self.check_name_hash(&fpath, &bytes);
+                }
+                Err(e) => eprintln!("[fsck] {}: {}", fpath, e),
+            }
+            if fi.fst_clus == 0 {
+                continue;
+            }
+            let contiguous = fi.no_fat_chain.then(|| fi.size.div_ceil(clus_sz) as u32);
+            let Some(chain) = self.follow(&fpath, fi.fst_clus, contiguous) else {
+                continue;
+            };
+            let whole = self.check_len(&fpath, fi.size, chain.len() as u32, clus_sz);
+            // a dir is only listed when its chain holds together, one with
+            // no FAT chain is listed by its run of clusters
+            if fi.is_dir && whole && !chain.is_empty() {
+                self.check_dir(&fpath, fi.fst_clus)?;
+            }
+        }
+        Ok(())
+    }
+
+    // the hash recomputed over NameLength characters of the FileName
+    // entries, up-cased by the volume's table
+    fn check_name_hash(&mut self, path: &str, set: &[u8]) {
+        let Some(stream) = set.get(DirEnt::SZ..2 * DirEnt::SZ) else {
+            return;
+        };
+        let recorded = u16::from_le_bytes([stream[4], stream[5]]);
+        let units: Vec<u16> = set[2 * DirEnt::SZ..]
+            .chunks_exact(DirEnt::SZ)
+            .flat_map(|ent| ent[2..].chunks_exact(2))
+            .map(|b| u16::from_le_bytes([b[0], b[1]]))
+            .take(stream[3] as usize)
+            .collect();
+        let computed = self.fio.upcase_table().name_hash(&units);
+        if recorded != computed {
+            self.problems.push(Problem::NameHashMismatch {
+                path: path.to_owned(),
+                recorded,
+                computed,
+            });
+        }
+    }
+
+    // whether the chain is as long as `size` needs
+    fn check_len(&mut self, path: &str, size: u64, clusters: u32, clus_sz: u64) -> bool {
+        let needed = size.div_ceil(clus_sz) as u32;
+        if clusters > needed {
+            self.problems.push(Problem::ChainTooLong {
+                path: path.to_owned(),
+                clusters,
+                needed,
+            });
+        } else if clusters < needed {
+            self.problems.push(Problem::ChainTooShort {
+                path: path.to_owned(),
+                size,
+                clusters,
+            });
+        }
+        clusters == needed
+    }
+
+    // the clusters from `first` on, over the FAT or `contiguous` of them,
+    // claimed for `path`. none when it starts out of the volume or runs
+    // into another file
+    fn follow(&mut self, path: &str, first: u32, contiguous: Option<u32>) -> Option<Vec<u32>> {
+        let last_clus = self.owners.len() as u32 - 1;
+        if !(2..=last_clus).contains(&first) {
+            self.problems.push(Problem::BadFirstCluster {
+                path: path.to_owned(),
+                clus: first,
+            });
+            return None;
+        }
+        let me = self.owners.add(path);
+        let (mut chain, mut free) = (vec![], 0);
+        let mut clus = first;
+        loop {
+            if contiguous == Some(chain.len() as u32) {
+                break;
+            }
+            match self.owners.claim(clus, me) {
+                Claim::Claimed => (),
+                Claim::Looped => {
+                    self.problems.push(Problem::Looped {
+                        path: path.to_owned(),
+                        last: *chain.last().unwrap(),
+                        to: clus,
+                    });
+                    break;
+                }
+                Claim::CrossLinked(other) => {
+                    let other = other.to_owned();
+                    self.problems.push(Problem::CrossLinked {
+                        path: path.to_owned(),
+                        clus,
+                        other,
+                    });
+                    return None;
+                }
+            }
+            chain.push(clus);
+            if !self.allocated(clus) {
+                free += 1;
+            }
+
+            let next = match contiguous {
+                Some(cnt) if chain.len() as u32 == cnt => break,
+                Some(_) => clus + 1,
+                None if self.fat[clus as usize] == EOC => break,
+                None => self.fat[clus as usize],
+            };
+            if !(2..=last_clus).contains(&next) {
+                self.problems.push(Problem::Unterminated {
+                    path: path.to_owned(),
+                    last: clus,
+                    next,
+                });
+                break;
+            }
+            clus = next;
+        }
+        if free > 0 {
+            self.problems.push(Problem::NotAllocated {
+                path: path.to_owned(),
+                clusters: free,
+            });
+        }
+        Some(chain)
+    }
+
+    fn allocated(&self, clus: u32) -> bool {
+        let no = (clus - 2) as usize;
+        self.bitmap[no / 8] & (1 << (no % 8)) != 0
+    }
+
+    fn check_leaked(&mut self) {
+        let clusters = (2..self.owners.len() as u32)
+            .filter(|&clus| !self.owners.is_claimed(clus) && self.allocated(clus))
+            .count() as u32;
+        if clusters > 0 {
+            self.problems.push(Problem::Leaked { clusters });
+        }
+    }
+}
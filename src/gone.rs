@@ -0,0 +1,277 @@
+// a device pulled out from under a mount, a USB reader yanked. once a read
+// fails with ENODEV or ENXIO, or the device's path is no more, the mount is
+// taken for gone: every operation fails with ENODEV, and that's said once.
+// other failed reads, a bad sector's EIO, only fail the read they're in.
+// with set_reconnect, a device that shows up again by the same path with
+// the same first sector and allocation tables is opened again and served on
+
+use std::{
+    cell::{Cell, RefCell},
+    io,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::device::Device;
+use crate::fs;
+use crate::{disk, exfat, fat32};
+
+// how often a gone device is looked for again
+const REOPEN_EVERY: Duration = Duration::from_secs(1);
+// the allocation tables are hashed in reads this big
+const CHUNK_SZ: u64 = 1 << 20;
+
+// whether gone devices are opened again, see set_reconnect
+static RECONNECT: AtomicBool = AtomicBool::new(false);
+
+// mounts from here on wait for their device to come back instead of
+// staying gone
+pub fn set_reconnect() {
+    RECONNECT.store(true, Ordering::Relaxed);
+}
+
+fn reconnecting() -> bool {
+    RECONNECT.load(Ordering::Relaxed)
+}
+
+// what tells the volume apart when it comes back: the first sector, less
+// the exFAT fields that change while it's in use elsewhere, which the boot
+// checksum leaves out too, then a hash of the allocation tables. a volume
+// written to while it was away has other tables, and what the mount has
+// listed and handed out of it is stale
+fn identity(dev: &dyn Device) -> io::Result<Vec<u8>> {
+    let mut sec = vec![0u8; 512];
+    dev.read_exact_at(&mut sec, 0)?;
+    if &sec[3..11] == b"EXFAT   " {
+        sec[106..108].fill(0);
+        sec[112] = 0;
+    }
+    Ok(sec)
+}
+
+// the FAT, and the bitmap of an exFAT volume. the FAT's first two entries
+// are left out, they carry the dirty bits
+fn tables(dev: &dyn Device) -> io::Result<Vec<u8>> {
+    let mut sec = [0u8; 512];
+    dev.read_exact_at(&mut sec, 0)?;
+    let mut hasher = Sha256::new();
+    let (start, len) = match disk::detect(dev, 0) {
+        Some("FAT32") => {
+            let b = fat32::spec::BootSec::new(&mut sec).map_err(io::Error::other)?;
+            let sec_sz = b.bpb_byts_per_sec as u64;
+            (
+                b.fat_start_sector() as u64 * sec_sz,
+                b.bpb_fat_sz_32 as u64 * sec_sz,
+            )
+        }
+        Some("exFAT") => {
+            let b = exfat::spec::BootSec::new(&sec).map_err(io::Error::other)?;
+            let shift = b.bytes_per_sector_shift;
+            hasher.update(exfat::Fio::new(dev)?.read_bitmap()?);
+            (
+                (b.fat_offset as u64) << shift,
+                (b.fat_length as u64) << shift,
+            )
+        }
+        _ => (0, 0),
+    };
+    let mut at = 8.min(len);
+    while at < len {
+        let mut buf = vec![0u8; (len - at).min(CHUNK_SZ) as usize];
+        dev.read_exact_at(&mut buf, start + at)?;
+        hasher.update(&buf);
+        at += buf.len() as u64;
+    }
+    Ok(hasher.finalize().to_vec())
+}
+
+// how a gone device is opened again, and what it has to look like then
+struct Reopen<D> {
+    open: Box<dyn Fn() -> io::Result<D>>,
+    identity: Vec<u8>,
+    tables: Vec<u8>,
+    last_try: Cell<Option<Instant>>,
+    told_other: Cell<bool>, // that another volume is there, said once
+    stale: Cell<bool>,      // it came back written to, it's not served again
+}
+
+pub struct Watch<D> {
+    dev: RefCell<D>,
+    name: String,
+    // looked at after a failed read, none when the name isn't a path that
+    // was there when mounted, like several image files
+    path: Option<PathBuf>,
+    gone: Arc<AtomicBool>,
+    reopen: Option<Reopen<D>>,
+}
+
+impl<D: Device> Watch<D> {
+    pub fn new(dev: D, name: &str, gone: Arc<AtomicBool>) -> Self {
+        let path = PathBuf::from(name);
+        Watch {
+            dev: RefCell::new(dev),
+            name: name.to_string(),
+            path: path.exists().then_some(path),
+            gone,
+            reopen: None,
+        }
+    }
+
+    // what says the device is gone, shared with what serves the mount
+    pub fn flag(&self) -> Arc<AtomicBool> {
+        self.gone.clone()
+    }
+
+    // once gone, the device is opened again with `open`. only when
+    // reconnecting, see set_reconnect
+    pub fn reopen_with(mut self, open: impl Fn() -> io::Result<D> + 'static) -> Self {
+        if !reconnecting() {
+            return self;
+        }
+        let known = {
+            let dev = self.dev.borrow();
+            identity(&*dev).and_then(|id| Ok((id, tables(&*dev)?)))
+        };
+        match known {
+            Ok((identity, tables)) => {
+                self.reopen = Some(Reopen {
+                    open: Box::new(open),
+                    identity,
+                    tables,
+                    last_try: Cell::new(None),
+                    told_other: Cell::new(false),
+                    stale: Cell::new(false),
+                })
+            }
+            Err(e) => eprintln!("[mount] {}: can't reconnect, {}", self.name, e),
+        }
+        self
+    }
+
+    // the device opened again and checked to be the same volume, at most
+    // every REOPEN_EVERY. whether it's back
+    fn try_reopen(&self) -> bool {
+        let Some(r) = &self.reopen else {
+            return false;
+        };
+        if r.stale.get() || r.last_try.get().is_some_and(|t| t.elapsed() < REOPEN_EVERY) {
+            return false;
+        }
+        r.last_try.set(Some(Instant::now()));
+        let Ok(dev) = (r.open)() else {
+            return false;
+        };
+        match identity(&dev) {
+            Ok(id) if id == r.identity => (),
+            Ok(_) => {
+                if !r.told_other.replace(true) {
+                    say!(
+                        "[mount] {}: another volume is there now, waiting for the one mounted",
+                        self.name
+                    );
+                }
+                return false;
+            }
+            Err(_) => return false,
+        }
+        match tables(&dev) {
+            Ok(tables) if tables == r.tables => (),
+            Ok(_) => {
+                r.stale.set(true);
+                say!(
+                    "[mount] {}: the volume is back written to elsewhere, what's mounted of \
+                     it is stale. unmount it",
+                    self.name
+                );
+                return false;
+            }
+            Err(_) => return false,
+        }
+        *self.dev.borrow_mut() = dev;
+        r.told_other.set(false);
+        self.gone.store(false, Ordering::Relaxed);
+        say!(
+            "[mount] {}: the device is back, serving it again",
+            self.name
+        );
+        true
+    }
+
+    fn vanished(&self, e: &io::Error) -> bool {
+        matches!(e.raw_os_error(), Some(libc::ENODEV | libc::ENXIO))
+            || self.path.as_ref().is_some_and(|path| !path.exists())
+    }
+
+    fn gone(&self) -> io::Error {
+        io::Error::from_raw_os_error(libc::ENODEV)
+    }
+}
+
+impl<D: Device> Device for Watch<D> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        if self.gone.load(Ordering::Relaxed) && !self.try_reopen() {
+            return Err(self.gone());
+        }
+        let read = self.dev.borrow().read_at(buf, offset);
+        match read {
+            Ok(n) => Ok(n),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => Err(e),
+            Err(e) if self.vanished(&e) => {
+                if !self.gone.swap(true, Ordering::Relaxed) {
+                    let what = match self.reopen {
+                        Some(_) => "until it's back",
+                        None => "from here on. unmount it",
+                    };
+                    say!(
+                        "[mount] {}: the device is gone ({}), everything on the mount fails \
+                         with ENODEV {}",
+                        self.name,
+                        e,
+                        what
+                    );
+                }
+                Err(self.gone())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn usable_len(&self, extents: &[(u64, usize)]) -> usize {
+        self.dev.borrow().usable_len(extents)
+    }
+
+    fn bad_spans(&self, extents: &[(u64, usize)]) -> Vec<(usize, usize)> {
+        self.dev.borrow().bad_spans(extents)
+    }
+
+    fn len(&self) -> Option<u64> {
+        self.dev.borrow().len()
+    }
+}
+
+// run `op` against a mount's Fs. fails with Gone without running it once
+// the device is gone, unless reconnecting where its reads look for the
+// device, and when it failed because the device went away meanwhile
+pub fn guard<T>(
+    gone: &AtomicBool,
+    op: impl FnOnce() -> Result<T, fs::Error>,
+) -> Result<T, fs::Error> {
+    if is_gone(gone) {
+        return Err(fs::Error::Gone);
+    }
+    match op() {
+        Err(_) if gone.load(Ordering::Relaxed) => Err(fs::Error::Gone),
+        done => done,
+    }
+}
+
+// gone for what doesn't read the device, like statfs
+pub fn is_gone(gone: &AtomicBool) -> bool {
+    gone.load(Ordering::Relaxed) && !reconnecting()
+}
@@ -0,0 +1,629 @@
+// References:
+// [1] https://uefi.org/specs/UEFI/2.10/05_GUID_Partition_Table_Format.html
+
+use std::{fmt, io, str::FromStr};
+
+use scroll::{
+    ctx::{TryFromCtx, TryIntoCtx},
+    Pread, Pwrite, LE,
+};
+
+use crate::device::Device;
+use crate::mbr::{self, Mbr};
+
+// images and USB sticks alike, 4Kn media aren't handled
+pub const SEC_SZ: u64 = 512;
+const SIG: &[u8; 8] = b"EFI PART";
+const REVISION: u32 = 0x00010000;
+const HEADER_SZ: u32 = 92;
+const NUM_ENTRIES: u32 = 128;
+const ENTRY_SZ: u32 = 128;
+// sectors taken by the entry array, 128 entries of 128 bytes
+const ENTRY_SECS: u64 = (NUM_ENTRIES * ENTRY_SZ) as u64 / SEC_SZ;
+const NAME_LEN: usize = 36;
+// the largest entry array read, a header asking for more is taken for broken
+const MAX_ARRAY_SZ: u32 = 1 << 20;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("no valid GPT header, primary or backup")]
+    NotFound,
+    #[error("partition {0} is out of 1..={1}")]
+    BadSlot(usize, usize),
+    #[error("partition {0} is empty")]
+    Empty(usize),
+    #[error("partition {0} is already in use")]
+    InUse(usize),
+    #[error("partition would overlap partition {0}")]
+    Overlap(usize),
+    #[error("sectors {0}..={1} are outside the usable {2}..={3}")]
+    OutOfRange(u64, u64, u64, u64),
+    #[error("name is longer than {NAME_LEN} UTF-16 units")]
+    NameTooLong,
+    #[error("disk of {0} sectors is too small for a GPT")]
+    TooSmall(u64),
+    #[error("the entry array at {0}..={1} runs into the usable {2}..={3}")]
+    ArrayInUsable(u64, u64, u64, u64),
+    #[error("io error")]
+    Io(#[from] io::Error),
+    #[error("scroll failed")]
+    Scroll(#[from] scroll::Error),
+}
+
+// see exit::code, a disk without a GPT isn't one gpt-edit works with
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Io(e) => e,
+            Error::NotFound => io::Error::new(io::ErrorKind::Unsupported, e),
+            Error::Scroll(_) => io::Error::new(io::ErrorKind::InvalidData, e),
+            e => io::Error::new(io::ErrorKind::InvalidInput, e),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    pub const EFI_SYSTEM: Guid = Guid::from_fields(0xC12A7328, 0xF81F, 0x11D2, 0xBA4B00A0C93EC93B);
+    pub const BASIC_DATA: Guid = Guid::from_fields(0xEBD0A0A2, 0xB9E5, 0x4433, 0x87C068B6B72699C7);
+    pub const LINUX_FS: Guid = Guid::from_fields(0x0FC63DAF, 0x8483, 0x4772, 0x8E793D69E4C47D0B);
+
+    // the first three fields are stored little endian, the rest as is
+    pub const fn from_fields(a: u32, b: u16, c: u16, d: u64) -> Guid {
+        let (a, b, c, d) = (
+            a.to_le_bytes(),
+            b.to_le_bytes(),
+            c.to_le_bytes(),
+            d.to_be_bytes(),
+        );
+        Guid([
+            a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1], d[0], d[1], d[2], d[3], d[4], d[5],
+            d[6], d[7],
+        ])
+    }
+
+    // a version 4 GUID from the kernel's random pool
+    pub fn random() -> io::Result<Guid> {
+        let mut g = [0u8; 16];
+        #[cfg(unix)]
+        std::fs::File::open("/dev/urandom")?.read_exact_at(&mut g, 0)?;
+        // std seeds its hash keys from the system's random source
+        #[cfg(not(unix))]
+        for half in g.chunks_mut(8) {
+            use std::hash::{BuildHasher, Hasher};
+            let bits = std::collections::hash_map::RandomState::new()
+                .build_hasher()
+                .finish();
+            half.copy_from_slice(&bits.to_le_bytes());
+        }
+        g[7] = (g[7] & 0x0F) | 0x40;
+        g[8] = (g[8] & 0x3F) | 0x80;
+        Ok(Guid(g))
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0 == [0; 16]
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let g = &self.0;
+        write!(
+            f,
+            "{:08X}-{:04X}-{:04X}-{:04X}-{:012X}",
+            u32::from_le_bytes([g[0], g[1], g[2], g[3]]),
+            u16::from_le_bytes([g[4], g[5]]),
+            u16::from_le_bytes([g[6], g[7]]),
+            u16::from_be_bytes([g[8], g[9]]),
+            u64::from_be_bytes([0, 0, g[10], g[11], g[12], g[13], g[14], g[15]]),
+        )
+    }
+}
+
+impl fmt::Debug for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+// either the textual form or one of the short names esp, msdata and linux
+impl FromStr for Guid {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "esp" => return Ok(Guid::EFI_SYSTEM),
+            "msdata" => return Ok(Guid::BASIC_DATA),
+            "linux" => return Ok(Guid::LINUX_FS),
+            _ => (),
+        }
+        let parts: Vec<&str> = s.split('-').collect();
+        let lens: Vec<usize> = parts.iter().map(|p| p.len()).collect();
+        if lens != [8, 4, 4, 4, 12] {
+            return Err(format!("{} is not a GUID", s));
+        }
+        let hex = |p: &str| u64::from_str_radix(p, 16).map_err(|e| e.to_string());
+        Ok(Guid::from_fields(
+            hex(parts[0])? as u32,
+            hex(parts[1])? as u16,
+            hex(parts[2])? as u16,
+            hex(parts[3])? << 48 | hex(parts[4])?,
+        ))
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Header {
+    header_crc32: u32,
+    my_lba: u64,
+    alternate_lba: u64,
+    first_usable_lba: u64,
+    last_usable_lba: u64,
+    disk_guid: Guid,
+    part_entry_lba: u64,
+    num_part_entries: u32,
+    size_of_part_entry: u32,
+    part_entry_array_crc32: u32,
+}
+
+impl<'a> TryFromCtx<'a, scroll::Endian> for Header {
+    type Error = scroll::Error;
+    fn try_from_ctx(from: &'a [u8], _ctx: scroll::Endian) -> Result<(Self, usize), Self::Error> {
+        if &from[..8] != SIG {
+            return Err(scroll::Error::BadInput {
+                size: 8,
+                msg: "bad signature",
+            });
+        }
+        Ok((
+            Header {
+                header_crc32: from.pread_with(16, LE)?,
+                my_lba: from.pread_with(24, LE)?,
+                alternate_lba: from.pread_with(32, LE)?,
+                first_usable_lba: from.pread_with(40, LE)?,
+                last_usable_lba: from.pread_with(48, LE)?,
+                disk_guid: Guid(from.pread_with(56, LE)?),
+                part_entry_lba: from.pread_with(72, LE)?,
+                num_part_entries: from.pread_with(80, LE)?,
+                size_of_part_entry: from.pread_with(84, LE)?,
+                part_entry_array_crc32: from.pread_with(88, LE)?,
+            },
+            HEADER_SZ as usize,
+        ))
+    }
+}
+
+impl TryIntoCtx<scroll::Endian> for &Header {
+    type Error = scroll::Error;
+    fn try_into_ctx(self, into: &mut [u8], _ctx: scroll::Endian) -> Result<usize, Self::Error> {
+        into.pwrite_with(&SIG[..], 0, ())?;
+        into.pwrite_with(REVISION, 8, LE)?;
+        into.pwrite_with(HEADER_SZ, 12, LE)?;
+        into.pwrite_with(self.header_crc32, 16, LE)?;
+        into.pwrite_with(0u32, 20, LE)?;
+        into.pwrite_with(self.my_lba, 24, LE)?;
+        into.pwrite_with(self.alternate_lba, 32, LE)?;
+        into.pwrite_with(self.first_usable_lba, 40, LE)?;
+        into.pwrite_with(self.last_usable_lba, 48, LE)?;
+        into.pwrite_with(&self.disk_guid.0[..], 56, ())?;
+        into.pwrite_with(self.part_entry_lba, 72, LE)?;
+        into.pwrite_with(self.num_part_entries, 80, LE)?;
+        into.pwrite_with(self.size_of_part_entry, 84, LE)?;
+        into.pwrite_with(self.part_entry_array_crc32, 88, LE)?;
+        Ok(HEADER_SZ as usize)
+    }
+}
+
+impl Header {
+    // the header bytes with the crc computed over them, crc field zeroed
+    fn dump(&mut self) -> Vec<u8> {
+        let mut buf = vec![0u8; SEC_SZ as usize];
+        self.header_crc32 = 0;
+        buf.pwrite_with(&*self, 0, LE).unwrap();
+        self.header_crc32 = crc32fast::hash(&buf[..HEADER_SZ as usize]);
+        buf.pwrite_with(self.header_crc32, 16, LE).unwrap();
+        buf
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PartEntry {
+    pub type_guid: Guid,
+    pub unique_guid: Guid,
+    pub first_lba: u64,
+    pub last_lba: u64,
+    pub attrs: u64,
+    name: [u16; NAME_LEN],
+}
+
+impl<'a> TryFromCtx<'a, scroll::Endian> for PartEntry {
+    type Error = scroll::Error;
+    fn try_from_ctx(from: &'a [u8], _ctx: scroll::Endian) -> Result<(Self, usize), Self::Error> {
+        let mut name = [0u16; NAME_LEN];
+        for (i, c) in name.iter_mut().enumerate() {
+            *c = from.pread_with(56 + i * 2, LE)?;
+        }
+        Ok((
+            PartEntry {
+                type_guid: Guid(from.pread_with(0, LE)?),
+                unique_guid: Guid(from.pread_with(16, LE)?),
+                first_lba: from.pread_with(32, LE)?,
+                last_lba: from.pread_with(40, LE)?,
+                attrs: from.pread_with(48, LE)?,
+                name,
+            },
+            ENTRY_SZ as usize,
+        ))
+    }
+}
+
+impl TryIntoCtx<scroll::Endian> for &PartEntry {
+    type Error = scroll::Error;
+    fn try_into_ctx(self, into: &mut [u8], _ctx: scroll::Endian) -> Result<usize, Self::Error> {
+        into.pwrite_with(&self.type_guid.0[..], 0, ())?;
+        into.pwrite_with(&self.unique_guid.0[..], 16, ())?;
+        into.pwrite_with(self.first_lba, 32, LE)?;
+        into.pwrite_with(self.last_lba, 40, LE)?;
+        into.pwrite_with(self.attrs, 48, LE)?;
+        for (i, &c) in self.name.iter().enumerate() {
+            into.pwrite_with(c, 56 + i * 2, LE)?;
+        }
+        Ok(ENTRY_SZ as usize)
+    }
+}
+
+impl Default for PartEntry {
+    fn default() -> Self {
+        PartEntry {
+            type_guid: Guid::default(),
+            unique_guid: Guid::default(),
+            first_lba: 0,
+            last_lba: 0,
+            attrs: 0,
+            name: [0; NAME_LEN],
+        }
+    }
+}
+
+impl PartEntry {
+    pub fn is_empty(&self) -> bool {
+        self.type_guid.is_zero()
+    }
+
+    pub fn name(&self) -> String {
+        let len = self.name.iter().position(|&c| c == 0).unwrap_or(NAME_LEN);
+        String::from_utf16_lossy(&self.name[..len])
+    }
+
+    fn set_name(&mut self, name: &str) -> Result<(), Error> {
+        let units: Vec<u16> = name.encode_utf16().collect();
+        if units.len() > NAME_LEN {
+            return Err(Error::NameTooLong);
+        }
+        self.name = [0; NAME_LEN];
+        self.name[..units.len()].copy_from_slice(&units);
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct Gpt {
+    header: Header,
+    pub entries: Vec<PartEntry>,
+}
+
+impl Gpt {
+    // an empty table for a disk of `disk_secs` sectors, both copies of the
+    // entry array leaving the usable range in between
+    pub fn new(disk_secs: u64) -> Result<Self, Error> {
+        if disk_secs < 2 * (ENTRY_SECS + 1) + 2 {
+            return Err(Error::TooSmall(disk_secs));
+        }
+        Ok(Gpt {
+            header: Header {
+                header_crc32: 0,
+                my_lba: 1,
+                alternate_lba: disk_secs - 1,
+                first_usable_lba: 2 + ENTRY_SECS,
+                last_usable_lba: disk_secs - 2 - ENTRY_SECS,
+                disk_guid: Guid::random()?,
+                part_entry_lba: 2,
+                num_part_entries: NUM_ENTRIES,
+                size_of_part_entry: ENTRY_SZ,
+                part_entry_array_crc32: 0,
+            },
+            entries: vec![PartEntry::default(); NUM_ENTRIES as usize],
+        })
+    }
+
+    // the primary table, or the backup one at the end of the disk when the
+    // primary is damaged
+    pub fn read<D: Device>(device: &D, disk_secs: u64) -> Result<Self, Error> {
+        if disk_secs < 2 {
+            return Err(Error::NotFound);
+        }
+        for lba in [1, disk_secs - 1] {
+            if let Some(gpt) = Gpt::read_at(device, lba)? {
+                return Ok(gpt);
+            }
+        }
+        Err(Error::NotFound)
+    }
+
+    fn read_at<D: Device>(device: &D, lba: u64) -> Result<Option<Self>, Error> {
+        let mut buf = vec![0u8; SEC_SZ as usize];
+        device.read_exact_at(&mut buf, lba * SEC_SZ)?;
+        let Ok(mut header) = buf.pread_with::<Header>(0, LE) else {
+            return Ok(None);
+        };
+        buf[16..20].fill(0);
+        if header.my_lba != lba
+            || crc32fast::hash(&buf[..HEADER_SZ as usize]) != header.header_crc32
+            || header.size_of_part_entry < ENTRY_SZ
+        {
+            return Ok(None);
+        }
+
+        let arr_sz = header
+            .num_part_entries
+            .checked_mul(header.size_of_part_entry)
+            .filter(|&sz| sz <= MAX_ARRAY_SZ);
+        let (Some(arr_sz), Some(arr_off)) = (arr_sz, header.part_entry_lba.checked_mul(SEC_SZ))
+        else {
+            return Ok(None);
+        };
+        let mut arr = vec![0u8; arr_sz as usize];
+        device.read_exact_at(&mut arr, arr_off)?;
+        if crc32fast::hash(&arr) != header.part_entry_array_crc32 {
+            return Ok(None);
+        }
+        let mut entries = vec![];
+        for ent in arr.chunks(header.size_of_part_entry as usize) {
+            entries.push(ent.pread_with::<PartEntry>(0, LE)?);
+        }
+        // the backup is turned into a primary on the next write. the entry
+        // count and size stay what the disk has, write checks they fit
+        if lba != 1 {
+            header.alternate_lba = header.my_lba;
+            header.my_lba = 1;
+            header.part_entry_lba = 2;
+        }
+        Ok(Some(Gpt { header, entries }))
+    }
+
+    // write the protective MBR, the primary and then the backup table.
+    // refused when either copy of the entry array would cover usable sectors
+    pub fn write<D: Device>(&mut self, device: &D) -> Result<(), Error> {
+        let disk_secs = self.header.alternate_lba + 1;
+        let ent_sz = self.header.size_of_part_entry as usize;
+        let arr_secs = (self.entries.len() * ent_sz).div_ceil(SEC_SZ as usize) as u64;
+        let (lo, hi) = (self.header.first_usable_lba, self.header.last_usable_lba);
+        let primary = self.header.part_entry_lba;
+        let backup_lba = self.header.alternate_lba.saturating_sub(arr_secs);
+        for first in [primary, backup_lba] {
+            let last = first + arr_secs - 1;
+            if first <= hi && last >= lo {
+                return Err(Error::ArrayInUsable(first, last, lo, hi));
+            }
+        }
+
+        let mut mbr = Mbr::empty();
+        mbr.add(
+            1,
+            mbr::TYPE_PROTECTIVE,
+            1,
+            (disk_secs - 1).min(u32::MAX as u64) as u32,
+        )
+        .unwrap();
+        device.write_all_at(&mbr.dump(), 0)?;
+
+        let mut arr = vec![0u8; self.entries.len() * ent_sz];
+        for (i, ent) in self.entries.iter().enumerate() {
+            arr.pwrite_with(ent, i * ent_sz, LE)?;
+        }
+        self.header.part_entry_array_crc32 = crc32fast::hash(&arr);
+
+        let mut backup = self.header.clone();
+        backup.my_lba = self.header.alternate_lba;
+        backup.alternate_lba = self.header.my_lba;
+        backup.part_entry_lba = backup_lba;
+        device.write_all_at(&arr, self.header.part_entry_lba * SEC_SZ)?;
+        device.write_all_at(&self.header.dump(), self.header.my_lba * SEC_SZ)?;
+        device.write_all_at(&arr, backup.part_entry_lba * SEC_SZ)?;
+        device.write_all_at(&backup.dump(), backup.my_lba * SEC_SZ)?;
+        Ok(())
+    }
+
+    pub fn disk_guid(&self) -> Guid {
+        self.header.disk_guid
+    }
+
+    fn slot(&mut self, slot: usize) -> Result<&mut PartEntry, Error> {
+        let cnt = self.entries.len();
+        if !(1..=cnt).contains(&slot) {
+            return Err(Error::BadSlot(slot, cnt));
+        }
+        Ok(&mut self.entries[slot - 1])
+    }
+
+    fn used(&mut self, slot: usize) -> Result<&mut PartEntry, Error> {
+        let ent = self.slot(slot)?;
+        if ent.is_empty() {
+            return Err(Error::Empty(slot));
+        }
+        Ok(ent)
+    }
+
+    pub fn add(
+        &mut self,
+        slot: usize,
+        typ: Guid,
+        first: u64,
+        nsecs: u64,
+        name: &str,
+    ) -> Result<(), Error> {
+        if !self.slot(slot)?.is_empty() {
+            return Err(Error::InUse(slot));
+        }
+        let last = (first + nsecs).saturating_sub(1);
+        let (lo, hi) = (self.header.first_usable_lba, self.header.last_usable_lba);
+        if nsecs == 0 || first < lo || last > hi {
+            return Err(Error::OutOfRange(first, last, lo, hi));
+        }
+        for (i, ent) in self.entries.iter().enumerate() {
+            if !ent.is_empty() && first <= ent.last_lba && last >= ent.first_lba {
+                return Err(Error::Overlap(i + 1));
+            }
+        }
+        let mut ent = PartEntry {
+            type_guid: typ,
+            unique_guid: Guid::random()?,
+            first_lba: first,
+            last_lba: last,
+            ..Default::default()
+        };
+        ent.set_name(name)?;
+        *self.slot(slot)? = ent;
+        Ok(())
+    }
+
+    pub fn remove(&mut self, slot: usize) -> Result<(), Error> {
+        *self.used(slot)? = PartEntry::default();
+        Ok(())
+    }
+
+    pub fn rename(&mut self, slot: usize, name: &str) -> Result<(), Error> {
+        self.used(slot)?.set_name(name)
+    }
+
+    pub fn retype(&mut self, slot: usize, typ: Guid) -> Result<(), Error> {
+        self.used(slot)?.type_guid = typ;
+        Ok(())
+    }
+}
+
+// the MBR partition types a hybrid MBR commonly mirrors GPT ones with
+fn mbr_type_of(typ: Guid) -> &'static [u8] {
+    match typ {
+        Guid::EFI_SYSTEM => &[0xEF, 0x0B, 0x0C],
+        Guid::BASIC_DATA => &[0x06, 0x07, 0x0B, 0x0C, 0x0E],
+        Guid::LINUX_FS => &[0x83],
+        _ => &[],
+    }
+}
+
+// how a hybrid MBR's entries disagree with the GPT, or how the MBR fails to
+// protect a GPT at all
+#[derive(Debug)]
+pub enum Mismatch {
+    NotProtective,
+    NoSectors {
+        slot: usize,
+        typ: u8,
+    },
+    Unmatched {
+        slot: usize,
+        first: u64,
+        last: u64,
+    },
+    Differs {
+        slot: usize,
+        part: usize,
+        mbr: (u64, u64),
+        gpt: (u64, u64),
+    },
+    TypeDiffers {
+        slot: usize,
+        part: usize,
+        typ: u8,
+        guid: Guid,
+    },
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mismatch::NotProtective => {
+                write!(f, "a GPT is present but the MBR has no protective entry")
+            }
+            Mismatch::NoSectors { slot, typ } => write!(
+                f,
+                "MBR partition {slot} has type 0x{typ:02X} but no sectors"
+            ),
+            Mismatch::Unmatched { slot, first, last } => write!(
+                f,
+                "MBR partition {slot} ({first}..={last}) has no GPT counterpart"
+            ),
+            Mismatch::Differs {
+                slot,
+                part,
+                mbr: (mf, ml),
+                gpt: (gf, gl),
+            } => write!(
+                f,
+                "MBR partition {slot} ({mf}..={ml}) disagrees with GPT partition {part} ({gf}..={gl})"
+            ),
+            Mismatch::TypeDiffers {
+                slot,
+                part,
+                typ,
+                guid,
+            } => write!(
+                f,
+                "MBR partition {slot} type 0x{typ:02X} doesn't fit GPT partition {part} type {guid}"
+            ),
+        }
+    }
+}
+
+// every non-protective MBR entry next to a GPT should mirror a GPT partition
+// exactly, anything else means the two tables tell different stories
+pub fn cross_check(mbr: &Mbr, gpt: &Gpt) -> Vec<Mismatch> {
+    let parts = mbr.partitions();
+    if !parts.iter().any(|p| p.typ() == mbr::TYPE_PROTECTIVE) {
+        return vec![Mismatch::NotProtective];
+    }
+    let mut found = vec![];
+    for (i, p) in parts.iter().enumerate() {
+        if p.is_empty() || p.typ() == mbr::TYPE_PROTECTIVE {
+            continue;
+        }
+        let (slot, first) = (i + 1, p.lba() as u64);
+        // can't mirror any GPT partition, and has no last sector to compare
+        if p.nsecs() == 0 {
+            found.push(Mismatch::NoSectors { slot, typ: p.typ() });
+            continue;
+        }
+        let last = first + p.nsecs() as u64 - 1;
+        let overlapping = gpt
+            .entries
+            .iter()
+            .enumerate()
+            .find(|(_, e)| !e.is_empty() && first <= e.last_lba && last >= e.first_lba);
+        match overlapping {
+            None => found.push(Mismatch::Unmatched { slot, first, last }),
+            Some((j, e)) if (e.first_lba, e.last_lba) != (first, last) => {
+                found.push(Mismatch::Differs {
+                    slot,
+                    part: j + 1,
+                    mbr: (first, last),
+                    gpt: (e.first_lba, e.last_lba),
+                })
+            }
+            Some((j, e)) => {
+                let types = mbr_type_of(e.type_guid);
+                if !types.is_empty() && !types.contains(&p.typ()) {
+                    found.push(Mismatch::TypeDiffers {
+                        slot,
+                        part: j + 1,
+                        typ: p.typ(),
+                        guid: e.type_guid,
+                    });
+                }
+            }
+        }
+    }
+    found
+}
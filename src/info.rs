@@ -0,0 +1,126 @@
+// what's on a device without knowing it up front: the partition table if
+// there is one, and a summary of each filesystem found
+
+use std::io;
+
+use crate::device::{self, Device, Slice};
+use crate::disk;
+use crate::ext2;
+use crate::fio::{self, FsType};
+use crate::gpt;
+use crate::table;
+
+// the fields every filesystem is summed up by, sizes in bytes
+struct Summary {
+    typ: &'static str,
+    label: String,
+    serial: String,
+    unit: &'static str, // what `unit_sz` is the size of, clusters or blocks
+    unit_sz: u32,
+    capacity: u64,
+    free: Option<u64>,
+}
+
+// `typ` as disk::detect named it. ext2 is read here, the rest through fio
+fn summarize(dev: impl Device, typ: &'static str) -> io::Result<Summary> {
+    if typ == "ext2" {
+        let mut buf = [0u8; 1024];
+        dev.read_exact_at(&mut buf, 1024)?;
+        let sblk = ext2::spec::Sblk::new(&buf).map_err(io::Error::other)?;
+        let st = sblk.statfs();
+        return Ok(Summary {
+            typ,
+            label: sblk.label(),
+            serial: sblk.uuid(),
+            unit: "block",
+            unit_sz: st.bsize,
+            capacity: st.blocks * st.bsize as u64,
+            free: Some(st.bfree * st.bsize as u64),
+        });
+    }
+    let fstype = fio::detect(&dev, 0).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("{} isn't supported", typ),
+        )
+    })?;
+    let vol = fio::open(dev, &fstype)?.volume()?;
+    Ok(Summary {
+        typ,
+        label: vol.label,
+        serial: format!("{:08X}", vol.serial),
+        unit: match fstype {
+            FsType::Fat32 | FsType::Exfat => "cluster",
+            FsType::Squashfs | FsType::Udf | FsType::Ext2 => "block",
+        },
+        unit_sz: vol.clus_sz,
+        capacity: vol.clus_cnt as u64 * vol.clus_sz as u64,
+        free: vol.free.map(|free| free as u64 * vol.clus_sz as u64),
+    })
+}
+
+fn print_summary(s: &Summary, indent: &str) {
+    println!("{}type: {}", indent, s.typ);
+    println!("{}label: {}", indent, s.label);
+    println!("{}serial: {}", indent, s.serial);
+    println!(
+        "{}{} size: {}",
+        indent,
+        s.unit,
+        table::size(s.unit_sz as u64, false)
+    );
+    println!("{}capacity: {}", indent, table::size(s.capacity, false));
+    match s.free {
+        Some(free) => println!("{}free: {}", indent, table::size(free, false)),
+        None => println!("{}free: unknown", indent),
+    }
+}
+
+pub fn info(path: &str) -> io::Result<()> {
+    let image = device::open(path, false)?;
+    if let Some(typ) = disk::detect(&image, 0) {
+        println!("{}: {} volume", path, typ);
+        print_summary(&summarize(image, typ)?, "  ");
+        return Ok(());
+    }
+    let disk_sz = device::size(&image)?;
+    let disk_secs = disk_sz / gpt::SEC_SZ;
+    let parts = disk::partitions(&image, disk_secs)?;
+    let Some(table) = disk::table(&image, disk_secs, &parts) else {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("{}: no partition table or filesystem found", path),
+        ));
+    };
+    let cnt = match parts.len() {
+        1 => String::from("1 partition"),
+        n => format!("{} partitions", n),
+    };
+    println!(
+        "{}: {} disk, {}, {}",
+        path,
+        table,
+        table::size(disk_sz, false),
+        cnt
+    );
+    for part in parts.iter() {
+        let (start, len) = (part.first * gpt::SEC_SZ, part.nsecs * gpt::SEC_SZ);
+        let Some(typ) = disk::detect(&image, start) else {
+            println!("  {}: {}, no filesystem found", part.no, part.name);
+            continue;
+        };
+        println!(
+            "  {}: {}, {} at sector {}",
+            part.no,
+            part.name,
+            table::size(len, false),
+            part.first
+        );
+        let dev = Slice::new(device::open(path, false)?, start, len);
+        match summarize(dev, typ) {
+            Ok(summary) => print_summary(&summary, "    "),
+            Err(e) => println!("    {}: {}", typ, e),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,207 @@
+// References:
+// [1] https://www.kernel.org/doc/html/latest/filesystems/ext4/journal.html
+
+use std::{collections::BTreeMap, io};
+
+use scroll::{Pread, BE};
+
+const MAGIC: u32 = 0xC03B3998;
+
+const DESCRIPTOR_BLOCK: u32 = 1;
+const COMMIT_BLOCK: u32 = 2;
+const SUPERBLOCK_V1: u32 = 3;
+const SUPERBLOCK_V2: u32 = 4;
+const REVOKE_BLOCK: u32 = 5;
+
+const INCOMPAT_64BIT: u32 = 0x2;
+const INCOMPAT_CSUM_V2: u32 = 0x8;
+const INCOMPAT_CSUM_V3: u32 = 0x10;
+
+const FLAG_ESCAPE: u32 = 0x1;
+const FLAG_SAME_UUID: u32 = 0x2;
+const FLAG_LAST_TAG: u32 = 0x8;
+
+// the journal superblock, the parts recovery needs
+struct Sb {
+    blocksize: u32,
+    maxlen: u32,
+    first: u32,
+    sequence: u32,
+    start: u32, // 0 when there's nothing to recover
+    incompat: u32,
+}
+
+impl Sb {
+    fn new(buf: &[u8]) -> io::Result<Self> {
+        let bad = |_| io::Error::other("short journal superblock");
+        let magic: u32 = buf.pread_with(0, BE).map_err(bad)?;
+        let typ: u32 = buf.pread_with(4, BE).map_err(bad)?;
+        if magic != MAGIC || !matches!(typ, SUPERBLOCK_V1 | SUPERBLOCK_V2) {
+            return Err(io::Error::other("no journal superblock"));
+        }
+        Ok(Sb {
+            blocksize: buf.pread_with(12, BE).map_err(bad)?,
+            maxlen: buf.pread_with(16, BE).map_err(bad)?,
+            first: buf.pread_with(20, BE).map_err(bad)?,
+            sequence: buf.pread_with(24, BE).map_err(bad)?,
+            start: buf.pread_with(28, BE).map_err(bad)?,
+            incompat: match typ {
+                SUPERBLOCK_V2 => buf.pread_with(40, BE).map_err(bad)?,
+                _ => 0,
+            },
+        })
+    }
+
+    fn has(&self, feature: u32) -> bool {
+        self.incompat & feature != 0
+    }
+
+    // the bytes of a descriptor block tag, without the uuid that may follow
+    fn tag_sz(&self) -> usize {
+        if self.has(INCOMPAT_CSUM_V3) {
+            if self.has(INCOMPAT_64BIT) {
+                16
+            } else {
+                12
+            }
+        } else {
+            let sz = if self.has(INCOMPAT_CSUM_V2) { 14 } else { 12 };
+            if self.has(INCOMPAT_64BIT) {
+                sz
+            } else {
+                sz - 4
+            }
+        }
+    }
+
+    // the block after `pos` in the circular log
+    fn next(&self, pos: u32) -> u32 {
+        if pos + 1 >= self.maxlen {
+            self.first
+        } else {
+            pos + 1
+        }
+    }
+}
+
+// a committed transaction: filesystem block -> journal block with its new
+// content, and whether the first 4 bytes were escaped
+struct Transaction {
+    seq: u32,
+    blocks: Vec<(u64, u32, bool)>,
+}
+
+// the filesystem blocks as the committed transactions in the journal leave
+// them, what replaying it would write. `read` reads a journal block, by its
+// number within the journal
+pub fn replay(
+    mut read: impl FnMut(u32) -> io::Result<Vec<u8>>,
+) -> io::Result<BTreeMap<u64, Vec<u8>>> {
+    let sb = Sb::new(&read(0)?)?;
+    let mut overlay = BTreeMap::new();
+    if sb.start == 0 {
+        return Ok(overlay);
+    }
+    let hdr = |buf: &[u8]| -> Option<(u32, u32)> {
+        let magic: u32 = buf.pread_with(0, BE).ok()?;
+        (magic == MAGIC).then_some((buf.pread_with(4, BE).ok()?, buf.pread_with(8, BE).ok()?))
+    };
+
+    // find the transactions that made it to their commit block, and the
+    // blocks they revoke
+    let mut done = vec![];
+    let mut revoked: BTreeMap<u64, u32> = BTreeMap::new();
+    let mut cur = Transaction {
+        seq: sb.sequence,
+        blocks: vec![],
+    };
+    let mut cur_revoked = vec![];
+    let mut pos = sb.start;
+    let tail = if sb.has(INCOMPAT_CSUM_V2 | INCOMPAT_CSUM_V3) {
+        4
+    } else {
+        0
+    };
+    for _ in 0..sb.maxlen {
+        let buf = read(pos)?;
+        let Some((typ, _)) = hdr(&buf).filter(|&(_, seq)| seq == cur.seq) else {
+            break;
+        };
+        match typ {
+            DESCRIPTOR_BLOCK => {
+                let mut off = 12;
+                loop {
+                    if off + sb.tag_sz() > buf.len() - tail {
+                        break;
+                    }
+                    let lo: u32 = buf.pread_with(off, BE).unwrap();
+                    let (flags, hi): (u32, u32) = if sb.has(INCOMPAT_CSUM_V3) {
+                        (
+                            buf.pread_with(off + 4, BE).unwrap(),
+                            buf.pread_with(off + 8, BE).unwrap(),
+                        )
+                    } else {
+                        let flags: u16 = buf.pread_with(off + 6, BE).unwrap();
+                        (flags as u32, buf.pread_with(off + 8, BE).unwrap_or(0))
+                    };
+                    let hi = if sb.has(INCOMPAT_64BIT) { hi } else { 0 };
+                    pos = sb.next(pos);
+                    cur.blocks
+                        .push(((hi as u64) << 32 | lo as u64, pos, flags & FLAG_ESCAPE != 0));
+                    off += sb.tag_sz();
+                    if flags & FLAG_SAME_UUID == 0 {
+                        off += 16;
+                    }
+                    if flags & FLAG_LAST_TAG != 0 {
+                        break;
+                    }
+                }
+            }
+            REVOKE_BLOCK => {
+                let used: u32 = buf.pread_with(12, BE).unwrap();
+                let rec_sz = if sb.has(INCOMPAT_64BIT) { 8 } else { 4 };
+                let mut off = 16;
+                while off + rec_sz <= (used as usize).min(buf.len()) {
+                    let blk = match rec_sz {
+                        8 => buf.pread_with::<u64>(off, BE).unwrap(),
+                        _ => buf.pread_with::<u32>(off, BE).unwrap() as u64,
+                    };
+                    cur_revoked.push(blk);
+                    off += rec_sz;
+                }
+            }
+            COMMIT_BLOCK => {
+                for blk in cur_revoked.drain(..) {
+                    revoked.insert(blk, cur.seq);
+                }
+                let seq = cur.seq.wrapping_add(1);
+                done.push(std::mem::replace(
+                    &mut cur,
+                    Transaction {
+                        seq,
+                        blocks: vec![],
+                    },
+                ));
+            }
+            _ => break,
+        }
+        pos = sb.next(pos);
+    }
+
+    // a block revoked in the same or a later transaction stays as it is on
+    // the device
+    for t in done {
+        for (blk, at, escaped) in t.blocks {
+            if revoked.get(&blk).is_some_and(|&seq| seq >= t.seq) {
+                continue;
+            }
+            let mut data = read(at)?;
+            data.truncate(sb.blocksize as usize);
+            if escaped {
+                data[..4].copy_from_slice(&MAGIC.to_be_bytes());
+            }
+            overlay.insert(blk, data);
+        }
+    }
+    Ok(overlay)
+}
@@ -0,0 +1,124 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, Read, Write},
+    path::Path,
+};
+
+use crate::device::Device;
+
+// offset -> (old, new) of the sectors whose content actually changes
+type Changed<'s> = BTreeMap<u64, (Vec<u8>, &'s Vec<u8>)>;
+
+// sectors to be rewritten, keyed by device offset. the original content of
+// each changed sector is appended to a journal before the new one goes out,
+// as records of `offset: u64 LE | len: u32 LE | bytes`
+pub struct Writes<'d> {
+    device: &'d dyn Device,
+    sec_sz: u64,
+    secs: BTreeMap<u64, Vec<u8>>,
+}
+
+impl<'d> Writes<'d> {
+    pub fn new(device: &'d dyn Device, sec_sz: u64) -> Self {
+        Writes {
+            device,
+            sec_sz,
+            secs: BTreeMap::new(),
+        }
+    }
+
+    pub fn read_sector(&self, off: u64) -> io::Result<Vec<u8>> {
+        let mut sec = vec![0u8; self.sec_sz as usize];
+        self.device.read_exact_at(&mut sec, off)?;
+        Ok(sec)
+    }
+
+    pub fn patch(&mut self, off: u64, bytes: &[u8]) -> io::Result<()> {
+        let mut done = 0;
+        while done < bytes.len() {
+            let pos = off + done as u64;
+            let sec_off = pos - pos % self.sec_sz;
+            let at = (pos - sec_off) as usize;
+            let n = (bytes.len() - done).min(self.sec_sz as usize - at);
+            if !self.secs.contains_key(&sec_off) {
+                let sec = self.read_sector(sec_off)?;
+                self.secs.insert(sec_off, sec);
+            }
+            let sec = self.secs.get_mut(&sec_off).unwrap();
+            sec[at..at + n].copy_from_slice(&bytes[done..done + n]);
+            done += n;
+        }
+        Ok(())
+    }
+
+    fn changed(&self) -> io::Result<Changed<'_>> {
+        let mut changed = BTreeMap::new();
+        for (&off, new) in self.secs.iter() {
+            let old = self.read_sector(off)?;
+            if &old != new {
+                changed.insert(off, (old, new));
+            }
+        }
+        Ok(changed)
+    }
+
+    // journal the original sectors, then write the new ones.
+    // return the number of sectors actually changed
+    pub fn commit(self, journal: &mut File) -> io::Result<usize> {
+        let changed = self.changed()?;
+        for (off, (old, _)) in changed.iter() {
+            journal.write_all(&off.to_le_bytes())?;
+            journal.write_all(&(old.len() as u32).to_le_bytes())?;
+            journal.write_all(old)?;
+        }
+        journal.sync_all()?;
+
+        for (&off, (_, new)) in changed.iter() {
+            self.device.write_all_at(new, off)?;
+        }
+        Ok(changed.len())
+    }
+
+    // write the new sectors in offset order without journaling them
+    pub fn apply(self) -> io::Result<usize> {
+        let changed = self.changed()?;
+        for (&off, (_, new)) in changed.iter() {
+            self.device.write_all_at(new, off)?;
+        }
+        Ok(changed.len())
+    }
+}
+
+// put back every sector recorded in `journal`, newest record first so a
+// sector journaled twice ends up with its oldest content
+pub fn rollback(device: &dyn Device, journal: &Path) -> io::Result<usize> {
+    let mut bytes = vec![];
+    File::open(journal)?.read_to_end(&mut bytes)?;
+
+    let mut records = vec![];
+    let mut rest = &bytes[..];
+    while !rest.is_empty() {
+        if rest.len() < 12 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "truncated journal record",
+            ));
+        }
+        let off = u64::from_le_bytes(rest[..8].try_into().unwrap());
+        let len = u32::from_le_bytes(rest[8..12].try_into().unwrap()) as usize;
+        if rest.len() < 12 + len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "truncated journal record",
+            ));
+        }
+        records.push((off, &rest[12..12 + len]));
+        rest = &rest[12 + len..];
+    }
+
+    for (off, data) in records.iter().rev() {
+        device.write_all_at(data, *off)?;
+    }
+    Ok(records.len())
+}
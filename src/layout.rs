@@ -0,0 +1,222 @@
+// the partition table checked against the filesystems in it: partitions
+// overlapping or running off the disk, volumes that don't fit their
+// partition and exFAT's PartitionOffset not where the partition starts.
+// sizes are in 512 byte sectors, the partition table's
+
+use std::io;
+
+use crate::device::{self, Device, Slice};
+use crate::disk;
+use crate::exfat;
+use crate::ext2;
+use crate::fat32::spec::BootSec;
+use crate::gpt;
+use crate::squashfs;
+
+// what a filesystem says about where it is
+struct Extent {
+    secs: Option<u64>, // how long the volume is, none when it doesn't say
+    // the disk sector the volume says it starts at, and the field saying so
+    start: Option<(u64, &'static str)>,
+    problems: Vec<String>, // the volume's own fields disagreeing
+}
+
+fn extent(dev: &dyn Device, typ: &str) -> io::Result<Extent> {
+    let mut buf = [0u8; 512];
+    dev.read_exact_at(&mut buf, 0)?;
+    let mut ext = Extent {
+        secs: None,
+        start: None,
+        problems: vec![],
+    };
+    match typ {
+        "FAT32" => {
+            let b = BootSec::new(&mut buf).map_err(io::Error::other)?;
+            let per = b.bpb_byts_per_sec as u64 / gpt::SEC_SZ;
+            let tot = match b.bpb_tot_sec_16 {
+                0 => b.bpb_tot_sec_32 as u64,
+                n => n as u64,
+            };
+            ext.secs = Some(tot * per);
+            // 0 on volumes formatted without a partition in mind
+            if b.bpb_hidd_sec != 0 {
+                ext.start = Some((b.bpb_hidd_sec as u64 * per, "BPB_HiddSec"));
+            }
+            if b.data_start_sector() as u64 > tot {
+                ext.problems
+                    .push(String::from("the FATs run past the volume's end"));
+            }
+        }
+        "exFAT" => {
+            let b = exfat::spec::BootSec::new(&buf).map_err(io::Error::other)?;
+            if !(9..=12).contains(&b.bytes_per_sector_shift) {
+                ext.problems.push(format!(
+                    "BytesPerSectorShift is {}, not 9 to 12",
+                    b.bytes_per_sector_shift
+                ));
+                return Ok(ext);
+            }
+            let per = (1u64 << b.bytes_per_sector_shift) / gpt::SEC_SZ;
+            match b.volumn_length.checked_mul(per) {
+                Some(secs) => ext.secs = Some(secs),
+                None => ext.problems.push(format!(
+                    "VolumeLength is {}, more sectors than a disk can have",
+                    b.volumn_length
+                )),
+            }
+            // 0 says to ignore it
+            match b.partition_offset.checked_mul(per) {
+                Some(0) => (),
+                Some(start) => ext.start = Some((start, "PartitionOffset")),
+                None => ext.problems.push(format!(
+                    "PartitionOffset is {}, past any disk",
+                    b.partition_offset
+                )),
+            }
+            let fats_end = b.fat_offset as u64 + b.fat_length as u64 * b.number_of_fats as u64;
+            if fats_end > b.cluster_heap_offset as u64 {
+                ext.problems
+                    .push(String::from("the FATs run into the cluster heap"));
+            }
+            let heap_end = (b.cluster_count as u64)
+                .checked_shl(b.sectors_per_cluster_shift as u32)
+                .filter(|&secs| secs >> b.sectors_per_cluster_shift == b.cluster_count as u64)
+                .map(|secs| b.cluster_heap_offset as u64 + secs);
+            match heap_end {
+                Some(end) if end <= b.volumn_length => (),
+                Some(end) => ext.problems.push(format!(
+                    "the cluster heap runs {} sectors past the volume's end",
+                    (end - b.volumn_length).saturating_mul(per)
+                )),
+                None => ext.problems.push(format!(
+                    "{} clusters of 2^{} sectors, more than a disk can have",
+                    b.cluster_count, b.sectors_per_cluster_shift
+                )),
+            }
+        }
+        "SquashFS" => {
+            let sb = squashfs::spec::SuperBlock::new(&buf).map_err(io::Error::other)?;
+            ext.secs = Some(sb.bytes_used.div_ceil(gpt::SEC_SZ));
+        }
+        "ext2" => {
+            let mut sblk = [0u8; 1024];
+            dev.read_exact_at(&mut sblk, 1024)?;
+            let st = ext2::spec::Sblk::new(&sblk)
+                .map_err(io::Error::other)?
+                .statfs();
+            ext.secs = Some(st.blocks * st.bsize as u64 / gpt::SEC_SZ);
+        }
+        _ => (),
+    }
+    Ok(ext)
+}
+
+// the volume in the `nsecs` sectors of `dev` against its extent, problems
+// and notes printed with `name`. `first` is where a partition starts, a
+// bare volume has no start to check. how many problems
+fn check_volume(dev: &dyn Device, typ: &str, name: &str, first: Option<u64>, nsecs: u64) -> usize {
+    let ext = match extent(dev, typ) {
+        Ok(ext) => ext,
+        Err(e) => {
+            eprintln!("[check-layout] {}: {} can't be read, {}", name, typ, e);
+            return 1;
+        }
+    };
+    let mut problems = ext.problems;
+    match ext.secs {
+        Some(secs) if secs > nsecs => problems.push(format!(
+            "the volume is {} sectors, {} more than there are",
+            secs,
+            secs - nsecs
+        )),
+        Some(secs) if secs < nsecs => say!(
+            "[check-layout] {}: the volume leaves the last {} sectors unused",
+            name,
+            nsecs - secs
+        ),
+        Some(_) => (),
+        None => say!(
+            "[check-layout] {}: {} doesn't say how long it is",
+            name,
+            typ
+        ),
+    }
+    if let (Some((start, field)), Some(first)) = (ext.start, first) {
+        if start != first {
+            problems.push(format!(
+                "{} puts the volume at sector {}, it's at {}",
+                field, start, first
+            ));
+        }
+    }
+    for problem in problems.iter() {
+        println!("[check-layout] {}: {}", name, problem);
+    }
+    problems.len()
+}
+
+// check `path`, a whole disk or a bare volume. how many problems there are
+pub fn check(path: &str) -> io::Result<usize> {
+    let image = device::open(path, false)?;
+    let disk_secs = device::size(&image)? / gpt::SEC_SZ;
+    if let Some(typ) = disk::detect(&image, 0) {
+        say!("[check-layout] {} volume, {} sectors", typ, disk_secs);
+        let problems = check_volume(&image, typ, path, None, disk_secs);
+        say!("[check-layout] {} problems", problems);
+        return Ok(problems);
+    }
+    let parts = disk::partitions(&image, disk_secs)?;
+    let Some(table) = disk::table(&image, disk_secs, &parts) else {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "no partition table or filesystem found",
+        ));
+    };
+    say!(
+        "[check-layout] {} disk, {} sectors, {} partitions",
+        table,
+        disk_secs,
+        parts.len()
+    );
+
+    let mut problems = 0;
+    for (i, a) in parts.iter().enumerate() {
+        for b in parts[i + 1..].iter() {
+            if a.first < b.first + b.nsecs && b.first < a.first + a.nsecs {
+                println!("[check-layout] p{} and p{} overlap", a.no, b.no);
+                problems += 1;
+            }
+        }
+    }
+    for part in parts.iter() {
+        let name = format!("p{}", part.no);
+        let end = part.first + part.nsecs;
+        if end > disk_secs {
+            println!(
+                "[check-layout] {}: runs {} sectors past the disk's end",
+                name,
+                end - disk_secs
+            );
+            problems += 1;
+        }
+        let Some(typ) = disk::detect(&image, part.first * gpt::SEC_SZ) else {
+            say!("[check-layout] {}: no filesystem found", name);
+            continue;
+        };
+        say!(
+            "[check-layout] {}: {}, sectors {} to {}",
+            name,
+            typ,
+            part.first,
+            end - 1
+        );
+        let dev = Slice::new(
+            device::open(path, false)?,
+            part.first * gpt::SEC_SZ,
+            part.nsecs * gpt::SEC_SZ,
+        );
+        problems += check_volume(&dev, typ, &name, Some(part.first), part.nsecs);
+    }
+    say!("[check-layout] {} problems", problems);
+    Ok(problems)
+}
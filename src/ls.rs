@@ -0,0 +1,143 @@
+// the entries of a dir, or a file by itself, as a table or one JSON object
+// a line
+
+use std::{
+    collections::BTreeSet,
+    io,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::device::Device;
+use crate::fio::{self, Finfo, Fio};
+use crate::table::{self, Table, Times};
+
+pub struct LsOpts {
+    pub header: bool,
+    pub bytes: bool,
+    pub times: Times,
+    pub recursive: bool,
+    pub jsonl: bool,
+}
+
+// d for a dir, then r, h and s for the read-only, hidden and system
+// attributes, `-` for each that isn't there
+fn attrs(fi: &Finfo) -> String {
+    [
+        (fi.is_dir, 'd'),
+        (fi.is_rdonly, 'r'),
+        (fi.is_hidden, 'h'),
+        (fi.is_system, 's'),
+    ]
+    .iter()
+    .map(|&(set, c)| if set { c } else { '-' })
+    .collect()
+}
+
+fn secs(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+// times in seconds since the epoch, the first cluster 0 when there's none
+fn json(path: &str, fi: &Finfo) -> String {
+    format!(
+        concat!(
+            r#"{{"path":{},"size":{},"created":{},"modified":{},"accessed":{},"#,
+            r#""dir":{},"rdonly":{},"hidden":{},"system":{},"first_cluster":{}}}"#
+        ),
+        table::json_str(path),
+        fi.size,
+        secs(fi.crt_time),
+        secs(fi.wrt_time),
+        secs(fi.acc_time),
+        fi.is_dir,
+        fi.is_rdonly,
+        fi.is_hidden,
+        fi.is_system,
+        fi.fst_clus
+    )
+}
+
+// `ents` of the dir at `dir` and with `recursive` what's under them, depth
+// first, handed to `emit` with their paths as they're read. a dir is only
+// gone into once, a corrupt one may link back to where it's in
+fn walk(
+    fio: &mut dyn Fio,
+    dir: &str,
+    ents: Vec<Finfo>,
+    recursive: Option<&mut BTreeSet<u32>>,
+    emit: &mut dyn FnMut(&str, &Finfo),
+) -> io::Result<()> {
+    let mut seen = recursive;
+    for fi in ents {
+        // FAT32 subdirs list themselves and their parents
+        if seen.is_some() && (fi.name == "." || fi.name == "..") {
+            continue;
+        }
+        let path = format!("{}/{}", dir, fi.name);
+        emit(&path, &fi);
+        let Some(seen) = seen.as_deref_mut() else {
+            continue;
+        };
+        if fi.is_dir && fi.fst_clus != 0 && seen.insert(fi.fst_clus) {
+            let sub = fio.list_dir(fi.fst_clus)?;
+            walk(fio, &path, sub, Some(seen), emit)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn ls(device: &dyn Device, path: &str, opts: &LsOpts) -> io::Result<()> {
+    let typ = fio::detect(device, 0).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            "not a FAT32, exFAT, SquashFS or UDF volume",
+        )
+    })?;
+    let mut fio = fio::open(device, &typ)?;
+    let mut dir = format!("/{}", path.trim_matches('/'));
+    let ents = match path.trim_matches('/') {
+        "" => {
+            dir.clear();
+            fio.list_root()?
+        }
+        _ => match fio::lookup(fio.as_mut(), path)? {
+            Some(fi) if fi.is_dir && fi.fst_clus == 0 => vec![],
+            Some(fi) if fi.is_dir => fio.list_dir(fi.fst_clus)?,
+            Some(fi) => {
+                dir.truncate(dir.rfind('/').unwrap());
+                vec![fi]
+            }
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{}: not found", path),
+                ))
+            }
+        },
+    };
+
+    let mut seen = BTreeSet::new();
+    let recursive = opts.recursive.then_some(&mut seen);
+    if opts.jsonl {
+        walk(fio.as_mut(), &dir, ents, recursive, &mut |path, fi| {
+            println!("{}", json(path, fi))
+        })?;
+        return Ok(());
+    }
+    let now = SystemTime::now();
+    let name = if opts.recursive { "path" } else { "name" };
+    let mut table = Table::new(&["attrs", "-size", "modified", name], opts.header);
+    walk(fio.as_mut(), &dir, ents, recursive, &mut |path, fi| {
+        table.row(vec![
+            attrs(fi),
+            table::size(fi.size, opts.bytes),
+            table::time(fi.wrt_time, opts.times, now),
+            match opts.recursive {
+                true => path.to_string(),
+                false => fi.name.clone(),
+            },
+        ])
+    })?;
+    table.print();
+    Ok(())
+}
@@ -1,29 +1,131 @@
+// first, say! is used by the modules after it
+#[macro_use]
+mod exit;
+
+mod align;
+mod attrib;
+mod cache;
+mod carve;
+mod clusters;
+mod defrag;
 mod device;
+mod diff;
+mod disk;
+#[cfg(all(unix, feature = "fuse"))]
+mod diskfuse;
 mod exfat;
+mod exfat_boot;
 mod ext2;
+mod extract;
 mod fat32;
+#[cfg(all(unix, feature = "fuse"))]
 mod fat32fuse;
+mod fatdump;
 mod fio;
+#[cfg(all(unix, feature = "fuse"))]
 mod fs;
+mod fsck;
+mod fsck_exfat;
+#[cfg(all(unix, feature = "fuse"))]
+mod gone;
+mod gpt;
+mod info;
+mod jbd2;
+mod journal;
+mod layout;
+mod ls;
+#[cfg(all(unix, feature = "fuse"))]
+mod manager;
 mod mbr;
+#[cfg(all(unix, feature = "fuse"))]
+mod mount_helper;
+mod mv;
+mod prefetch;
+mod put;
+mod rescue_boot;
+mod resize;
+mod retry;
+mod rm;
+mod search;
+mod space;
+mod squashfs;
+mod stats;
+mod table;
+#[cfg(test)]
+mod testing;
+mod throttle;
+mod touch;
+mod trace;
+mod udf;
 
 use std::{
-    fs::File,
-    io::{Read, Write},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::Duration,
 };
 
-use clap::{builder::PossibleValue, Parser, Subcommand};
-use fuser::MountOption;
+use clap::{builder::PossibleValue, ArgGroup, Args, Parser, Subcommand};
 
-use fat32fuse::{FsType, FuseW};
+use device::Device;
+#[cfg(all(unix, feature = "fuse"))]
+use fat32fuse::FuseW;
+use fio::{Finfo, FsType};
 use mbr::Mbr;
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 #[command(propagate_version = true)]
+#[command(after_help = exit::HELP)]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+    #[arg(long, global = true)]
+    odirect: bool,
+    #[arg(long, global = true, value_name = "BYTES", value_parser = clap::value_parser!(u64).range(1..))]
+    stripe: Option<u64>,
+    #[arg(long, global = true)]
+    split: bool,
+    #[arg(long, global = true, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    partition: Option<u64>,
+    #[arg(long, global = true)]
+    strict: bool,
+    #[arg(long, global = true)]
+    sanitize: bool,
+    #[arg(long, global = true)]
+    nfc: bool,
+    #[arg(long, global = true)]
+    stats: bool,
+    #[arg(long, global = true, value_name = "FILE")]
+    trace_io: Option<String>,
+    #[arg(long, global = true, value_name = "BYTES")]
+    cache_mem: Option<u64>,
+    #[arg(long, global = true, value_name = "RATE", value_parser = throttle::parse_rate)]
+    max_rate: Option<u64>,
+    #[arg(long, global = true)]
+    idle: bool,
+    #[arg(short, long, global = true)]
+    quiet: bool,
+}
+
+// how reads from flaky media are retried, see retry::Retry
+#[derive(Args)]
+struct RetryArgs {
+    #[arg(long, default_value_t = 0)]
+    retries: u32,
+    #[arg(long, default_value_t = 100, value_name = "MS")]
+    backoff: u64,
+    #[arg(long, value_enum, default_value_t = retry::OnError::Fail)]
+    on_error: retry::OnError,
+}
+
+impl RetryArgs {
+    fn opts(&self) -> retry::RetryOpts {
+        retry::RetryOpts {
+            retries: self.retries,
+            backoff: Duration::from_millis(self.backoff),
+            on_error: self.on_error,
+        }
+    }
 }
 
 #[derive(Subcommand)]
@@ -33,6 +135,290 @@ enum Commands {
         mount_point: String,
         #[arg(short, long, value_enum)]
         r#type: FsType,
+        #[arg(long)]
+        forensic: bool,
+        #[arg(long, conflicts_with = "keep_cache")]
+        direct_io: bool,
+        #[arg(long)]
+        keep_cache: bool,
+        #[arg(long, default_value_t = 16, value_name = "CLUSTERS")]
+        readahead: u32,
+        #[arg(long)]
+        background: bool,
+        #[arg(long, conflicts_with = "background")]
+        daemon: bool,
+        #[arg(long, conflicts_with_all = ["background", "daemon"])]
+        foreground: bool,
+        #[arg(long)]
+        reconnect: bool,
+        #[arg(long, value_name = "PATH")]
+        prefetch: Option<String>,
+        #[arg(long)]
+        verify: bool,
+        #[arg(long, value_enum, requires = "verify", default_value_t = fsck::OnBad::Refuse)]
+        on_bad: fsck::OnBad,
+        #[arg(long)]
+        uid: Option<u32>,
+        #[arg(long)]
+        gid: Option<u32>,
+        #[arg(long, value_enum, default_value_t = fio::LongNames::Truncate)]
+        long_names: fio::LongNames,
+    },
+    Umount {
+        mount_point: String,
+        #[arg(short = 'z', long)]
+        lazy: bool,
+    },
+    Mounts {
+        #[arg(short = 'H', long)]
+        no_header: bool,
+    },
+    Daemon {
+        #[arg(long, value_name = "PATH")]
+        socket: Option<String>,
+    },
+    Ctl {
+        #[arg(long, value_name = "PATH")]
+        socket: Option<String>,
+        #[command(subcommand)]
+        request: CtlRequest,
+    },
+    MountDisk {
+        device: String,
+        mount_point: String,
+        #[arg(long)]
+        reconnect: bool,
+        #[arg(long)]
+        uid: Option<u32>,
+        #[arg(long)]
+        gid: Option<u32>,
+        #[arg(long, value_enum, default_value_t = fio::LongNames::Truncate)]
+        long_names: fio::LongNames,
+    },
+    Extract {
+        device: String,
+        dest: String,
+        #[arg(short, long, value_enum)]
+        r#type: FsType,
+        #[arg(short, long, default_value_t = 1)]
+        jobs: usize,
+        #[arg(long)]
+        verify: bool,
+        #[arg(long, requires = "verify", value_name = "FILE")]
+        manifest: Option<String>,
+        #[arg(long, value_name = "MAP", conflicts_with = "on_error")]
+        rescue: Option<String>,
+        #[arg(long, value_enum, default_value_t = extract::Names::Replace)]
+        names: extract::Names,
+        #[command(flatten)]
+        retry: RetryArgs,
+    },
+    Recover {
+        device: String,
+        #[arg(long, value_name = "DIR")]
+        dest: Option<String>,
+    },
+    Put {
+        device: String,
+        host_file: String,
+        dest_path: String,
+    },
+    Rm {
+        device: String,
+        path: String,
+        #[arg(short, long)]
+        recursive: bool,
+    },
+    Mv {
+        device: String,
+        src_path: String,
+        dst_path: String,
+    },
+    Defrag {
+        device: String,
+        #[arg(long)]
+        dry_run: bool,
+        #[arg(long, conflicts_with = "dry_run", value_name = "FILE")]
+        journal: Option<String>,
+        #[arg(long, conflicts_with_all = ["dry_run", "journal"], value_name = "FILE")]
+        rollback: Option<String>,
+    },
+    Trim {
+        device: String,
+        #[arg(short, long, value_enum)]
+        r#type: FsType,
+    },
+    WipeFree {
+        device: String,
+        #[arg(short, long, value_enum)]
+        r#type: FsType,
+        #[arg(long, default_value = "00", value_parser = space::parse_pattern, value_name = "HEX")]
+        pattern: space::Pattern,
+        #[arg(long)]
+        slack: bool,
+    },
+    Clone {
+        src: String,
+        dst: String,
+        #[arg(short, long, value_enum)]
+        r#type: FsType,
+        #[command(flatten)]
+        retry: RetryArgs,
+    },
+    Sparsify {
+        device: String,
+        #[arg(short, long, value_enum)]
+        r#type: FsType,
+    },
+    Diff {
+        image_a: String,
+        image_b: String,
+        #[arg(short, long, value_enum)]
+        r#type: FsType,
+        #[arg(long)]
+        hash: bool,
+    },
+    #[command(group(ArgGroup::new("target").required(true)))]
+    Resize {
+        device: String,
+        #[arg(long, group = "target", value_name = "BYTES")]
+        size: Option<u64>,
+        #[arg(long, group = "target")]
+        shrink_to_used: bool,
+        #[arg(long, value_name = "FILE")]
+        journal: Option<String>,
+        #[arg(
+            long,
+            group = "target",
+            conflicts_with = "journal",
+            value_name = "FILE"
+        )]
+        rollback: Option<String>,
+    },
+    // `-h` clears the hidden bit here, help is only `--help`
+    #[command(disable_help_flag = true)]
+    Attrib {
+        #[arg(long, action = clap::ArgAction::Help)]
+        help: Option<bool>,
+        device: String,
+        path: String,
+        #[arg(short, long, value_enum)]
+        r#type: FsType,
+        #[arg(allow_hyphen_values = true, value_parser = attrib::parse_flag)]
+        flags: Vec<attrib::Flag>,
+    },
+    Touch {
+        device: String,
+        path: String,
+        #[arg(short, long, value_enum)]
+        r#type: FsType,
+        #[arg(long, value_parser = touch::parse_time)]
+        mtime: Option<touch::Stamp>,
+        #[arg(long, value_parser = touch::parse_time)]
+        crtime: Option<touch::Stamp>,
+        #[arg(long, value_parser = touch::parse_time)]
+        atime: Option<touch::Stamp>,
+    },
+    Align {
+        device: String,
+        #[arg(long, default_value_t = 4 << 20, value_name = "BYTES", value_parser = clap::value_parser!(u64).range(1..))]
+        erase_block: u64,
+    },
+    Fsck {
+        device: String,
+        #[arg(long)]
+        repair: bool,
+        #[arg(long, requires = "repair", value_name = "FILE")]
+        backup: Option<String>,
+        #[arg(long, requires = "repair")]
+        recover_orphans: bool,
+    },
+    Carve {
+        device: String,
+        dest: String,
+        #[arg(short, long, value_enum)]
+        r#type: FsType,
+        #[arg(long, default_value_t = 0, value_name = "BYTES")]
+        min_size: u64,
+        #[arg(long, default_value_t = 64 << 20, value_name = "BYTES")]
+        max_size: u64,
+    },
+    ClustersOf {
+        device: String,
+        path: String,
+    },
+    CarveChain {
+        device: String,
+        dest: String,
+        #[arg(long, value_name = "ClusNo")]
+        start_clus: u32,
+        #[arg(long, value_name = "BYTES")]
+        length: Option<u64>,
+    },
+    Grep {
+        device: String,
+        #[arg(long, value_parser = search::parse_needle, value_name = "HEX|STRING")]
+        pattern: space::Pattern,
+        #[arg(long, conflicts_with = "free_only")]
+        allocated_only: bool,
+        #[arg(long)]
+        free_only: bool,
+    },
+    Whose {
+        device: String,
+        #[arg(long, value_name = "BYTES")]
+        offset: u64,
+        #[arg(long, value_name = "FILE")]
+        index: Option<String>,
+    },
+    Owner {
+        device: String,
+        #[arg(long, value_name = "ClusNo")]
+        clus: u32,
+        #[arg(long, value_name = "FILE")]
+        index: Option<String>,
+    },
+    Info {
+        device: String,
+    },
+    CheckLayout {
+        device: String,
+    },
+    Prefetch {
+        device: String,
+        #[arg(default_value = "/")]
+        path: String,
+        #[arg(long)]
+        data: bool,
+    },
+    RescueBoot {
+        device: String,
+        #[arg(long, conflicts_with = "commit", value_name = "FILE")]
+        out: Option<String>,
+        #[arg(long)]
+        commit: bool,
+    },
+    Ls {
+        device: String,
+        #[arg(default_value = "/")]
+        path: String,
+        #[arg(short = 'H', long)]
+        no_header: bool,
+        #[arg(long)]
+        bytes: bool,
+        #[arg(long, value_enum, default_value_t = table::Times::Iso)]
+        times: table::Times,
+        #[arg(short = 'R', long)]
+        recursive: bool,
+        #[arg(long, conflicts_with_all = ["no_header", "bytes", "times"])]
+        jsonl: bool,
+    },
+    Fat {
+        device: String,
+        #[arg(long, value_parser = fatdump::parse_range, value_name = "FIRST..END")]
+        range: Option<std::ops::Range<u32>>,
+        #[arg(long)]
+        used: bool,
     },
     Fat32 {
         device: String,
@@ -46,6 +432,12 @@ enum Commands {
             value_name = "ClusNo"
         )]
         read_clus: u32,
+        #[arg(long, group = "instr")]
+        free: bool,
+        #[arg(long, group = "instr", value_name = "ClusNo")]
+        read_chain: Option<u32>,
+        #[arg(long, requires = "read_chain", value_name = "FILE")]
+        out: Option<String>,
     },
     Exfat {
         device: String,
@@ -61,61 +453,918 @@ enum Commands {
         read_clus: u32,
         #[arg(long, group = "instr", default_value_t = 0, value_name = "ClusNo")]
         read_dirents: u32,
+        #[arg(long, group = "instr")]
+        check_upcase: bool,
+        #[arg(long, group = "instr")]
+        boot_dump: bool,
+        #[arg(long, group = "instr")]
+        boot_diff: bool,
+        #[arg(long, group = "instr", value_name = "ClusNo")]
+        read_chain: Option<u32>,
+        #[arg(long, requires = "read_chain", value_name = "FILE")]
+        out: Option<String>,
     },
     Ext2 {
         device: String,
         #[arg(short, long, group = "instr")]
         info: bool,
+        #[arg(long, group = "instr")]
+        groups: bool,
+        #[arg(long, group = "instr", value_name = "INO")]
+        inode: Option<u32>,
+        #[arg(long, group = "instr", value_name = "PATH")]
+        cat: Option<String>,
+        #[arg(long, value_enum, default_value_t = ext2::Journal::Refuse)]
+        journal: ext2::Journal,
     },
     Mbr {
         device: String,
     },
+    #[command(group(ArgGroup::new("op").required(true)))]
+    MbrEdit {
+        device: String,
+        #[arg(long, group = "op", requires_all = ["start", "sectors", "part_type"], value_name = "SLOT")]
+        add: Option<usize>,
+        #[arg(long, group = "op", value_name = "SLOT")]
+        delete: Option<usize>,
+        #[arg(long, group = "op", requires = "sectors", value_name = "SLOT")]
+        resize: Option<usize>,
+        #[arg(long, group = "op", value_name = "SLOT")]
+        active: Option<usize>,
+        #[arg(long, group = "op", requires = "part_type", value_name = "SLOT")]
+        set_type: Option<usize>,
+        #[arg(long, value_name = "LBA")]
+        start: Option<u32>,
+        #[arg(long)]
+        sectors: Option<u32>,
+        #[arg(long = "type", value_parser = mbr::parse_type, value_name = "HEX")]
+        part_type: Option<u8>,
+    },
+    GptEdit {
+        device: String,
+        #[arg(long)]
+        create: bool,
+        #[arg(long, group = "op", requires_all = ["start", "sectors", "part_type"], value_name = "SLOT")]
+        add: Option<usize>,
+        #[arg(long, group = "op", value_name = "SLOT")]
+        remove: Option<usize>,
+        #[arg(long, group = "op", requires = "name", value_name = "SLOT")]
+        rename: Option<usize>,
+        #[arg(long, group = "op", requires = "part_type", value_name = "SLOT")]
+        retype: Option<usize>,
+        #[arg(long, value_name = "LBA")]
+        start: Option<u64>,
+        #[arg(long)]
+        sectors: Option<u64>,
+        #[arg(long = "type", value_name = "GUID")]
+        part_type: Option<gpt::Guid>,
+        #[arg(long)]
+        name: Option<String>,
+    },
+}
+
+// what `ctl` asks the daemon, see manager::Request
+#[derive(Subcommand)]
+enum CtlRequest {
+    List,
+    Mount {
+        device: String,
+        mount_point: String,
+        #[arg(short, long, value_enum)]
+        r#type: FsType,
+        #[arg(long)]
+        uid: Option<u32>,
+        #[arg(long)]
+        gid: Option<u32>,
+    },
+    Umount {
+        mount_point: String,
+    },
+    Stats,
 }
 
 impl clap::ValueEnum for FsType {
     fn value_variants<'a>() -> &'a [Self] {
-        &[FsType::Fat32, FsType::Exfat]
+        &[FsType::Fat32, FsType::Exfat, FsType::Squashfs, FsType::Udf]
     }
 
     fn to_possible_value(&self) -> Option<clap::builder::PossibleValue> {
         match *self {
             FsType::Fat32 => Some(PossibleValue::new("fat32")),
             FsType::Exfat => Some(PossibleValue::new("exfat")),
+            FsType::Squashfs => Some(PossibleValue::new("squashfs")),
+            FsType::Udf => Some(PossibleValue::new("udf")),
+            // only found on a disk's partitions, none of the commands taking
+            // a type reads it
+            FsType::Ext2 => None,
+        }
+    }
+}
+
+// the volume a command works on, see disk::open_volume. gives up with why
+// it can't be opened
+fn open_volume(path: &str, write: bool) -> device::Image {
+    disk::open_volume(path, write).unwrap_or_else(|e| {
+        eprintln!("{}: {}", path, e);
+        std::process::exit(exit::code(&e));
+    })
+}
+
+// the whole volume checked the way fsck does, exits when there are
+// problems and they're to be refused
+#[cfg(all(unix, feature = "fuse"))]
+fn verify_before_mount(device: &str, typ: &FsType, on_bad: fsck::OnBad) {
+    let verified = fsck::verify(open_volume(device, false)).unwrap_or_else(|e| exit::fail(e));
+    let Some(problems) = verified else {
+        eprintln!(
+            "[mount] only FAT32 and exFAT can be verified, {:?} is mounted unchecked",
+            typ
+        );
+        return;
+    };
+    for problem in problems.iter() {
+        eprintln!("[mount] {}", problem);
+    }
+    match (problems.len(), on_bad) {
+        (0, _) => say!("[mount] verified, no problems found"),
+        (n, fsck::OnBad::Refuse) => {
+            eprintln!(
+                "[mount] {} problems found, not mounting. run fsck, or mount with --on-bad warn",
+                n
+            );
+            std::process::exit(exit::CORRUPT);
         }
+        (n, fsck::OnBad::Warn) => eprintln!(
+            "[mount] WARNING: {} problems found, mounting anyway. what's served may be corrupt",
+            n
+        ),
     }
 }
 
+// the cluster chain from `first` on to `out`, or stdout without one
+fn stream_chain(device: &str, first: u32, out: Option<&str>) {
+    let file = open_volume(device, false);
+    let done = match out {
+        Some(path) => std::fs::File::create(path)
+            .and_then(|f| clusters::read_chain(&file, first, &mut std::io::BufWriter::new(f))),
+        None => clusters::read_chain(&file, first, &mut std::io::stdout().lock()),
+    };
+    if let Err(e) = done {
+        exit::fail(e);
+    }
+}
+
+// a file name in the working dir made unique by the current unix time
+fn stamped(prefix: &str, ext: &str) -> PathBuf {
+    let secs = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    PathBuf::from(format!("{}-{}.{}", prefix, secs, ext))
+}
+
+// clap exits with 2 on bad arguments, here that's exit::USAGE
+fn usage(e: clap::Error) -> Cli {
+    let _ = e.print();
+    std::process::exit(match e.use_stderr() {
+        true => exit::USAGE,
+        false => exit::OK,
+    })
+}
+
+// a mount command where this build can't mount. on Windows that takes a
+// WinFsp adapter, which there isn't yet
+#[cfg(not(all(unix, feature = "fuse")))]
+fn no_mount(cmd: &str) -> ! {
+    match cfg!(windows) {
+        true => eprintln!(
+            "{} isn't supported on Windows yet, there's no WinFsp adapter",
+            cmd
+        ),
+        false => eprintln!(
+            "{} needs the fuse feature, which is available on Linux and macOS",
+            cmd
+        ),
+    }
+    std::process::exit(exit::USAGE);
+}
+
+// whether the command goes to the background in a forked child
+#[cfg(unix)]
+fn forks(command: &Commands) -> bool {
+    matches!(command, Commands::Mount { background, daemon, .. } if *background || *daemon)
+}
+
 fn main() {
-    let cli = Cli::parse();
+    #[cfg(all(unix, feature = "fuse"))]
+    let cli = match mount_helper::translate(&std::env::args().collect::<Vec<_>>()) {
+        Some(Ok(args)) => Cli::try_parse_from(args).unwrap_or_else(usage),
+        Some(Err(e)) => {
+            eprintln!("[{}] {}", mount_helper::NAME, e);
+            std::process::exit(exit::USAGE);
+        }
+        None => Cli::try_parse().unwrap_or_else(usage),
+    };
+    #[cfg(not(all(unix, feature = "fuse")))]
+    let cli = Cli::try_parse().unwrap_or_else(usage);
+    if cli.quiet {
+        exit::set_quiet();
+    }
+    if cli.odirect {
+        device::set_odirect();
+    }
+    if let Some(unit) = cli.stripe {
+        device::set_stripe(unit);
+    }
+    if cli.split {
+        device::set_split();
+    }
+    if let Some(no) = cli.partition {
+        disk::set_partition(no as usize);
+    }
+    if cli.strict {
+        exfat::set_strict();
+    }
+    if cli.sanitize {
+        fio::set_sanitize();
+    }
+    if cli.nfc {
+        fio::set_nfc();
+    }
+    if let Some(bytes) = cli.cache_mem {
+        cache::set_budget(bytes);
+    }
+    if let Some(rate) = cli.max_rate {
+        throttle::set_max_rate(rate);
+    }
+    if cli.idle {
+        if let Err(e) = throttle::set_idle() {
+            exit::fail(e);
+        }
+    }
+    if let Some(path) = &cli.trace_io {
+        if let Err(e) = trace::start(path) {
+            exit::fail(e);
+        }
+    }
+    let _stats = cli.stats.then(|| {
+        // a forked child keeps only the thread that forked, a mount going
+        // to the background starts it in the child, see mount_helper::detach
+        #[cfg(unix)]
+        if !forks(&cli.command) {
+            stats::dump_on_sigusr1();
+        }
+        stats::DumpOnDrop
+    });
 
     match &cli.command {
         Commands::Mount {
             device,
             mount_point,
             r#type,
+            forensic,
+            direct_io,
+            keep_cache,
+            readahead,
+            background,
+            daemon,
+            foreground: _,
+            reconnect,
+            prefetch,
+            verify,
+            on_bad,
+            uid,
+            gid,
+            long_names,
         } => {
-            let opts = vec![
-                MountOption::AllowOther,
-                MountOption::AutoUnmount,
-                MountOption::RO,
-            ];
-            match fuser::mount2(FuseW::new(device, r#type.clone()), mount_point, &opts) {
-                Ok(()) => (),
-                Err(e) => {
-                    println!("{}", e);
+            #[cfg(all(unix, feature = "fuse"))]
+            {
+                fat32fuse::set_owner(*uid, *gid);
+                fs::set_long_names(*long_names);
+                if *reconnect {
+                    gone::set_reconnect();
+                }
+                // before going to the background, so a refusal shows up
+                // where the mount was asked for
+                if *verify {
+                    verify_before_mount(device, r#type, *on_bad);
+                }
+                if *daemon {
+                    mount_helper::daemon(mount_point, cli.stats);
+                } else if *background {
+                    mount_helper::background(mount_point, cli.stats);
+                }
+                // reads always reach us with direct_io, the kernel keeps
+                // file data cached across opens with keep_cache
+                let open_flags = match (direct_io, keep_cache) {
+                    (true, _) => fuser::consts::FOPEN_DIRECT_IO,
+                    (_, true) => fuser::consts::FOPEN_KEEP_CACHE,
+                    _ => 0,
+                };
+                let opts = mount_helper::options(device);
+                let mut fuse =
+                    FuseW::new(device, r#type.clone(), *forensic, open_flags, *readahead)
+                        .unwrap_or_else(|e| exit::fail(e));
+                if let Some(path) = prefetch {
+                    match fuse.prefetch(path) {
+                        Ok(done) => say!("[mount] prefetched {}", done),
+                        Err(e) => eprintln!("[mount] prefetching {} failed, {}", path, e),
+                    }
+                }
+                match fuser::mount2(fuse, mount_point, &opts) {
+                    Ok(()) => (),
+                    Err(e) => {
+                        exit::fail(e);
+                    }
+                };
+            }
+            // no FUSE here, the other commands work on the device directly
+            #[cfg(not(all(unix, feature = "fuse")))]
+            {
+                let _ = (
+                    device,
+                    mount_point,
+                    r#type,
+                    forensic,
+                    direct_io,
+                    keep_cache,
+                    readahead,
+                    background,
+                    daemon,
+                    reconnect,
+                    prefetch,
+                    verify,
+                    on_bad,
+                    uid,
+                    gid,
+                    long_names,
+                );
+                no_mount("mount");
+            }
+        }
+        Commands::Umount { mount_point, lazy } => {
+            #[cfg(all(unix, feature = "fuse"))]
+            {
+                // only ours, where the kernel's table can be read
+                let dir = std::fs::canonicalize(mount_point)
+                    .map(|dir| dir.to_string_lossy().into_owned())
+                    .unwrap_or_else(|_| mount_point.clone());
+                if let Ok(mounts) = mount_helper::mounts() {
+                    if !mounts.iter().any(|(_, at)| *at == dir) {
+                        eprintln!("[umount] {} isn't a fat32x mount", dir);
+                        std::process::exit(exit::USAGE);
+                    }
                 }
+                if let Err(e) = mount_helper::unmount(&dir, *lazy) {
+                    eprintln!("[umount] {}", e);
+                    std::process::exit(exit::code(&e));
+                }
+                say!("[umount] {} unmounted", dir);
+            }
+            #[cfg(not(all(unix, feature = "fuse")))]
+            {
+                let _ = (mount_point, lazy);
+                no_mount("umount");
+            }
+        }
+        Commands::Mounts { no_header } => {
+            #[cfg(all(unix, feature = "fuse"))]
+            {
+                let mounts = mount_helper::mounts().unwrap_or_else(|e| exit::fail(e));
+                let mut table = table::Table::new(&["DEVICE", "MOUNTPOINT"], !no_header);
+                for (device, dir) in mounts {
+                    table.row(vec![device, dir]);
+                }
+                table.print();
+            }
+            #[cfg(not(all(unix, feature = "fuse")))]
+            {
+                let _ = no_header;
+                no_mount("mounts");
+            }
+        }
+        Commands::Daemon { socket } => {
+            #[cfg(all(unix, feature = "fuse"))]
+            {
+                let socket = socket
+                    .as_ref()
+                    .map_or_else(manager::default_socket, PathBuf::from);
+                if let Err(e) = manager::daemon(&socket) {
+                    exit::fail(e);
+                }
+            }
+            #[cfg(not(all(unix, feature = "fuse")))]
+            {
+                let _ = socket;
+                no_mount("daemon");
+            }
+        }
+        Commands::Ctl { socket, request } => {
+            #[cfg(all(unix, feature = "fuse"))]
+            {
+                let socket = socket
+                    .as_ref()
+                    .map_or_else(manager::default_socket, PathBuf::from);
+                let request = match request {
+                    CtlRequest::List => manager::Request::List,
+                    CtlRequest::Mount {
+                        device,
+                        mount_point,
+                        r#type,
+                        uid,
+                        gid,
+                    } => manager::Request::Mount(
+                        r#type.clone(),
+                        device.clone(),
+                        mount_point.clone(),
+                        (*uid, *gid),
+                    ),
+                    CtlRequest::Umount { mount_point } => {
+                        manager::Request::Umount(mount_point.clone())
+                    }
+                    CtlRequest::Stats => manager::Request::Stats,
+                };
+                if let Err(e) = manager::ctl(&socket, &request) {
+                    exit::fail(e);
+                }
+            }
+            #[cfg(not(all(unix, feature = "fuse")))]
+            {
+                let _ = (socket, request);
+                no_mount("ctl");
+            }
+        }
+        Commands::MountDisk {
+            device,
+            mount_point,
+            reconnect,
+            uid,
+            gid,
+            long_names,
+        } => {
+            #[cfg(all(unix, feature = "fuse"))]
+            {
+                fat32fuse::set_owner(*uid, *gid);
+                fs::set_long_names(*long_names);
+                if *reconnect {
+                    gone::set_reconnect();
+                }
+                let opts = mount_helper::options(device);
+                match diskfuse::DiskFuse::new(device)
+                    .and_then(|fs| fuser::mount2(fs, mount_point, &opts))
+                {
+                    Ok(()) => (),
+                    Err(e) => {
+                        exit::fail(e);
+                    }
+                };
+            }
+            #[cfg(not(all(unix, feature = "fuse")))]
+            {
+                let _ = (device, mount_point, reconnect, uid, gid, long_names);
+                no_mount("mount-disk");
+            }
+        }
+        Commands::Extract {
+            device,
+            dest,
+            r#type,
+            jobs,
+            verify,
+            manifest,
+            rescue,
+            names,
+            retry,
+        } => {
+            let file = open_volume(device, false);
+            let mut retry_opts = retry.opts();
+            if rescue.is_some() {
+                // unreadable sectors come back as zeros, the map says where
+                retry_opts.on_error = retry::OnError::Zero;
+            }
+            let file = retry::Retry::new(file, retry_opts);
+            let opts = extract::ExtractOpts {
+                jobs: *jobs,
+                names: *names,
+                manifest: verify.then(|| match manifest {
+                    Some(path) => PathBuf::from(path),
+                    None => PathBuf::from(format!("{}.sha256", dest.trim_end_matches('/'))),
+                }),
+                map: rescue.as_ref().map(PathBuf::from),
+            };
+            if let Err(e) = extract::extract(&file, r#type, Path::new(dest), &opts) {
+                exit::fail(e);
+            }
+            retry::report(&file);
+        }
+        Commands::Recover { device, dest } => {
+            let file = open_volume(device, false);
+            let mut fio = exfat::Fio::new(file).unwrap_or_else(|e| exit::fail(e));
+            let found = fio.recover_entsets().unwrap_or_else(|e| exit::fail(e));
+            for (parent, fi) in found.iter() {
+                println!(
+                    "{}{}  {} bytes  first cluster {}",
+                    parent, fi.name, fi.size, fi.fst_clus
+                );
+            }
+            say!("[recover] {} entry sets recovered", found.len());
+            if let Some(dest) = dest {
+                let mut ents: Vec<Finfo> = found.into_iter().map(|(_, fi)| fi).collect();
+                fio::dedup_names(&mut ents);
+                if let Err(e) = extract::extract_entries(&mut fio, ents, Path::new(dest)) {
+                    exit::fail(e);
+                }
+            }
+        }
+        Commands::Put {
+            device,
+            host_file,
+            dest_path,
+        } => {
+            let file = open_volume(device, true);
+            if let Err(e) = put::put(&file, Path::new(host_file), dest_path) {
+                exit::fail(e);
+            }
+        }
+        Commands::Rm {
+            device,
+            path,
+            recursive,
+        } => {
+            let file = open_volume(device, true);
+            if let Err(e) = rm::rm(&file, path, *recursive) {
+                exit::fail(e);
+            }
+        }
+        Commands::Mv {
+            device,
+            src_path,
+            dst_path,
+        } => {
+            let file = open_volume(device, true);
+            if let Err(e) = mv::mv(&file, src_path, dst_path) {
+                exit::fail(e);
+            }
+        }
+        Commands::Defrag {
+            device,
+            dry_run,
+            journal,
+            rollback,
+        } => {
+            let file = open_volume(device, !*dry_run);
+            if let Some(rollback) = rollback {
+                match journal::rollback(&file, Path::new(rollback)) {
+                    Ok(n) => say!("[defrag] rolled back {} sectors", n),
+                    Err(e) => exit::fail(e),
+                }
+                return;
+            }
+            let journal = match journal {
+                Some(path) => PathBuf::from(path),
+                None => stamped("fat32x-defrag", "journal"),
+            };
+            let mut fio = fat32::fio::Fio::new(file).unwrap_or_else(|e| exit::fail(e));
+            if let Err(e) = defrag::defrag(&mut fio, *dry_run, &journal) {
+                exit::fail(e);
+            }
+        }
+        Commands::Trim { device, r#type } => {
+            let file = open_volume(device, true);
+            if let Err(e) = space::trim(&file, r#type) {
+                exit::fail(e);
+            }
+        }
+        Commands::WipeFree {
+            device,
+            r#type,
+            pattern,
+            slack,
+        } => {
+            let file = open_volume(device, true);
+            if let Err(e) = space::wipe_free(&file, r#type, pattern, *slack) {
+                exit::fail(e);
+            }
+        }
+        Commands::Clone {
+            src,
+            dst,
+            r#type,
+            retry,
+        } => {
+            let file = open_volume(src, false);
+            let file = retry::Retry::new(file, retry.opts());
+            if let Err(e) = space::clone(&file, r#type, Path::new(dst)) {
+                exit::fail(e);
+            }
+            retry::report(&file);
+        }
+        Commands::Sparsify { device, r#type } => {
+            let file = open_volume(device, true);
+            if let Err(e) = space::sparsify(&file, r#type) {
+                exit::fail(e);
+            }
+        }
+        Commands::Diff {
+            image_a,
+            image_b,
+            r#type,
+            hash,
+        } => {
+            if let Err(e) = diff::diff(Path::new(image_a), Path::new(image_b), r#type, *hash) {
+                exit::fail(e);
+            }
+        }
+        Commands::Resize {
+            device,
+            size,
+            shrink_to_used: _,
+            journal,
+            rollback,
+        } => {
+            if let Some(rollback) = rollback {
+                let file = open_volume(device, true);
+                match journal::rollback(&file, Path::new(rollback)) {
+                    Ok(n) => say!("[resize] rolled back {} sectors", n),
+                    Err(e) => exit::fail(e),
+                }
+                return;
+            }
+            let journal = match journal {
+                Some(path) => PathBuf::from(path),
+                None => stamped("fat32x-resize", "journal"),
+            };
+            let file = open_volume(device, true);
+            if let Err(e) = resize::resize(&file, *size, &journal) {
+                exit::fail(e);
+            }
+        }
+        Commands::Attrib {
+            help: _,
+            device,
+            path,
+            r#type,
+            flags,
+        } => {
+            let file = open_volume(device, !flags.is_empty());
+            if let Err(e) = attrib::attrib(&file, r#type, path, flags) {
+                exit::fail(e);
+            }
+        }
+        Commands::Touch {
+            device,
+            path,
+            r#type,
+            mtime,
+            crtime,
+            atime,
+        } => {
+            let times = touch::Times {
+                mtime: mtime.clone(),
+                crtime: crtime.clone(),
+                atime: atime.clone(),
+            };
+            let file = open_volume(device, !times.is_empty());
+            if let Err(e) = touch::touch(&file, r#type, path, &times) {
+                exit::fail(e);
+            }
+        }
+        Commands::Align {
+            device,
+            erase_block,
+        } => {
+            let file = device::open(device, false).unwrap_or_else(|e| exit::fail(e));
+            if let Err(e) = align::check(&file, *erase_block) {
+                exit::fail(e);
+            }
+        }
+        Commands::Fsck {
+            device,
+            repair,
+            backup,
+            recover_orphans,
+        } => {
+            let file = open_volume(device, *repair);
+            if disk::detect(&file, 0) == Some("exFAT") {
+                if *repair {
+                    say!("[fsck] exFAT volumes are only checked, nothing is repaired");
+                }
+                let mut fio = exfat::Fio::new(file).unwrap_or_else(|e| exit::fail(e));
+                let mut fsck = fsck_exfat::Fsck::new(&mut fio).unwrap_or_else(|e| exit::fail(e));
+                fsck.check().unwrap_or_else(|e| exit::fail(e));
+                for problem in fsck.problems.iter() {
+                    println!("{}", problem);
+                }
+                say!("[fsck] {} problems found", fsck.problems.len());
+                if !fsck.problems.is_empty() {
+                    std::process::exit(exit::CORRUPT);
+                }
+                return;
+            }
+            let mut fio = fat32::fio::Fio::new(file).unwrap_or_else(|e| exit::fail(e));
+            let mut fsck = fsck::Fsck::new(&mut fio).unwrap_or_else(|e| exit::fail(e));
+            fsck.check().unwrap_or_else(|e| exit::fail(e));
+            for problem in fsck.problems.iter() {
+                println!("{}", problem);
+            }
+            say!("[fsck] {} problems found", fsck.problems.len());
+            if *repair && !fsck.problems.is_empty() {
+                let opts = fsck::RepairOpts {
+                    backup: match backup {
+                        Some(path) => PathBuf::from(path),
+                        None => stamped("fat32x-fsck", "bak"),
+                    },
+                    recover_orphans: *recover_orphans,
+                };
+                match fsck.repair(&opts) {
+                    Ok(n) => say!(
+                        "[fsck] repaired, {} sectors written, backup: {}",
+                        n,
+                        opts.backup.display()
+                    ),
+                    Err(e) => exit::fail(e),
+                }
+            } else if !fsck.problems.is_empty() {
+                std::process::exit(exit::CORRUPT);
+            }
+        }
+        Commands::Carve {
+            device,
+            dest,
+            r#type,
+            min_size,
+            max_size,
+        } => {
+            let file = open_volume(device, false);
+            let opts = carve::CarveOpts {
+                min_size: *min_size,
+                max_size: *max_size,
             };
+            if let Err(e) = carve::carve(&file, r#type, Path::new(dest), &opts) {
+                exit::fail(e);
+            }
+        }
+        Commands::ClustersOf { device, path } => {
+            let file = open_volume(device, false);
+            match clusters::clusters_of(&file, path) {
+                // one run a line, as dd's skip= and count= in bytes
+                Ok(runs) => {
+                    for (off, len) in runs {
+                        println!("{} {}", off, len);
+                    }
+                }
+                Err(e) => exit::fail(e),
+            }
+        }
+        Commands::CarveChain {
+            device,
+            dest,
+            start_clus,
+            length,
+        } => {
+            let file = open_volume(device, false);
+            if let Err(e) = clusters::carve_chain(&file, *start_clus, *length, Path::new(dest)) {
+                exit::fail(e);
+            }
+        }
+        Commands::Grep {
+            device,
+            pattern,
+            allocated_only,
+            free_only,
+        } => {
+            let file = open_volume(device, false);
+            let only = match (allocated_only, free_only) {
+                (true, _) => search::Only::Allocated,
+                (_, true) => search::Only::Free,
+                _ => search::Only::All,
+            };
+            if let Err(e) = search::grep(&file, pattern, only) {
+                exit::fail(e);
+            }
+        }
+        Commands::Whose {
+            device,
+            offset,
+            index,
+        } => {
+            let file = open_volume(device, false);
+            match clusters::whose(&file, *offset, index.as_deref().map(Path::new)) {
+                Ok(who) => println!("{}", who),
+                Err(e) => exit::fail(e),
+            }
+        }
+        Commands::Owner {
+            device,
+            clus,
+            index,
+        } => {
+            let file = open_volume(device, false);
+            match clusters::owner(&file, *clus, index.as_deref().map(Path::new)) {
+                Ok(who) => println!("cluster {}: {}", clus, who),
+                Err(e) => exit::fail(e),
+            }
+        }
+        Commands::Info { device } => {
+            if let Err(e) = info::info(device) {
+                exit::fail(e);
+            }
+        }
+        Commands::CheckLayout { device } => match layout::check(device) {
+            Ok(0) => (),
+            Ok(_) => std::process::exit(exit::CORRUPT),
+            Err(e) => exit::fail(e),
+        },
+        Commands::Prefetch { device, path, data } => {
+            let file = open_volume(device, false);
+            let start = std::time::Instant::now();
+            match prefetch::prefetch(&file, path, *data) {
+                Ok(done) => say!(
+                    "[prefetch] {} read in {:.1}s",
+                    done,
+                    start.elapsed().as_secs_f64()
+                ),
+                Err(e) => exit::fail(e),
+            }
+        }
+        Commands::RescueBoot {
+            device,
+            out,
+            commit,
+        } => {
+            let file = open_volume(device, *commit);
+            let out = match out {
+                Some(path) => PathBuf::from(path),
+                None => stamped("fat32x-boot", "bin"),
+            };
+            if let Err(e) = rescue_boot::run(&file, &out, *commit) {
+                exit::fail(e);
+            }
+        }
+        Commands::Ls {
+            device,
+            path,
+            no_header,
+            bytes,
+            times,
+            recursive,
+            jsonl,
+        } => {
+            let opts = ls::LsOpts {
+                header: !no_header,
+                bytes: *bytes,
+                times: *times,
+                recursive: *recursive,
+                jsonl: *jsonl,
+            };
+            if let Err(e) = ls::ls(&open_volume(device, false), path, &opts) {
+                exit::fail(e);
+            }
+        }
+        Commands::Fat {
+            device,
+            range,
+            used,
+        } => {
+            let file = open_volume(device, false);
+            let range = range.clone().unwrap_or(0..u32::MAX);
+            if let Err(e) = fatdump::dump(file, range, *used) {
+                exit::fail(e);
+            }
         }
         Commands::Fat32 {
             device,
             info,
             read_clus,
+            free,
+            read_chain,
+            out,
         } => {
-            let mut fio = fat32::fio::Fio::new(File::open(device).unwrap());
+            if let Some(first) = read_chain {
+                stream_chain(device, *first, out.as_deref());
+                return;
+            }
+            let mut fio =
+                fat32::fio::Fio::new(open_volume(device, false)).unwrap_or_else(|e| exit::fail(e));
             if *info {
                 println!("{:?}", fio.bootsec)
+            } else if *free {
+                let scanned = fio.scan_free().unwrap_or_else(|e| exit::fail(e));
+                println!("[fat32] {} of {} clusters free", scanned, fio.clus_cnt());
+                match fio.fsinfo() {
+                    None => println!("[fat32] no FSInfo sector, statfs scans the FAT"),
+                    Some(fsinfo) if fsinfo.free_count == 0xFFFFFFFF => {
+                        println!("[fat32] FSInfo free count unknown, statfs scans the FAT")
+                    }
+                    Some(fsinfo) if fsinfo.free_count != scanned => println!(
+                        "[fat32] FSInfo free count is {}, off by {}",
+                        fsinfo.free_count,
+                        fsinfo.free_count as i64 - scanned as i64
+                    ),
+                    Some(_) => println!("[fat32] FSInfo free count agrees"),
+                }
             } else if *read_clus != 0 {
-                let clus = fio.read_clus(*read_clus);
+                let clus = fio.read_clus(*read_clus).unwrap_or_else(|e| exit::fail(e));
                 std::io::stdout().write_all(&clus).unwrap();
             }
         }
@@ -124,32 +1373,276 @@ fn main() {
             info,
             read_clus,
             read_dirents,
+            check_upcase,
+            boot_dump,
+            boot_diff,
+            read_chain,
+            out,
         } => {
-            let file = File::open(device).expect("device can't be opened");
-            let mut fio = exfat::Fio::new(file);
+            if let Some(first) = read_chain {
+                stream_chain(device, *first, out.as_deref());
+                return;
+            }
+            let file = open_volume(device, false);
+            // the boot regions are read as they are, valid or not
+            if *boot_dump || *boot_diff {
+                let done = if *boot_dump {
+                    exfat_boot::dump(&file)
+                } else {
+                    exfat_boot::diff(&file)
+                };
+                if let Err(e) = done {
+                    exit::fail(e);
+                }
+                return;
+            }
+            // the up-case table is checked below, --strict or not
+            let fio = match check_upcase {
+                true => exfat::Fio::lenient(file),
+                false => exfat::Fio::new(file),
+            };
+            let mut fio = fio.unwrap_or_else(|e| exit::fail(e));
             if *info {
-                println!("{:?}", fio.bootsec)
+                let b = &fio.bootsec;
+                println!("{:?}", b);
+                println!(
+                    "volume flags: active FAT {}, dirty {}, media failure {}",
+                    b.active_fat(),
+                    b.is_dirty(),
+                    b.media_failure()
+                );
+                match b.percent_in_use() {
+                    Some(p) => println!("percent in use: {}%", p),
+                    None => println!("percent in use: not recorded"),
+                }
+                for warning in b.flag_warnings() {
+                    println!("warning: {}", warning);
+                }
             } else if *read_clus != 0 {
-                let clus = fio.read_clus(*read_clus);
+                let clus = fio.read_clus(*read_clus).unwrap_or_else(|e| exit::fail(e));
                 std::io::stdout().write_all(&clus).unwrap();
             } else if *read_dirents != 0 {
-                let ents = fio.read_dirents(*read_dirents);
+                let ents = fio
+                    .read_dirents(*read_dirents)
+                    .unwrap_or_else(|e| exit::fail(e));
                 println!("{:#?}", ents);
+            } else if *check_upcase {
+                match fio.check_upcase() {
+                    Ok(table) => println!("up-case table ok, {} code units mapped", table.mapped()),
+                    Err(e) => {
+                        eprintln!("up-case table: {}", e);
+                        std::process::exit(exit::CORRUPT);
+                    }
+                }
             }
         }
-        Commands::Ext2 { device, info } => {
-            let file = File::open(device).expect("device can't be opened");
-            let fio = ext2::Fio::new(file);
+        Commands::Ext2 {
+            device,
+            info,
+            groups,
+            inode,
+            cat,
+            journal,
+        } => {
+            let file = open_volume(device, false);
+            let mut fio = match ext2::Fio::new(file, *journal) {
+                Ok(fio) => fio,
+                Err(e) => {
+                    exit::fail(e);
+                }
+            };
             if *info {
                 println!("{:?}", fio.sblk);
+                let st = fio.sblk.statfs();
+                let bsize = st.bsize as u64;
+                println!(
+                    "size: {}, free: {}, available: {}",
+                    table::size(st.blocks * bsize, false),
+                    table::size(st.bfree * bsize, false),
+                    table::size(st.bavail * bsize, false)
+                );
+                println!("inodes: {}, free: {}", st.files, st.ffree);
+            } else if *groups {
+                let groups = fio.read_groups().unwrap_or_else(|e| exit::fail(e));
+                for (i, g) in groups.iter().enumerate() {
+                    println!(
+                        "group {}: block bitmap {}, inode bitmap {}, inode table {}, \
+                         free blocks {}, free inodes {}, dirs {}",
+                        i,
+                        g.block_bitmap,
+                        g.inode_bitmap,
+                        g.inode_table,
+                        g.free_blocks_count,
+                        g.free_inodes_count,
+                        g.used_dirs_count
+                    );
+                }
+            } else if let Some(ino) = *inode {
+                match fio.read_inode(ino) {
+                    Ok(Some(inode)) => println!("inode {}\n{}", ino, inode),
+                    Ok(None) => exit::fail(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("no inode {}", ino),
+                    )),
+                    Err(e) => exit::fail(e),
+                }
+            } else if let Some(path) = cat {
+                let res = fio
+                    .lookup_path(path)
+                    .and_then(|(_, inode)| fio.cat(&inode, &mut std::io::stdout().lock()));
+                if let Err(e) = res {
+                    exit::fail(e);
+                }
             }
         }
         Commands::Mbr { device } => {
-            let mut file = File::open(device).expect("device can't be opened");
+            let file = device::open(device, false).unwrap_or_else(|e| exit::fail(e));
             let mut buf = [0u8; 512];
-            file.read_exact(&mut buf).unwrap();
+            file.read_exact_at(&mut buf, 0)
+                .unwrap_or_else(|e| exit::fail(e));
             let mbr = Mbr::new(&buf).unwrap();
             println!("{:X?}", mbr);
+
+            let disk_secs = device::size(&file).unwrap_or_else(|e| exit::fail(e)) / gpt::SEC_SZ;
+            let parts = mbr.partitions();
+            let protective = parts
+                .iter()
+                .filter(|p| p.typ() == mbr::TYPE_PROTECTIVE)
+                .count();
+            let Ok(gpt) = gpt::Gpt::read(&file, disk_secs) else {
+                if protective != 0 {
+                    eprintln!("[mbr] protective entry without a valid GPT behind it");
+                }
+                return;
+            };
+            let used = parts.iter().filter(|p| !p.is_empty()).count();
+            let mismatches = gpt::cross_check(&mbr, &gpt);
+            match protective {
+                0 => println!("[mbr] GPT disk with a plain MBR"),
+                _ if used > protective => println!("[mbr] hybrid MBR, the GPT is authoritative"),
+                _ => println!("[mbr] protective MBR, see gpt-edit for the partitions"),
+            }
+            for m in mismatches.iter() {
+                println!("[mbr] {}", m);
+            }
+        }
+        Commands::MbrEdit {
+            device,
+            add,
+            delete,
+            resize,
+            active,
+            set_type,
+            start,
+            sectors,
+            part_type,
+        } => {
+            let file = device::open(device, true).unwrap_or_else(|e| exit::fail(e));
+            let mut buf = [0u8; Mbr::SZ];
+            // a fresh image has nothing to keep, start from a blank table
+            let mut mbr = match file.read_exact_at(&mut buf, 0).map(|_| Mbr::new(&buf)) {
+                Ok(Ok(mbr)) if mbr.is_valid() => mbr,
+                _ => {
+                    say!("[mbr] no signature, starting from an empty table");
+                    Mbr::empty()
+                }
+            };
+            let res = if let Some(slot) = add {
+                mbr.add(*slot, part_type.unwrap(), start.unwrap(), sectors.unwrap())
+            } else if let Some(slot) = delete {
+                mbr.delete(*slot)
+            } else if let Some(slot) = resize {
+                mbr.resize(*slot, sectors.unwrap())
+            } else if let Some(slot) = active {
+                mbr.set_active(*slot)
+            } else if let Some(slot) = set_type {
+                mbr.set_type(*slot, part_type.unwrap())
+            } else {
+                unreachable!()
+            };
+            if let Err(e) = res {
+                exit::fail(e);
+            }
+            if let Err(e) = file.write_all_at(&mbr.dump(), 0) {
+                exit::fail(e);
+            }
+            for (i, part) in mbr.partitions().iter().enumerate() {
+                if !part.is_empty() {
+                    println!(
+                        "{}{} type 0x{:02X}  start {}  {} sectors",
+                        i + 1,
+                        if part.is_active() { "*" } else { " " },
+                        part.typ(),
+                        part.lba(),
+                        part.nsecs()
+                    );
+                }
+            }
+        }
+        Commands::GptEdit {
+            device,
+            create,
+            add,
+            remove,
+            rename,
+            retype,
+            start,
+            sectors,
+            part_type,
+            name,
+        } => {
+            let file = device::open(device, true).unwrap_or_else(|e| exit::fail(e));
+            let disk_secs = device::size(&file).unwrap_or_else(|e| exit::fail(e)) / gpt::SEC_SZ;
+            let gpt = if *create {
+                gpt::Gpt::new(disk_secs)
+            } else {
+                gpt::Gpt::read(&file, disk_secs)
+            };
+            let mut gpt = match gpt {
+                Ok(gpt) => gpt,
+                Err(e) => {
+                    exit::fail(e);
+                }
+            };
+            let name = name.as_deref().unwrap_or("");
+            let res = if let Some(slot) = add {
+                gpt.add(
+                    *slot,
+                    part_type.unwrap(),
+                    start.unwrap(),
+                    sectors.unwrap(),
+                    name,
+                )
+            } else if let Some(slot) = remove {
+                gpt.remove(*slot)
+            } else if let Some(slot) = rename {
+                gpt.rename(*slot, name)
+            } else if let Some(slot) = retype {
+                gpt.retype(*slot, part_type.unwrap())
+            } else {
+                Ok(())
+            };
+            let changed = *create || add.or(*remove).or(*rename).or(*retype).is_some();
+            if let Err(e) = res.and_then(|_| match changed {
+                true => gpt.write(&file),
+                false => Ok(()),
+            }) {
+                exit::fail(e);
+            }
+            println!("disk {}", gpt.disk_guid());
+            for (i, ent) in gpt.entries.iter().enumerate() {
+                if !ent.is_empty() {
+                    println!(
+                        "{:<3} {}  {}..={}  {}  {}",
+                        i + 1,
+                        ent.type_guid,
+                        ent.first_lba,
+                        ent.last_lba,
+                        ent.unique_guid,
+                        ent.name()
+                    );
+                }
+            }
         }
     }
 }
@@ -0,0 +1,460 @@
+// `fat32x daemon`: several mounts served by one process, added and taken
+// down at runtime through a unix socket that `fat32x ctl` talks to. mounts
+// of the same image share one open device and a cache of what was read
+// from it
+//
+// the protocol is a line per connection, the request's words separated by
+// tabs, an empty word for an option not given, answered with `ok` and the lines of the answer, or with
+// `error<TAB>exit code<TAB>why`
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io::{self, BufRead, BufReader, Write},
+    os::unix::{
+        fs::{MetadataExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use clap::ValueEnum;
+
+use crate::cache::Cache;
+use crate::device::{Device, Image};
+use crate::disk;
+use crate::exit;
+use crate::fat32fuse::{self, FuseW};
+use crate::fio::FsType;
+use crate::gone::Watch;
+use crate::mount_helper;
+use crate::stats;
+use crate::table::{self, Table, Times};
+
+// what's cached is read in blocks this big
+const BLOCK_SZ: u64 = 64 << 10;
+// the shares of the cache budget an image's blocks get, against a
+// mount's dir listings, see cache::Cache
+const BLOCKS_WEIGHT: u64 = 4;
+
+// where the socket is when --socket isn't given
+pub fn default_socket() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => Path::new(&dir).join("fat32x.sock"),
+        None => PathBuf::from(format!("/tmp/fat32x-{}.sock", unsafe { libc::getuid() })),
+    }
+}
+
+// an image opened once for all its mounts
+struct Shared {
+    path: String,
+    image: Image,
+    blocks: Mutex<Blocks>,
+    cache: Cache,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+// blocks by number, evicted oldest first
+#[derive(Default)]
+struct Blocks {
+    map: BTreeMap<u64, Arc<Vec<u8>>>,
+    order: VecDeque<u64>,
+}
+
+impl Shared {
+    // the block, shorter than BLOCK_SZ at the end of the image
+    fn block(&self, no: u64) -> io::Result<Arc<Vec<u8>>> {
+        if let Some(block) = self.blocks.lock().unwrap().map.get(&no) {
+            stats::add(&self.hits, 1);
+            return Ok(block.clone());
+        }
+        stats::add(&self.misses, 1);
+        let mut buf = vec![0u8; BLOCK_SZ as usize];
+        let mut len = 0;
+        while len < buf.len() {
+            match self
+                .image
+                .read_at(&mut buf[len..], no * BLOCK_SZ + len as u64)
+            {
+                Ok(0) => break,
+                Ok(n) => len += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+        buf.truncate(len);
+        let block = Arc::new(buf);
+        let mut blocks = self.blocks.lock().unwrap();
+        match blocks.map.insert(no, block.clone()) {
+            Some(old) => self.cache.release(old.len() as u64),
+            None => blocks.order.push_back(no),
+        }
+        self.cache.charge(block.len() as u64);
+        while self.cache.over() && blocks.order.len() > 1 {
+            let old = blocks.order.pop_front().unwrap();
+            if let Some(old) = blocks.map.remove(&old) {
+                self.cache.evicted(old.len() as u64);
+            }
+        }
+        Ok(block)
+    }
+
+    fn cached_bytes(&self) -> u64 {
+        let blocks = self.blocks.lock().unwrap();
+        blocks.map.values().map(|b| b.len() as u64).sum()
+    }
+}
+
+// what a mount reads the shared image through
+struct Cached(Arc<Shared>);
+
+impl Device for Cached {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let block = self.0.block(offset / BLOCK_SZ)?;
+        let from = (offset % BLOCK_SZ) as usize;
+        if from >= block.len() {
+            return Ok(0);
+        }
+        let n = buf.len().min(block.len() - from);
+        buf[..n].copy_from_slice(&block[from..from + n]);
+        Ok(n)
+    }
+
+    fn len(&self) -> Option<u64> {
+        self.0.image.len()
+    }
+}
+
+struct Mount {
+    device: String,
+    typ: FsType,
+    owner: Owner,
+    since: SystemTime,
+    shared: Arc<Shared>,
+}
+
+#[derive(Default)]
+struct Daemon {
+    // by mount point
+    mounts: Mutex<BTreeMap<String, Mount>>,
+    // by the image's canonical path, gone with its last mount
+    images: Mutex<BTreeMap<PathBuf, Weak<Shared>>>,
+}
+
+// the --uid and --gid of a mount, the daemon's own when not given
+pub type Owner = (Option<u32>, Option<u32>);
+
+fn fs_name(typ: &FsType) -> String {
+    typ.to_possible_value().unwrap().get_name().to_string()
+}
+
+// a path the way it's kept, so the same dir given two ways is one mount
+fn canonical(path: &str) -> String {
+    match std::fs::canonicalize(path) {
+        Ok(path) => path.to_string_lossy().into_owned(),
+        Err(_) => path.to_string(),
+    }
+}
+
+impl Daemon {
+    fn shared(&self, device: &str) -> io::Result<Arc<Shared>> {
+        let key = PathBuf::from(canonical(device));
+        let mut images = self.images.lock().unwrap();
+        if let Some(shared) = images.get(&key).and_then(Weak::upgrade) {
+            return Ok(shared);
+        }
+        let shared = Arc::new(Shared {
+            path: key.to_string_lossy().into_owned(),
+            image: disk::open_volume(device, false)?,
+            blocks: Mutex::new(Blocks::default()),
+            cache: Cache::new("blocks", BLOCKS_WEIGHT),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        });
+        images.retain(|_, shared| shared.strong_count() > 0);
+        images.insert(key, Arc::downgrade(&shared));
+        Ok(shared)
+    }
+
+    fn mount(
+        self: &Arc<Self>,
+        typ: FsType,
+        device: &str,
+        dir: &str,
+        owner: Owner,
+    ) -> io::Result<()> {
+        let dir = canonical(dir);
+        let shared = self.shared(device)?;
+        let before = std::fs::metadata(&dir)?.dev();
+        {
+            // checked and taken at once, requests are served side by side
+            let mut mounts = self.mounts.lock().unwrap();
+            if mounts.contains_key(&dir) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} is already mounted", dir),
+                ));
+            }
+            mounts.insert(
+                dir.clone(),
+                Mount {
+                    device: device.to_string(),
+                    typ: typ.clone(),
+                    owner,
+                    since: SystemTime::now(),
+                    shared: shared.clone(),
+                },
+            );
+        }
+
+        let daemon = self.clone();
+        let (name, at) = (device.to_string(), dir.clone());
+        let served = thread::spawn(move || {
+            let opts = mount_helper::options(&name);
+            let device = Watch::new(Cached(shared), &name, Arc::default());
+            let served = FuseW::with_device(device, &name, typ, false, 0, 16).and_then(|mut fs| {
+                fs.own(owner.0, owner.1);
+                fuser::mount2(fs, &at, &opts)
+            });
+            if let Err(e) = served {
+                eprintln!("[daemon] {}: {}", at, e);
+            }
+            daemon.mounts.lock().unwrap().remove(&at);
+            say!("[daemon] {} unmounted", at);
+        });
+
+        // up once the dir is another filesystem, as mount_helper::background
+        // waits for it
+        for _ in 0..200 {
+            if served.is_finished() {
+                self.mounts.lock().unwrap().remove(&dir);
+                return Err(io::Error::other(format!(
+                    "mounting {} failed, see the daemon's output",
+                    dir
+                )));
+            }
+            if std::fs::metadata(&dir).map(|m| m.dev()).ok() != Some(before) {
+                say!("[daemon] {} mounted at {}", device, dir);
+                return Ok(());
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("{} didn't show up in time", dir),
+        ))
+    }
+
+    fn umount(&self, dir: &str) -> io::Result<()> {
+        let dir = canonical(dir);
+        if !self.mounts.lock().unwrap().contains_key(&dir) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("nothing of this daemon is mounted at {}", dir),
+            ));
+        }
+        // the mount's thread forgets it once the kernel lets go
+        mount_helper::unmount(&dir, false)
+    }
+
+    // mount point, type, device, since when, in unix seconds, and the
+    // --uid and --gid it was given
+    fn list(&self) -> Vec<String> {
+        let mounts = self.mounts.lock().unwrap();
+        mounts
+            .iter()
+            .map(|(dir, m)| {
+                let since = m.since.duration_since(UNIX_EPOCH).unwrap().as_secs();
+                let (uid, gid) = owner_words(m.owner);
+                format!(
+                    "{}\t{}\t{}\t{}\t{}\t{}",
+                    dir,
+                    fs_name(&m.typ),
+                    m.device,
+                    since,
+                    uid,
+                    gid
+                )
+            })
+            .collect()
+    }
+
+    fn stats(&self) -> Vec<String> {
+        let mut lines = stats::lines();
+        let mounts = self.mounts.lock().unwrap();
+        let mut images: BTreeMap<&str, (&Shared, usize)> = BTreeMap::new();
+        for m in mounts.values() {
+            images.entry(&m.shared.path).or_insert((&m.shared, 0)).1 += 1;
+        }
+        for (path, (shared, n)) in images {
+            lines.push(format!(
+                "[stats] {}: {} mounts, {} cached, {} hits, {} misses",
+                path,
+                n,
+                table::size(shared.cached_bytes(), false),
+                shared.hits.load(Ordering::Relaxed),
+                shared.misses.load(Ordering::Relaxed)
+            ));
+        }
+        lines
+    }
+
+    fn answer(self: &Arc<Self>, request: &str) -> io::Result<Vec<String>> {
+        let words: Vec<&str> = request.split('\t').collect();
+        match words[..] {
+            ["list"] => Ok(self.list()),
+            ["stats"] => Ok(self.stats()),
+            ["mount", typ, device, dir, uid, gid] => {
+                let typ = FsType::from_str(typ, true)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+                let owner = (id_word(uid)?, id_word(gid)?);
+                self.mount(typ, device, dir, owner).map(|()| vec![])
+            }
+            ["umount", dir] => self.umount(dir).map(|()| vec![]),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("not a request: {:?}", request),
+            )),
+        }
+    }
+
+    fn serve(self: &Arc<Self>, stream: UnixStream) -> io::Result<()> {
+        let mut request = String::new();
+        BufReader::new(&stream).read_line(&mut request)?;
+        let mut out = io::BufWriter::new(&stream);
+        match self.answer(request.trim_end_matches('\n')) {
+            Ok(lines) => {
+                writeln!(out, "ok")?;
+                for line in lines {
+                    writeln!(out, "{}", line)?;
+                }
+            }
+            Err(e) => writeln!(out, "error\t{}\t{}", exit::code(&e), e)?,
+        }
+        out.flush()
+    }
+}
+
+fn owner_words(owner: Owner) -> (String, String) {
+    let word = |id: Option<u32>| id.map_or_else(String::new, |id| id.to_string());
+    (word(owner.0), word(owner.1))
+}
+
+fn id_word(word: &str) -> io::Result<Option<u32>> {
+    match word {
+        "" => Ok(None),
+        _ => word.parse().map(Some).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("not an id: {}", word))
+        }),
+    }
+}
+
+// listen on `socket` until killed. the mounts are unmounted with the
+// process, see mount_helper::options
+pub fn daemon(socket: &Path) -> io::Result<()> {
+    if socket.exists() {
+        if UnixStream::connect(socket).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("a daemon is listening on {} already", socket.display()),
+            ));
+        }
+        // left over from one that was killed
+        std::fs::remove_file(socket)?;
+    }
+    let listener = UnixListener::bind(socket)?;
+    std::fs::set_permissions(socket, std::fs::Permissions::from_mode(0o600))?;
+    say!("[daemon] listening on {}", socket.display());
+    // the mounts belong to whoever runs the daemon unless ctl says
+    fat32fuse::set_owner(None, None);
+    let daemon = Arc::new(Daemon::default());
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("[daemon] {}", e);
+                continue;
+            }
+        };
+        // a mount waits for the kernel, the others aren't held up by it
+        let daemon = daemon.clone();
+        thread::spawn(move || {
+            if let Err(e) = daemon.serve(stream) {
+                eprintln!("[daemon] {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+// what `fat32x ctl` asks
+pub enum Request {
+    List,
+    Mount(FsType, String, String, Owner),
+    Umount(String),
+    Stats,
+}
+
+// send `request` to the daemon and print the answer, exits with the code
+// the daemon gives when it's refused
+pub fn ctl(socket: &Path, request: &Request) -> io::Result<()> {
+    let line = match request {
+        Request::List => String::from("list"),
+        Request::Mount(typ, device, dir, owner) => {
+            let (uid, gid) = owner_words(*owner);
+            format!(
+                "mount\t{}\t{}\t{}\t{}\t{}",
+                fs_name(typ),
+                canonical(device),
+                canonical(dir),
+                uid,
+                gid
+            )
+        }
+        Request::Umount(dir) => format!("umount\t{}", canonical(dir)),
+        Request::Stats => String::from("stats"),
+    };
+    let mut stream = UnixStream::connect(socket).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("no daemon on {}: {}", socket.display(), e),
+        )
+    })?;
+    writeln!(stream, "{}", line)?;
+    let mut lines = BufReader::new(stream).lines();
+    let status = lines.next().transpose()?.unwrap_or_default();
+    if let Some(refused) = status.strip_prefix("error\t") {
+        let (code, why) = refused.split_once('\t').unwrap_or(("4", refused));
+        eprintln!("[ctl] {}", why);
+        std::process::exit(code.parse().unwrap_or(exit::IO));
+    }
+    let lines: Vec<String> = lines.collect::<io::Result<_>>()?;
+    match request {
+        Request::List => {
+            let now = SystemTime::now();
+            let mut table = Table::new(&["TYPE", "-UP", "DEVICE", "MOUNTPOINT"], true);
+            for line in lines {
+                let f: Vec<&str> = line.split('\t').collect();
+                let [dir, typ, device, since, ..] = f[..] else {
+                    continue;
+                };
+                let since = UNIX_EPOCH + Duration::from_secs(since.parse().unwrap_or(0));
+                table.row(vec![
+                    typ.to_string(),
+                    table::time(since, Times::Age, now),
+                    device.to_string(),
+                    dir.to_string(),
+                ]);
+            }
+            table.print();
+        }
+        _ => lines.iter().for_each(|line| println!("{}", line)),
+    }
+    Ok(())
+}
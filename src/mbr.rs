@@ -1,8 +1,44 @@
 #![allow(dead_code)]
 
-use scroll::{ctx::TryFromCtx, Pread, LE};
+use std::io;
 
-#[derive(Debug)]
+use scroll::{
+    ctx::{TryFromCtx, TryIntoCtx},
+    Pread, Pwrite, LE,
+};
+
+pub const SIG: u16 = 0xAA55;
+// the type guarding a GPT disk from tools that only know MBR
+pub const TYPE_PROTECTIVE: u8 = 0xEE;
+// CHS stops at cylinder 1023, anything past it is recorded as the maximum
+const HEADS: u32 = 255;
+const SECS_PER_TRACK: u32 = 63;
+const CHS_MAX: [u8; 3] = [0xFE, 0xFF, 0xFF];
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("partition slot {0} is out of 1..=4")]
+    BadSlot(usize),
+    #[error("partition {0} is empty")]
+    Empty(usize),
+    #[error("partition {0} is already in use")]
+    InUse(usize),
+    #[error("partition would overlap partition {0}")]
+    Overlap(usize),
+    #[error("partition can't be empty or start at sector 0")]
+    BadRange,
+    #[error("type 0x00 marks an unused slot")]
+    BadType,
+}
+
+// what's asked of the table can't be done, see exit::code
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, e)
+    }
+}
+
+#[derive(Debug, Default, Clone)]
 pub struct PartitionEntry {
     active: u8,
     first_sec: [u8; 3],
@@ -29,6 +65,68 @@ impl<'a> TryFromCtx<'a, scroll::Endian> for PartitionEntry {
     }
 }
 
+impl TryIntoCtx<scroll::Endian> for &PartitionEntry {
+    type Error = scroll::Error;
+    fn try_into_ctx(self, into: &mut [u8], _ctx: scroll::Endian) -> Result<usize, Self::Error> {
+        into.pwrite_with(self.active, 0, LE)?;
+        into.pwrite_with(&self.first_sec[..], 1, ())?;
+        into.pwrite_with(self.typ, 4, LE)?;
+        into.pwrite_with(&self.last_sec[..], 5, ())?;
+        into.pwrite_with(self.lba, 8, LE)?;
+        into.pwrite_with(self.nsecs, 12, LE)?;
+        Ok(16)
+    }
+}
+
+impl PartitionEntry {
+    fn new(typ: u8, lba: u32, nsecs: u32) -> Self {
+        PartitionEntry {
+            active: 0,
+            first_sec: chs(lba),
+            typ,
+            last_sec: chs(lba + (nsecs - 1)),
+            lba,
+            nsecs,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.typ == 0
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active == 0x80
+    }
+
+    pub fn typ(&self) -> u8 {
+        self.typ
+    }
+
+    pub fn lba(&self) -> u32 {
+        self.lba
+    }
+
+    pub fn nsecs(&self) -> u32 {
+        self.nsecs
+    }
+
+    fn end(&self) -> u64 {
+        self.lba as u64 + self.nsecs as u64
+    }
+}
+
+// the legacy cylinder/head/sector address of `lba`, 255 heads and 63 sectors
+// per track as every partitioning tool since the 90s assumes
+fn chs(lba: u32) -> [u8; 3] {
+    let cyl = lba / (HEADS * SECS_PER_TRACK);
+    if cyl > 1023 {
+        return CHS_MAX;
+    }
+    let head = lba / SECS_PER_TRACK % HEADS;
+    let sec = lba % SECS_PER_TRACK + 1;
+    [head as u8, (sec | (cyl >> 8) << 6) as u8, cyl as u8]
+}
+
 #[derive(Debug)]
 pub struct Mbr {
     boot_code: [u8; 446],
@@ -40,6 +138,8 @@ pub struct Mbr {
 }
 
 impl Mbr {
+    pub const SZ: usize = 512;
+
     pub fn new(buf: &[u8]) -> Result<Self, scroll::Error> {
         Ok(Mbr {
             boot_code: buf.pread_with(0, LE)?,
@@ -50,4 +150,151 @@ impl Mbr {
             boot_sig: buf.pread_with(510, LE)?,
         })
     }
+
+    // an all-zero sector with no boot code and no partitions
+    pub fn empty() -> Self {
+        Mbr::new(&[0u8; Mbr::SZ]).unwrap()
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.boot_sig == SIG
+    }
+
+    // the sector as it goes on disk, always carrying the signature
+    pub fn dump(&self) -> [u8; Mbr::SZ] {
+        let mut buf = [0u8; Mbr::SZ];
+        buf[..446].copy_from_slice(&self.boot_code);
+        for (i, part) in self.partitions().iter().enumerate() {
+            buf.pwrite_with(*part, 446 + i * 16, LE).unwrap();
+        }
+        buf.pwrite_with(SIG, 510, LE).unwrap();
+        buf
+    }
+
+    pub fn partitions(&self) -> [&PartitionEntry; 4] {
+        [
+            &self.partition_1,
+            &self.partition_2,
+            &self.partition_3,
+            &self.partition_4,
+        ]
+    }
+
+    fn slot(&mut self, slot: usize) -> Result<&mut PartitionEntry, Error> {
+        match slot {
+            1 => Ok(&mut self.partition_1),
+            2 => Ok(&mut self.partition_2),
+            3 => Ok(&mut self.partition_3),
+            4 => Ok(&mut self.partition_4),
+            _ => Err(Error::BadSlot(slot)),
+        }
+    }
+
+    fn used(&mut self, slot: usize) -> Result<&mut PartitionEntry, Error> {
+        let part = self.slot(slot)?;
+        if part.is_empty() {
+            return Err(Error::Empty(slot));
+        }
+        Ok(part)
+    }
+
+    // the sectors [lba, lba + nsecs) must not be claimed by another slot
+    fn check_range(&self, slot: usize, lba: u32, nsecs: u32) -> Result<(), Error> {
+        if lba == 0 || nsecs == 0 || lba as u64 + nsecs as u64 > 1 << 32 {
+            return Err(Error::BadRange);
+        }
+        let end = lba as u64 + nsecs as u64;
+        for (i, part) in self.partitions().iter().enumerate() {
+            if i + 1 != slot
+                && !part.is_empty()
+                && (lba as u64) < part.end()
+                && end > part.lba as u64
+            {
+                return Err(Error::Overlap(i + 1));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn add(&mut self, slot: usize, typ: u8, lba: u32, nsecs: u32) -> Result<(), Error> {
+        if typ == 0 {
+            return Err(Error::BadType);
+        }
+        if !self.slot(slot)?.is_empty() {
+            return Err(Error::InUse(slot));
+        }
+        self.check_range(slot, lba, nsecs)?;
+        *self.slot(slot)? = PartitionEntry::new(typ, lba, nsecs);
+        Ok(())
+    }
+
+    pub fn delete(&mut self, slot: usize) -> Result<(), Error> {
+        *self.used(slot)? = PartitionEntry::default();
+        Ok(())
+    }
+
+    pub fn resize(&mut self, slot: usize, nsecs: u32) -> Result<(), Error> {
+        let lba = self.used(slot)?.lba;
+        self.check_range(slot, lba, nsecs)?;
+        let part = self.used(slot)?;
+        part.nsecs = nsecs;
+        part.last_sec = chs(lba + (nsecs - 1));
+        Ok(())
+    }
+
+    // at most one partition is bootable, setting it clears the others
+    pub fn set_active(&mut self, slot: usize) -> Result<(), Error> {
+        self.used(slot)?;
+        for i in 1..=4 {
+            self.slot(i)?.active = if i == slot { 0x80 } else { 0 };
+        }
+        Ok(())
+    }
+
+    pub fn set_type(&mut self, slot: usize, typ: u8) -> Result<(), Error> {
+        if typ == 0 {
+            return Err(Error::BadType);
+        }
+        self.used(slot)?.typ = typ;
+        Ok(())
+    }
+}
+
+// a partition type byte, hex with or without the 0x prefix
+pub fn parse_type(s: &str) -> Result<u8, String> {
+    u8::from_str_radix(s.trim_start_matches("0x"), 16).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut rng = fastrand::Rng::with_seed(0x4B2);
+        for _ in 0..1000 {
+            let mut buf = [0u8; Mbr::SZ];
+            rng.fill(&mut buf);
+            buf.pwrite_with(SIG, 510, LE).unwrap();
+            assert_eq!(Mbr::new(&buf).unwrap().dump(), buf);
+        }
+    }
+
+    #[test]
+    fn edits_survive_a_dump() {
+        let mut mbr = Mbr::empty();
+        mbr.add(1, 0x0C, 2048, 4096).unwrap();
+        mbr.add(3, 0x83, 8192, 1 << 20).unwrap();
+        mbr.set_active(3).unwrap();
+        let back = Mbr::new(&mbr.dump()).unwrap();
+        assert!(back.is_valid());
+        let parts = back.partitions();
+        assert_eq!(
+            (parts[0].typ(), parts[0].lba(), parts[0].nsecs()),
+            (0x0C, 2048, 4096)
+        );
+        assert!(parts[1].is_empty());
+        assert!(parts[2].is_active() && !parts[0].is_active());
+        assert_eq!(parts[2].first_sec, chs(8192));
+    }
 }
@@ -0,0 +1,349 @@
+use std::{
+    ffi::CString,
+    fs::File,
+    io::{self, BufRead, BufReader},
+    os::{fd::FromRawFd, unix::fs::MetadataExt},
+    path::Path,
+    process::Command,
+    sync::Mutex,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use fuser::MountOption;
+
+use crate::disk;
+use crate::stats;
+
+// the name mount(8) runs us by for `fat32x` entries in /etc/fstab, through
+// a symlink or a copy of the binary
+pub const NAME: &str = "mount.fat32x";
+
+// mount options that only matter to mount(8) itself or to other helpers.
+// rw is there too, mount(8) passes it by default and the mount is read-only
+// whatever is asked for
+const PASSED_OVER: [&str; 13] = [
+    "defaults", "auto", "noauto", "user", "users", "nouser", "nofail", "_netdev", "nosuid",
+    "nodev", "noexec", "ro", "rw",
+];
+
+// the arguments of our own `mount` command for a helper invocation
+// `mount.fat32x <device> <dir> [-o opts] [-snfv]`, none when we weren't
+// run as the helper
+pub fn translate(argv: &[String]) -> Option<Result<Vec<String>, String>> {
+    let prog = Path::new(argv.first()?).file_name()?.to_string_lossy();
+    if prog != NAME {
+        return None;
+    }
+    Some(parse(&argv[1..]))
+}
+
+fn parse(args: &[String]) -> Result<Vec<String>, String> {
+    let mut pos = vec![];
+    let mut opts = vec![];
+    let mut sloppy = false;
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        match arg.as_str() {
+            "-o" => opts.extend(
+                it.next()
+                    .ok_or("-o needs a value")?
+                    .split(',')
+                    .filter(|o| !o.is_empty())
+                    .map(String::from),
+            ),
+            "-s" => sloppy = true,
+            // no mtab, fake and verbose, mount(8) handles them
+            "-n" | "-f" | "-v" => (),
+            "-t" => {
+                it.next();
+            }
+            flag if flag.starts_with('-') => return Err(format!("unknown flag {}", flag)),
+            _ => pos.push(arg.clone()),
+        }
+    }
+    let [dev, dir] = pos.as_slice() else {
+        return Err(format!("usage: {} <device> <dir> [-o opts]", NAME));
+    };
+
+    let mut out = vec![
+        "fat32x".to_string(),
+        "mount".to_string(),
+        dev.clone(),
+        dir.clone(),
+        "--background".to_string(),
+    ];
+    let mut typ = None;
+    for opt in opts {
+        match opt.split_once('=') {
+            Some(("type", t)) => typ = Some(t.to_string()),
+            Some(("readahead", n)) => out.extend(["--readahead".to_string(), n.to_string()]),
+            Some(("on_bad", v)) => out.extend(["--on-bad".to_string(), v.to_string()]),
+            Some(("uid", n)) => out.extend(["--uid".to_string(), n.to_string()]),
+            Some(("gid", n)) => out.extend(["--gid".to_string(), n.to_string()]),
+            Some(("long_names", v)) => out.extend(["--long-names".to_string(), v.to_string()]),
+            None if matches!(
+                opt.as_str(),
+                "forensic" | "direct_io" | "keep_cache" | "verify" | "reconnect"
+            ) =>
+            {
+                out.push(format!("--{}", opt.replace('_', "-")))
+            }
+            None if PASSED_OVER.contains(&opt.as_str()) => (),
+            _ if opt.starts_with("x-") || opt.starts_with("comment=") => (),
+            _ if sloppy => (),
+            _ => return Err(format!("unknown mount option {}", opt)),
+        }
+    }
+    let typ = match typ {
+        Some(typ) => typ,
+        None => {
+            let file = disk::open_volume(dev, false).map_err(|e| format!("{}: {}", dev, e))?;
+            match disk::detect(&file, 0) {
+                Some("FAT32") => "fat32".to_string(),
+                Some("exFAT") => "exfat".to_string(),
+                Some("SquashFS") => "squashfs".to_string(),
+                Some("UDF") => "udf".to_string(),
+                _ => {
+                    return Err(format!(
+                        "{}: no FAT32, exFAT, SquashFS or UDF volume, give type=",
+                        dev
+                    ))
+                }
+            }
+        }
+    };
+    out.extend(["--type".to_string(), typ]);
+    Ok(out)
+}
+
+// fork, the child goes on to serve the mount while the parent waits for it
+// to show up at `dir` and exits, as mount(8) expects of a helper. returns
+// in the child only
+pub fn background(dir: &str, stats: bool) {
+    detach(dir, false, stats);
+}
+
+// background for a service manager: the parent says on stdout when the
+// mount is up, what the child prints goes to syslog (and so the journal)
+pub fn daemon(dir: &str, stats: bool) {
+    detach(dir, true, stats);
+}
+
+// the child goes on with the mount, with `stats` it dumps them on SIGUSR1.
+// nothing may have spawned a thread before, the child keeps only this one
+fn detach(dir: &str, daemon: bool, stats: bool) {
+    let before = std::fs::metadata(dir).map(|m| m.dev()).ok();
+    match unsafe { libc::fork() } {
+        -1 => {
+            eprintln!("[mount] can't fork, staying in the foreground");
+        }
+        0 => {
+            unsafe { libc::setsid() };
+            // first, see stats::dump_on_sigusr1
+            if stats {
+                stats::dump_on_sigusr1();
+            }
+            if daemon {
+                to_syslog();
+            }
+        }
+        child => {
+            for _ in 0..200 {
+                let mut status = 0;
+                if unsafe { libc::waitpid(child, &mut status, libc::WNOHANG) } == child {
+                    if daemon {
+                        eprintln!("[mount] failed, see syslog for why");
+                    }
+                    std::process::exit(match libc::WIFEXITED(status) {
+                        true => libc::WEXITSTATUS(status).max(1),
+                        false => 1,
+                    });
+                }
+                if std::fs::metadata(dir).map(|m| m.dev()).ok() != before {
+                    if daemon {
+                        say!("[mount] ready, pid {}", child);
+                    }
+                    std::process::exit(0);
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+            eprintln!("[mount] {} didn't show up in time", dir);
+            std::process::exit(1);
+        }
+    }
+}
+
+// the threads passing what's printed on to syslog, see to_syslog
+static LOGGERS: Mutex<Vec<JoinHandle<()>>> = Mutex::new(vec![]);
+
+// at exit, the lines still in the pipes reach syslog before the process
+// is gone: the pipes are closed and the loggers get a moment to finish.
+// a child the mount left behind may hold them open, so not forever
+extern "C" fn drain_loggers() {
+    unsafe {
+        libc::close(libc::STDOUT_FILENO);
+        libc::close(libc::STDERR_FILENO);
+    }
+    let Ok(loggers) = LOGGERS.lock() else {
+        return;
+    };
+    let until = Instant::now() + Duration::from_secs(1);
+    while loggers.iter().any(|t| !t.is_finished()) && Instant::now() < until {
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+// stdout logged at LOG_INFO and stderr, where errors go, at LOG_ERR
+fn to_syslog() {
+    unsafe {
+        libc::openlog(c"fat32x".as_ptr(), libc::LOG_PID, libc::LOG_DAEMON);
+        libc::atexit(drain_loggers);
+    }
+    for (fd, priority) in [
+        (libc::STDOUT_FILENO, libc::LOG_INFO),
+        (libc::STDERR_FILENO, libc::LOG_ERR),
+    ] {
+        let mut ends = [0; 2];
+        if unsafe { libc::pipe(ends.as_mut_ptr()) } != 0 {
+            continue;
+        }
+        unsafe {
+            libc::dup2(ends[1], fd);
+            libc::close(ends[1]);
+        }
+        let read = unsafe { File::from_raw_fd(ends[0]) };
+        let logger = thread::spawn(move || {
+            for line in BufReader::new(read).lines().map_while(Result::ok) {
+                let Ok(msg) = CString::new(line) else {
+                    continue;
+                };
+                unsafe { libc::syslog(priority, c"%s".as_ptr(), msg.as_ptr()) };
+            }
+        });
+        if let Ok(mut loggers) = LOGGERS.lock() {
+            loggers.push(logger);
+        }
+    }
+}
+
+// what every mount of ours is made with. the subtype makes the mount's type
+// fuse.fat32x, which is how `mounts` tells ours apart
+pub fn options(device: &str) -> Vec<MountOption> {
+    vec![
+        MountOption::AllowOther,
+        MountOption::AutoUnmount,
+        MountOption::RO,
+        MountOption::FSName(device.to_string()),
+        MountOption::Subtype(String::from("fat32x")),
+    ]
+}
+
+// (device, mount point) of every fat32x mount, from the kernel's table
+#[cfg(target_os = "linux")]
+pub fn mounts() -> io::Result<Vec<(String, String)>> {
+    let table = std::fs::read_to_string("/proc/self/mounts")?;
+    Ok(table
+        .lines()
+        .filter_map(|line| {
+            let f: Vec<&str> = line.split(' ').collect();
+            (f.len() > 2 && f[2] == "fuse.fat32x").then(|| (unescape(f[0]), unescape(f[1])))
+        })
+        .collect())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn mounts() -> io::Result<Vec<(String, String)>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "listing mounts needs /proc, only there on Linux",
+    ))
+}
+
+// the mount table has spaces, tabs, newlines and backslashes as \ooo
+#[cfg(target_os = "linux")]
+fn unescape(field: &str) -> String {
+    let b = field.as_bytes();
+    let mut out = vec![];
+    let mut i = 0;
+    while i < b.len() {
+        let octal = b
+            .get(i + 1..i + 4)
+            .filter(|d| d.iter().all(|c| (b'0'..=b'7').contains(c)));
+        match (b[i], octal) {
+            (b'\\', Some(d)) => {
+                out.push((d[0] - b'0') * 64 + (d[1] - b'0') * 8 + (d[2] - b'0'));
+                i += 4;
+            }
+            (c, _) => {
+                out.push(c);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+// detach the FUSE mount at `dir`: fusermount, which needs no root, on Linux
+// and umount elsewhere. `lazy` detaches it even while busy, it goes away
+// for good once nothing uses it. Linux only, umount has no such option
+pub fn unmount(dir: &str, lazy: bool) -> io::Result<()> {
+    // FUSE 3 installs only fusermount3
+    #[cfg(target_os = "linux")]
+    let (prog, out) = {
+        let args = if lazy { &["-u", "-z"][..] } else { &["-u"] };
+        let run = |prog| Command::new(prog).args(args).arg(dir).output();
+        match run("fusermount") {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => ("fusermount3", run("fusermount3")),
+            out => ("fusermount", out),
+        }
+    };
+    #[cfg(target_os = "linux")]
+    let missing = "neither fusermount nor fusermount3 was found, they come with FUSE";
+    #[cfg(not(target_os = "linux"))]
+    if lazy {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--lazy is only supported on Linux",
+        ));
+    }
+    #[cfg(not(target_os = "linux"))]
+    let (prog, out) = ("umount", Command::new("umount").arg(dir).output());
+    #[cfg(not(target_os = "linux"))]
+    let missing = "umount wasn't found";
+    let out = out.map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => io::Error::new(io::ErrorKind::NotFound, missing),
+        _ => e,
+    })?;
+    if out.status.success() {
+        return Ok(());
+    }
+    // what they print, told apart by the strerror in it
+    let why = String::from_utf8_lossy(&out.stderr);
+    let (kind, msg) = if why.contains("busy") {
+        (
+            io::ErrorKind::ResourceBusy,
+            format!(
+                "{} is busy, a file in it is open or it's someone's working dir. \
+                 --lazy detaches it anyway",
+                dir
+            ),
+        )
+    } else if why.contains("not found in") || why.contains("not mounted") {
+        (
+            io::ErrorKind::InvalidInput,
+            format!("nothing is mounted at {}", dir),
+        )
+    } else if why.contains("ermission denied") || why.contains("not permitted") {
+        (
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "{} was mounted by someone else, only they or root can unmount it",
+                dir
+            ),
+        )
+    } else {
+        (io::ErrorKind::Other, format!("{}: {}", prog, why.trim()))
+    };
+    Err(io::Error::new(kind, msg))
+}
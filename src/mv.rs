@@ -0,0 +1,85 @@
+use std::io;
+
+use crate::device::Image;
+use crate::fat32::spec::ClusNo;
+use crate::fat32::write::{self, Writer};
+
+// rename the entry at `src` on a FAT32 volume, or move it to another dir.
+// `dst` naming an existing dir moves the entry into it under its old name
+pub fn mv(file: &Image, src: &str, dst: &str) -> io::Result<()> {
+    let src = src.trim_matches('/');
+    if src.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the root can't be moved",
+        ));
+    }
+    write::no_dots(src)?;
+    write::no_dots(dst)?;
+    let (src_parent, src_name) = src.rsplit_once('/').unwrap_or(("", src));
+
+    let mut w = Writer::new(file)?;
+    let src_dir = w.dir_clus(src_parent)?;
+    let fi = w
+        .find(src_dir, src_name)?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} not found", src)))?;
+
+    let dst = dst.trim_matches('/');
+    let (dst_parent, name) = dst.rsplit_once('/').unwrap_or(("", dst));
+    let mut dir = w.dir_clus(dst_parent)?;
+    let mut name = name.to_string();
+    if name.is_empty() {
+        name = fi.name.clone();
+    } else if let Some(t) = w.find(dir, &name)?.filter(|t| t.id != fi.id && t.is_dir) {
+        dir = t.fst_clus;
+        name = fi.name.clone();
+    }
+    match w.find(dir, &name)? {
+        Some(t) if t.id == fi.id && t.name == name => {
+            say!("[mv] {} is already named {}", src, name);
+            return Ok(());
+        }
+        Some(t) if t.id != fi.id => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists", t.name),
+            ))
+        }
+        _ => {}
+    }
+    if fi.is_dir && dir != src_dir && inside(&mut w, dir, fi.fst_clus)? {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} can't be moved into itself", src),
+        ));
+    }
+
+    let ent = w.read_sfn(&fi)?;
+    w.add_entry_like(dir, &name, ent)?;
+    w.remove_entry(src_dir, &fi)?;
+    if fi.is_dir && dir != src_dir && fi.fst_clus != 0 {
+        w.set_dotdot(fi.fst_clus, dir)?;
+    }
+    let n = w.flush()?;
+    say!("[mv] {} moved as {}", src, name);
+    say!("[mv] {} sectors written", n);
+    Ok(())
+}
+
+// whether the dir starting at `dir` is `ancestor` or lies below it, found by
+// walking the `..` entries up to the root
+fn inside(w: &mut Writer, mut dir: ClusNo, ancestor: ClusNo) -> io::Result<bool> {
+    let root = w.fio.root_clusno;
+    let mut hops = 0;
+    while dir != root && dir != 0 && hops < 65536 {
+        if dir == ancestor {
+            return Ok(true);
+        }
+        dir = match w.find(dir, "..")? {
+            Some(up) => up.fst_clus,
+            None => return Ok(false),
+        };
+        hops += 1;
+    }
+    Ok(false)
+}
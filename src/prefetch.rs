@@ -0,0 +1,95 @@
+// a subtree walked ahead of something that will go through all of it, a
+// media scan on a mount. what's read stays in the kernel's cache of the
+// device, and on a mount in its own dir caches, see fs::Fs::prefetch
+
+use std::{collections::BTreeSet, fmt, io};
+
+use crate::device::Device;
+use crate::fio::{self, Finfo, Fio};
+use crate::table;
+
+// how much file data goes in one read
+const CHUNK_SZ: u32 = 1 << 20;
+
+#[derive(Default)]
+pub struct Prefetched {
+    pub dirs: u64,
+    pub files: u64,
+    pub bytes: u64, // file data read ahead
+}
+
+impl fmt::Display for Prefetched {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} dirs, {} files", self.dirs, self.files)?;
+        if self.bytes != 0 {
+            write!(f, ", {} of data", table::size(self.bytes, false))?;
+        }
+        Ok(())
+    }
+}
+
+// the data of `fi` read and dropped. how many bytes
+pub fn read_ahead(fio: &mut dyn Fio, fi: &Finfo) -> io::Result<u64> {
+    let mut off = 0;
+    while off < fi.size {
+        let len = (fi.size - off).min(CHUNK_SZ as u64) as u32;
+        if fio.read_file(fi, off, len)?.is_empty() {
+            break;
+        }
+        off += len as u64;
+    }
+    Ok(off)
+}
+
+// everything under `path` on the volume, the data of files too with `data`
+pub fn prefetch(device: &dyn Device, path: &str, data: bool) -> io::Result<Prefetched> {
+    let typ = fio::detect(device, 0).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            "not a FAT32, exFAT, SquashFS or UDF volume",
+        )
+    })?;
+    let mut fio = fio::open(device, &typ)?;
+    let mut done = Prefetched {
+        dirs: 1,
+        ..Default::default()
+    };
+    let ents = match path.trim_matches('/') {
+        "" => fio.list_root()?,
+        _ => match fio::lookup(fio.as_mut(), path)? {
+            Some(fi) if fi.is_dir && fi.fst_clus == 0 => vec![],
+            Some(fi) if fi.is_dir => fio.list_dir(fi.fst_clus)?,
+            Some(fi) => {
+                done.dirs = 0;
+                vec![fi]
+            }
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{}: not found", path),
+                ))
+            }
+        },
+    };
+
+    // a dir is only gone into once, a corrupt one may link back up
+    let mut seen = BTreeSet::new();
+    let mut todo = vec![ents];
+    while let Some(ents) = todo.pop() {
+        for fi in ents {
+            if fi.name == "." || fi.name == ".." {
+                continue;
+            }
+            if !fi.is_dir {
+                done.files += 1;
+                if data {
+                    done.bytes += read_ahead(fio.as_mut(), &fi)?;
+                }
+            } else if fi.fst_clus != 0 && seen.insert(fi.fst_clus) {
+                done.dirs += 1;
+                todo.push(fio.list_dir(fi.fst_clus)?);
+            }
+        }
+    }
+    Ok(done)
+}
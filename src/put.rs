@@ -0,0 +1,71 @@
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    path::Path,
+};
+
+use crate::device::Image;
+use crate::fat32::write::Writer;
+
+// copy the host file `src` to `dest` on a FAT32 volume. `dest` naming an
+// existing dir (or ending with `/`) puts the file in it under its host name
+pub fn put(file: &Image, src: &Path, dest: &str) -> io::Result<()> {
+    let host = File::open(src)?;
+    let size = host.metadata()?.len();
+    if size > u32::MAX as u64 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "FAT32 files are limited to 4 GiB - 1 bytes",
+        ));
+    }
+
+    let mut w = Writer::new(file)?;
+    let (mut parent, mut name) = match dest.trim_end_matches('/').rsplit_once('/') {
+        Some((parent, name)) => (parent.to_string(), name.to_string()),
+        None => (String::new(), dest.trim_end_matches('/').to_string()),
+    };
+    let host_name = || {
+        src.file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no file name to use"))
+    };
+    if dest.ends_with('/') || name.is_empty() {
+        parent = format!("{}/{}", parent, name);
+        name = host_name()?;
+    }
+    let mut dir = w.dir_clus(&parent)?;
+    if let Some(fi) = w.find(dir, &name)?.filter(|fi| fi.is_dir) {
+        dir = fi.fst_clus;
+        name = host_name()?;
+    }
+    if w.find(dir, &name)?.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} already exists", name),
+        ));
+    }
+
+    let chain = w.alloc(size.div_ceil(w.fio.clus_sz() as u64) as u32)?;
+    let written = w.write_data(&chain, &mut BufReader::new(host))?;
+    if written != size {
+        return Err(io::Error::other(format!(
+            "{} changed while copying",
+            src.display()
+        )));
+    }
+    w.add_entry(
+        dir,
+        &name,
+        false,
+        chain.first().copied().unwrap_or(0),
+        size as u32,
+    )?;
+    let n = w.flush()?;
+    say!(
+        "[put] {} bytes in {} clusters, {} metadata sectors written",
+        size,
+        chain.len(),
+        n
+    );
+    Ok(())
+}
@@ -0,0 +1,534 @@
+// a boot sector for a volume whose own was wiped, sector 0 zeroed by a
+// partial wipe. taken from the backup when there's one left, the FAT32
+// backup boot sector or the exFAT backup boot region, else worked out from
+// what the wipe left behind: where the FATs start, and where directories
+// sit against the cluster numbers they give themselves. refer to [1] and [2]
+// [1] https://download.microsoft.com/download/1/6/1/161ba512-40e2-4cc9-843a-923143f3456c/fatgen103.doc
+// [2] https://learn.microsoft.com/en-us/windows/win32/fileio/exfat-specification
+
+use std::{collections::BTreeMap, fs, io, path::Path};
+
+use crate::device::{self, Device, Image};
+use crate::disk;
+use crate::exfat::spec::{self as exspec, dirent::DirEnt};
+use crate::exfat_boot::{self, Region};
+use crate::fat32::spec::{BootSec, FsInfo};
+
+const SEC_SZ: u64 = 512;
+// how far into the volume the FATs and directories are looked for
+const SCAN_MAX: u64 = 1 << 30;
+const CHUNK_SZ: usize = 1 << 20;
+// where the FAT32 backup boot sector can be, it's in the reserved region
+// and formatters put it at 6
+const BACKUP_MAX: u64 = 64;
+// what's kept of the evidence that can repeat a lot
+const KEEP: usize = 256;
+// the most an up-case table takes, 2 bytes for each of the 65536 units
+const UPCASE_MAX: u64 = 128 << 10;
+
+// a boot sector or region for sector 0, and what it was worked out from
+pub struct Rescued {
+    pub typ: &'static str,
+    pub from: String,
+    pub bytes: Vec<u8>,
+}
+
+fn invalid(what: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what)
+}
+
+// everything in the first SCAN_MAX bytes that tells where things are, as
+// offsets in 512 byte sectors
+#[derive(Default)]
+struct Scan {
+    fat32_fats: Vec<(u64, u8)>, // sector, media byte
+    exfat_fats: Vec<u64>,
+    // sector of a subdir's "." entry, the cluster it says it's in
+    dots: Vec<(u64, u32)>,
+    exfat_roots: Vec<ExfatRoot>,
+    // sectors starting like an up-case table
+    upcases: Vec<u64>,
+}
+
+// what an exFAT root dir says of the bitmap and the up-case table
+#[derive(Clone, Copy)]
+struct ExfatRoot {
+    sec: u64,
+    bitmap_len: u64,
+    checksum: u32,
+    upcase_clus: u32,
+    upcase_len: u64,
+}
+
+fn u32_at(sec: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(sec[off..off + 4].try_into().unwrap())
+}
+
+// refer to [1], the first 2 entries: the media byte with the rest set, and
+// end of chain with the clean and no-error bits that may be cleared
+fn is_fat32_fat(sec: &[u8]) -> bool {
+    let (ent0, ent1) = (u32_at(sec, 0), u32_at(sec, 4));
+    ent0 & 0x0FFFFF00 == 0x0FFFFF00 && ent0 & 0xFF >= 0xF0 && ent1 & 0x03FFFFFF == 0x03FFFFFF
+}
+
+// refer to [2] 4.1, the 2 entries are fixed
+fn is_exfat_fat(sec: &[u8]) -> bool {
+    u32_at(sec, 0) == 0xFFFFFFF8 && u32_at(sec, 4) == 0xFFFFFFFF
+}
+
+// the "." and ".." entries a FAT32 subdir starts with
+fn dot_clus(sec: &[u8]) -> Option<u32> {
+    let dir = |ent: &[u8], name: &[u8]| &ent[..11] == name && ent[11] & 0x10 != 0;
+    if !dir(&sec[..32], b".          ") || !dir(&sec[32..64], b"..         ") {
+        return None;
+    }
+    let hi = u16::from_le_bytes([sec[20], sec[21]]) as u32;
+    let lo = u16::from_le_bytes([sec[26], sec[27]]) as u32;
+    Some(hi << 16 | lo)
+}
+
+// the bitmap and up-case table entries in the first few of sector `no`
+fn exfat_root(sec: &[u8], no: u64) -> Option<ExfatRoot> {
+    let (mut bitmap, mut upcase) = (None, None);
+    for ent in sec.chunks_exact(32).take(4) {
+        match DirEnt::new(ent, 0, 0) {
+            Ok(DirEnt::AllocBitmap(b)) => bitmap = Some(b.data_length),
+            Ok(DirEnt::UpcaseTable(u)) => upcase = Some(u),
+            _ => (),
+        }
+    }
+    let upcase = upcase.filter(|u| u.data_length <= UPCASE_MAX)?;
+    Some(ExfatRoot {
+        sec: no,
+        bitmap_len: bitmap?,
+        checksum: upcase.table_checksum,
+        upcase_clus: upcase.first_cluster,
+        upcase_len: upcase.data_length,
+    })
+}
+
+// the first 128 entries of any up-case table, the mandatory ones, refer to
+// [2] 7.2.5. compressed tables give identity runs as 0xFFFF and a length
+fn is_upcase(sec: &[u8]) -> bool {
+    let mut words = sec
+        .chunks_exact(2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]) as usize);
+    let mut table = vec![];
+    while table.len() < 128 {
+        match words.next() {
+            Some(0xFFFF) => {
+                let run = words.next().unwrap_or(0);
+                table.extend(table.len()..table.len() + run);
+            }
+            Some(to) => table.push(to),
+            None => return false,
+        }
+    }
+    table
+        .iter()
+        .take(128)
+        .enumerate()
+        .all(|(c, &to)| match c as u8 {
+            b'a'..=b'z' => to == c - 0x20,
+            _ => to == c,
+        })
+}
+
+fn scan(dev: &dyn Device, vol_secs: u64) -> io::Result<Scan> {
+    let mut found = Scan::default();
+    let end = (vol_secs * SEC_SZ).min(SCAN_MAX);
+    let mut buf = vec![0u8; CHUNK_SZ];
+    let mut off = 0;
+    while off < end {
+        let len = (CHUNK_SZ as u64).min(end - off) as usize;
+        dev.read_exact_at(&mut buf[..len], off)?;
+        for (i, sec) in buf[..len].chunks_exact(SEC_SZ as usize).enumerate() {
+            let no = off / SEC_SZ + i as u64;
+            // the boot regions, the exFAT ones included
+            if no < 24 || sec.iter().all(|&b| b == 0) {
+                continue;
+            }
+            if is_exfat_fat(sec) {
+                found.exfat_fats.push(no);
+            }
+            if is_fat32_fat(sec) && found.fat32_fats.len() < KEEP {
+                found.fat32_fats.push((no, sec[0]));
+            }
+            if let Some(clus) = dot_clus(sec).filter(|_| found.dots.len() < KEEP) {
+                found.dots.push((no, clus));
+            }
+            if let Some(root) = exfat_root(sec, no) {
+                found.exfat_roots.push(root);
+            }
+            if found.upcases.len() < KEEP && is_upcase(sec) {
+                found.upcases.push(no);
+            }
+        }
+        off += len as u64;
+    }
+    Ok(found)
+}
+
+// the value most of `votes` agree on
+fn most<T: Ord + Copy>(votes: impl Iterator<Item = T>) -> Option<T> {
+    let mut counts = BTreeMap::new();
+    for vote in votes {
+        *counts.entry(vote).or_insert(0) += 1;
+    }
+    counts.into_iter().max_by_key(|&(_, n)| n).map(|(v, _)| v)
+}
+
+// sectors per cluster from subdirs found at `sec` being cluster `clus`,
+// knowing where the data region starts
+fn spc_from(dots: &[(u64, u32)], data_start: u64) -> Option<u64> {
+    most(dots.iter().filter_map(|&(sec, clus)| {
+        let (secs, clusters) = (sec.checked_sub(data_start)?, clus.checked_sub(2)? as u64);
+        let spc = secs.checked_div(clusters)?;
+        (secs % clusters == 0 && spc.is_power_of_two() && spc <= 128).then_some(spc)
+    }))
+}
+
+// the FAT32 backup boot sector, found by it saying where it is
+fn fat32_backup(dev: &dyn Device) -> Option<(u64, [u8; 512])> {
+    let mut sec = [0u8; 512];
+    for no in 1..BACKUP_MAX {
+        dev.read_exact_at(&mut sec, no * SEC_SZ).ok()?;
+        let Ok(b) = BootSec::new(&mut sec) else {
+            continue;
+        };
+        if b.bs_boot_sign == 0xAA55
+            && b.bs_fil_sys_type == *b"FAT32   "
+            && b.bpb_byts_per_sec as u64 * b.bpb_bk_boot_sec as u64 == no * SEC_SZ
+            && b.bpb_sec_per_clus.is_power_of_two()
+            && b.bpb_num_fats >= 1
+        {
+            return Some((no, sec));
+        }
+    }
+    None
+}
+
+fn fat32_infer(dev: &dyn Device, found: &Scan, vol_secs: u64) -> io::Result<Rescued> {
+    let &(rsvd, media) = found
+        .fat32_fats
+        .first()
+        .ok_or_else(|| invalid(String::from("no FAT found, nothing to go by")))?;
+    say!("[rescue-boot] a FAT starts at sector {}", rsvd);
+    // further copies as far apart as the first two
+    let mut num_fats = 1;
+    let mut fat_sz = None;
+    if let Some(&(second, _)) = found.fat32_fats.get(1) {
+        let sz = second - rsvd;
+        num_fats = 2 + found
+            .fat32_fats
+            .iter()
+            .skip(2)
+            .enumerate()
+            .take_while(|&(i, &(no, _))| no == rsvd + (i as u64 + 2) * sz)
+            .count() as u64;
+        fat_sz = Some(sz);
+        say!("[rescue-boot] {} FATs of {} sectors", num_fats, sz);
+    }
+
+    let (spc, data_start) = match fat_sz {
+        Some(sz) => {
+            let data_start = rsvd + num_fats * sz;
+            let spc = spc_from(&found.dots, data_start)
+                .ok_or_else(|| invalid(String::from("no subdir found to size the clusters by")))?;
+            (spc, data_start)
+        }
+        // one FAT, its size follows from where the data region is, which
+        // two subdirs tell along with the cluster size
+        None => {
+            let mut dots = found.dots.clone();
+            dots.sort_by_key(|&(_, clus)| clus);
+            let spc = most(dots.windows(2).filter_map(|w| {
+                let ((a, ca), (b, cb)) = (w[0], w[1]);
+                let (secs, clusters) = (b.checked_sub(a)?, cb.checked_sub(ca)? as u64);
+                let spc = secs.checked_div(clusters)?;
+                (secs % clusters == 0 && spc.is_power_of_two() && spc <= 128).then_some(spc)
+            }))
+            .ok_or_else(|| invalid(String::from("one FAT and fewer than 2 subdirs found")))?;
+            let (sec, clus) = dots[0];
+            let data_start = (clus as u64)
+                .checked_sub(2)
+                .and_then(|clusters| sec.checked_sub(clusters * spc))
+                .filter(|&start| start > rsvd)
+                .ok_or_else(|| invalid(String::from("the subdirs found don't add up")))?;
+            say!("[rescue-boot] 1 FAT of {} sectors", data_start - rsvd);
+            (spc, data_start)
+        }
+    };
+    say!(
+        "[rescue-boot] subdirs put the data region at sector {}, {} sectors per cluster",
+        data_start,
+        spc
+    );
+    let clusters = vol_secs.saturating_sub(data_start) / spc;
+    if clusters < 65526 {
+        return Err(invalid(format!(
+            "{} clusters is too few for FAT32",
+            clusters
+        )));
+    }
+
+    let mut fsinfo = [0u8; 512];
+    dev.read_exact_at(&mut fsinfo, SEC_SZ)?;
+    let has_fsinfo = FsInfo::new(&fsinfo).is_ok_and(|f| f.is_valid());
+    let mut b = BootSec::new(&mut [0u8; 512]).unwrap();
+    b.bs_jmp_boot = [0xEB, 0x58, 0x90];
+    b.bs_oem_name = *b"MSWIN4.1";
+    b.bpb_byts_per_sec = SEC_SZ as u16;
+    b.bpb_sec_per_clus = spc as u8;
+    b.bpb_rsvd_sec_cnt = rsvd as u16;
+    b.bpb_num_fats = num_fats as u8;
+    b.bpb_media = media;
+    b.bpb_sec_per_trk = 63;
+    b.bpb_num_heads = 255;
+    b.bpb_tot_sec_32 = vol_secs as u32;
+    b.bpb_fat_sz_32 = ((data_start - rsvd) / num_fats) as u32;
+    // nothing says otherwise, formatters put the root first
+    b.bpb_root_clus = 2;
+    b.bpb_fs_info = has_fsinfo as u16;
+    b.bs_drv_num = 0x80;
+    b.bs_boot_sig = 0x29;
+    b.bs_vol_lab = *b"NO NAME    ";
+    b.bs_fil_sys_type = *b"FAT32   ";
+    b.bs_boot_sign = 0xAA55;
+    Ok(Rescued {
+        typ: "FAT32",
+        from: String::from("the FATs and subdirs"),
+        bytes: b.dump().to_vec(),
+    })
+}
+
+fn exfat_infer(dev: &dyn Device, found: &Scan, vol_secs: u64) -> io::Result<Rescued> {
+    let &fat_offset = found.exfat_fats.first().unwrap();
+    let ExfatRoot {
+        sec: root,
+        bitmap_len,
+        checksum,
+        upcase_clus,
+        upcase_len,
+    } = found.exfat_roots[0];
+    say!(
+        "[rescue-boot] a FAT starts at sector {}, the root dir at {}",
+        fat_offset,
+        root
+    );
+    // the up-case table that has the checksum the root dir gives it
+    let mut table = vec![0u8; upcase_len as usize];
+    let upcase = found.upcases.iter().copied().find(|&sec| {
+        dev.read_exact_at(&mut table, sec * SEC_SZ).is_ok()
+            && exspec::table_checksum(&table) == checksum
+    });
+    let upcase = upcase.ok_or_else(|| invalid(String::from("the up-case table wasn't found")))?;
+    say!("[rescue-boot] the up-case table is at sector {}", upcase);
+
+    // the cluster size that puts the up-case table in its cluster and
+    // leaves as many clusters as the bitmap has bits for
+    let layout = (0..=(25 - 9)).find_map(|shift: u32| {
+        let spc = 1u64 << shift;
+        let heap = upcase.checked_sub((upcase_clus as u64).checked_sub(2)? * spc)?;
+        if heap <= fat_offset || heap > root || (root - heap) % spc != 0 {
+            return None;
+        }
+        // formatters give the heap the rest of the volume
+        let count = (vol_secs - heap) >> shift;
+        (count.div_ceil(8) == bitmap_len).then_some((shift, heap, count))
+    });
+    let (shift, heap, count) =
+        layout.ok_or_else(|| invalid(String::from("no cluster size fits what was found")))?;
+    let root_clus = (root - heap) / (1 << shift) + 2;
+    say!(
+        "[rescue-boot] the cluster heap starts at sector {}, {} clusters of {} sectors",
+        heap,
+        count,
+        1 << shift
+    );
+
+    let mut region = vec![0u8; Region::SECS * SEC_SZ as usize];
+    let boot = &mut region[..512];
+    boot[..3].copy_from_slice(&[0xEB, 0x76, 0x90]);
+    boot[3..11].copy_from_slice(b"EXFAT   ");
+    boot[72..80].copy_from_slice(&vol_secs.to_le_bytes());
+    boot[80..84].copy_from_slice(&(fat_offset as u32).to_le_bytes());
+    let fat_len = ((count + 2) * 4).div_ceil(SEC_SZ) as u32;
+    boot[84..88].copy_from_slice(&fat_len.to_le_bytes());
+    boot[88..92].copy_from_slice(&(heap as u32).to_le_bytes());
+    boot[92..96].copy_from_slice(&(count as u32).to_le_bytes());
+    boot[96..100].copy_from_slice(&(root_clus as u32).to_le_bytes());
+    boot[104..106].copy_from_slice(&[0, 1]);
+    boot[108] = 9;
+    boot[109] = shift as u8;
+    boot[110] = 1;
+    boot[111] = 0x80;
+    boot[112] = 0xFF;
+    boot[510..512].copy_from_slice(&[0x55, 0xAA]);
+    // the extended boot sectors, refer to [2] 3.2
+    for no in 1..=8 {
+        let end = (no + 1) * SEC_SZ as usize;
+        region[end - 4..end].copy_from_slice(&0xAA550000u32.to_le_bytes());
+    }
+    let sum = exspec::boot_checksum(&region, SEC_SZ as u16);
+    for b in region[11 * SEC_SZ as usize..].chunks_exact_mut(4) {
+        b.copy_from_slice(&sum.to_le_bytes());
+    }
+    Ok(Rescued {
+        typ: "exFAT",
+        from: String::from("the FAT, the root dir and the up-case table"),
+        bytes: region,
+    })
+}
+
+// what goes at sector 0 of the volume on `dev` of `vol_secs` sectors
+pub fn rescue(dev: &dyn Device, vol_secs: u64) -> io::Result<Rescued> {
+    if let Some(typ) = disk::detect(dev, 0) {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("sector 0 holds a good {} boot sector", typ),
+        ));
+    }
+    if let Ok((_, backup)) = exfat_boot::read_regions(&dev) {
+        if backup.checksum_ok() {
+            return Ok(Rescued {
+                typ: "exFAT",
+                from: String::from("the backup boot region"),
+                bytes: backup.bytes().to_vec(),
+            });
+        }
+        eprintln!("[rescue-boot] the exFAT backup boot region's checksum is bad, not using it");
+    }
+    if let Some((no, sec)) = fat32_backup(dev) {
+        return Ok(Rescued {
+            typ: "FAT32",
+            from: format!("the backup boot sector at sector {}", no),
+            bytes: sec.to_vec(),
+        });
+    }
+
+    say!("[rescue-boot] no backup boot sector, working it out from what's left");
+    let found = scan(dev, vol_secs)?;
+    say!(
+        "[rescue-boot] {} FAT starts, {} subdirs, {} exFAT root dirs found",
+        found.fat32_fats.len() + found.exfat_fats.len(),
+        found.dots.len(),
+        found.exfat_roots.len()
+    );
+    if !found.exfat_fats.is_empty() && !found.exfat_roots.is_empty() {
+        return exfat_infer(dev, &found, vol_secs);
+    }
+    fat32_infer(dev, &found, vol_secs)
+}
+
+// rescue the boot sector of the volume in `file`, written to `out`, or to
+// sector 0 with `commit`
+pub fn run(file: &Image, out: &Path, commit: bool) -> io::Result<()> {
+    let vol_secs = device::size(file)? / SEC_SZ;
+    let rescued = rescue(file, vol_secs)?;
+    say!(
+        "[rescue-boot] {} boot sector from {}",
+        rescued.typ,
+        rescued.from
+    );
+    if !commit {
+        fs::write(out, &rescued.bytes)?;
+        say!(
+            "[rescue-boot] written to {}, --commit puts it at sector 0",
+            out.display()
+        );
+        return Ok(());
+    }
+    file.write_all_at(&rescued.bytes, 0)?;
+    file.sync_all()?;
+    match disk::detect(file, 0) {
+        Some(typ) => say!("[rescue-boot] written, the volume reads as {} again", typ),
+        None => say!("[rescue-boot] written, but the volume still isn't recognized"),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Mem(Vec<u8>);
+
+    impl Device for Mem {
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+            let data = self.0.get(offset as usize..).unwrap_or_default();
+            let n = buf.len().min(data.len());
+            buf[..n].copy_from_slice(&data[..n]);
+            Ok(n)
+        }
+    }
+
+    // a FAT32 volume of `vol_secs` with 2 FATs of `fat_sz` after 32
+    // reserved sectors, one sector clusters and subdirs at clusters 5 and 9
+    fn fat32_volume(vol_secs: u64, fat_sz: u32) -> (Vec<u8>, Vec<u8>) {
+        let mut img = vec![0u8; (vol_secs * SEC_SZ) as usize];
+        let mut b = BootSec::new(&mut [0u8; 512]).unwrap();
+        b.bs_jmp_boot = [0xEB, 0x58, 0x90];
+        b.bs_oem_name = *b"MSWIN4.1";
+        b.bpb_byts_per_sec = 512;
+        b.bpb_sec_per_clus = 1;
+        b.bpb_rsvd_sec_cnt = 32;
+        b.bpb_num_fats = 2;
+        b.bpb_media = 0xF8;
+        b.bpb_sec_per_trk = 63;
+        b.bpb_num_heads = 255;
+        b.bpb_tot_sec_32 = vol_secs as u32;
+        b.bpb_fat_sz_32 = fat_sz;
+        b.bpb_root_clus = 2;
+        b.bpb_fs_info = 1;
+        b.bs_drv_num = 0x80;
+        b.bs_boot_sig = 0x29;
+        b.bs_vol_lab = *b"NO NAME    ";
+        b.bs_fil_sys_type = *b"FAT32   ";
+        b.bs_boot_sign = 0xAA55;
+        let boot = b.dump().to_vec();
+        img[..512].copy_from_slice(&boot);
+        let fsinfo = &mut img[512..1024];
+        fsinfo[..4].copy_from_slice(&0x41615252u32.to_le_bytes());
+        fsinfo[484..488].copy_from_slice(&0x61417272u32.to_le_bytes());
+        fsinfo[508..512].copy_from_slice(&0xAA550000u32.to_le_bytes());
+        for fat in 0..2 {
+            let off = ((32 + fat * fat_sz) as u64 * SEC_SZ) as usize;
+            img[off..off + 4].copy_from_slice(&0x0FFFFFF8u32.to_le_bytes());
+            img[off + 4..off + 8].copy_from_slice(&0x0FFFFFFFu32.to_le_bytes());
+        }
+        let data_start = 32 + 2 * fat_sz as u64;
+        for clus in [5u32, 9] {
+            let off = ((data_start + clus as u64 - 2) * SEC_SZ) as usize;
+            let dir = &mut img[off..off + 64];
+            dir[..11].copy_from_slice(b".          ");
+            dir[32..43].copy_from_slice(b"..         ");
+            dir[11] = 0x10;
+            dir[43] = 0x10;
+            dir[20..22].copy_from_slice(&((clus >> 16) as u16).to_le_bytes());
+            dir[26..28].copy_from_slice(&(clus as u16).to_le_bytes());
+        }
+        (img, boot)
+    }
+
+    #[test]
+    fn fat32_from_the_backup() {
+        let (mut img, mut boot) = fat32_volume(80000, 630);
+        // BPB_BkBootSec
+        boot[50] = 6;
+        img[6 * 512..7 * 512].copy_from_slice(&boot);
+        img[..512].fill(0);
+        let rescued = rescue(&Mem(img), 80000).unwrap();
+        assert_eq!(rescued.from, "the backup boot sector at sector 6");
+        assert_eq!(rescued.bytes, boot);
+    }
+
+    #[test]
+    fn fat32_worked_out() {
+        let (mut img, boot) = fat32_volume(80000, 630);
+        img[..512].fill(0);
+        let rescued = rescue(&Mem(img), 80000).unwrap();
+        assert_eq!(rescued.typ, "FAT32");
+        assert_eq!(rescued.bytes, boot);
+    }
+}
@@ -0,0 +1,331 @@
+// References:
+// [1] https://download.microsoft.com/download/1/6/1/161ba512-40e2-4cc9-843a-923143f3456c/fatgen103.doc
+
+use std::{collections::HashMap, fs::File, io, path::Path};
+
+use crate::device::{Device, Image};
+use crate::fat32::fio::Fio;
+use crate::fat32::spec::{ClusNo, DirEnt, FsInfo};
+use crate::fsck::{set_ent, sync_fats, ENT_MASK};
+use crate::journal::Writes;
+
+// fewer clusters and the volume would have to be FAT16, refer to [1]
+// (check_fat32 draws the line one cluster higher)
+const MIN_CLUS: u32 = 65526;
+const MAX_CLUS: u32 = 0x0FFFFFF5;
+const CHUNK_SZ: u64 = 1 << 20;
+
+struct Layout {
+    tot_sec: u32,
+    fat_sz: u32,
+    clus_cnt: u32,
+}
+
+// where a cluster chain is referenced from, for the chains that get moved
+struct Heads {
+    dirents: HashMap<ClusNo, u64>, // first cluster -> id of the short entry
+    subdirs: HashMap<ClusNo, Vec<ClusNo>>, // dir first cluster -> its subdirs' first clusters
+}
+
+// grow the volume to `size` bytes or shrink it, down to the smallest size
+// still holding the data in use when `size` is None. shrinking first moves
+// the clusters past the new end into free ones below it, then the data region
+// follows the FATs when their size changes. the relocation and the new FATs,
+// boot sector and FSInfo are each a journaled commit `journal::rollback` can
+// undo. moving the data region isn't, a resize interrupted while at it
+// leaves a broken volume behind. an image file cut short by a finished
+// shrink has lost what the journal would point back at
+pub fn resize(file: &Image, size: Option<u64>, journal_path: &Path) -> io::Result<()> {
+    let mut fio = Fio::new(file)?;
+    fio.writable()?;
+    let bps = fio.bootsec.bpb_byts_per_sec as u64;
+    let old = Layout {
+        tot_sec: fio.bootsec.bpb_tot_sec_32,
+        fat_sz: fio.bootsec.bpb_fat_sz_32,
+        clus_cnt: fio.clus_cnt(),
+    };
+    let mut fat = fio.read_fat_copy(0)?;
+    fat.truncate(old.clus_cnt as usize + 2);
+    let used = (2..fat.len()).filter(|&c| fat[c] & ENT_MASK != 0).count() as u32;
+
+    let new = match size {
+        Some(size) => {
+            let tot_sec = u32::try_from(size / bps).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "{} bytes is more than the 2^32 - 1 sectors FAT32 counts",
+                        size
+                    ),
+                )
+            })?;
+            layout(&fio, tot_sec)
+        }
+        None => {
+            let mut tot_sec =
+                fio.bootsec.bpb_rsvd_sec_cnt as u32 + used * fio.bootsec.bpb_sec_per_clus as u32;
+            loop {
+                let new = layout(&fio, tot_sec);
+                if new.clus_cnt >= used.max(MIN_CLUS) {
+                    break new;
+                }
+                tot_sec += fio.bootsec.bpb_sec_per_clus as u32;
+            }
+        }
+    };
+    if new.clus_cnt < MIN_CLUS || new.clus_cnt > MAX_CLUS {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} clusters is out of the FAT32 range", new.clus_cnt),
+        ));
+    }
+    if new.clus_cnt < used {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} clusters can't hold the {} in use", new.clus_cnt, used),
+        ));
+    }
+
+    let is_file = match file.file() {
+        Ok(f) => f.metadata()?.file_type().is_file(),
+        // split and striped images keep their size, like a device
+        Err(_) => false,
+    };
+    let new_len = new.tot_sec as u64 * bps;
+    if is_file && new.tot_sec > old.tot_sec {
+        file.file()?.set_len(new_len)?;
+    }
+    // anything else can't grow past what it holds
+    match file.len() {
+        Some(len) if !is_file && new_len > len => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} bytes won't fit, the device holds {}", new_len, len),
+            ))
+        }
+        _ => {}
+    }
+
+    let mut journal = File::create(journal_path)?;
+    let mut root_clus = fio.bootsec.bpb_root_clus;
+    if new.clus_cnt < old.clus_cnt {
+        root_clus = relocate(file, &mut fio, &mut fat, new.clus_cnt + 1, &mut journal)?;
+    }
+
+    // the data region moves along with its start when the FATs change size
+    let rsvd = fio.bootsec.bpb_rsvd_sec_cnt as u64;
+    let nf = fio.bootsec.bpb_num_fats as u64;
+    let (from, to) = (
+        (rsvd + nf * old.fat_sz as u64) * bps,
+        (rsvd + nf * new.fat_sz as u64) * bps,
+    );
+    let last_used = (2..fat.len()).rev().find(|&c| fat[c] & ENT_MASK != 0);
+    if let Some(last_used) = last_used.filter(|_| from != to) {
+        let len = (last_used as u64 - 1) * fio.clus_sz() as u64;
+        move_region(file, from, to, len)?;
+    }
+
+    fat.resize(new.fat_sz as usize * bps as usize / 4, 0);
+    for ent in fat[new.clus_cnt as usize + 2..].iter_mut() {
+        *ent = 0;
+    }
+    let mut writes = Writes::new(file, bps);
+    let fat_bytes: Vec<u8> = fat.iter().flat_map(|ent| ent.to_le_bytes()).collect();
+    for copy in 0..nf {
+        writes.patch((rsvd + copy * new.fat_sz as u64) * bps, &fat_bytes)?;
+    }
+
+    let bk = fio.bootsec.bpb_bk_boot_sec as u64;
+    let mut boot = vec![0u8; bps as usize];
+    file.read_exact_at(&mut boot, 0)?;
+    boot[32..36].copy_from_slice(&new.tot_sec.to_le_bytes());
+    boot[36..40].copy_from_slice(&new.fat_sz.to_le_bytes());
+    boot[44..48].copy_from_slice(&root_clus.to_le_bytes());
+    writes.patch(0, &boot)?;
+    if bk != 0 {
+        writes.patch(bk * bps, &boot)?;
+    }
+
+    let free = (2..new.clus_cnt as usize + 2)
+        .filter(|&c| fat[c] & ENT_MASK == 0)
+        .count() as u32;
+    let fs_info = fio.bootsec.bpb_fs_info as u64;
+    let mut fs_info_secs = vec![fs_info];
+    if bk != 0 {
+        fs_info_secs.push(bk + fs_info);
+    }
+    for sec in fs_info_secs
+        .into_iter()
+        .filter(|&sec| fs_info != 0 && sec != 0)
+    {
+        let mut buf = vec![0u8; bps as usize];
+        file.read_exact_at(&mut buf, sec * bps)?;
+        if FsInfo::new(&buf).is_ok_and(|fsinfo| fsinfo.is_valid()) {
+            let at = FsInfo::FREE_COUNT_OFF;
+            buf[at..at + 4].copy_from_slice(&free.to_le_bytes());
+            buf[at + 4..at + 8].copy_from_slice(&0xFFFFFFFFu32.to_le_bytes());
+            writes.patch(sec * bps, &buf)?;
+        }
+    }
+    let sectors = writes.commit(&mut journal)?;
+
+    if is_file && new.tot_sec < old.tot_sec {
+        file.file()?.set_len(new.tot_sec as u64 * bps)?;
+    }
+    file.sync_all()?;
+    say!(
+        "[resize] {} -> {} sectors, {} -> {} clusters, FAT {} -> {} sectors",
+        old.tot_sec,
+        new.tot_sec,
+        old.clus_cnt,
+        new.clus_cnt,
+        old.fat_sz,
+        new.fat_sz
+    );
+    say!(
+        "[resize] {} sectors journaled to {}",
+        sectors,
+        journal_path.display()
+    );
+    Ok(())
+}
+
+// the FAT size has to cover every cluster of the data region it leaves,
+// F * bps / 4 >= (tot - rsvd - nf * F) / spc + 2, refer to [1]
+fn layout(fio: &Fio, tot_sec: u32) -> Layout {
+    let b = &fio.bootsec;
+    let (rsvd, nf, spc) = (
+        b.bpb_rsvd_sec_cnt as u64,
+        b.bpb_num_fats as u64,
+        b.bpb_sec_per_clus as u64,
+    );
+    let tot = tot_sec as u64;
+    let per_sec = b.bpb_byts_per_sec as u64 * spc / 4 + nf;
+    let fat_sz = (tot.saturating_sub(rsvd) + 2 * spc).div_ceil(per_sec);
+    let clus_cnt = tot.saturating_sub(rsvd + nf * fat_sz) / spc;
+    Layout {
+        tot_sec,
+        fat_sz: fat_sz as u32,
+        clus_cnt: clus_cnt as u32,
+    }
+}
+
+// move every allocated cluster past `max_clus` into a free one below it and
+// repoint whatever referenced it. the data goes to free clusters first, then
+// the FATs and the entries are switched over in one journaled commit. return
+// the (possibly moved) root cluster
+fn relocate(
+    file: &Image,
+    fio: &mut Fio,
+    fat: &mut [u32],
+    max_clus: ClusNo,
+    journal: &mut File,
+) -> io::Result<ClusNo> {
+    let high: Vec<ClusNo> = (max_clus + 1..fat.len() as u32)
+        .filter(|&c| fat[c as usize] & ENT_MASK != 0)
+        .collect();
+    let mut root = fio.bootsec.bpb_root_clus;
+    if high.is_empty() {
+        return Ok(root);
+    }
+
+    let mut heads = Heads {
+        dirents: HashMap::new(),
+        subdirs: HashMap::new(),
+    };
+    walk(fio, root, &mut heads)?;
+    let mut pred: HashMap<ClusNo, ClusNo> = HashMap::new();
+    for clus in 2..fat.len() as u32 {
+        let next = fat[clus as usize] & ENT_MASK;
+        if (2..fat.len() as u32).contains(&next) {
+            pred.insert(next, clus);
+        }
+    }
+
+    let free: Vec<ClusNo> = (2..=max_clus)
+        .filter(|&c| fat[c as usize] & ENT_MASK == 0)
+        .collect();
+    let mut free = free.into_iter();
+    let old = fat.to_vec();
+    let mut moved: HashMap<ClusNo, ClusNo> = HashMap::new();
+    for &clus in high.iter() {
+        let to = free.next().unwrap();
+        let data = fio.read_clus(clus)?;
+        file.write_all_at(&data, fio.clus_offset(to))?;
+
+        set_ent(fat, to, fat[clus as usize] & ENT_MASK);
+        set_ent(fat, clus, 0);
+        let next = fat[to as usize] & ENT_MASK;
+        if pred.get(&next) == Some(&clus) {
+            pred.insert(next, to);
+        }
+        if let Some(p) = pred.remove(&clus) {
+            set_ent(fat, p, to);
+        }
+        moved.insert(clus, to);
+    }
+
+    // repoint the entries (and the dirs' `.` and their subdirs' `..`) of the
+    // chains whose first cluster moved, wherever those entries ended up. the
+    // root has no `.`, and a `..` pointing at it is 0 wherever it is
+    let at = |clus: ClusNo| *moved.get(&clus).unwrap_or(&clus);
+    let mut patches: Vec<(ClusNo, u64, ClusNo)> = vec![];
+    let old_root = root;
+    for (&from, &to) in moved.iter() {
+        let parent = if from == old_root {
+            root = to;
+            0
+        } else if let Some(&id) = heads.dirents.get(&from) {
+            patches.push((at(id as u32), id >> 32, to));
+            if heads.subdirs.contains_key(&from) {
+                patches.push((to, 0, to));
+            }
+            to
+        } else {
+            continue;
+        };
+        for &sub in heads.subdirs.get(&from).into_iter().flatten() {
+            patches.push((at(sub), 1, parent));
+        }
+    }
+    let mut writes = Writes::new(file, fio.bootsec.bpb_byts_per_sec as u64);
+    sync_fats(&mut writes, fio, &old, fat)?;
+    for (clus, idx, fst_clus) in patches {
+        let off = fio.clus_offset(clus) + idx * DirEnt::SZ as u64;
+        writes.patch(off + 20, &((fst_clus >> 16) as u16).to_le_bytes())?;
+        writes.patch(off + 26, &(fst_clus as u16).to_le_bytes())?;
+    }
+    writes.commit(journal)?;
+    say!("[resize] {} clusters relocated", moved.len());
+    Ok(root)
+}
+
+fn walk(fio: &mut Fio, dir: ClusNo, heads: &mut Heads) -> io::Result<()> {
+    let mut subdirs = vec![];
+    for fi in fio.read_dirents(dir)? {
+        if fi.name == "." || fi.name == ".." || fi.fst_clus == 0 {
+            continue;
+        }
+        heads.dirents.insert(fi.fst_clus, fi.id);
+        if fi.is_dir {
+            subdirs.push(fi.fst_clus);
+            walk(fio, fi.fst_clus, heads)?;
+        }
+    }
+    heads.subdirs.insert(dir, subdirs);
+    Ok(())
+}
+
+// memmove on the device, copying from the far end first when moving forward
+fn move_region(file: &Image, from: u64, to: u64, len: u64) -> io::Result<()> {
+    let mut buf = vec![0u8; CHUNK_SZ as usize];
+    let chunks = len.div_ceil(CHUNK_SZ);
+    for i in 0..chunks {
+        let i = if to > from { chunks - 1 - i } else { i };
+        let off = i * CHUNK_SZ;
+        let n = CHUNK_SZ.min(len - off) as usize;
+        file.read_exact_at(&mut buf[..n], from + off)?;
+        file.write_all_at(&buf[..n], to + off)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,185 @@
+use std::{cmp::min, io, sync::Mutex, thread, time::Duration};
+
+use clap::ValueEnum;
+
+use crate::device::Device;
+
+// failures are tracked per sector, the smallest unit a card gives up on
+const SEC_SZ: u64 = 512;
+
+// what happens to data that stays unreadable after the retries
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum OnError {
+    // the read fails, as without this layer
+    Fail,
+    // the unreadable sectors read as zeros
+    Zero,
+    // the unreadable sectors read as zeros, and the fio layer leaves them
+    // out: file reads stop short of them, dir listings pass over them
+    Skip,
+}
+
+#[derive(Debug, Clone)]
+pub struct RetryOpts {
+    pub retries: u32,
+    // the wait before the first retry, doubled for each one after it
+    pub backoff: Duration,
+    pub on_error: OnError,
+}
+
+// a device that retries failed reads and remembers the sectors that never
+// came back, so a dying card is only hammered once per bad spot
+pub struct Retry<D> {
+    dev: D,
+    opts: RetryOpts,
+    bad: Mutex<Vec<(u64, u64)>>, // (offset, len), sorted and merged
+}
+
+impl<D: Device> Retry<D> {
+    pub fn new(dev: D, opts: RetryOpts) -> Self {
+        Retry {
+            dev,
+            opts,
+            bad: Mutex::new(vec![]),
+        }
+    }
+
+    // the unreadable byte ranges found so far
+    pub fn bad_regions(&self) -> Vec<(u64, u64)> {
+        self.bad.lock().unwrap().clone()
+    }
+
+    // the first known unreadable byte in [offset, offset + len)
+    fn first_bad(&self, offset: u64, len: u64) -> Option<u64> {
+        let bad = self.bad.lock().unwrap();
+        bad.iter()
+            .find(|&&(off, n)| off < offset + len && offset < off + n)
+            .map(|&(off, _)| off.max(offset))
+    }
+
+    fn record(&self, offset: u64, len: u64) {
+        let mut bad = self.bad.lock().unwrap();
+        bad.push((offset, len));
+        bad.sort_unstable();
+        let mut merged: Vec<(u64, u64)> = vec![];
+        for &(off, n) in bad.iter() {
+            match merged.last_mut() {
+                Some((last, last_n)) if *last + *last_n >= off => {
+                    *last_n = (*last_n).max(off + n - *last);
+                }
+                _ => merged.push((off, n)),
+            }
+        }
+        *bad = merged;
+    }
+
+    // `f` until it succeeds or the retries run out
+    fn retrying<T>(&self, mut f: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+        let mut wait = self.opts.backoff;
+        let mut tries = 0;
+        loop {
+            match f() {
+                Err(e) if e.kind() != io::ErrorKind::UnexpectedEof && tries < self.opts.retries => {
+                    thread::sleep(wait);
+                    wait *= 2;
+                    tries += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+impl<D: Device> Device for Retry<D> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let len = buf.len() as u64;
+        if self.first_bad(offset, len).is_none() {
+            match self.retrying(|| self.dev.read_at(buf, offset)) {
+                Err(e) if self.opts.on_error == OnError::Fail => return Err(e),
+                Err(_) => (),
+                ok => return ok,
+            }
+        } else if self.opts.on_error == OnError::Fail {
+            return Err(io::Error::other(format!(
+                "unreadable data at byte {}",
+                offset
+            )));
+        }
+
+        // narrow the failure down to sectors, the readable ones keep their data
+        let mut pos = 0;
+        while pos < len {
+            let at = offset + pos;
+            let end = min((at / SEC_SZ + 1) * SEC_SZ - offset, len);
+            let chunk = &mut buf[pos as usize..end as usize];
+            if self.first_bad(at, chunk.len() as u64).is_some() {
+                chunk.fill(0);
+            } else {
+                match self.retrying(|| self.dev.read_exact_at(chunk, at)) {
+                    Ok(()) => (),
+                    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(pos as usize),
+                    Err(_) => {
+                        self.record(at, chunk.len() as u64);
+                        chunk.fill(0);
+                    }
+                }
+            }
+            pos = end;
+        }
+        Ok(len as usize)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        self.dev.write_at(buf, offset)
+    }
+
+    fn usable_len(&self, extents: &[(u64, usize)]) -> usize {
+        let mut n = 0;
+        for &(off, len) in extents {
+            if self.opts.on_error == OnError::Skip {
+                if let Some(bad) = self.first_bad(off, len as u64) {
+                    return n + (bad - off) as usize;
+                }
+            }
+            n += len;
+        }
+        n
+    }
+
+    fn bad_spans(&self, extents: &[(u64, usize)]) -> Vec<(usize, usize)> {
+        let bad = self.bad.lock().unwrap();
+        let mut spans: Vec<(usize, usize)> = vec![];
+        let mut pos = 0;
+        for &(off, len) in extents {
+            let end = off + len as u64;
+            for &(b_off, b_len) in bad.iter().filter(|&&(o, n)| o < end && off < o + n) {
+                let from = b_off.max(off);
+                let to = (b_off + b_len).min(end);
+                let start = pos + (from - off) as usize;
+                match spans.last_mut() {
+                    Some((s, n)) if *s + *n == start => *n += (to - from) as usize,
+                    _ => spans.push((start, (to - from) as usize)),
+                }
+            }
+            pos += len;
+        }
+        spans
+    }
+
+    fn len(&self) -> Option<u64> {
+        self.dev.len()
+    }
+}
+
+// list what couldn't be read, if anything
+pub fn report<D: Device>(dev: &Retry<D>) {
+    let bad = dev.bad_regions();
+    if bad.is_empty() {
+        return;
+    }
+    let total: u64 = bad.iter().map(|(_, len)| len).sum();
+    say!("[retry] {} unreadable regions, {} bytes", bad.len(), total);
+    for (off, len) in bad {
+        println!("  {}..{}", off, off + len);
+    }
+}
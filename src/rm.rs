@@ -0,0 +1,68 @@
+use std::io;
+
+use crate::device::Image;
+use crate::fat32::spec::ClusNo;
+use crate::fat32::write::{self, Writer};
+
+// delete the entry at `path` from a FAT32 volume and free its clusters,
+// everything below it too when `recursive` is set
+pub fn rm(file: &Image, path: &str, recursive: bool) -> io::Result<()> {
+    let path = path.trim_matches('/');
+    if path.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the root can't be removed",
+        ));
+    }
+    write::no_dots(path)?;
+    let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+
+    let mut w = Writer::new(file)?;
+    let dir = w.dir_clus(parent)?;
+    let fi = w
+        .find(dir, name)?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} not found", path)))?;
+    if fi.is_dir && !recursive {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is a directory, use -r", path),
+        ));
+    }
+
+    let mut files = 0;
+    if fi.is_dir && fi.fst_clus != 0 {
+        files = free_tree(&mut w, fi.fst_clus)?;
+    }
+    if fi.fst_clus != 0 {
+        w.free_chain(fi.fst_clus);
+    }
+    w.remove_entry(dir, &fi)?;
+    let n = w.flush()?;
+    if fi.is_dir {
+        say!("[rm] {} removed with {} entries below it", path, files);
+    } else {
+        say!("[rm] {} removed", path);
+    }
+    say!("[rm] {} sectors written", n);
+    Ok(())
+}
+
+// free the chains of everything below the dir starting at `dir`, its own
+// chain is left to the caller. return the number of entries freed
+fn free_tree(w: &mut Writer, dir: ClusNo) -> io::Result<usize> {
+    let chain = w.chain_of(dir);
+    let mut cnt = 0;
+    for fi in w.fio.read_dirents_in(&chain)? {
+        if fi.name == "." || fi.name == ".." {
+            continue;
+        }
+        if fi.is_dir && fi.fst_clus != 0 {
+            cnt += free_tree(w, fi.fst_clus)?;
+        }
+        if fi.fst_clus != 0 {
+            w.free_chain(fi.fst_clus);
+        }
+        cnt += 1;
+    }
+    Ok(cnt)
+}
@@ -0,0 +1,76 @@
+// look for a byte pattern in the cluster heap of a volume, and tell which
+// file each hit is in
+
+use std::io;
+
+use crate::clusters::{Heap, Owners};
+use crate::device::{Device, Image};
+use crate::fatdump::Ent;
+use crate::space::{self, Pattern};
+
+const CHUNK_SZ: u64 = 1 << 20;
+
+// hex when it starts with 0x, such as "0xdeadbeef", else the string's bytes
+pub fn parse_needle(s: &str) -> Result<Pattern, String> {
+    if s.starts_with("0x") {
+        return space::parse_pattern(s);
+    }
+    if s.is_empty() {
+        return Err(String::from("the pattern is empty"));
+    }
+    Ok(s.as_bytes().to_vec())
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Only {
+    All,
+    Allocated,
+    Free,
+}
+
+// every place `pattern` starts at, by cluster and offset into it. the tree
+// is walked for the owners of the clusters on the first hit in a used one
+pub fn grep(device: &Image, pattern: &[u8], only: Only) -> io::Result<()> {
+    let heap = Heap::new(device)?;
+    let heap_len = (heap.ents.len() as u64 - 2) * heap.clus_sz;
+    // a hit can straddle two reads, as much of the last read as its length
+    // less a byte is read again
+    let keep = pattern.len() as u64 - 1;
+    let mut owners: Option<Owners> = None;
+    let mut found = 0;
+    let mut buf = vec![];
+    let mut at = 0;
+    while at < heap_len {
+        let start = at - keep.min(at);
+        let end = (at + CHUNK_SZ).min(heap_len);
+        buf.resize((end - start) as usize, 0);
+        device.read_exact_at(&mut buf, heap.base + start)?;
+        for (i, _) in buf
+            .windows(pattern.len())
+            .enumerate()
+            .filter(|(_, w)| *w == pattern)
+        {
+            let pos = start + i as u64;
+            let clus = (pos / heap.clus_sz) as u32 + 2;
+            let free = matches!(heap.ents[clus as usize], Ent::Free);
+            let who = match (only, free) {
+                (Only::Allocated, true) | (Only::Free, false) => continue,
+                (_, true) => String::from("free"),
+                (_, false) => {
+                    if owners.is_none() {
+                        owners = Some(heap.owners(device)?);
+                    }
+                    owners
+                        .as_ref()
+                        .and_then(|o| o.of(clus))
+                        .unwrap_or_else(|| String::from("allocated, in no file"))
+                }
+            };
+            println!("{} +{}: {}", clus, pos % heap.clus_sz, who);
+            found += 1;
+        }
+        at = end;
+    }
+    say!("[grep] {} matches", found);
+    Ok(())
+}
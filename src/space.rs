@@ -0,0 +1,388 @@
+use std::{
+    fs::{File, Metadata, OpenOptions},
+    io,
+    path::Path,
+};
+
+use crate::device::{Device, Image};
+use crate::fio::{Fio, FsType};
+use crate::{exfat, ext2, fat32, squashfs, udf};
+
+const CHUNK_SZ: usize = 1 << 20;
+const HOLE_SZ: usize = 4096;
+// BLKDISCARD, _IO(0x12, 119) from linux/fs.h
+#[cfg(target_os = "linux")]
+const BLKDISCARD: libc::c_ulong = 0x1277;
+
+// where the clusters of a volume are free, as byte extents on the device
+pub struct Space {
+    pub volume_len: u64,
+    pub free: Vec<(u64, u64)>, // (offset, len), runs of free clusters merged
+}
+
+impl Space {
+    pub fn free_bytes(&self) -> u64 {
+        self.free.iter().map(|(_, len)| len).sum()
+    }
+
+    // the complement of `free` within the volume
+    pub fn used(&self) -> Vec<(u64, u64)> {
+        let mut used = vec![];
+        let mut pos = 0;
+        for &(off, len) in self.free.iter() {
+            if off > pos {
+                used.push((pos, off - pos));
+            }
+            pos = off + len;
+        }
+        if self.volume_len > pos {
+            used.push((pos, self.volume_len - pos));
+        }
+        used
+    }
+}
+
+// free clusters come from the FAT on FAT32 and from the allocation bitmap on exFAT
+pub fn scan(device: &dyn Device, typ: &FsType) -> io::Result<Space> {
+    let mut free = vec![];
+    let mut push = |off: u64, len: u64| match free.last_mut() {
+        Some((last_off, last_len)) if *last_off + *last_len == off => *last_len += len,
+        _ => free.push((off, len)),
+    };
+    Ok(match typ {
+        FsType::Fat32 => {
+            let fio = fat32::fio::Fio::new(device)?;
+            let fat = fio.read_fat_copy(0)?;
+            let max_clus = (fio.clus_cnt() + 1).min(fat.len() as u32 - 1);
+            let clus_sz = fio.clus_sz() as u64;
+            for clus in 2..=max_clus {
+                if fat[clus as usize] & 0x0FFFFFFF == 0 {
+                    push(fio.clus_offset(clus), clus_sz);
+                }
+            }
+            Space {
+                volume_len: fio.bootsec.bpb_tot_sec_32 as u64 * fio.bootsec.bpb_byts_per_sec as u64,
+                free,
+            }
+        }
+        FsType::Exfat => {
+            let mut fio = exfat::Fio::new(device)?;
+            let bitmap = fio.read_bitmap()?;
+            let clus_sz = fio.clus_sz() as u64;
+            for i in 0..fio.clus_cnt() {
+                if bitmap[i as usize / 8] & (1 << (i % 8)) == 0 {
+                    push(fio.clus_offset(i + 2), clus_sz);
+                }
+            }
+            Space {
+                volume_len: fio.bootsec.volumn_length << fio.bootsec.bytes_per_sector_shift,
+                free,
+            }
+        }
+        // packed end to end, nothing in it is free
+        FsType::Squashfs => Space {
+            volume_len: squashfs::Fio::new(device)?.sb.bytes_used,
+            free,
+        },
+        // the space bitmaps aren't read, nothing is taken as free
+        FsType::Udf => {
+            let fio = udf::Fio::new(device)?;
+            let end = fio
+                .partitions
+                .values()
+                .map(|&(start, len)| start + len)
+                .max();
+            Space {
+                volume_len: end.unwrap_or(0) as u64 * fio.block_size as u64,
+                free,
+            }
+        }
+        // nor are the block bitmaps
+        FsType::Ext2 => {
+            let sblk = ext2::Fio::new(device, ext2::Journal::Replay)?.sblk;
+            Space {
+                volume_len: sblk.blocks_cnt() as u64 * sblk.blk_sz() as u64,
+                free,
+            }
+        }
+    })
+}
+
+// the unused tail of each file's last cluster, as (offset, len) byte extents
+pub fn slack(device: &dyn Device, typ: &FsType) -> io::Result<Vec<(u64, u64)>> {
+    let mut slack = vec![];
+    match typ {
+        FsType::Fat32 => {
+            let mut fio = fat32::fio::Fio::new(device)?;
+            let fat = fio.read_fat_copy(0)?;
+            let max_clus = (fio.clus_cnt() + 1).min(fat.len() as u32 - 1);
+            let clus_sz = fio.clus_sz() as u64;
+            let mut dirs = vec![fio.root_clusno];
+            while let Some(dir) = dirs.pop() {
+                for fi in fio.read_dirents(dir)? {
+                    if fi.name == "." || fi.name == ".." || fi.fst_clus == 0 {
+                        continue;
+                    }
+                    if fi.is_dir {
+                        dirs.push(fi.fst_clus);
+                        continue;
+                    }
+                    let tail = fi.size % clus_sz;
+                    if tail == 0 {
+                        continue;
+                    }
+                    let chain =
+                        fat32::fio::chain_in(&fat, max_clus, fi.fst_clus).unwrap_or_default();
+                    if let Some(&last) = chain.get((fi.size / clus_sz) as usize) {
+                        slack.push((fio.clus_offset(last) + tail, clus_sz - tail));
+                    }
+                }
+            }
+        }
+        FsType::Exfat => {
+            let mut fio = exfat::Fio::new(device)?;
+            let clus_sz = fio.clus_sz() as u64;
+            let mut dirs = vec![fio.root_clusno];
+            while let Some(dir) = dirs.pop() {
+                for fi in fio.list_dir_page(dir, 0, usize::MAX)?.0 {
+                    if fi.is_dir {
+                        if fi.fst_clus != 0 {
+                            dirs.push(fi.fst_clus);
+                        }
+                        continue;
+                    }
+                    let tail = fi.size % clus_sz;
+                    if tail == 0 {
+                        continue;
+                    }
+                    let clusters = fio.clusters_of(&fi)?;
+                    if let Some(&last) = clusters.get((fi.size / clus_sz) as usize) {
+                        slack.push((fio.clus_offset(last) + tail, clus_sz - tail));
+                    }
+                }
+            }
+        }
+        FsType::Squashfs | FsType::Udf | FsType::Ext2 => (),
+    }
+    Ok(slack)
+}
+
+// tell the storage a byte range no longer holds data: BLKDISCARD on block
+// devices, a punched hole in image files. write paths that free clusters
+// can hand the freed extents here as well
+#[cfg(target_os = "linux")]
+pub fn discard(file: &File, off: u64, len: u64) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let ret = if is_disk(file)? {
+        let range: [u64; 2] = [off, len];
+        unsafe { libc::ioctl(file.as_raw_fd(), BLKDISCARD, &range) }
+    } else {
+        unsafe {
+            libc::fallocate(
+                file.as_raw_fd(),
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                off as libc::off_t,
+                len as libc::off_t,
+            )
+        }
+    };
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn discard(file: &File, off: u64, len: u64) -> io::Result<()> {
+    let _ = (file, off, len);
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "discarding ranges isn't supported on this platform",
+    ))
+}
+
+// whether `file` is a disk or partition rather than an image file
+fn is_disk(file: &File) -> io::Result<bool> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+        Ok(file.metadata()?.file_type().is_block_device())
+    }
+    // raw disks and volumes may not answer metadata queries at all
+    #[cfg(not(unix))]
+    Ok(file.metadata().map_or(true, |meta| !meta.is_file()))
+}
+
+// the bytes a file takes up on disk, holes left out where that's known
+fn allocated(meta: &Metadata) -> u64 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        meta.blocks() * 512
+    }
+    #[cfg(not(unix))]
+    meta.len()
+}
+
+pub fn trim(file: &Image, typ: &FsType) -> io::Result<()> {
+    let dev = file.file()?;
+    let space = scan(file, typ)?;
+    for &(off, len) in space.free.iter() {
+        discard(dev, off, len)?;
+    }
+    say!(
+        "[trim] {} ranges, {} bytes discarded",
+        space.free.len(),
+        space.free_bytes()
+    );
+    Ok(())
+}
+
+// overwrite the free clusters (and with `slack`, the tails of the files' last
+// clusters) with `pattern` repeated
+pub fn wipe_free(file: &Image, typ: &FsType, pattern: &[u8], slack: bool) -> io::Result<()> {
+    let mut extents = scan(file, typ)?.free;
+    if slack {
+        extents.extend(self::slack(file, typ)?);
+    }
+    let mut buf = vec![0u8; CHUNK_SZ];
+    if !pattern.is_empty() {
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b = pattern[i % pattern.len()];
+        }
+    }
+
+    let mut total = 0;
+    for &(off, len) in extents.iter() {
+        let mut done = 0;
+        while done < len {
+            // keep the pattern aligned to the device offset
+            let pos = off + done;
+            let skew = if pattern.is_empty() {
+                0
+            } else {
+                (pos % pattern.len() as u64) as usize
+            };
+            let n = (len - done).min((CHUNK_SZ - skew) as u64) as usize;
+            file.write_all_at(&buf[skew..skew + n], pos)?;
+            done += n as u64;
+        }
+        total += len;
+    }
+    file.sync_all()?;
+    say!(
+        "[wipe-free] {} ranges, {} bytes overwritten",
+        extents.len(),
+        total
+    );
+    Ok(())
+}
+
+// copy a volume leaving its free clusters out: everything else (boot and
+// reserved regions, FATs, bitmap, dirs and file data) is copied, all-zero
+// blocks and free clusters become holes in an image or get discarded on a
+// block device
+pub fn clone(src: &dyn Device, typ: &FsType, dst: &Path) -> io::Result<()> {
+    let space = scan(src, typ)?;
+    let out = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(dst)?;
+    let is_blk = is_disk(&out)?;
+    if !is_blk {
+        out.set_len(0)?;
+        out.set_len(space.volume_len)?;
+    }
+
+    if is_blk {
+        for &(off, len) in space.free.iter() {
+            // a failed discard only costs the stale data staying around
+            let _ = discard(&out, off, len);
+        }
+    }
+
+    let mut buf = vec![0u8; CHUNK_SZ];
+    let mut copied = 0;
+    for (off, len) in space.used() {
+        let mut done = 0;
+        while done < len {
+            let n = (len - done).min(CHUNK_SZ as u64) as usize;
+            src.read_exact_at(&mut buf[..n], off + done)?;
+            for (i, blk) in buf[..n].chunks(HOLE_SZ).enumerate() {
+                if is_blk || blk.iter().any(|&b| b != 0) {
+                    out.write_all_at(blk, off + done + (i * HOLE_SZ) as u64)?;
+                }
+            }
+            done += n as u64;
+        }
+        copied += len;
+    }
+    out.sync_all()?;
+    say!(
+        "[clone] {} of {} bytes copied, {} bytes free left out",
+        copied,
+        space.volume_len,
+        space.free_bytes()
+    );
+    Ok(())
+}
+
+// punch holes in an image file at its free clusters and at all-zero blocks
+// of the used ones, the files on the volume read back exactly the same
+pub fn sparsify(file: &Image, typ: &FsType) -> io::Result<()> {
+    let image = file.file()?;
+    let meta = image.metadata()?;
+    if !meta.file_type().is_file() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "sparsify works on image files only",
+        ));
+    }
+    let before = allocated(&meta);
+
+    let space = scan(file, typ)?;
+    for &(off, len) in space.free.iter() {
+        discard(image, off, len)?;
+    }
+    let mut buf = vec![0u8; CHUNK_SZ];
+    for (off, len) in space.used() {
+        let mut done = 0;
+        while done < len {
+            let n = (len - done).min(CHUNK_SZ as u64) as usize;
+            file.read_exact_at(&mut buf[..n], off + done)?;
+            for (i, blk) in buf[..n].chunks(HOLE_SZ).enumerate() {
+                if blk.len() == HOLE_SZ && blk.iter().all(|&b| b == 0) {
+                    discard(image, off + done + (i * HOLE_SZ) as u64, HOLE_SZ as u64)?;
+                }
+            }
+            done += n as u64;
+        }
+    }
+    file.sync_all()?;
+
+    let after = allocated(&image.metadata()?);
+    say!("[sparsify] {} bytes on disk, was {}", after, before);
+    Ok(())
+}
+
+// a byte pattern, aliased so clap takes it as one value rather than many
+pub type Pattern = Vec<u8>;
+
+// a hex string such as "00" or "deadbeef"
+pub fn parse_pattern(s: &str) -> Result<Pattern, String> {
+    let s = s.trim_start_matches("0x");
+    if !s.is_ascii() {
+        return Err(String::from("expected hex digits"));
+    }
+    if s.is_empty() || !s.len().is_multiple_of(2) {
+        return Err(String::from("expected an even number of hex digits"));
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(|e| e.to_string()))
+        .collect()
+}
@@ -0,0 +1,681 @@
+// References:
+// [1] https://dr-emann.github.io/squashfs/squashfs.html
+
+use std::{
+    collections::BTreeMap,
+    io::{self, Read, Write},
+    time::Duration,
+    time::SystemTime,
+};
+
+use crate::device::Device;
+use crate::fio::{self, Finfo};
+use crate::trace;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("scroll read failed")]
+    Scroll(#[from] scroll::Error),
+    #[error("device read failed: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("{0} decompression failed")]
+    Decompress(&'static str),
+    #[error("compressor {0} isn't supported")]
+    Compressor(u16),
+    #[error("metadata runs off the archive at {0:#x}")]
+    Metadata(u64),
+    #[error("undefined inode type {0}")]
+    UndefinedInode(u16),
+}
+
+// device failures stay as they are, the rest means the image is broken
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+// what's decompressed into, failing once it would take more than its
+// limit, so a block can't unpack to more than a block
+struct Capped(Vec<u8>, usize);
+
+impl Write for Capped {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() > self.1 - self.0.len() {
+            return Err(io::Error::other("past the block size"));
+        }
+        self.0.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub mod spec {
+    use scroll::{Pread, LE};
+
+    #[allow(dead_code)]
+    #[derive(Debug)]
+    pub struct SuperBlock {
+        magic: u32, // check only, "hsqs"
+        pub inode_count: u32,
+        pub mod_time: u32,
+        pub block_size: u32,
+        pub frag_count: u32,
+        pub compressor: u16, // 1 gzip, 2 lzma, 3 lzo, 4 xz, 5 lz4, 6 zstd
+        pub block_log: u16,
+        pub flags: u16,
+        pub id_count: u16,
+        version_major: u16, // check only, 4
+        version_minor: u16, // check only, 0
+        pub root_inode_ref: u64,
+        pub bytes_used: u64,
+        pub id_table_start: u64,
+        pub xattr_id_table_start: u64, // `unused`
+        pub inode_table_start: u64,
+        pub directory_table_start: u64,
+        pub fragment_table_start: u64,
+        pub export_table_start: u64, // `unused`
+    }
+
+    impl SuperBlock {
+        pub const SZ: usize = 96;
+        const MAGIC: u32 = 0x73717368;
+
+        pub fn new(buf: &[u8]) -> Result<Self, scroll::Error> {
+            Ok(SuperBlock {
+                magic: buf.pread_with(0, LE)?,
+                inode_count: buf.pread_with(4, LE)?,
+                mod_time: buf.pread_with(8, LE)?,
+                block_size: buf.pread_with(12, LE)?,
+                frag_count: buf.pread_with(16, LE)?,
+                compressor: buf.pread_with(20, LE)?,
+                block_log: buf.pread_with(22, LE)?,
+                flags: buf.pread_with(24, LE)?,
+                id_count: buf.pread_with(26, LE)?,
+                version_major: buf.pread_with(28, LE)?,
+                version_minor: buf.pread_with(30, LE)?,
+                root_inode_ref: buf.pread_with(32, LE)?,
+                bytes_used: buf.pread_with(40, LE)?,
+                id_table_start: buf.pread_with(48, LE)?,
+                xattr_id_table_start: buf.pread_with(56, LE)?,
+                inode_table_start: buf.pread_with(64, LE)?,
+                directory_table_start: buf.pread_with(72, LE)?,
+                fragment_table_start: buf.pread_with(80, LE)?,
+                export_table_start: buf.pread_with(88, LE)?,
+            })
+        }
+
+        pub fn is_valid(&self) -> bool {
+            self.magic == Self::MAGIC
+                && self.version_major == 4
+                && self.version_minor == 0
+                && (4096..=1 << 20).contains(&self.block_size)
+                && 1 << self.block_log == self.block_size
+        }
+    }
+
+    // a data block or fragment size: bit 24 marks it stored uncompressed,
+    // 0 is a block of zeros that takes no space
+    pub fn block_size(raw: u32) -> (u32, bool) {
+        (raw & 0xFFFFFF, raw & 0x1000000 == 0)
+    }
+
+    pub const NO_FRAGMENT: u32 = 0xFFFFFFFF;
+
+    #[derive(Debug)]
+    pub enum InodeKind {
+        Dir {
+            start_block: u32, // relative to the directory table
+            offset: u16,
+            listing_sz: u32, // 3 more than the listing's bytes
+        },
+        File {
+            blocks_start: u64,
+            file_size: u64,
+            frag: u32,
+            frag_offset: u32,
+            block_sizes: Vec<u32>,
+        },
+        Symlink {
+            target: Vec<u8>,
+        },
+        // devices, fifos and sockets
+        Other,
+    }
+
+    #[allow(dead_code)]
+    #[derive(Debug)]
+    pub struct Inode {
+        pub typ: u16,
+        pub permissions: u16,
+        pub mtime: u32,
+        pub inode_number: u32,
+        pub kind: InodeKind,
+    }
+
+    impl Inode {
+        pub const HDR_SZ: usize = 16;
+
+        // the fixed part of the inode of type `typ` after the header, up to
+        // its block list or symlink target
+        pub fn fixed_sz(typ: u16) -> Option<usize> {
+            match typ {
+                1 => Some(16),
+                2 => Some(16),
+                3 => Some(8),
+                4 | 5 => Some(8),
+                6 | 7 => Some(4),
+                8 => Some(24),
+                9 => Some(40),
+                10 => Some(8),
+                11 | 12 => Some(12),
+                13 | 14 => Some(8),
+                _ => None,
+            }
+        }
+
+        // the bytes following the fixed part: the block list of a file, the
+        // target of a symlink
+        pub fn tail_sz(typ: u16, fixed: &[u8], block_size: u32) -> Result<usize, scroll::Error> {
+            let blocks = |size: u64, frag: u32| {
+                let full = size / block_size as u64;
+                let partial = !size.is_multiple_of(block_size as u64) && frag == NO_FRAGMENT;
+                (full + partial as u64) as usize * 4
+            };
+            Ok(match typ {
+                2 => blocks(
+                    fixed.pread_with::<u32>(12, LE)? as u64,
+                    fixed.pread_with(4, LE)?,
+                ),
+                9 => blocks(fixed.pread_with(8, LE)?, fixed.pread_with(28, LE)?),
+                3 | 10 => fixed.pread_with::<u32>(4, LE)? as usize,
+                _ => 0,
+            })
+        }
+
+        // `buf` holds the header, the fixed part and the tail
+        pub fn new(buf: &[u8]) -> Result<Self, scroll::Error> {
+            let typ: u16 = buf.pread_with(0, LE)?;
+            let body = &buf[Self::HDR_SZ..];
+            let block_sizes = |off: usize| -> Result<Vec<u32>, scroll::Error> {
+                body[off..]
+                    .chunks_exact(4)
+                    .map(|b| b.pread_with(0, LE))
+                    .collect()
+            };
+            let kind = match typ {
+                1 => InodeKind::Dir {
+                    start_block: body.pread_with(0, LE)?,
+                    listing_sz: body.pread_with::<u16>(8, LE)? as u32,
+                    offset: body.pread_with(10, LE)?,
+                },
+                8 => InodeKind::Dir {
+                    listing_sz: body.pread_with(4, LE)?,
+                    start_block: body.pread_with(8, LE)?,
+                    offset: body.pread_with(18, LE)?,
+                },
+                2 => InodeKind::File {
+                    blocks_start: body.pread_with::<u32>(0, LE)? as u64,
+                    frag: body.pread_with(4, LE)?,
+                    frag_offset: body.pread_with(8, LE)?,
+                    file_size: body.pread_with::<u32>(12, LE)? as u64,
+                    block_sizes: block_sizes(16)?,
+                },
+                9 => InodeKind::File {
+                    blocks_start: body.pread_with(0, LE)?,
+                    file_size: body.pread_with(8, LE)?,
+                    frag: body.pread_with(28, LE)?,
+                    frag_offset: body.pread_with(32, LE)?,
+                    block_sizes: block_sizes(40)?,
+                },
+                3 | 10 => {
+                    let len: u32 = body.pread_with(4, LE)?;
+                    InodeKind::Symlink {
+                        target: body[8..8 + len as usize].to_vec(),
+                    }
+                }
+                _ => InodeKind::Other,
+            };
+            Ok(Inode {
+                typ,
+                permissions: buf.pread_with(2, LE)?,
+                mtime: buf.pread_with(8, LE)?,
+                inode_number: buf.pread_with(12, LE)?,
+                kind,
+            })
+        }
+    }
+
+    // an entry of a directory listing, pointing at its inode
+    #[allow(dead_code)]
+    #[derive(Debug)]
+    pub struct DirEnt {
+        pub inode_ref: u64,
+        pub typ: u16,
+        pub name: String,
+    }
+
+    // a listing is a run of headers, each followed by up to 256 entries
+    // whose inodes share a metadata block
+    pub fn parse_listing(buf: &[u8]) -> Result<Vec<DirEnt>, scroll::Error> {
+        let mut ret = vec![];
+        let mut off = 0;
+        while off + 12 <= buf.len() {
+            let count: u32 = buf.pread_with(off, LE)?;
+            let start: u32 = buf.pread_with(off + 4, LE)?;
+            off += 12;
+            for _ in 0..=count {
+                let ino_off: u16 = buf.pread_with(off, LE)?;
+                let typ: u16 = buf.pread_with(off + 4, LE)?;
+                let name_len = buf.pread_with::<u16>(off + 6, LE)? as usize + 1;
+                let name = buf
+                    .get(off + 8..off + 8 + name_len)
+                    .ok_or(scroll::Error::TooBig {
+                        size: name_len,
+                        len: buf.len() - off - 8,
+                    })?;
+                ret.push(DirEnt {
+                    inode_ref: (start as u64) << 16 | ino_off as u64,
+                    typ,
+                    name: String::from_utf8_lossy(name).into_owned(),
+                });
+                off += 8 + name_len;
+            }
+        }
+        Ok(ret)
+    }
+}
+
+use spec::{Inode, InodeKind, SuperBlock};
+
+const METADATA_SZ: usize = 8192;
+// marks a Finfo id as an inode reference, which may be 0 or 1 otherwise
+const ID_TAG: u64 = 1 << 63;
+
+pub struct Fio<D: Device> {
+    device: D,
+    pub sb: SuperBlock,
+    // position -> (uncompressed block, position of the next one)
+    metadata: BTreeMap<u64, (Vec<u8>, u64)>,
+    // inode references of the directories listed so far, a Finfo's fst_clus
+    // is the index here
+    dirs: Vec<u64>,
+    dir_nos: BTreeMap<u64, u32>,
+    // what readdir pages through
+    listed: fio::LastListed,
+    frag_index: Vec<u64>,
+    // the last data block or fragment read, by position
+    last_block: Option<(u64, Vec<u8>)>,
+    // (inode, block n, where it starts) after the last read, the next read
+    // of the file goes on from there instead of adding up the sizes before
+    next_block: Option<(u64, usize, u64)>,
+}
+
+impl<D: Device> Fio<D> {
+    // Unsupported when the superblock isn't one fat32x can read
+    pub fn new(device: D) -> io::Result<Self> {
+        let _p = trace::purpose("boot");
+        let mut buf = [0u8; SuperBlock::SZ];
+        device.read_exact_at(&mut buf, 0)?;
+        let mut sb = SuperBlock::new(&buf).map_err(Error::from)?;
+        if !sb.is_valid() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "not a SquashFS archive: the superblock isn't valid",
+            ));
+        }
+
+        sb.bytes_used = fio::fit_count(
+            "squashfs",
+            "bytes used",
+            sb.bytes_used,
+            &[(fio::reach(&device), "the device")],
+        );
+        let mut fio = Fio {
+            device,
+            dirs: vec![sb.root_inode_ref],
+            dir_nos: BTreeMap::from([(sb.root_inode_ref, 0)]),
+            listed: fio::LastListed::default(),
+            metadata: BTreeMap::new(),
+            frag_index: vec![],
+            last_block: None,
+            next_block: None,
+            sb,
+        };
+        // the index, 8 bytes per 512 fragments, lies within the archive
+        let cnt = fio::fit_count(
+            "squashfs",
+            "fragment index count",
+            fio.sb.frag_count.div_ceil(512) as u64,
+            &[(
+                fio.sb
+                    .bytes_used
+                    .saturating_sub(fio.sb.fragment_table_start)
+                    / 8,
+                "the archive",
+            )],
+        ) as usize;
+        let mut index = vec![0u8; cnt * 8];
+        fio.device
+            .read_exact_at(&mut index, fio.sb.fragment_table_start)?;
+        fio.frag_index = index
+            .chunks_exact(8)
+            .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+            .collect();
+        Ok(fio)
+    }
+
+    fn decompress(&self, data: &[u8], max: usize) -> Result<Vec<u8>, Error> {
+        match self.sb.compressor {
+            1 => miniz_oxide::inflate::decompress_to_vec_zlib_with_limit(data, max)
+                .map_err(|_| Error::Decompress("gzip")),
+            2 => {
+                let mut out = Capped(Vec::new(), max);
+                let opts = lzma_rs::decompress::Options {
+                    memlimit: Some(max),
+                    ..Default::default()
+                };
+                lzma_rs::lzma_decompress_with_options(&mut &data[..], &mut out, &opts)
+                    .map_err(|_| Error::Decompress("lzma"))?;
+                Ok(out.0)
+            }
+            4 => {
+                let mut out = Capped(Vec::new(), max);
+                lzma_rs::xz_decompress(&mut &data[..], &mut out)
+                    .map_err(|_| Error::Decompress("xz"))?;
+                Ok(out.0)
+            }
+            6 => {
+                let mut out = vec![];
+                ruzstd::decoding::StreamingDecoder::new(data)
+                    .map_err(|_| Error::Decompress("zstd"))?
+                    .take(max as u64 + 1)
+                    .read_to_end(&mut out)
+                    .map_err(|_| Error::Decompress("zstd"))?;
+                if out.len() > max {
+                    return Err(Error::Decompress("zstd"));
+                }
+                Ok(out)
+            }
+            other => Err(Error::Compressor(other)),
+        }
+    }
+
+    // the metadata block at `pos` uncompressed, and where the next starts
+    fn metadata_block(&mut self, pos: u64) -> Result<(Vec<u8>, u64), Error> {
+        if let Some(block) = self.metadata.get(&pos) {
+            return Ok(block.clone());
+        }
+        if pos + 2 > self.sb.bytes_used {
+            return Err(Error::Metadata(pos));
+        }
+        let mut hdr = [0u8; 2];
+        self.device.read_exact_at(&mut hdr, pos)?;
+        let hdr = u16::from_le_bytes(hdr);
+        let len = (hdr & 0x7FFF) as usize;
+        let mut buf = vec![0u8; len];
+        self.device.read_exact_at(&mut buf, pos + 2)?;
+        let data = match hdr & 0x8000 {
+            0 => self.decompress(&buf, METADATA_SZ)?,
+            _ => buf,
+        };
+        let block = (data, pos + 2 + len as u64);
+        self.metadata.insert(pos, block.clone());
+        Ok(block)
+    }
+
+    // `len` bytes of the metadata stream from `offset` into the block at `pos`
+    fn read_metadata(&mut self, mut pos: u64, offset: usize, len: usize) -> Result<Vec<u8>, Error> {
+        // `len` comes from the media, it's only as long as the blocks there
+        let mut ret = Vec::with_capacity(len.min(METADATA_SZ));
+        let mut skip = offset;
+        while ret.len() < len {
+            let (block, next) = self.metadata_block(pos)?;
+            let from = skip.min(block.len());
+            let take = (len - ret.len()).min(block.len() - from);
+            ret.extend(&block[from..from + take]);
+            skip -= from;
+            pos = next;
+        }
+        Ok(ret)
+    }
+
+    pub fn read_inode(&mut self, inode_ref: u64) -> Result<Inode, Error> {
+        let _p = trace::purpose("dirent");
+        let pos = self.sb.inode_table_start + (inode_ref >> 16);
+        let offset = (inode_ref & 0xFFFF) as usize;
+        let hdr = self.read_metadata(pos, offset, Inode::HDR_SZ)?;
+        let typ = u16::from_le_bytes([hdr[0], hdr[1]]);
+        let fixed = Inode::fixed_sz(typ).ok_or(Error::UndefinedInode(typ))?;
+        let buf = self.read_metadata(pos, offset, Inode::HDR_SZ + fixed)?;
+        let tail = Inode::tail_sz(typ, &buf[Inode::HDR_SZ..], self.sb.block_size)?;
+        let buf = self.read_metadata(pos, offset, Inode::HDR_SZ + fixed + tail)?;
+        Ok(Inode::new(&buf)?)
+    }
+
+    fn list(&mut self, dir_ref: u64) -> Result<Vec<Finfo>, Error> {
+        let InodeKind::Dir {
+            start_block,
+            offset,
+            listing_sz,
+        } = self.read_inode(dir_ref)?.kind
+        else {
+            return Ok(vec![]);
+        };
+        let pos = self.sb.directory_table_start + start_block as u64;
+        let listing =
+            self.read_metadata(pos, offset as usize, listing_sz.saturating_sub(3) as usize)?;
+        let mut ret = vec![];
+        for ent in spec::parse_listing(&listing)? {
+            let inode = self.read_inode(ent.inode_ref)?;
+            let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(inode.mtime as u64);
+            let (is_dir, size, fst_clus) = match &inode.kind {
+                InodeKind::Dir { .. } => (true, 0, self.dir_no(ent.inode_ref)),
+                InodeKind::File { file_size, .. } => (false, *file_size, 0),
+                // the target is what reading a symlink gives
+                InodeKind::Symlink { target } => (false, target.len() as u64, 0),
+                InodeKind::Other => continue,
+            };
+            ret.push(Finfo {
+                pos: ret.len() as u64,
+                id: ID_TAG | ent.inode_ref,
+                name: fio::shown_name(ent.name),
+                is_rdonly: inode.permissions & 0o222 == 0,
+                is_hidden: false,
+                is_system: false,
+                is_dir,
+                size,
+                valid_size: size,
+                fst_clus,
+                no_fat_chain: false,
+                crt_time: mtime,
+                wrt_time: mtime,
+                acc_time: mtime,
+            });
+        }
+        Ok(ret)
+    }
+
+    fn dir_no(&mut self, dir_ref: u64) -> u32 {
+        if let Some(&no) = self.dir_nos.get(&dir_ref) {
+            return no;
+        }
+        self.dirs.push(dir_ref);
+        let no = self.dirs.len() as u32 - 1;
+        self.dir_nos.insert(dir_ref, no);
+        no
+    }
+
+    // a data block or fragment block, uncompressed
+    fn read_block(&mut self, pos: u64, raw_sz: u32) -> Result<Vec<u8>, Error> {
+        let (len, compressed) = spec::block_size(raw_sz);
+        if len == 0 {
+            return Ok(vec![0u8; self.sb.block_size as usize]);
+        }
+        if let Some((at, block)) = &self.last_block {
+            if *at == pos {
+                return Ok(block.clone());
+            }
+        }
+        let mut buf = vec![0u8; len as usize];
+        self.device.read_exact_at(&mut buf, pos)?;
+        let block = match compressed {
+            true => self.decompress(&buf, self.sb.block_size as usize)?,
+            false => buf,
+        };
+        self.last_block = Some((pos, block.clone()));
+        Ok(block)
+    }
+
+    // (position, size) of fragment block `no`
+    fn fragment(&mut self, no: u32) -> Result<(u64, u32), Error> {
+        let &pos = self
+            .frag_index
+            .get(no as usize / 512)
+            .ok_or(Error::Metadata(self.sb.fragment_table_start))?;
+        let ent = self.read_metadata(pos, (no as usize % 512) * 16, 16)?;
+        Ok((
+            u64::from_le_bytes(ent[..8].try_into().unwrap()),
+            u32::from_le_bytes(ent[8..12].try_into().unwrap()),
+        ))
+    }
+
+    fn read(&mut self, fi: &Finfo, offset: u64, size: u32) -> Result<Vec<u8>, Error> {
+        let _p = trace::purpose("data");
+        let inode = self.read_inode(fi.id & !ID_TAG)?;
+        let (blocks_start, file_size, frag, frag_offset, block_sizes) = match inode.kind {
+            InodeKind::File {
+                blocks_start,
+                file_size,
+                frag,
+                frag_offset,
+                block_sizes,
+            } => (blocks_start, file_size, frag, frag_offset, block_sizes),
+            InodeKind::Symlink { target } => {
+                let from = offset.min(target.len() as u64) as usize;
+                let to = (from + size as usize).min(target.len());
+                return Ok(target[from..to].to_vec());
+            }
+            _ => return Ok(vec![]),
+        };
+        let end = (offset + size as u64).min(file_size);
+        let bs = self.sb.block_size as u64;
+        let mut ret = vec![];
+        let mut at = offset;
+        let ino = fi.id & !ID_TAG;
+        let mut idx = (at / bs) as usize;
+        // blocks are where the ones before them end
+        let (from_idx, mut pos) = match self.next_block {
+            Some((i, n, pos)) if i == ino && n <= idx => (n, pos),
+            _ => (0, blocks_start),
+        };
+        pos += block_sizes
+            .iter()
+            .take(idx)
+            .skip(from_idx)
+            .map(|&s| spec::block_size(s).0 as u64)
+            .sum::<u64>();
+        while at < end {
+            let block = match block_sizes.get(idx) {
+                Some(&raw) => {
+                    let block = self.read_block(pos, raw)?;
+                    pos += spec::block_size(raw).0 as u64;
+                    self.next_block = Some((ino, idx + 1, pos));
+                    block
+                }
+                // the tail packed into a fragment block
+                None if frag != spec::NO_FRAGMENT => {
+                    let (pos, sz) = self.fragment(frag)?;
+                    let block = self.read_block(pos, sz)?;
+                    let tail = (file_size % bs) as usize;
+                    block
+                        .get(frag_offset as usize..frag_offset as usize + tail)
+                        .ok_or(Error::Metadata(pos))?
+                        .to_vec()
+                }
+                None => break,
+            };
+            let from = (at % bs) as usize;
+            let to = block.len().min(from + (end - at) as usize);
+            if from >= to {
+                break;
+            }
+            ret.extend(&block[from..to]);
+            at += (to - from) as u64;
+            idx += 1;
+            // a block cut short, what's past it isn't there
+            if at < end && at != idx as u64 * bs {
+                break;
+            }
+        }
+        Ok(ret)
+    }
+}
+
+impl<D: Device> fio::Fio for Fio<D> {
+    fn list_dir(&mut self, no: u32) -> io::Result<Vec<Finfo>> {
+        match self.dirs.get(no as usize) {
+            Some(&dir_ref) => Ok(self.list(dir_ref)?),
+            None => Ok(vec![]),
+        }
+    }
+
+    fn list_root(&mut self) -> io::Result<Vec<Finfo>> {
+        self.list_dir(0)
+    }
+
+    fn list_dir_page(
+        &mut self,
+        no: u32,
+        from: u64,
+        max: usize,
+    ) -> io::Result<(Vec<Finfo>, Option<u64>)> {
+        let mut listed = std::mem::take(&mut self.listed);
+        let page = listed.page(no, from, max, |no| match self.dirs.get(no as usize) {
+            Some(&dir_ref) => Ok(self.list(dir_ref)?),
+            None => Ok(vec![]),
+        });
+        self.listed = listed;
+        page
+    }
+
+    fn read_file(&mut self, fi: &Finfo, offset: u64, size: u32) -> io::Result<Vec<u8>> {
+        self.read(fi, offset, size).map_err(io::Error::from)
+    }
+
+    // blocks are read whole to be decompressed, there's no retry layer
+    // zero-filling parts of them
+    fn unreadable(&mut self, _fi: &Finfo, _offset: u64, _size: u32) -> Vec<(u64, u64)> {
+        vec![]
+    }
+
+    fn volume(&mut self) -> io::Result<fio::VolumeInfo> {
+        Ok(fio::VolumeInfo {
+            serial: 0,
+            label: String::new(),
+            clus_sz: self.sb.block_size,
+            clus_cnt: self.sb.bytes_used.div_ceil(self.sb.block_size as u64) as u32,
+            free: None,
+        })
+    }
+
+    // the metadata blocks read, the dir and fragment tables and the dir
+    // listed last
+    fn held(&self) -> u64 {
+        let metadata: u64 = self
+            .metadata
+            .values()
+            .map(|(block, _)| block.len() as u64 + 48)
+            .sum();
+        let tables =
+            (self.dirs.len() * 8 + self.dir_nos.len() * 48 + self.frag_index.len() * 8) as u64;
+        let block = self.last_block.as_ref().map_or(0, |(_, b)| b.len() as u64);
+        metadata + tables + block + self.listed.held()
+    }
+}
@@ -0,0 +1,94 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::cache;
+
+// counters bumped by the layers they're named after, printed by dump()
+pub struct Stats {
+    pub dev_reads: AtomicU64,
+    pub dev_read_bytes: AtomicU64,
+    pub dev_writes: AtomicU64,
+    pub dev_write_bytes: AtomicU64,
+    pub fat_reads: AtomicU64, // single FAT entries looked up
+    pub fat_walks: AtomicU64, // cluster chains followed
+    pub dir_hits: AtomicU64,  // listings served from the mount's dir cache
+    pub dir_misses: AtomicU64,
+}
+
+pub static STATS: Stats = Stats {
+    dev_reads: AtomicU64::new(0),
+    dev_read_bytes: AtomicU64::new(0),
+    dev_writes: AtomicU64::new(0),
+    dev_write_bytes: AtomicU64::new(0),
+    fat_reads: AtomicU64::new(0),
+    fat_walks: AtomicU64::new(0),
+    dir_hits: AtomicU64::new(0),
+    dir_misses: AtomicU64::new(0),
+};
+
+pub fn add(counter: &AtomicU64, n: u64) {
+    counter.fetch_add(n, Ordering::Relaxed);
+}
+
+// what dump prints, for the daemon to hand out over its socket
+pub fn lines() -> Vec<String> {
+    let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+    let s = &STATS;
+    let mut lines = vec![
+        format!(
+            "[stats] device: {} reads {} bytes, {} writes {} bytes",
+            get(&s.dev_reads),
+            get(&s.dev_read_bytes),
+            get(&s.dev_writes),
+            get(&s.dev_write_bytes)
+        ),
+        format!(
+            "[stats] fat: {} entry reads, {} chain walks",
+            get(&s.fat_reads),
+            get(&s.fat_walks)
+        ),
+        format!(
+            "[stats] dir cache: {} hits, {} misses",
+            get(&s.dir_hits),
+            get(&s.dir_misses)
+        ),
+    ];
+    lines.extend(cache::lines());
+    lines
+}
+
+pub fn dump() {
+    for line in lines() {
+        println!("{}", line);
+    }
+}
+
+// prints the stats when dropped, so every way out of main reports them
+pub struct DumpOnDrop;
+
+impl Drop for DumpOnDrop {
+    fn drop(&mut self) {
+        dump();
+    }
+}
+
+// dump the stats whenever the process gets SIGUSR1, for long running mounts.
+// the signal is blocked here and picked up by a thread of its own, so it
+// has to be called before any other thread is spawned
+#[cfg(unix)]
+pub fn dump_on_sigusr1() {
+    unsafe {
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGUSR1);
+        if libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut()) != 0 {
+            eprintln!("[stats] SIGUSR1 can't be caught");
+            return;
+        }
+        std::thread::spawn(move || loop {
+            let mut sig = 0;
+            if libc::sigwait(&set, &mut sig) == 0 && sig == libc::SIGUSR1 {
+                dump();
+            }
+        });
+    }
+}
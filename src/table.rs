@@ -0,0 +1,120 @@
+// what the listing commands print: columns lined up, sizes in binary units
+// and times as ISO 8601 or an age. only the last column, the name, may hold
+// spaces, so awk's fields stay put
+
+use std::time::SystemTime;
+
+use chrono::{DateTime, Local};
+use clap::ValueEnum;
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Times {
+    // 2024-05-01T12:30:00+02:00, in the local time zone
+    Iso,
+    // how long ago, as 45s, 20m, 3h, 12d or 2y
+    Age,
+}
+
+pub struct Table {
+    header: Option<Vec<String>>,
+    rows: Vec<Vec<String>>,
+    right: Vec<bool>, // the columns aligned to the right, numbers
+}
+
+impl Table {
+    // the columns named, `-` in front of a name for one aligned right
+    pub fn new(columns: &[&str], header: bool) -> Self {
+        let right = columns.iter().map(|c| c.starts_with('-')).collect();
+        let names = columns
+            .iter()
+            .map(|c| c.trim_start_matches('-').to_string());
+        Table {
+            header: header.then(|| names.collect()),
+            rows: vec![],
+            right,
+        }
+    }
+
+    pub fn row(&mut self, cells: Vec<String>) {
+        self.rows.push(cells);
+    }
+
+    pub fn print(&self) {
+        let rows: Vec<&Vec<String>> = self.header.iter().chain(self.rows.iter()).collect();
+        let mut widths = vec![0; self.right.len()];
+        for row in rows.iter() {
+            for (w, cell) in widths.iter_mut().zip(row.iter()) {
+                *w = (*w).max(cell.chars().count());
+            }
+        }
+        let last = self.right.len() - 1;
+        for row in rows {
+            let line: Vec<String> = row
+                .iter()
+                .enumerate()
+                .map(|(i, cell)| match (self.right[i], i == last) {
+                    (true, _) => format!("{:>1$}", cell, widths[i]),
+                    (false, false) => format!("{:<1$}", cell, widths[i]),
+                    (false, true) => cell.clone(),
+                })
+                .collect();
+            println!("{}", line.join("  "));
+        }
+    }
+}
+
+// 1536 as "1.5KiB", `exact` for the plain byte count
+pub fn size(bytes: u64, exact: bool) -> String {
+    const UNITS: [&str; 5] = ["", "KiB", "MiB", "GiB", "TiB"];
+    let mut n = bytes as f64;
+    let mut unit = 0;
+    while n >= 1024.0 && unit < UNITS.len() - 1 {
+        n /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        _ if exact => bytes.to_string(),
+        0 => bytes.to_string(),
+        _ => format!("{:.1}{}", n, UNITS[unit]),
+    }
+}
+
+pub fn time(t: SystemTime, times: Times, now: SystemTime) -> String {
+    match times {
+        Times::Iso => DateTime::<Local>::from(t)
+            .format("%Y-%m-%dT%H:%M:%S%:z")
+            .to_string(),
+        Times::Age => match now.duration_since(t) {
+            Ok(age) => {
+                let secs = age.as_secs();
+                match secs {
+                    0..60 => format!("{}s", secs),
+                    60..3600 => format!("{}m", secs / 60),
+                    3600..86400 => format!("{}h", secs / 3600),
+                    86400..31536000 => format!("{}d", secs / 86400),
+                    _ => format!("{}y", secs / 31536000),
+                }
+            }
+            // a clock set wrong when it was written
+            Err(_) => String::from("future"),
+        },
+    }
+}
+
+// `s` as a JSON string, quoted and escaped
+pub fn json_str(s: &str) -> String {
+    let mut out = String::from('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
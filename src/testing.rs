@@ -0,0 +1,12 @@
+// helpers the tests of several modules share
+
+// random bytes read in and dumped back come out the same, `check` is
+// handed a buffer of `sz` of them and does both
+pub fn round_trips(sz: usize, mut check: impl FnMut(&mut [u8])) {
+    let mut rng = fastrand::Rng::with_seed(0xFA732);
+    for _ in 0..1000 {
+        let mut buf = vec![0u8; sz];
+        rng.fill(&mut buf);
+        check(&mut buf);
+    }
+}
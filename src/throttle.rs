@@ -0,0 +1,92 @@
+// going easy on a device that's in use or dying: reads held to a rate by a
+// token bucket, and the process put at the back of the IO queue
+
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+// bytes a second, 0 for no limit. see set_max_rate
+static RATE: AtomicU64 = AtomicU64::new(0);
+// bytes that can be read right away, and when that was worked out
+static BUCKET: Mutex<Option<(f64, Instant)>> = Mutex::new(None);
+
+// "10M", "512K" or plain bytes, K, M and G being 1024 based
+pub fn parse_rate(s: &str) -> Result<u64, String> {
+    let (num, shift) = match s.char_indices().last() {
+        Some((at, 'K' | 'k')) => (&s[..at], 10),
+        Some((at, 'M' | 'm')) => (&s[..at], 20),
+        Some((at, 'G' | 'g')) => (&s[..at], 30),
+        _ => (s, 0),
+    };
+    let n = num.parse::<u64>().map_err(|e| e.to_string())?;
+    match n.checked_mul(1 << shift) {
+        Some(0) => Err(String::from("the rate can't be 0")),
+        Some(rate) => Ok(rate),
+        None => Err(String::from("the rate is too large")),
+    }
+}
+
+// reads from every device from here on average at most `bytes` a second,
+// with up to a second's worth let through at once
+pub fn set_max_rate(bytes: u64) {
+    RATE.store(bytes, Ordering::Relaxed);
+}
+
+// wait until `len` more bytes can be read. the wait happens holding the
+// bucket so concurrent readers queue up behind it
+pub fn take(len: usize) {
+    let rate = RATE.load(Ordering::Relaxed);
+    if rate == 0 {
+        return;
+    }
+    let rate = rate as f64;
+    let mut bucket = BUCKET.lock().unwrap();
+    let now = Instant::now();
+    let (tokens, last) = bucket.unwrap_or((rate, now));
+    let tokens = (tokens + now.duration_since(last).as_secs_f64() * rate).min(rate) - len as f64;
+    if tokens < 0.0 {
+        thread::sleep(Duration::from_secs_f64(-tokens / rate));
+    }
+    *bucket = Some((tokens, now));
+}
+
+// the lowest IO priority there is and the lowest CPU one, so other IO to
+// the device comes first
+#[cfg(target_os = "linux")]
+pub fn set_idle() -> io::Result<()> {
+    // IOPRIO_WHO_PROCESS and IOPRIO_CLASS_IDLE from linux/ioprio.h
+    const WHO_PROCESS: libc::c_int = 1;
+    const CLASS_IDLE: libc::c_int = 3 << 13;
+    if unsafe { libc::syscall(libc::SYS_ioprio_set, WHO_PROCESS, 0, CLASS_IDLE) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    nice()
+}
+
+// the lowest CPU priority, there's no IO priority to set
+#[cfg(all(unix, not(target_os = "linux")))]
+pub fn set_idle() -> io::Result<()> {
+    nice()
+}
+
+#[cfg(unix)]
+fn nice() -> io::Result<()> {
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS as _, 0, 19) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+pub fn set_idle() -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "there's no idle priority to set on Windows",
+    ))
+}
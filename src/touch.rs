@@ -0,0 +1,151 @@
+use std::{io, time::SystemTime};
+
+use chrono::{DateTime, Datelike, FixedOffset, Local, NaiveDate, NaiveDateTime, Timelike};
+
+use crate::attrib;
+use crate::device::{Device, Image};
+use crate::exfat;
+use crate::fat32;
+use crate::fio::{self, FsType};
+
+// a wall clock time as given, with its UTC offset when one was given. FAT32
+// keeps only the wall clock, exFAT records the offset too
+#[derive(Debug, Clone)]
+pub struct Stamp {
+    wall: NaiveDateTime,
+    tz: Option<FixedOffset>,
+}
+
+#[derive(Debug, Default)]
+pub struct Times {
+    pub mtime: Option<Stamp>,
+    pub crtime: Option<Stamp>,
+    pub atime: Option<Stamp>,
+}
+
+impl Times {
+    pub fn is_empty(&self) -> bool {
+        self.mtime.is_none() && self.crtime.is_none() && self.atime.is_none()
+    }
+}
+
+// `2024-01-02 03:04:05.67`, `T` in place of the space and a trailing offset
+// like `+08:00` or `Z` are fine too, so is the date alone
+pub fn parse_time(s: &str) -> Result<Stamp, String> {
+    let stamp = if let Ok(dt) = DateTime::parse_from_rfc3339(s) {
+        Stamp {
+            wall: dt.naive_local(),
+            tz: Some(*dt.offset()),
+        }
+    } else if let Some(wall) = ["%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M:%S%.f"]
+        .iter()
+        .find_map(|fmt| NaiveDateTime::parse_from_str(s, fmt).ok())
+    {
+        Stamp { wall, tz: None }
+    } else if let Ok(date) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        Stamp {
+            wall: date.and_hms_opt(0, 0, 0).unwrap(),
+            tz: None,
+        }
+    } else {
+        return Err(format!("{} isn't a time like 2024-01-02 03:04:05", s));
+    };
+    if !(1980..=2107).contains(&stamp.wall.year()) {
+        return Err(format!("{} is out of 1980..=2107", s));
+    }
+    if let Some(tz) = stamp.tz {
+        let secs = tz.local_minus_utc();
+        if secs % (15 * 60) != 0 || !(-16 * 3600..=15 * 3600 + 45 * 60).contains(&secs) {
+            return Err(format!("{} has an offset exFAT can't record", s));
+        }
+    }
+    Ok(stamp)
+}
+
+// the FAT date and time fields of `wall` and the 10ms units past the 2 second
+// resolution, 0..=199
+fn encode(wall: &NaiveDateTime) -> (u16, u16, u8) {
+    let (date, time) = fat32::spec::date_time(wall);
+    let ms10 = (wall.second() % 2 * 100 + wall.nanosecond().min(999_999_999) / 10_000_000) as u8;
+    (date, time, ms10)
+}
+
+// the exFAT offset byte, 15 minute units with the top bit marking it valid
+fn tz_byte(tz: Option<FixedOffset>) -> u8 {
+    match tz {
+        Some(tz) => 0x80 | ((tz.local_minus_utc() / (15 * 60)) as i8 as u8 & 0x7F),
+        None => 0,
+    }
+}
+
+// set the given times of the entry at `path`, then print all three
+pub fn touch(file: &Image, typ: &FsType, path: &str, times: &Times) -> io::Result<()> {
+    let not_found = || io::Error::new(io::ErrorKind::NotFound, format!("{} not found", path));
+
+    match typ {
+        FsType::Fat32 => {
+            let mut fio = fat32::fio::Fio::new(file)?;
+            fio.writable()?;
+            let fi = fio::lookup(&mut fio, path)?.ok_or_else(not_found)?;
+            let off = fio.dirent_offset(fi.id);
+            let mut ent = [0u8; 32];
+            file.read_exact_at(&mut ent, off)?;
+            let old = ent;
+            if let Some(t) = &times.crtime {
+                let (date, time, ms10) = encode(&t.wall);
+                ent[13] = ms10;
+                ent[14..16].copy_from_slice(&time.to_le_bytes());
+                ent[16..18].copy_from_slice(&date.to_le_bytes());
+            }
+            if let Some(t) = &times.atime {
+                ent[18..20].copy_from_slice(&encode(&t.wall).0.to_le_bytes());
+            }
+            if let Some(t) = &times.mtime {
+                let (date, time, _) = encode(&t.wall);
+                ent[22..24].copy_from_slice(&time.to_le_bytes());
+                ent[24..26].copy_from_slice(&date.to_le_bytes());
+            }
+            if ent != old {
+                file.write_all_at(&ent, off)?;
+            }
+        }
+        FsType::Exfat => {
+            let mut fio = exfat::Fio::new(file)?;
+            let fi = fio::lookup(&mut fio, path)?.ok_or_else(not_found)?;
+            fio.update_primary(fi.id, |ent| {
+                // (datetime, 10ms increment, offset) positions in the entry
+                let fields = [
+                    (&times.crtime, 8, Some(20), 22),
+                    (&times.mtime, 12, Some(21), 23),
+                    (&times.atime, 16, None, 24),
+                ];
+                for (t, dt_at, ms10_at, tz_at) in fields {
+                    let Some(t) = t else { continue };
+                    let (date, time, ms10) = encode(&t.wall);
+                    let dt = (date as u32) << 16 | time as u32;
+                    ent[dt_at..dt_at + 4].copy_from_slice(&dt.to_le_bytes());
+                    if let Some(at) = ms10_at {
+                        ent[at] = ms10;
+                    }
+                    ent[tz_at] = tz_byte(t.tz);
+                }
+            })?;
+        }
+        FsType::Squashfs | FsType::Udf | FsType::Ext2 => return Err(attrib::read_only(typ)),
+    }
+    if !times.is_empty() {
+        file.sync_all()?;
+    }
+
+    // read back through a fresh view so the output is what's on disk
+    let fi = fio::lookup(fio::open(file, typ)?.as_mut(), path)?.ok_or_else(not_found)?;
+    let show = |t: SystemTime| {
+        DateTime::<Local>::from(t)
+            .format("%Y-%m-%d %H:%M:%S%.3f")
+            .to_string()
+    };
+    println!("mtime   {}", show(fi.wrt_time));
+    println!("crtime  {}", show(fi.crt_time));
+    println!("atime   {}", show(fi.acc_time));
+    Ok(())
+}
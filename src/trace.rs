@@ -0,0 +1,62 @@
+use std::{
+    cell::Cell,
+    fs::File,
+    io::{self, LineWriter, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, OnceLock,
+    },
+    time::Instant,
+};
+
+static ON: AtomicBool = AtomicBool::new(false);
+static SINK: Mutex<Option<Box<dyn Write + Send>>> = Mutex::new(None);
+static START: OnceLock<Instant> = OnceLock::new();
+
+thread_local! {
+    // what the device accesses of this thread are for, see purpose()
+    static PURPOSE: Cell<&'static str> = const { Cell::new("other") };
+}
+
+// record every device access from here on to `path`, stdout for `-`
+pub fn start(path: &str) -> io::Result<()> {
+    let sink: Box<dyn Write + Send> = match path {
+        "-" => Box::new(io::stdout()),
+        path => Box::new(LineWriter::new(File::create(path)?)),
+    };
+    *SINK.lock().unwrap() = Some(sink);
+    START.get_or_init(Instant::now);
+    ON.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+// tags the device accesses of this thread with `tag` until dropped
+pub struct Purpose(&'static str);
+
+impl Drop for Purpose {
+    fn drop(&mut self) {
+        PURPOSE.with(|p| p.set(self.0));
+    }
+}
+
+// nested purposes win, reading a FAT sector while looking up file data
+// traces as "fat"
+pub fn purpose(tag: &'static str) -> Purpose {
+    Purpose(PURPOSE.with(|p| p.replace(tag)))
+}
+
+// one JSON object per line, the time in microseconds since tracing started
+pub fn record(op: &str, offset: u64, len: usize) {
+    if !ON.load(Ordering::Relaxed) {
+        return;
+    }
+    let us = START.get().map_or(0, |t| t.elapsed().as_micros());
+    let tag = PURPOSE.with(|p| p.get());
+    if let Some(sink) = SINK.lock().unwrap().as_mut() {
+        let _ = writeln!(
+            sink,
+            r#"{{"us":{},"op":"{}","offset":{},"len":{},"purpose":"{}"}}"#,
+            us, op, offset, len, tag
+        );
+    }
+}
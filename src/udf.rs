@@ -0,0 +1,928 @@
+// References:
+// [1] ECMA-167 3rd edition, https://www.ecma-international.org/publications-and-standards/standards/ecma-167/
+// [2] OSTA UDF 2.60, http://www.osta.org/specs/pdf/udf260.pdf
+
+use std::{collections::BTreeMap, io, time::SystemTime};
+
+use scroll::{Pread, LE};
+
+use crate::device::Device;
+use crate::fio::{self, Finfo};
+use crate::trace;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("scroll read failed")]
+    Scroll(#[from] scroll::Error),
+    #[error("device read failed: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("no {0} at block {1}")]
+    Descriptor(&'static str, u32),
+    #[error("block {1} of partition reference {0} isn't mapped")]
+    Unmapped(u16, u32),
+    #[error("{0} partitions aren't supported")]
+    PartitionType(String),
+    #[error("allocation descriptor type {0} isn't supported")]
+    AdType(u8),
+}
+
+// device failures stay as they are, the rest means the image is broken
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+pub mod spec {
+    use std::time::SystemTime;
+
+    use chrono::{FixedOffset, TimeZone};
+    use scroll::{Pread, LE};
+
+    // the anchor volume descriptor pointer is at this block, whatever the
+    // block size
+    pub const ANCHOR_BLOCK: u32 = 256;
+
+    pub const TAG_PVD: u16 = 1;
+    pub const TAG_AVDP: u16 = 2;
+    pub const TAG_VDP: u16 = 3;
+    pub const TAG_PD: u16 = 5;
+    pub const TAG_LVD: u16 = 6;
+    pub const TAG_TD: u16 = 8;
+    pub const TAG_FSD: u16 = 256;
+    pub const TAG_FID: u16 = 257;
+    pub const TAG_AED: u16 = 258;
+    pub const TAG_FE: u16 = 261;
+    pub const TAG_EFE: u16 = 266;
+
+    // the header of every descriptor
+    #[allow(dead_code)]
+    #[derive(Debug)]
+    pub struct Tag {
+        pub id: u16,
+        pub version: u16,
+        pub location: u32, // the block the descriptor says it's at
+        sum_ok: bool,
+    }
+
+    impl Tag {
+        pub const SZ: usize = 16;
+
+        pub fn new(buf: &[u8]) -> Result<Self, scroll::Error> {
+            let checksum: u8 = buf.pread(4)?;
+            // the checksum byte is the sum of the other 15 bytes of the tag
+            let sum = buf
+                .get(..Self::SZ)
+                .ok_or(scroll::Error::TooBig {
+                    size: Self::SZ,
+                    len: buf.len(),
+                })?
+                .iter()
+                .enumerate()
+                .filter(|&(i, _)| i != 4)
+                .fold(0u8, |sum, (_, &b)| sum.wrapping_add(b));
+            Ok(Tag {
+                id: buf.pread_with(0, LE)?,
+                version: buf.pread_with(2, LE)?,
+                location: buf.pread_with(12, LE)?,
+                sum_ok: sum == checksum,
+            })
+        }
+
+        pub fn is(&self, id: u16, location: u32) -> bool {
+            self.sum_ok && self.id == id && self.location == location
+        }
+    }
+
+    // kinds of extent, in the top 2 bits of an allocation descriptor's length
+    pub const RECORDED: u8 = 0;
+    pub const NEXT: u8 = 3; // continues in an allocation extent descriptor
+
+    // a short_ad or long_ad, the blocks being in partition `part_ref`
+    #[derive(Debug, Clone, Copy)]
+    pub struct Extent {
+        pub len: u32,
+        pub kind: u8,
+        pub lb: u32,
+        pub part_ref: u16,
+    }
+
+    impl Extent {
+        pub const SHORT_SZ: usize = 8;
+        pub const LONG_SZ: usize = 16;
+
+        pub fn short(buf: &[u8], part_ref: u16) -> Result<Self, scroll::Error> {
+            let len: u32 = buf.pread_with(0, LE)?;
+            Ok(Extent {
+                len: len & 0x3FFFFFFF,
+                kind: (len >> 30) as u8,
+                lb: buf.pread_with(4, LE)?,
+                part_ref,
+            })
+        }
+
+        pub fn long(buf: &[u8]) -> Result<Self, scroll::Error> {
+            let part_ref = buf.pread_with(8, LE)?;
+            Self::short(buf, part_ref)
+        }
+    }
+
+    // OSTA compressed unicode: a compression id of 8 for one byte per
+    // character, 16 for big endian UCS-2
+    pub fn cs0(buf: &[u8]) -> String {
+        match buf.split_first() {
+            Some((8 | 254, chars)) => chars.iter().map(|&c| c as char).collect(),
+            Some((16 | 255, chars)) => {
+                let units: Vec<u16> = chars
+                    .chunks_exact(2)
+                    .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                    .collect();
+                String::from_utf16_lossy(&units)
+            }
+            _ => String::new(),
+        }
+    }
+
+    // a fixed size field holding cs0, its used length in the last byte
+    pub fn dstring(buf: &[u8]) -> String {
+        match buf.split_last() {
+            Some((&len, chars)) => cs0(&chars[..(len as usize).min(chars.len())]),
+            None => String::new(),
+        }
+    }
+
+    // the identifier of an entity id (regid), e.g. "*UDF Metadata Partition"
+    pub fn regid(buf: &[u8]) -> String {
+        let ident = &buf[1..24];
+        let len = ident.iter().position(|&b| b == 0).unwrap_or(ident.len());
+        String::from_utf8_lossy(&ident[..len]).into_owned()
+    }
+
+    pub fn timestamp(buf: &[u8]) -> Option<SystemTime> {
+        let type_tz: u16 = buf.pread_with(0, LE).ok()?;
+        let year: i16 = buf.pread_with(2, LE).ok()?;
+        // minutes from UTC as a signed 12-bit number, -2047 if not given
+        let tz = ((type_tz << 4) as i16) >> 4;
+        let tz = match tz {
+            -2047 => FixedOffset::east_opt(0)?,
+            tz => FixedOffset::east_opt(tz as i32 * 60)?,
+        };
+        let time = tz
+            .with_ymd_and_hms(
+                year.into(),
+                buf[4].into(),
+                buf[5].into(),
+                buf[6].into(),
+                buf[7].into(),
+                buf[8].into(),
+            )
+            .single()?;
+        let micros = buf[9] as u32 * 10_000 + buf[10] as u32 * 100 + buf[11] as u32;
+        Some(SystemTime::from(time) + std::time::Duration::from_micros(micros as u64))
+    }
+
+    pub struct PartitionDesc {
+        pub vdsn: u32,
+        pub number: u16,
+        pub start: u32,
+        pub len: u32,
+    }
+
+    impl PartitionDesc {
+        pub fn new(buf: &[u8]) -> Result<Self, scroll::Error> {
+            Ok(PartitionDesc {
+                vdsn: buf.pread_with(16, LE)?,
+                number: buf.pread_with(22, LE)?,
+                start: buf.pread_with(188, LE)?,
+                len: buf.pread_with(192, LE)?,
+            })
+        }
+    }
+
+    #[derive(Debug)]
+    pub enum PartMap {
+        Physical {
+            number: u16,
+        },
+        // the blocks of a metadata partition are those of a file in the
+        // physical partition, its extents are filled in once it's read
+        Metadata {
+            number: u16,
+            file_lb: u32,
+            extents: Vec<Extent>,
+        },
+        // a physical partition that may remap defective packets, the
+        // remapping isn't followed
+        Sparable {
+            number: u16,
+        },
+        Other(String),
+    }
+
+    impl PartMap {
+        pub fn new(buf: &[u8]) -> Result<Self, scroll::Error> {
+            let typ: u8 = buf.pread(0)?;
+            Ok(match typ {
+                1 => PartMap::Physical {
+                    number: buf.pread_with(4, LE)?,
+                },
+                2 => {
+                    let ident = buf.get(4..36).ok_or(scroll::Error::TooBig {
+                        size: 36,
+                        len: buf.len(),
+                    })?;
+                    match regid(ident).as_str() {
+                        "*UDF Metadata Partition" => PartMap::Metadata {
+                            number: buf.pread_with(38, LE)?,
+                            file_lb: buf.pread_with(40, LE)?,
+                            extents: vec![],
+                        },
+                        "*UDF Sparable Partition" => PartMap::Sparable {
+                            number: buf.pread_with(38, LE)?,
+                        },
+                        other => PartMap::Other(other.to_string()),
+                    }
+                }
+                other => PartMap::Other(format!("type {}", other)),
+            })
+        }
+    }
+
+    pub struct LogicalVolume {
+        pub vdsn: u32,
+        pub label: String,
+        pub block_size: u32,
+        pub fsd: Extent,
+        pub maps: Vec<PartMap>,
+    }
+
+    impl LogicalVolume {
+        const MAPS_AT: usize = 440;
+
+        pub fn new(buf: &[u8]) -> Result<Self, scroll::Error> {
+            let table_len: u32 = buf.pread_with(264, LE)?;
+            let cnt: u32 = buf.pread_with(268, LE)?;
+            let table = buf
+                .get(Self::MAPS_AT..Self::MAPS_AT + table_len as usize)
+                .ok_or(scroll::Error::TooBig {
+                    size: table_len as usize,
+                    len: buf.len() - Self::MAPS_AT,
+                })?;
+            let mut maps = vec![];
+            let mut off = 0;
+            for _ in 0..cnt {
+                let len = table.pread::<u8>(off + 1)? as usize;
+                maps.push(PartMap::new(&table[off..])?);
+                off += len.max(2);
+            }
+            Ok(LogicalVolume {
+                vdsn: buf.pread_with(16, LE)?,
+                label: dstring(&buf[84..212]),
+                block_size: buf.pread_with(212, LE)?,
+                fsd: Extent::long(&buf[248..])?,
+                maps,
+            })
+        }
+    }
+
+    pub const FILE_TYPE_DIR: u8 = 4;
+    pub const FILE_TYPE_FILE: u8 = 5;
+    pub const FILE_TYPE_SYMLINK: u8 = 12;
+
+    // how the allocation descriptors are recorded, the low 3 bits of the
+    // ICB tag flags
+    pub const AD_SHORT: u8 = 0;
+    pub const AD_LONG: u8 = 1;
+    pub const AD_EMBEDDED: u8 = 3; // the data itself is in the entry
+
+    // a file entry or extended file entry
+    #[allow(dead_code)]
+    #[derive(Debug)]
+    pub struct FileEntry {
+        pub file_type: u8,
+        pub ad_type: u8,
+        pub uid: u32,
+        pub gid: u32,
+        pub permissions: u32,
+        pub info_len: u64,
+        pub atime: Option<SystemTime>,
+        pub mtime: Option<SystemTime>,
+        pub crtime: Option<SystemTime>, // extended file entries only
+        pub ads: Vec<u8>,
+    }
+
+    impl FileEntry {
+        pub fn new(buf: &[u8], extended: bool) -> Result<Self, scroll::Error> {
+            let flags: u16 = buf.pread_with(34, LE)?;
+            let (times, l_ea_at) = match extended {
+                true => (80, 208),
+                false => (72, 168),
+            };
+            let l_ea: u32 = buf.pread_with(l_ea_at, LE)?;
+            let l_ad: u32 = buf.pread_with(l_ea_at + 4, LE)?;
+            let ads_at = l_ea_at + 8 + l_ea as usize;
+            let ads = buf
+                .get(ads_at..ads_at + l_ad as usize)
+                .ok_or(scroll::Error::TooBig {
+                    size: l_ad as usize,
+                    len: buf.len().saturating_sub(ads_at),
+                })?;
+            Ok(FileEntry {
+                file_type: buf.pread(27)?,
+                ad_type: (flags & 7) as u8,
+                uid: buf.pread_with(36, LE)?,
+                gid: buf.pread_with(40, LE)?,
+                permissions: buf.pread_with(44, LE)?,
+                info_len: buf.pread_with(56, LE)?,
+                atime: timestamp(&buf[times..]),
+                mtime: timestamp(&buf[times + 12..]),
+                crtime: match extended {
+                    true => timestamp(&buf[times + 24..]),
+                    false => None,
+                },
+                ads: ads.to_vec(),
+            })
+        }
+
+        // the owner's write permission
+        pub fn is_rdonly(&self) -> bool {
+            self.permissions & 0x800 == 0
+        }
+    }
+
+    pub const FID_HIDDEN: u8 = 0x1;
+    pub const FID_DELETED: u8 = 0x4;
+    pub const FID_PARENT: u8 = 0x8;
+
+    // a file identifier descriptor, a directory's entry
+    #[derive(Debug)]
+    pub struct Fid {
+        pub characteristics: u8,
+        pub icb: Extent,
+        pub name: String,
+    }
+
+    // a directory's content is a run of file identifiers, each padded to
+    // 4 bytes
+    pub fn parse_fids(buf: &[u8]) -> Result<Vec<Fid>, scroll::Error> {
+        let mut ret = vec![];
+        let mut off = 0;
+        while off + 38 <= buf.len() {
+            let tag = Tag::new(&buf[off..])?;
+            if !tag.sum_ok || tag.id != TAG_FID {
+                break;
+            }
+            let l_fi = buf.pread::<u8>(off + 19)? as usize;
+            let l_iu = buf.pread_with::<u16>(off + 36, LE)? as usize;
+            let name_at = off + 38 + l_iu;
+            let name = buf
+                .get(name_at..name_at + l_fi)
+                .ok_or(scroll::Error::TooBig {
+                    size: l_fi,
+                    len: buf.len().saturating_sub(name_at),
+                })?;
+            ret.push(Fid {
+                characteristics: buf.pread(off + 18)?,
+                icb: Extent::long(&buf[off + 20..])?,
+                name: cs0(name),
+            });
+            off += (38 + l_iu + l_fi).next_multiple_of(4);
+        }
+        Ok(ret)
+    }
+
+    // a symlink's content is a run of path components, put back together
+    // as a path
+    pub fn symlink_target(buf: &[u8]) -> String {
+        let mut parts = vec![];
+        let mut absolute = false;
+        let mut off = 0;
+        while off + 4 <= buf.len() {
+            let len = buf[off + 1] as usize;
+            let ident = buf.get(off + 4..off + 4 + len).unwrap_or_default();
+            match buf[off] {
+                1 | 2 => {
+                    absolute = true;
+                    parts.clear();
+                }
+                3 => parts.push("..".to_string()),
+                4 => parts.push(".".to_string()),
+                5 => parts.push(cs0(ident)),
+                _ => (),
+            }
+            off += 4 + len;
+        }
+        match absolute {
+            true => format!("/{}", parts.join("/")),
+            false => parts.join("/"),
+        }
+    }
+}
+
+use spec::{Extent, FileEntry, PartMap, Tag};
+
+// marks a Finfo id as an ICB location, which may be 0 or 1 otherwise
+const ID_TAG: u64 = 1 << 63;
+
+// the block size of the UDF volume starting at byte `off`, found by the
+// block its anchor is at
+pub fn probe(dev: &(impl Device + ?Sized), off: u64) -> Option<u32> {
+    [512, 1024, 2048, 4096].into_iter().find(|&bs| {
+        let mut buf = vec![0u8; bs as usize];
+        dev.read_exact_at(&mut buf, off + spec::ANCHOR_BLOCK as u64 * bs as u64)
+            .is_ok()
+            && Tag::new(&buf).is_ok_and(|tag| tag.is(spec::TAG_AVDP, spec::ANCHOR_BLOCK))
+    })
+}
+
+pub struct Fio<D: Device> {
+    device: D,
+    pub block_size: u32,
+    pub label: String,
+    pub volume_set: String,
+    // partition number -> (first block, blocks)
+    pub partitions: BTreeMap<u16, (u32, u32)>,
+    maps: Vec<PartMap>,
+    root: Extent,
+    // ICB locations of the directories listed so far, a Finfo's fst_clus is
+    // the index here
+    dirs: Vec<Extent>,
+    dir_nos: BTreeMap<(u16, u32), u32>,
+    // what readdir pages through
+    listed: fio::LastListed,
+}
+
+impl<D: Device> Fio<D> {
+    pub fn new(device: D) -> io::Result<Self> {
+        let _p = trace::purpose("boot");
+        Ok(Self::load(device)?)
+    }
+
+    fn load(device: D) -> Result<Self, Error> {
+        let block_size = probe(&device, 0).ok_or(Error::Descriptor(
+            "anchor volume descriptor pointer",
+            spec::ANCHOR_BLOCK,
+        ))?;
+        let mut fio = Fio {
+            device,
+            block_size,
+            label: String::new(),
+            volume_set: String::new(),
+            partitions: BTreeMap::new(),
+            maps: vec![],
+            root: Extent {
+                len: 0,
+                kind: spec::RECORDED,
+                lb: 0,
+                part_ref: 0,
+            },
+            dirs: vec![],
+            dir_nos: BTreeMap::new(),
+            listed: fio::LastListed::default(),
+        };
+        let anchor = fio.read_sector(spec::ANCHOR_BLOCK)?;
+        let lvd = fio.read_vds(anchor.pread_with(20, LE)?, anchor.pread_with(16, LE)?)?;
+        fio.label = lvd.label;
+        fio.maps = lvd.maps;
+
+        // the metadata file of a metadata partition sits in the physical
+        // partition of the same number
+        for i in 0..fio.maps.len() {
+            let PartMap::Metadata {
+                number, file_lb, ..
+            } = fio.maps[i]
+            else {
+                continue;
+            };
+            let phys = fio
+                .maps
+                .iter()
+                .position(|m| matches!(m, PartMap::Physical { number: n } if *n == number))
+                .ok_or(Error::PartitionType(
+                    "metadata without physical".to_string(),
+                ))?;
+            let icb = Extent {
+                len: block_size,
+                kind: spec::RECORDED,
+                lb: file_lb,
+                part_ref: phys as u16,
+            };
+            let fe = fio.read_fe(&icb)?;
+            let found = fio.extents(&fe, phys as u16)?;
+            if let PartMap::Metadata { extents, .. } = &mut fio.maps[i] {
+                *extents = found;
+            }
+        }
+
+        let fsd = fio.read_block(lvd.fsd.part_ref, lvd.fsd.lb)?;
+        if !Tag::new(&fsd)?.is(spec::TAG_FSD, lvd.fsd.lb) {
+            return Err(Error::Descriptor("file set descriptor", lvd.fsd.lb));
+        }
+        fio.root = Extent::long(&fsd[400..])?;
+        fio.dirs.push(fio.root);
+        fio.dir_nos.insert((fio.root.part_ref, fio.root.lb), 0);
+        Ok(fio)
+    }
+
+    fn read_sector(&self, no: u32) -> Result<Vec<u8>, Error> {
+        let mut buf = vec![0u8; self.block_size as usize];
+        self.device
+            .read_exact_at(&mut buf, no as u64 * self.block_size as u64)?;
+        Ok(buf)
+    }
+
+    // the volume descriptor sequence of `len` bytes at sector `loc`: the
+    // partitions found land in self, the logical volume is returned
+    fn read_vds(&mut self, mut loc: u32, len: u32) -> Result<spec::LogicalVolume, Error> {
+        let mut lvd: Option<spec::LogicalVolume> = None;
+        let mut vdsns: BTreeMap<u16, u32> = BTreeMap::new();
+        let mut pvd_vdsn = None;
+        let bs = self.block_size;
+        let seq_end = |loc: u32, len: u32| {
+            loc.checked_add(len / bs)
+                .ok_or(Error::Descriptor("volume descriptor sequence", loc))
+        };
+        let mut end = seq_end(loc, len)?;
+        // a pointer may continue the sequence elsewhere, a few times at most
+        let mut hops = 0;
+        while loc < end {
+            let buf = self.read_sector(loc)?;
+            let tag = Tag::new(&buf)?;
+            if !tag.is(tag.id, loc) {
+                break;
+            }
+            match tag.id {
+                spec::TAG_PVD => {
+                    let vdsn: u32 = buf.pread_with(16, LE)?;
+                    if pvd_vdsn.is_none_or(|v| vdsn >= v) {
+                        pvd_vdsn = Some(vdsn);
+                        self.volume_set = spec::dstring(&buf[72..200]);
+                    }
+                }
+                spec::TAG_PD => {
+                    let pd = spec::PartitionDesc::new(&buf)?;
+                    if vdsns.get(&pd.number).is_none_or(|&v| pd.vdsn >= v) {
+                        vdsns.insert(pd.number, pd.vdsn);
+                        let on_device = (fio::reach(&self.device) / self.block_size as u64)
+                            .saturating_sub(pd.start as u64);
+                        let len = fio::fit_count(
+                            "udf",
+                            "partition length",
+                            pd.len as u64,
+                            &[(on_device, "the device")],
+                        );
+                        self.partitions.insert(pd.number, (pd.start, len as u32));
+                    }
+                }
+                spec::TAG_LVD => {
+                    let new = spec::LogicalVolume::new(&buf)?;
+                    if lvd.as_ref().is_none_or(|l| new.vdsn >= l.vdsn) {
+                        lvd = Some(new);
+                    }
+                }
+                spec::TAG_VDP if hops < 8 => {
+                    let len: u32 = buf.pread_with(20, LE)?;
+                    loc = buf.pread_with(24, LE)?;
+                    end = seq_end(loc, len)?;
+                    hops += 1;
+                    continue;
+                }
+                spec::TAG_TD => break,
+                _ => (),
+            }
+            loc += 1;
+        }
+        let lvd = lvd.ok_or(Error::Descriptor("logical volume descriptor", loc))?;
+        if lvd.block_size != self.block_size {
+            return Err(Error::PartitionType(format!(
+                "{} byte block",
+                lvd.block_size
+            )));
+        }
+        Ok(lvd)
+    }
+
+    // the device offset of block `lb` of partition reference `part_ref`,
+    // and how many blocks from there on are contiguous
+    fn locate(&self, part_ref: u16, lb: u32) -> Result<(u64, u32), Error> {
+        let bs = self.block_size;
+        let physical = |number: &u16, lb: u32| {
+            let &(start, len) = self.partitions.get(number)?;
+            let left = len.checked_sub(lb).filter(|&left| left > 0)?;
+            Some(((start as u64 + lb as u64) * bs as u64, left))
+        };
+        match self.maps.get(part_ref as usize) {
+            Some(PartMap::Physical { number } | PartMap::Sparable { number }) => {
+                physical(number, lb).ok_or(Error::Unmapped(part_ref, lb))
+            }
+            Some(PartMap::Metadata {
+                number, extents, ..
+            }) => {
+                let mut rel = lb;
+                for e in extents {
+                    let blocks = e.len.div_ceil(bs);
+                    if rel < blocks {
+                        let (pos, left) =
+                            physical(number, e.lb + rel).ok_or(Error::Unmapped(part_ref, lb))?;
+                        return Ok((pos, left.min(blocks - rel)));
+                    }
+                    rel -= blocks;
+                }
+                Err(Error::Unmapped(part_ref, lb))
+            }
+            Some(PartMap::Other(typ)) => Err(Error::PartitionType(typ.clone())),
+            None => Err(Error::Unmapped(part_ref, lb)),
+        }
+    }
+
+    fn read_block(&self, part_ref: u16, lb: u32) -> Result<Vec<u8>, Error> {
+        let mut buf = vec![0u8; self.block_size as usize];
+        let (pos, _) = self.locate(part_ref, lb)?;
+        self.device.read_exact_at(&mut buf, pos)?;
+        Ok(buf)
+    }
+
+    pub fn read_fe(&self, icb: &Extent) -> Result<FileEntry, Error> {
+        let _p = trace::purpose("dirent");
+        let buf = self.read_block(icb.part_ref, icb.lb)?;
+        let tag = Tag::new(&buf)?;
+        if !tag.is(spec::TAG_FE, icb.lb) && !tag.is(spec::TAG_EFE, icb.lb) {
+            return Err(Error::Descriptor("file entry", icb.lb));
+        }
+        Ok(FileEntry::new(&buf, tag.id == spec::TAG_EFE)?)
+    }
+
+    // the extents of a file, following allocation extent descriptors. short
+    // ones are in the partition of the file entry, `part_ref`
+    fn extents(&self, fe: &FileEntry, part_ref: u16) -> Result<Vec<Extent>, Error> {
+        let sz = match fe.ad_type {
+            spec::AD_SHORT => Extent::SHORT_SZ,
+            spec::AD_LONG => Extent::LONG_SZ,
+            spec::AD_EMBEDDED => return Ok(vec![]),
+            other => return Err(Error::AdType(other)),
+        };
+        let mut ret = vec![];
+        let mut ads = fe.ads.clone();
+        // the chain can't be longer than the volume, cut it off way before
+        for _ in 0..1024 {
+            let mut next = None;
+            for ad in ads.chunks_exact(sz) {
+                let e = match fe.ad_type {
+                    spec::AD_SHORT => Extent::short(ad, part_ref)?,
+                    _ => Extent::long(ad)?,
+                };
+                if e.len == 0 {
+                    break;
+                }
+                if e.kind == spec::NEXT {
+                    next = Some(e);
+                    break;
+                }
+                ret.push(e);
+            }
+            let Some(e) = next else {
+                return Ok(ret);
+            };
+            let buf = self.read_block(e.part_ref, e.lb)?;
+            if !Tag::new(&buf)?.is(spec::TAG_AED, e.lb) {
+                return Err(Error::Descriptor("allocation extent descriptor", e.lb));
+            }
+            let len = buf.pread_with::<u32>(20, LE)? as usize;
+            ads = buf[24..(24 + len).min(buf.len())].to_vec();
+        }
+        Ok(ret)
+    }
+
+    // where a file's bytes in [offset, offset + size) are, as runs of
+    // (device offset, len) up to its end. none for the runs of unrecorded
+    // extents, which read as zeros
+    fn file_extents(
+        &self,
+        fe: &FileEntry,
+        part_ref: u16,
+        offset: u64,
+        size: u64,
+    ) -> Result<Vec<(Option<u64>, usize)>, Error> {
+        let end = (offset + size).min(fe.info_len);
+        let bs = self.block_size as u64;
+        let mut ret = vec![];
+        let mut at = 0u64; // where the extent starts in the file
+        for e in self.extents(fe, part_ref)? {
+            let (from, to) = (offset.max(at), end.min(at + e.len as u64));
+            if from < to && e.kind != spec::RECORDED {
+                ret.push((None, (to - from) as usize));
+            } else if from < to {
+                let rel = from - at;
+                let mut lb = e.lb + (rel / bs) as u32;
+                let mut skip = rel % bs;
+                let mut left = to - from;
+                while left > 0 {
+                    let (pos, blocks) = self.locate(e.part_ref, lb)?;
+                    let n = left.min(blocks as u64 * bs - skip);
+                    ret.push((Some(pos + skip), n as usize));
+                    left -= n;
+                    lb += blocks;
+                    skip = 0;
+                }
+            }
+            at += e.len as u64;
+            if at >= end {
+                break;
+            }
+        }
+        Ok(ret)
+    }
+
+    // `size` bytes of a file's content from `offset`, short at its end
+    fn content(
+        &self,
+        fe: &FileEntry,
+        part_ref: u16,
+        offset: u64,
+        size: u64,
+    ) -> Result<Vec<u8>, Error> {
+        if fe.ad_type == spec::AD_EMBEDDED {
+            let end = (offset + size).min(fe.info_len) as usize;
+            let from = (offset as usize).min(fe.ads.len());
+            let to = end.min(fe.ads.len()).max(from);
+            return Ok(fe.ads[from..to].to_vec());
+        }
+        let mut ret = vec![];
+        for (pos, len) in self.file_extents(fe, part_ref, offset, size)? {
+            let at = ret.len();
+            ret.resize(at + len, 0);
+            if let Some(pos) = pos {
+                self.device.read_exact_at(&mut ret[at..], pos)?;
+            }
+        }
+        Ok(ret)
+    }
+
+    fn list(&mut self, dir: Extent) -> Result<Vec<Finfo>, Error> {
+        let fe = self.read_fe(&dir)?;
+        if fe.file_type != spec::FILE_TYPE_DIR {
+            return Ok(vec![]);
+        }
+        let buf = self.content(&fe, dir.part_ref, 0, fe.info_len)?;
+        let mut ret = vec![];
+        for fid in spec::parse_fids(&buf)? {
+            if fid.characteristics & (spec::FID_DELETED | spec::FID_PARENT) != 0 {
+                continue;
+            }
+            let fe = self.read_fe(&fid.icb)?;
+            let (is_dir, size, fst_clus) = match fe.file_type {
+                spec::FILE_TYPE_DIR => (true, 0, self.dir_no(fid.icb)),
+                spec::FILE_TYPE_FILE => (false, fe.info_len, 0),
+                // the target is what reading a symlink gives
+                spec::FILE_TYPE_SYMLINK => {
+                    let raw = self.content(&fe, fid.icb.part_ref, 0, fe.info_len)?;
+                    (false, spec::symlink_target(&raw).len() as u64, 0)
+                }
+                // devices, fifos, sockets and the like
+                _ => continue,
+            };
+            let mtime = fe.mtime.unwrap_or(SystemTime::UNIX_EPOCH);
+            ret.push(Finfo {
+                pos: ret.len() as u64,
+                id: ID_TAG | (fid.icb.part_ref as u64) << 32 | fid.icb.lb as u64,
+                name: fio::shown_name(fid.name),
+                is_rdonly: fe.is_rdonly(),
+                is_hidden: fid.characteristics & spec::FID_HIDDEN != 0,
+                is_system: false,
+                is_dir,
+                size,
+                valid_size: size,
+                fst_clus,
+                no_fat_chain: false,
+                crt_time: fe.crtime.unwrap_or(mtime),
+                wrt_time: mtime,
+                acc_time: fe.atime.unwrap_or(mtime),
+            });
+        }
+        Ok(ret)
+    }
+
+    fn dir_no(&mut self, icb: Extent) -> u32 {
+        if let Some(&no) = self.dir_nos.get(&(icb.part_ref, icb.lb)) {
+            return no;
+        }
+        self.dirs.push(icb);
+        let no = self.dirs.len() as u32 - 1;
+        self.dir_nos.insert((icb.part_ref, icb.lb), no);
+        no
+    }
+
+    // the ICB a Finfo id points at
+    fn icb_of(&self, fi: &Finfo) -> Extent {
+        Extent {
+            len: self.block_size,
+            kind: spec::RECORDED,
+            lb: fi.id as u32,
+            part_ref: (fi.id >> 32) as u16,
+        }
+    }
+
+    fn read(&self, fi: &Finfo, offset: u64, size: u32) -> Result<Vec<u8>, Error> {
+        let _p = trace::purpose("data");
+        let icb = self.icb_of(fi);
+        let fe = self.read_fe(&icb)?;
+        if fe.file_type == spec::FILE_TYPE_SYMLINK {
+            let raw = self.content(&fe, icb.part_ref, 0, fe.info_len)?;
+            let target = spec::symlink_target(&raw).into_bytes();
+            let from = offset.min(target.len() as u64) as usize;
+            let to = (from + size as usize).min(target.len());
+            return Ok(target[from..to].to_vec());
+        }
+        self.content(&fe, icb.part_ref, offset, size as u64)
+    }
+}
+
+impl<D: Device> fio::Fio for Fio<D> {
+    fn list_dir(&mut self, no: u32) -> io::Result<Vec<Finfo>> {
+        match self.dirs.get(no as usize) {
+            Some(&dir) => Ok(self.list(dir)?),
+            None => Ok(vec![]),
+        }
+    }
+
+    fn list_root(&mut self) -> io::Result<Vec<Finfo>> {
+        self.list_dir(0)
+    }
+
+    fn list_dir_page(
+        &mut self,
+        no: u32,
+        from: u64,
+        max: usize,
+    ) -> io::Result<(Vec<Finfo>, Option<u64>)> {
+        let mut listed = std::mem::take(&mut self.listed);
+        let page = listed.page(no, from, max, |no| match self.dirs.get(no as usize) {
+            Some(&dir) => Ok(self.list(dir)?),
+            None => Ok(vec![]),
+        });
+        self.listed = listed;
+        page
+    }
+
+    fn read_file(&mut self, fi: &Finfo, offset: u64, size: u32) -> io::Result<Vec<u8>> {
+        self.read(fi, offset, size).map_err(io::Error::from)
+    }
+
+    // symlinks and embedded data come from the file entry, none of them
+    // is counted
+    fn unreadable(&mut self, fi: &Finfo, offset: u64, size: u32) -> Vec<(u64, u64)> {
+        let icb = self.icb_of(fi);
+        let Ok(fe) = self.read_fe(&icb) else {
+            return vec![];
+        };
+        if fe.file_type == spec::FILE_TYPE_SYMLINK {
+            return vec![];
+        }
+        let extents = self
+            .file_extents(&fe, icb.part_ref, offset, size as u64)
+            .unwrap_or_default();
+        // runs of zeros aren't on the device, each run on it is asked alone
+        let mut spans = vec![];
+        let mut at = 0;
+        for (pos, len) in extents {
+            if let Some(pos) = pos {
+                for (start, n) in self.device.bad_spans(&[(pos, len)]) {
+                    spans.push((at + start, n));
+                }
+            }
+            at += len;
+        }
+        fio::file_spans(offset, spans)
+    }
+
+    // the volume set identifier starts with 8 hex digits meant to be unique,
+    // the serial blkid shows
+    fn volume(&mut self) -> io::Result<fio::VolumeInfo> {
+        let serial = self
+            .volume_set
+            .get(..8)
+            .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+            .unwrap_or(0);
+        Ok(fio::VolumeInfo {
+            serial,
+            label: self.label.clone(),
+            clus_sz: self.block_size,
+            clus_cnt: self
+                .partitions
+                .values()
+                .map(|&(_, len)| len as u64)
+                .sum::<u64>()
+                .min(u32::MAX as u64) as u32,
+            free: None,
+        })
+    }
+
+    // the dirs listed so far and the one listed last
+    fn held(&self) -> u64 {
+        let tables = self.dirs.len() * std::mem::size_of::<Extent>() + self.dir_nos.len() * 48;
+        tables as u64 + self.listed.held()
+    }
+}